@@ -1,14 +1,11 @@
 extern crate irsc;
 extern crate env_logger;
-extern crate openssl;
 
 use irsc::color::bold;
 use irsc::*;
 use irsc::Command::*;
 use irsc::Reply::*;
 
-use openssl::ssl::{ Ssl, SslContext, SslMethod };
-
 static NAME: &'static str = "rusticbot";
 static DESC: &'static str = "A bot, written in Rust.";
 
@@ -38,8 +35,7 @@ fn callback(server: &mut Client, msg: &Message, event: Option<Event>) {
 fn main() {
     env_logger::init().unwrap();
     let mut s = OwnedClient::new();
-    let ssl = Ssl::new(&SslContext::new(SslMethod::Tlsv1).unwrap()).unwrap();
-    s.connect_ssl("irc.mozilla.org", 6697, ssl);
+    s.connect_tls("irc.mozilla.org", 6697, &TlsConfig::new());
     s.register(NAME, NAME, DESC, None);
 
     // Dedicate this thread to listening and event processing
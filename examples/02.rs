@@ -1,13 +1,10 @@
 extern crate irsc;
-extern crate openssl;
 extern crate env_logger;
 
 use irsc::*;
 use irsc::Command::*;
 use irsc::Reply::*;
 
-use openssl::ssl::{ Ssl, SslContext, SslMethod };
-
 // Here should be the constants of this bot, like the nickname or description,
 // to avoid repetition. However, for better readability, the constants have been
 // inlined below.
@@ -19,13 +16,10 @@ fn main() {
     env_logger::init().ok().expect("Failed to initialise env_logger");
 
     let mut s = OwnedClient::new();
-    // Try to use Tlsv1 to connect. This might fail, depending on your version of
-    // OpenSSL. This example does not try with other methods on failure.
-    let ssl = Ssl::new(&SslContext::new(SslMethod::Tlsv1).unwrap()).unwrap();
-
-    // Connect using the newly constructed Ssl configuration.
-    // If Ssl is not desired, use .connect(...) without the ssl argument.
-    s.connect_ssl("irc.mozilla.org", 6697, ssl);
+    // Connect with TLS. The default configuration verifies the certificate
+    // and hostname; see TlsConfig for client certificates and pinning.
+    // If TLS is not desired, use .connect(...) instead.
+    s.connect_tls("irc.mozilla.org", 6697, &TlsConfig::new());
 
     // Send the USER and NICK message in one go:
     // register(nick, user, description, [password])
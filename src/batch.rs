@@ -0,0 +1,240 @@
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+
+use message::Message;
+use ident::Ident;
+use text;
+
+/// A group of messages the server sent with `BATCH`, collected until its end.
+///
+/// Messages of nested batches are part of their parent, in the order they
+/// arrived, together with the `BATCH` lines that start and end them.
+/// The `batch` tag of each message tells which batch it belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    pub reference: String,
+    pub kind: String,
+    pub params: Vec<String>,
+    pub messages: Vec<Message>,
+    /// The `label` of the command this batch answers, for `labeled-response`.
+    pub label: Option<String>,
+    parent: Option<String>
+}
+
+/// Servers that split or rejoined, and the users that went or came with them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Netsplit {
+    pub servers: (String, String),
+    pub users: Vec<Ident>
+}
+
+impl Batch {
+    fn users(&self, command: &[u8]) -> Vec<Ident> {
+        self.messages.iter()
+            .filter(|m| &*m.command() == command && m.tag("batch") == Some(&self.reference[..]))
+            .filter_map(|m| m.ident())
+            .collect()
+    }
+
+    fn servers(&self) -> Option<(String, String)> {
+        match (self.params.get(0), self.params.get(1)) {
+            (Some(a), Some(b)) => Some((a.clone(), b.clone())),
+            _ => None
+        }
+    }
+
+    /// The QUITs of a `netsplit` batch.
+    pub fn netsplit(&self) -> Option<Netsplit> {
+        if self.kind != "netsplit" { return None }
+        self.servers().map(|s| Netsplit { servers: s, users: self.users(b"QUIT") })
+    }
+
+    /// The JOINs of a `netjoin` batch.
+    pub fn netjoin(&self) -> Option<Netsplit> {
+        if self.kind != "netjoin" { return None }
+        self.servers().map(|s| Netsplit { servers: s, users: self.users(b"JOIN") })
+    }
+
+    /// The target of a `chathistory` batch.
+    pub fn chathistory_target(&self) -> Option<&str> {
+        if self.kind == "chathistory" { self.params.first().map(|s| &s[..]) } else { None }
+    }
+
+    /// Messages directly in this batch, not in nested ones.
+    pub fn own_messages(&self) -> Vec<&Message> {
+        self.messages.iter().filter(|m| m.tag("batch") == Some(&self.reference[..])).collect()
+    }
+}
+
+/// What became of a message passed to `Batches::handle`.
+#[derive(Debug)]
+pub enum Collected {
+    /// Not part of any batch.
+    Not,
+    /// Kept for a batch that isn't complete yet.
+    Absorbed,
+    /// This ended a batch that isn't nested in another.
+    Complete(Batch)
+}
+
+/// Batches that have been started, but not ended yet.
+#[derive(Debug, Default)]
+pub struct Batches {
+    open: HashMap<String, Batch>
+}
+
+impl Batches {
+    pub fn new() -> Batches { Batches::default() }
+
+    pub fn clear(&mut self) { self.open.clear() }
+
+    pub fn is_open(&self, reference: &str) -> bool { self.open.contains_key(reference) }
+
+    /// Whether `reference` is an open batch of replayed messages, `chathistory`
+    /// or `znc.in/playback`, or is nested in one.
+    pub fn is_replay(&self, reference: &str) -> bool {
+        let mut next = self.open.get(reference);
+        while let Some(b) = next {
+            if b.kind == "chathistory" || b.kind == "znc.in/playback" { return true }
+            next = b.parent.as_ref().and_then(|p| self.open.get(p));
+        }
+        false
+    }
+
+    pub fn handle(&mut self, msg: &Message) -> Collected {
+        let parent = msg.tag("batch").map(ToOwned::to_owned)
+            .and_then(|p| if self.open.contains_key(&p) { Some(p) } else { None });
+
+        if &*msg.command() == b"BATCH" {
+            let elements: Vec<String> = msg.elements().iter()
+                .map(|e| text::def_lossy_decode(e)).collect();
+            let reference = match elements.first() {
+                Some(r) if r.len() > 1 => r.clone(),
+                _ => return Collected::Not
+            };
+
+            if reference.starts_with("+") {
+                if let Some(ref p) = parent {
+                    self.open.get_mut(p).unwrap().messages.push(msg.clone());
+                }
+                self.open.insert(reference[1..].to_owned(), Batch {
+                    reference: reference[1..].to_owned(),
+                    kind: elements.get(1).cloned().unwrap_or(String::new()),
+                    params: elements.iter().skip(2).cloned().collect(),
+                    messages: Vec::new(),
+                    label: msg.tag("label").map(ToOwned::to_owned),
+                    parent: parent
+                });
+                return Collected::Absorbed
+            } else if reference.starts_with("-") {
+                let batch = match self.open.remove(&reference[1..]) {
+                    Some(b) => b,
+                    None => return Collected::Not
+                };
+                return match batch.parent.as_ref().and_then(|p| self.open.get_mut(p)) {
+                    Some(parent) => {
+                        parent.messages.extend(batch.messages.into_iter());
+                        parent.messages.push(msg.clone());
+                        Collected::Absorbed
+                    },
+                    None => Collected::Complete(batch)
+                }
+            }
+        }
+
+        match parent {
+            Some(p) => {
+                self.open.get_mut(&p).unwrap().messages.push(msg.clone());
+                Collected::Absorbed
+            },
+            None => Collected::Not
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use batch::{ Batches, Collected };
+
+    fn feed(b: &mut Batches, lines: &[&str]) -> Vec<Collected> {
+        lines.iter().map(|l| b.handle(&Message::parse(l.as_bytes()).unwrap())).collect()
+    }
+
+    fn complete(c: Option<Collected>) -> ::batch::Batch {
+        match c {
+            Some(Collected::Complete(b)) => b,
+            other => panic!("Expected a complete batch, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn nested() {
+        let mut b = Batches::new();
+        let mut c = feed(&mut b, &[
+            ":irc.host BATCH +outer example.com/outer",
+            "@batch=outer :nick!u@h PRIVMSG #rust :one",
+            "@batch=outer :irc.host BATCH +inner example.com/inner",
+            "@batch=inner :nick!u@h PRIVMSG #rust :two",
+            "@batch=outer :irc.host BATCH -inner",
+            ":nick!u@h PRIVMSG #rust :live",
+            ":irc.host BATCH -outer"
+        ]);
+        let outer = complete(c.pop());
+        assert!(match c.pop() { Some(Collected::Not) => true, _ => false });
+        assert!(c.iter().all(|c| match *c { Collected::Absorbed => true, _ => false }));
+        assert!(!b.is_open("outer") && !b.is_open("inner"));
+
+        feed(&mut b, &[
+            ":irc.host BATCH +h chathistory #rust",
+            "@batch=h :irc.host BATCH +n example.com/inner",
+            ":irc.host BATCH +o example.com/other"
+        ]);
+        assert!(b.is_replay("h") && b.is_replay("n"));
+        assert!(!b.is_replay("o") && !b.is_replay("gone"));
+
+        assert_eq!(outer.kind, "example.com/outer");
+        assert_eq!(outer.messages.len(), 4);
+        assert_eq!(outer.own_messages().len(), 3);
+        assert_eq!(outer.messages[2].tag("batch"), Some("inner"));
+    }
+
+    #[test]
+    fn netsplit_and_netjoin() {
+        let mut b = Batches::new();
+        let split = complete(feed(&mut b, &[
+            ":irc.host BATCH +s netsplit irc.hub other.host",
+            "@batch=s :a!u@h QUIT :irc.hub other.host",
+            "@batch=s :b!u@h QUIT :irc.hub other.host",
+            ":irc.host BATCH -s"
+        ]).pop()).netsplit().unwrap();
+        assert_eq!(split.servers, ("irc.hub".to_owned(), "other.host".to_owned()));
+        assert_eq!(split.users.iter().map(|i| &i.nickname[..]).collect::<Vec<_>>(), vec!["a", "b"]);
+
+        let join = complete(feed(&mut b, &[
+            ":irc.host BATCH +j netjoin irc.hub other.host",
+            "@batch=j :a!u@h JOIN #rust",
+            ":irc.host BATCH -j"
+        ]).pop());
+        assert!(join.netsplit().is_none());
+        assert_eq!(join.netjoin().unwrap().users.len(), 1);
+    }
+
+    #[test]
+    fn never_closed() {
+        let mut b = Batches::new();
+        feed(&mut b, &[
+            ":irc.host BATCH +open chathistory #rust",
+            "@batch=open :nick!u@h PRIVMSG #rust :old"
+        ]);
+        assert!(b.is_open("open"));
+        // Ends of unknown batches, and tags naming them, aren't taken.
+        let c = feed(&mut b, &[":irc.host BATCH -other", "@batch=other :nick!u@h PRIVMSG #rust :hi"]);
+        assert!(c.iter().all(|c| match *c { Collected::Not => true, _ => false }));
+
+        b.clear();
+        assert!(!b.is_open("open"));
+        let c = feed(&mut b, &["@batch=open :nick!u@h PRIVMSG #rust :new"]);
+        assert!(match c[0] { Collected::Not => true, _ => false });
+    }
+}
@@ -0,0 +1,32 @@
+//! A minimal IRC server for testing, built on `irsc::server`.
+//!
+//! Usage: `irscd [address] [server name]`, which defaults to
+//! `127.0.0.1:6667` and `irc.local`.
+
+extern crate irsc;
+
+use std::env;
+use std::net::TcpListener;
+use std::process;
+
+use irsc::server::{ Config, Server };
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or("127.0.0.1:6667".to_owned());
+    let mut config = Config::default();
+    if let Some(name) = args.next() { config.name = name }
+
+    let listener = match TcpListener::bind(&addr[..]) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", addr, e);
+            process::exit(1)
+        }
+    };
+    println!("{} listening on {}", config.name, addr);
+    if let Err(e) = Server::new(config).listen(listener) {
+        eprintln!("{}", e);
+        process::exit(1)
+    }
+}
@@ -0,0 +1,241 @@
+use std::borrow::ToOwned;
+use std::collections::VecDeque;
+use std::cmp;
+
+use linear_map::LinearMap;
+
+use message::{ self, Message };
+use batch::Batch;
+use casemap::CaseMapping;
+use label::{ pending, Pending, Promise };
+use timestamp::Timestamp;
+use text;
+use ::IrscError;
+
+/// A network of a bouncer with `soju.im/bouncer-networks`, like soju.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    pub id: String,
+    /// Like `name`, `host`, `port`, `nickname` and `state`.
+    pub attributes: LinearMap<String, String>
+}
+
+impl Network {
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(|v| &v[..])
+    }
+
+    /// The name to show, falling back to the host and the id.
+    pub fn name(&self) -> &str {
+        self.attribute("name").or(self.attribute("host")).unwrap_or(&self.id)
+    }
+
+    /// `connected`, `connecting` or `disconnected`.
+    pub fn state(&self) -> Option<&str> { self.attribute("state") }
+
+    pub fn is_connected(&self) -> bool { self.state() == Some("connected") }
+}
+
+/// Parse attributes, which are formatted like message tags.
+pub fn parse_attributes(s: &str) -> LinearMap<String, String> {
+    s.split(';').filter(|a| !a.is_empty()).map(|a| match a.find('=') {
+        Some(i) => (a[..i].to_owned(), message::unescape_tag_value(a[i + 1..].as_bytes())),
+        None => (a.to_owned(), String::new())
+    }).collect()
+}
+
+pub fn format_attributes(attributes: &[(&str, &str)]) -> String {
+    let formatted: Vec<String> = attributes.iter()
+        .map(|&(k, v)| format!("{}={}", k, message::escape_tag_value(v)))
+        .collect();
+    formatted.join(";")
+}
+
+/// What changed about a network.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetworkChange {
+    /// A network was added, or its attributes changed. Has all attributes.
+    Updated(Network),
+    Removed(String)
+}
+
+/// The networks of the bouncer, as far as it told us.
+///
+/// `BOUNCER NETWORK` lines add networks, or update the attributes they name;
+/// others are left as they were. They come as the answer to `LISTNETWORKS`,
+/// and as notifications with `soju.im/bouncer-networks-notify`.
+#[derive(Default)]
+pub struct Networks {
+    networks: LinearMap<String, Network>,
+    lists: VecDeque<Promise<Vec<Network>>>
+}
+
+impl Networks {
+    pub fn new() -> Networks { Networks::default() }
+
+    pub fn get(&self, id: &str) -> Option<&Network> { self.networks.get(id) }
+
+    pub fn all(&self) -> Vec<&Network> { self.networks.values().collect() }
+
+    pub fn clear(&mut self) { self.networks.clear() }
+
+    /// Wait for the answer to `BOUNCER LISTNETWORKS`.
+    pub fn list(&mut self) -> Pending<Vec<Network>> {
+        let (promise, pending) = pending(String::new());
+        self.lists.push_back(promise);
+        pending
+    }
+
+    /// Look at `BOUNCER NETWORK` lines, and `FAIL BOUNCER` errors, which fail
+    /// the oldest list request.
+    pub fn handle(&mut self, msg: &Message) -> Option<NetworkChange> {
+        let p: Vec<String> = msg.elements().iter().map(|e| text::def_lossy_decode(e)).collect();
+        match &*msg.command() {
+            b"BOUNCER" if p.len() >= 3 && p[0] == "NETWORK" => {
+                let id = p[1].clone();
+                if p[2] == "*" {
+                    return self.networks.remove(&id).map(|_| NetworkChange::Removed(id))
+                }
+                let network = self.networks.entry(id.clone())
+                    .or_insert_with(|| Network { id: id, attributes: LinearMap::new() });
+                for (k, v) in parse_attributes(&p[2]) {
+                    // An empty value removes the attribute.
+                    if v.is_empty() { network.attributes.remove(&k); }
+                    else { network.attributes.insert(k, v); }
+                }
+                Some(NetworkChange::Updated(network.clone()))
+            },
+            b"FAIL" if p.len() >= 2 && p[0] == "BOUNCER" => {
+                if let Some(promise) = self.lists.pop_front() {
+                    promise.complete(Err(IrscError::Reply(p[1].clone(), p[p.len() - 1].clone())));
+                }
+                None
+            },
+            _ => None
+        }
+    }
+
+    /// Take a completed batch, if it answers `LISTNETWORKS`. Its lines have
+    /// already been passed to `handle`.
+    pub fn handle_batch(&mut self, batch: &Batch) -> bool {
+        if batch.kind != "soju.im/bouncer-networks" { return false }
+        let ids: Vec<String> = batch.own_messages().iter()
+            .filter(|m| &*m.command() == b"BOUNCER")
+            .filter_map(|m| m.elements().get(1).map(|e| text::def_lossy_decode(e)))
+            .collect();
+        if let Some(promise) = self.lists.pop_front() {
+            promise.complete(Ok(ids.iter().filter_map(|id| self.networks.get(id)).cloned().collect()));
+        }
+        true
+    }
+
+    /// Fail everything that is still waiting, e.g. because the connection was lost.
+    pub fn fail_all(&mut self) {
+        for p in self.lists.drain(..) { p.complete(Err(IrscError::NotConnected)) }
+    }
+}
+
+/// What was seen of a bouncer's buffers, to replay only what was missed
+/// with `znc.in/playback`.
+#[derive(Clone, Debug, Default)]
+pub struct Playback {
+    latest: Option<Timestamp>
+}
+
+impl Playback {
+    pub fn new() -> Playback { Playback::default() }
+
+    /// The server time of the newest message seen.
+    pub fn latest(&self) -> Option<Timestamp> { self.latest }
+
+    /// Remember the server time of `msg`. Replayed messages are older than
+    /// what was seen live, so they don't move `latest` back.
+    pub fn handle(&mut self, msg: &Message) {
+        if let Some(t) = msg.server_time() {
+            self.latest = Some(self.latest.map(|l| cmp::max(l, t)).unwrap_or(t));
+        }
+    }
+
+    /// The message to `*playback` that replays `buffer` (`*` for all) since
+    /// `from`, or since the newest message seen.
+    pub fn play(&self, buffer: &str, from: Option<Timestamp>) -> String {
+        let from = from.or(self.latest).unwrap_or(Timestamp(0));
+        format!("PLAY {} {}", buffer, seconds(from))
+    }
+}
+
+/// Seconds since the epoch, with milliseconds, as ZNC wants them.
+fn seconds(t: Timestamp) -> String {
+    format!("{}.{:03}", t.millis() / 1000, t.millis() % 1000)
+}
+
+/// The buffer `msg` belongs to, as a bouncer keeps them: the channel, or the
+/// other side of a query. Our own messages, which bouncers send with
+/// `znc.in/self-message`, belong to their target.
+pub fn buffer(msg: &Message, me: &str, mapping: CaseMapping) -> Option<String> {
+    match &*msg.command() {
+        b"PRIVMSG" | b"NOTICE" | b"TAGMSG" => (),
+        _ => return None
+    }
+    let target = match msg.elements().first() {
+        Some(t) => text::def_lossy_decode(t),
+        None => return None
+    };
+    let from = msg.ident().map(|i| i.nickname);
+    if !msg.is_from_self() && mapping.eq(&target, me) { from } else { Some(target) }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use batch::{ Batches, Collected };
+    use casemap::CaseMapping;
+    use timestamp::Timestamp;
+    use bouncer::{ buffer, Networks, NetworkChange, Playback };
+
+    fn msg(s: &str) -> Message { Message::parse(s.as_bytes()).unwrap() }
+
+    #[test]
+    fn networks() {
+        let mut n = Networks::new();
+        let mut b = Batches::new();
+        let list = n.list();
+        for l in &[":bnc BATCH +a soju.im/bouncer-networks",
+                   "@batch=a :bnc BOUNCER NETWORK 1 name=Libera;state=connected",
+                   "@batch=a :bnc BOUNCER NETWORK 2 host=irc.oftc.net;state=disconnected",
+                   ":bnc BATCH -a"] {
+            let m = msg(l);
+            n.handle(&m);
+            if let Collected::Complete(batch) = b.handle(&m) { assert!(n.handle_batch(&batch)) }
+        }
+        let list = list.wait().unwrap();
+        assert_eq!(list.iter().map(|n| n.name()).collect::<Vec<_>>(), vec!["Libera", "irc.oftc.net"]);
+
+        match n.handle(&msg(":bnc BOUNCER NETWORK 2 state=connected;name=OFTC\\sIRC")) {
+            Some(NetworkChange::Updated(ref net)) => {
+                assert_eq!(net.name(), "OFTC IRC");
+                assert!(net.is_connected());
+                assert_eq!(net.attribute("host"), Some("irc.oftc.net"));
+            },
+            other => panic!("{:?}", other)
+        }
+        assert_eq!(n.handle(&msg(":bnc BOUNCER NETWORK 1 *")), Some(NetworkChange::Removed("1".to_owned())));
+        assert_eq!(n.all().len(), 1);
+    }
+
+    #[test]
+    fn playback() {
+        let mut p = Playback::new();
+        assert_eq!(p.play("*", None), "PLAY * 0.000");
+        p.handle(&msg("@time=2020-01-01T00:00:01.500Z :n!u@h PRIVMSG #rust :new"));
+        p.handle(&msg("@time=2019-12-31T23:59:00.000Z :n!u@h PRIVMSG #rust :replayed"));
+        assert_eq!(p.latest(), Some(Timestamp(1577836801500)));
+        assert_eq!(p.play("#rust", None), "PLAY #rust 1577836801.500");
+
+        let mut own = msg(":me!u@h PRIVMSG alice :hi");
+        own.set_from_self(true);
+        assert_eq!(buffer(&own, "me", CaseMapping::Rfc1459), Some("alice".to_owned()));
+        assert_eq!(buffer(&msg(":alice!u@h PRIVMSG Me :hi"), "me", CaseMapping::Rfc1459), Some("alice".to_owned()));
+        assert_eq!(buffer(&msg(":alice!u@h PRIVMSG #rust :hi"), "me", CaseMapping::Rfc1459), Some("#rust".to_owned()));
+    }
+}
@@ -0,0 +1,149 @@
+use std::borrow::ToOwned;
+
+use linear_map::LinearMap;
+
+/// What the client should send after a `CAP` message from the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reaction {
+    /// `CAP REQ` these capabilities, separated by spaces.
+    Request(String),
+    /// `CAP END`, negotiation is done.
+    End
+}
+
+/// State of IRCv3 capability negotiation.
+///
+/// Capabilities are only requested if they are both wanted by the user and
+/// offered by the server. Some offer a value, like `sts=port=6697` or
+/// `draft/multiline=max-bytes=4096`, which can be retrieved with `value`.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    wanted: Vec<String>,
+    available: LinearMap<String, String>,
+    enabled: Vec<String>,
+    negotiating: bool
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities { Capabilities::default() }
+
+    pub fn want(&mut self, cap: &str) {
+        if !self.wants(cap) { self.wanted.push(cap.to_owned()) }
+    }
+
+    pub fn wants(&self, cap: &str) -> bool {
+        self.wanted.iter().any(|c| c == cap)
+    }
+
+    pub fn wanted(&self) -> &[String] { &self.wanted }
+
+    pub fn is_available(&self, cap: &str) -> bool {
+        self.available.contains_key(cap)
+    }
+
+    /// The value the server gave for `cap`, empty if it gave none.
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        self.available.get(cap).map(|v| &v[..])
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.iter().any(|c| c == cap)
+    }
+
+    pub fn enabled(&self) -> &[String] { &self.enabled }
+
+    /// Whether registration is on hold, waiting for `CAP END`.
+    pub fn is_negotiating(&self) -> bool { self.negotiating }
+
+    /// Called when `CAP LS` was sent during registration.
+    pub fn start(&mut self) {
+        self.negotiating = true;
+        self.enabled.clear();
+    }
+
+    /// Forget everything the server said, for a new connection.
+    pub fn reset(&mut self) {
+        self.available.clear();
+        self.enabled.clear();
+        self.negotiating = false;
+    }
+
+    fn missing(&self) -> Vec<&str> {
+        self.wanted.iter()
+            .filter(|c| self.is_available(c) && !self.is_enabled(c))
+            .map(|c| &c[..]).collect()
+    }
+
+    fn finish(&mut self) -> Option<Reaction> {
+        if self.negotiating {
+            self.negotiating = false;
+            Some(Reaction::End)
+        } else { None }
+    }
+
+    /// Process a `CAP` message. `more` is true if another line of the same
+    /// reply follows.
+    pub fn handle(&mut self, subcommand: &str, more: bool, caps: &str) -> Option<Reaction> {
+        let list = caps.split(' ').filter(|c| !c.is_empty());
+        match subcommand {
+            "LS" | "NEW" => {
+                for cap in list {
+                    let (name, value) = match cap.find('=') {
+                        Some(i) => (&cap[..i], &cap[i + 1..]),
+                        None => (cap, "")
+                    };
+                    self.available.insert(name.to_owned(), value.to_owned());
+                }
+                if more { return None }
+                let missing = self.missing().connect(" ");
+                if !missing.is_empty() {
+                    Some(Reaction::Request(missing))
+                } else if subcommand == "LS" { self.finish() } else { None }
+            },
+            "ACK" => {
+                for cap in list {
+                    if cap.starts_with("-") {
+                        self.enabled.retain(|c| *c != cap[1..]);
+                    } else if !self.is_enabled(cap) {
+                        self.enabled.push(cap.to_owned());
+                    }
+                }
+                if more { None } else { self.finish() }
+            },
+            "NAK" => if more { None } else { self.finish() },
+            "DEL" => {
+                for cap in list {
+                    self.available.remove(cap);
+                    self.enabled.retain(|c| c != cap);
+                }
+                None
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cap::{ Capabilities, Reaction };
+
+    #[test]
+    fn negotiation() {
+        let mut c = Capabilities::new();
+        c.want("server-time");
+        c.want("batch");
+        c.start();
+        assert_eq!(c.handle("LS", true, "multi-prefix server-time"), None);
+        assert_eq!(c.handle("LS", false, "sts=port=6697"),
+                   Some(Reaction::Request("server-time".to_owned())));
+        assert_eq!(c.value("sts"), Some("port=6697"));
+        assert_eq!(c.handle("ACK", false, "server-time"), Some(Reaction::End));
+        assert!(c.is_enabled("server-time"));
+        assert!(!c.is_negotiating());
+
+        assert_eq!(c.handle("NEW", false, "batch"), Some(Reaction::Request("batch".to_owned())));
+        assert_eq!(c.handle("ACK", false, "batch"), None);
+        c.handle("DEL", false, "batch");
+        assert!(!c.is_enabled("batch"));
+    }
+}
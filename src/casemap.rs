@@ -0,0 +1,64 @@
+use std::str::FromStr;
+use std::borrow::ToOwned;
+
+use ::IrscError;
+
+/// Which characters a server considers equal when comparing nicknames and
+/// channel names, as announced by the `CASEMAPPING` ISUPPORT token.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CaseMapping {
+    /// Only `A-Z` and `a-z` are equal.
+    Ascii,
+    /// Like `Ascii`, and additionally `[]\~` are the uppercase of `{}|^`.
+    Rfc1459,
+    /// Like `Rfc1459`, but without `~` and `^`.
+    StrictRfc1459
+}
+
+impl Default for CaseMapping {
+    /// RFC 2812 says `rfc1459`, which is also what servers default to.
+    fn default() -> CaseMapping { CaseMapping::Rfc1459 }
+}
+
+impl FromStr for CaseMapping {
+    type Err = IrscError;
+    fn from_str(s: &str) -> ::std::result::Result<CaseMapping, IrscError> {
+        match s {
+            "ascii" => Ok(CaseMapping::Ascii),
+            "rfc1459" => Ok(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Ok(CaseMapping::StrictRfc1459),
+            _ => Err(IrscError::NotFound)
+        }
+    }
+}
+
+impl CaseMapping {
+    pub fn lower_char(&self, c: char) -> char {
+        match (*self, c) {
+            (_, 'A'...'Z') => ((c as u8) + 32) as char,
+            (CaseMapping::Ascii, c) => c,
+            (_, '[') => '{',
+            (_, ']') => '}',
+            (_, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            (_, c) => c
+        }
+    }
+
+    pub fn lower(&self, s: &str) -> String {
+        s.chars().map(|c| self.lower_char(c)).collect()
+    }
+
+    pub fn eq(&self, a: &str, b: &str) -> bool {
+        a.len() == b.len()
+            && a.chars().zip(b.chars()).all(|(a, b)| self.lower_char(a) == self.lower_char(b))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CaseMapping::Ascii => "ascii",
+            CaseMapping::Rfc1459 => "rfc1459",
+            CaseMapping::StrictRfc1459 => "strict-rfc1459"
+        }
+    }
+}
@@ -9,6 +9,8 @@ use std::net::TcpStream;
 use std::borrow::Cow::{ self, Borrowed, Owned };
 use std::sync::{ Arc, RwLock };
 use std::mem;
+use std::result;
+use std::borrow::ToOwned;
 use std::cell::UnsafeCell;
 
 use message::Message;
@@ -16,88 +18,381 @@ use command::Command;
 use command::Command::*;
 use reply::Reply;
 use event::Event;
+use cap::{ Capabilities, Reaction };
+use batch::{ Batches, Collected };
+use label::{ Labels, Pending, Response };
+use query::{ Queries, WhoisInfo, WhoEntry, ListEntry };
+use isupport::ISupport;
+use presence::{ self, Presence, Method, Change };
+use users::{ Users, User };
+use echo::Echoes;
+use history::{ Histories, Query };
+use bouncer::{ self, Networks, Network, Playback };
+use multiline::{ self, Limits, Multiline };
+use tags::{ ClientTag, Typing };
+use sts::{ Advertised, Policy, PolicyStore, MemoryStore };
+use timestamp::{ Clock, Timestamp };
 use text::*;
 use ::{ DEBUG, Result, IrscError };
 
-use openssl::ssl::{ Ssl, SslContext, SslMethod, SslStream };
+use tls::{ self, TlsConfig };
+use transport::Transport;
+use proxy::Proxy;
+use connect::ConnectOptions;
+#[cfg(feature = "websocket")]
+use websocket;
 
-/// Yes, I don't like the name either, but it's private, so...
-enum StreamKind {
-    Plain(TcpStream),
-    Ssl(SslStream<TcpStream>)
+/// What `register` was called with, to register again after an STS upgrade.
+#[derive(Clone)]
+struct Registration {
+    nick: String,
+    user: String,
+    desc: String,
+    pass: Option<String>
 }
 
-impl Write for StreamKind {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match *self {
-            StreamKind::Plain(ref mut s) => s.write(buf),
-            StreamKind::Ssl(ref mut s) => s.write(buf)
+pub struct Client {
+    stream: Option<Box<Transport>>,
+    address: Option<(String, u16)>,
+    /// The URL, when connected to a WebSocket gateway.
+    websocket: Option<String>,
+    registration: Option<Registration>,
+    sts: Box<PolicyStore>,
+    sts_upgrade: Option<u16>,
+    tls: TlsConfig,
+    proxy: Option<Proxy>,
+    options: ConnectOptions,
+    encoding: EncodingPolicy,
+    caps: Capabilities,
+    clock: Clock,
+    batches: Batches,
+    labels: Labels,
+    queries: Queries,
+    isupport: ISupport,
+    presence: Presence,
+    users: Users,
+    echoes: Echoes,
+    history: Histories,
+    networks: Networks,
+    playback: Playback,
+    /// The bouncer network to bind to during registration.
+    bind: Option<String>,
+    next_reference: u64
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            stream: None,
+            address: None,
+            websocket: None,
+            registration: None,
+            sts: Box::new(MemoryStore::new()),
+            sts_upgrade: None,
+            tls: TlsConfig::new(),
+            proxy: None,
+            options: ConnectOptions::new(),
+            encoding: EncodingPolicy::new(),
+            caps: Capabilities::new(),
+            clock: Clock::default(),
+            batches: Batches::new(),
+            labels: Labels::new(),
+            queries: Queries::new(),
+            isupport: ISupport::new(),
+            presence: Presence::new(),
+            users: Users::new(),
+            echoes: Echoes::new(),
+            history: Histories::new(),
+            networks: Networks::new(),
+            playback: Playback::new(),
+            bind: None,
+            next_reference: 0
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        match *self {
-            StreamKind::Plain(ref mut s) => s.flush(),
-            StreamKind::Ssl(ref mut s) => s.flush()
+    pub fn capabilities(&self) -> &Capabilities { &self.caps }
+
+    /// Ask for an IRCv3 capability. Call this before `register` to have it
+    /// negotiated during registration; afterwards, it's requested right away
+    /// if the server offers it.
+    pub fn request_capability(&mut self, cap: &str) -> Result<()> {
+        // Replayed messages only carry the time they were sent with server-time.
+        if cap == "znc.in/playback" {
+            let r = self.request_capability("server-time");
+            if r.is_err() { return r }
         }
+        self.caps.want(cap);
+        if self.caps.is_available(cap) && !self.caps.is_enabled(cap) && !self.caps.is_negotiating() {
+            self.send(CAP(None, "REQ".into(), false, cap.into()))
+        } else { Result(Ok(())) }
     }
-}
 
-impl Read for StreamKind {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match *self {
-            StreamKind::Plain(ref mut s) => s.read(buf),
-            StreamKind::Ssl(ref mut s) => s.read(buf)
+    /// Which clock `Message::timestamp` follows, see `Clock`.
+    pub fn clock(&self) -> Clock { self.clock }
+    pub fn set_clock(&mut self, clock: Clock) { self.clock = clock }
+
+    /// How incoming lines are decoded and outgoing ones encoded.
+    pub fn encoding(&self) -> &EncodingPolicy { &self.encoding }
+    pub fn encoding_mut(&mut self) -> &mut EncodingPolicy { &mut self.encoding }
+    pub fn set_encoding(&mut self, policy: EncodingPolicy) { self.encoding = policy }
+
+    /// Users on the channels we're in. Request `account-notify`, `away-notify`,
+    /// `extended-join`, `chghost`, `setname` and `account-tag` to keep their
+    /// accounts, away messages, hosts and real names current.
+    pub fn users(&self) -> &Users { &self.users }
+
+    pub fn user(&self, nick: &str) -> Option<&User> { self.users.get(nick) }
+
+    /// What the server announced in `RPL_ISUPPORT`.
+    pub fn isupport(&self) -> &ISupport { &self.isupport }
+
+    /// Users watched with `watch`, and which of them are online.
+    pub fn presence(&self) -> &Presence { &self.presence }
+
+    /// Get `Event::Online` and `Event::Offline` when `nick` comes or goes.
+    /// Uses MONITOR or WATCH if the server has them, and ISON otherwise.
+    pub fn watch(&mut self, nick: &str) -> Result<()> {
+        let actions = self.presence.watch(nick);
+        self.send_presence(actions)
+    }
+
+    pub fn unwatch(&mut self, nick: &str) -> Result<()> {
+        let actions = self.presence.unwatch(nick);
+        self.send_presence(actions)
+    }
+
+    /// Check with ISON on the watched users that MONITOR or WATCH can't take.
+    /// This also happens whenever the server pings.
+    pub fn poll_presence(&mut self) -> Result<()> {
+        let actions = self.presence.poll();
+        self.send_presence(actions)
+    }
+
+    fn send_presence(&mut self, actions: Vec<presence::Action>) -> Result<()> {
+        let method = self.presence.method();
+        for action in actions {
+            let (add, list) = match action {
+                presence::Action::Ison(list) => {
+                    let r = self.send(ISON(list.iter().map(|n| (&n[..]).into()).collect()));
+                    if r.is_err() { return r }
+                    continue
+                },
+                presence::Action::Add(list) => ("+", list),
+                presence::Action::Remove(list) => ("-", list)
+            };
+            let r = if method == Some(Method::Monitor) {
+                let targets = list.join(",");
+                self.send(MONITOR(add.into(), Some((&targets[..]).into())))
+            } else {
+                let entries: Vec<String> = list.iter().map(|n| format!("{}{}", add, n)).collect();
+                self.send(WATCH(entries.iter().map(|e| (&e[..]).into()).collect()))
+            };
+            if r.is_err() { return r }
         }
+        Result(Ok(()))
     }
-}
 
+    /// Where STS policies are kept. By default, they're only kept in memory;
+    /// use `sts::FileStore` to keep them between runs.
+    pub fn set_sts_store(&mut self, store: Box<PolicyStore>) { self.sts = store }
 
-pub struct Client {
-    stream: Option<StreamKind>,
-}
+    pub fn sts_policies(&self) -> Vec<Policy> { self.sts.all() }
+    pub fn clear_sts_policy(&mut self, host: &str) { self.sts.remove(host) }
+    pub fn clear_sts_policies(&mut self) { self.sts.clear() }
 
-impl Client {
-    pub fn new() -> Client {
-        Client { stream: None }
+    pub fn is_secure(&self) -> bool {
+        self.stream.as_ref().map(|s| s.is_secure()) == Some(true)
+    }
+
+    /// Act on the `sts` capability, if the server advertised it.
+    fn check_sts(&mut self) {
+        let (host, port) = match self.address.clone() {
+            Some(_) if self.websocket.is_some() => return,
+            Some(a) => a,
+            None => return
+        };
+        match self.caps.value("sts").and_then(|v| Advertised::parse(v, self.is_secure())) {
+            Some(Advertised::Upgrade(port)) => self.sts_upgrade = Some(port),
+            Some(Advertised::Persist { duration: 0, .. }) => self.sts.remove(&host),
+            Some(Advertised::Persist { duration, preload }) =>
+                self.sts.set(Policy::new(&host, port, duration, preload)),
+            None => ()
+        }
+    }
+
+    /// Reconnect with TLS on `port`, and register again.
+    fn upgrade(&mut self, port: u16) -> result::Result<Box<Read + Send>, IrscError> {
+        let host = match self.address.clone() {
+            Some((host, _)) => host,
+            None => return Err(IrscError::NotConnected)
+        };
+        info!("Reconnecting to {} with TLS on port {}, as its STS policy demands", host, port);
+        self.stream = None;
+        self.caps.reset();
+        self.batches.clear();
+        let config = self.tls.clone();
+        try!(self.connect_tls(&host, port, &config).inner());
+        if let Some(r) = self.registration.clone() {
+            try!(self.register(&r.nick, &r.user, &r.desc, r.pass.as_ref().map(|p| &p[..])).inner());
+        }
+        self.reader()
+    }
+
+    fn reader(&self) -> result::Result<Box<Read + Send>, IrscError> {
+        match self.stream {
+            Some(ref s) => s.reader().map_err(IrscError::Io),
+            None => Err(IrscError::NotConnected)
+        }
     }
 
     fn handle_event(&mut self, msg: &Message) {
+        match &*msg.command() {
+            b"005" => {
+                let params: Vec<String> = msg.elements().iter().map(|e| def_lossy_decode(e)).collect();
+                // Skip our nickname, and "are supported by this server" at the end.
+                if params.len() > 2 { self.isupport.handle(&params[1..params.len() - 1]) }
+                self.users.set_casemapping(self.isupport.casemapping());
+                self.encoding.set_casemapping(self.isupport.casemapping());
+            },
+            // The end of the MOTD, or the lack of one, ends registration.
+            b"376" | b"422" if self.presence.method().is_none() => {
+                let actions = self.presence.start(&self.isupport);
+                let _ = self.send_presence(actions);
+            },
+            _ => ()
+        }
+        // Catch up on what the bouncer kept while we were away.
+        if (&*msg.command() == b"376" || &*msg.command() == b"422") && self.caps.is_enabled("znc.in/playback") {
+            let _ = self.play_back("*", None);
+        }
+
         let _ = match Command::from_message(msg) {
-            Some(PING(s1, s2)) => self.send(PONG(s1, s2)),
+            Some(PING(s1, s2)) => {
+                let _ = self.poll_presence();
+                self.send(PONG(s1, s2))
+            },
+            Some(CAP(_, sub, more, caps)) => {
+                let sub = def_lossy_decode(&sub);
+                let reaction = self.caps.handle(&sub, more, &def_lossy_decode(&caps));
+                if sub == "LS" || sub == "NEW" {
+                    self.check_sts();
+                    // No point in going on, when we'll reconnect anyway.
+                    if self.sts_upgrade.is_some() { return }
+                }
+                match reaction {
+                    Some(Reaction::Request(list)) =>
+                        self.send(CAP(None, "REQ".into(), false, (&list[..]).into())),
+                    Some(Reaction::End) => {
+                        // Binding to a bouncer network has to happen before registration ends.
+                        if let Some(id) = self.bind.clone() {
+                            if self.caps.is_enabled("soju.im/bouncer-networks") {
+                                let _ = self.send(BOUNCER("BIND".into(), vec![(&id[..]).into()]));
+                            }
+                        }
+                        self.send(CAP(None, "END".into(), false, "".into()))
+                    },
+                    None => Result(Ok(()))
+                }
+            },
             _ => Result(Ok(()))
         };
     }
 
+    /// A client that speaks over `transport`, which is already connected.
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Client {
+        let mut client = Client::new();
+        client.stream = Some(Box::new(transport));
+        client
+    }
+
+    /// Use `transport`, which is already connected. STS policies don't apply to it.
+    pub fn connect_transport<T: Transport + 'static>(&mut self, transport: T) -> Result<()> {
+        if self.stream.is_some() { return Result(Err(IrscError::AlreadyConnected)) }
+        self.stream = Some(Box::new(transport));
+        Result(Ok(()))
+    }
+
+    /// Close the connection. `listen` returns once it has read everything
+    /// that came before.
+    pub fn disconnect(&mut self) -> Result<()> {
+        match self.stream.take() {
+            Some(mut s) => Result(s.shutdown().map_err(IrscError::Io)),
+            None => Result(Err(IrscError::NotConnected))
+        }
+    }
+
+    /// Connect without TLS, unless there is an STS policy for `host`.
     pub fn connect(&mut self, host: &str, port: u16) -> Result<()> {
-        let s = &mut self.stream;
-        if s.is_some() { return Result(Err(IrscError::AlreadyConnected)) }
-        *s = match TcpStream::connect((host, port)) {
-            Ok(tcp) => Some(StreamKind::Plain(tcp)),
-            Err(e) => return Result(Err(IrscError::Io(e)))
+        if self.stream.is_some() { return Result(Err(IrscError::AlreadyConnected)) }
+        if let Some(policy) = self.sts.get(host) {
+            info!("Connecting to {} with TLS on port {}, as its STS policy demands", host, policy.port);
+            let config = self.tls.clone();
+            return self.connect_tls(host, policy.port, &config)
+        }
+
+        self.stream = match self.open(host, port) {
+            Ok(tcp) => Some(Box::new(tcp)),
+            Err(e) => return Result(Err(e))
         };
+        self.address = Some((host.to_owned(), port));
+        self.websocket = None;
 
         Result(Ok(()))
     }
 
-    pub fn connect_ssl(&mut self, host: &str, port: u16, ssl: Ssl) -> Result<()> {
-        let s = &mut self.stream;
-        if s.is_some() { return Result(Err(IrscError::AlreadyConnected)) };
-        let tcp_stream = match TcpStream::connect((host, port)) {
-            Ok(tcp) => Some(tcp),
-            Err(e) => return Result(Err(IrscError::Io(e)))
+    /// Connect with TLS, set up according to `config`. It's also kept for
+    /// later STS upgrades, as with `set_tls_config`.
+    pub fn connect_tls(&mut self, host: &str, port: u16, config: &TlsConfig) -> Result<()> {
+        if self.stream.is_some() { return Result(Err(IrscError::AlreadyConnected)) };
+        let tcp = match self.open(host, port) {
+            Ok(tcp) => tcp,
+            Err(e) => return Result(Err(e))
         };
-
-        match tcp_stream.map(|tcp| SslStream::new_from(ssl, tcp)) {
-            Some(Ok(ssl_stream)) => {
-                *s = Some(StreamKind::Ssl(ssl_stream));
+        match tls::wrap(tcp, host, config) {
+            Ok(stream) => {
+                self.stream = Some(Box::new(stream));
+                self.address = Some((host.to_owned(), port));
+                self.websocket = None;
+                self.tls = config.clone();
                 Result(Ok(()))
             },
-            Some(Err(ssl_error)) => Result(Err(IrscError::Ssl(ssl_error))),
-            None => Result(Err(IrscError::NotConnected))
+            Err(e) => Result(Err(e))
         }
     }
 
+    /// Make connections through `proxy`, or directly with `None`.
+    pub fn set_proxy(&mut self, proxy: Option<Proxy>) { self.proxy = proxy }
+
+    /// How connections are made: from which address, and how addresses are tried.
+    pub fn set_connect_options(&mut self, options: ConnectOptions) { self.options = options }
+
+    fn open(&self, host: &str, port: u16) -> result::Result<TcpStream, IrscError> {
+        self.options.connect_via(self.proxy.as_ref(), host, port)
+    }
+
+    /// Connect to a WebSocket gateway at `url`, like `wss://irc.example.org/webirc`.
+    /// `config` is used for `wss://` URLs, and kept as with `connect_tls`.
+    /// STS policies don't apply to WebSockets.
+    #[cfg(feature = "websocket")]
+    pub fn connect_websocket(&mut self, url: &str, config: &TlsConfig) -> Result<()> {
+        if self.stream.is_some() { return Result(Err(IrscError::AlreadyConnected)) };
+        let stream = match websocket::connect(url, |host, port| self.open(host, port), config) {
+            Ok(s) => s,
+            Err(e) => return Result(Err(e))
+        };
+        {
+            let (host, port) = stream.address();
+            self.address = Some((host.to_owned(), port));
+        }
+        self.websocket = Some(url.to_owned());
+        self.tls = config.clone();
+        self.stream = Some(Box::new(stream));
+        Result(Ok(()))
+    }
+
+    /// How TLS is set up when an STS policy requires it.
+    pub fn set_tls_config(&mut self, config: TlsConfig) { self.tls = config }
+
     #[inline]
     fn send_raw(&mut self, s: &[u8]) -> Result<()> {
         if DEBUG && s.len() > 1024 {
@@ -106,12 +401,13 @@ impl Client {
 
         Result(self.stream.as_mut()
             .ok_or(IrscError::NotConnected)
-            .and_then(|mut stream| stream.write_all(s)
-                                         .and_then(|_| stream.flush())
+            .and_then(|stream| stream.writer().write_all(s)
+                                          .and_then(|_| stream.writer().flush())
                                          .map_err(IrscError::Io)))
     }
 
     fn send_message(&mut self, msg: Message) -> Result<()> {
+        let msg = msg.encode(&self.encoding);
         self.send_raw(msg.bytes())
     }
 
@@ -119,37 +415,406 @@ impl Client {
         self.send_message(cmd.to_message())
     }
 
+    /// Send a command with a `label` tag, and get a handle for the server's answer.
+    /// Needs the `labeled-response` capability, see `request_capability`.
+    pub fn send_labeled(&mut self, cmd: Command) -> Result<Pending> {
+        if !self.caps.is_enabled("labeled-response") {
+            return Result(Err(IrscError::Unsupported("labeled-response")))
+        }
+        let pending = self.labels.create();
+        let msg = cmd.to_message().with_tag("label", Some(pending.label()));
+        match self.send_message(msg).inner() {
+            Ok(()) => Result(Ok(pending)),
+            Err(e) => {
+                self.labels.cancel(pending.label());
+                Result(Err(e))
+            }
+        }
+    }
+
+    /// Ask the server about `nick`. Fails with `IrscError::Reply` if there's no such user.
+    pub fn whois(&mut self, nick: &str) -> Result<Pending<WhoisInfo>> {
+        let pending = self.queries.whois(nick);
+        Result(self.send(WHOIS(None, vec![nick.into()])).inner().map(|_| pending))
+    }
+
+    /// List the users matching `mask`, or on the channel `mask`.
+    pub fn who(&mut self, mask: &str) -> Result<Pending<Vec<WhoEntry>>> {
+        let (pending, _) = self.queries.who(mask, None);
+        Result(self.send(WHO(Some(mask.into()), None)).inner().map(|_| pending))
+    }
+
+    /// Like `who`, but only ask for some `fields`, like `cnfa` for channel, nick, flags
+    /// and account. Needs a server that supports WHOX.
+    pub fn whox(&mut self, mask: &str, fields: &str) -> Result<Pending<Vec<WhoEntry>>> {
+        let (pending, token) = self.queries.who(mask, Some(fields));
+        let options = format!("%{}t,{}", fields.trim_left_matches('%').replace("t", ""), token.unwrap());
+        Result(self.send(WHO(Some(mask.into()), Some((&options[..]).into()))).inner().map(|_| pending))
+    }
+
+    /// List channels, or only those in `channels`.
+    pub fn list(&mut self, channels: &[&str]) -> Result<Pending<Vec<ListEntry>>> {
+        let pending = self.queries.list();
+        Result(self.send(LIST(channels.iter().map(|&c| c.into()).collect(), None)).inner().map(|_| pending))
+    }
+
+    /// Send a message, and get a handle for its echo, which is the message as
+    /// the server accepted it, with its `msgid` and `time`.
+    /// Needs the `echo-message` capability, see `request_capability`.
+    pub fn send_echoed(&mut self, cmd: Command) -> Result<Pending<Message>> {
+        if !self.caps.is_enabled("echo-message") {
+            return Result(Err(IrscError::Unsupported("echo-message")))
+        }
+        let labeled = self.caps.is_enabled("labeled-response");
+        let (msg, pending) = self.echoes.expect(cmd.to_message(), labeled);
+        Result(self.send_message(msg).inner().map(|_| pending))
+    }
+
+    fn can_fetch_history(&self) -> result::Result<(), IrscError> {
+        if !self.isupport.has("CHATHISTORY") { Err(IrscError::Unsupported("chathistory")) }
+        else if !self.caps.is_enabled("batch") { Err(IrscError::Unsupported("batch")) }
+        else { Ok(()) }
+    }
+
+    fn send_history(&mut self, params: Vec<String>) -> Result<()> {
+        self.send(CHATHISTORY((&params[0][..]).into(), params[1..].iter().map(|p| (&p[..]).into()).collect()))
+    }
+
+    /// Fetch up to `limit` messages of the channel or query `target` from the
+    /// history the server keeps, oldest first. The server returns at most as
+    /// many as its `CHATHISTORY` ISUPPORT token says at once; with `paging`,
+    /// more requests are made until `limit` is reached or history runs out.
+    pub fn chathistory(&mut self, target: &str, query: Query, limit: usize, paging: bool)
+    -> Result<Pending<Vec<Message>>> {
+        if let Err(e) = self.can_fetch_history() { return Result(Err(e)) }
+        let max = self.isupport.number("CHATHISTORY");
+        let (pending, params) = self.history.request(target, query, limit, max, paging);
+        Result(self.send_history(params).inner().map(|_| pending))
+    }
+
+    /// The channels and queries that had messages between `from` and `to`,
+    /// with the time of the latest message of each.
+    pub fn chathistory_targets(&mut self, from: Timestamp, to: Timestamp, limit: usize)
+    -> Result<Pending<Vec<(String, Timestamp)>>> {
+        if let Err(e) = self.can_fetch_history() { return Result(Err(e)) }
+        let (pending, params) = self.history.request_targets(from, to, limit);
+        Result(self.send_history(params).inner().map(|_| pending))
+    }
+
+    /// The networks of the bouncer we're connected to, see `list_networks`.
+    pub fn networks(&self) -> &Networks { &self.networks }
+
+    /// Ask a bouncer, like soju, for its networks. Needs `soju.im/bouncer-networks`;
+    /// with `soju.im/bouncer-networks-notify`, changes arrive as `Event::Network`.
+    pub fn list_networks(&mut self) -> Result<Pending<Vec<Network>>> {
+        if !self.caps.is_enabled("soju.im/bouncer-networks") {
+            return Result(Err(IrscError::Unsupported("soju.im/bouncer-networks")))
+        }
+        let pending = self.networks.list();
+        Result(self.send(BOUNCER("LISTNETWORKS".into(), Vec::new())).inner().map(|_| pending))
+    }
+
+    /// Add a network to the bouncer, with attributes like `("host", "irc.libera.chat")`.
+    pub fn add_network(&mut self, attributes: &[(&str, &str)]) -> Result<()> {
+        let attributes = bouncer::format_attributes(attributes);
+        self.send(BOUNCER("ADDNETWORK".into(), vec![(&attributes[..]).into()]))
+    }
+
+    /// Change attributes of the bouncer network `id`.
+    pub fn change_network(&mut self, id: &str, attributes: &[(&str, &str)]) -> Result<()> {
+        let attributes = bouncer::format_attributes(attributes);
+        self.send(BOUNCER("CHANGENETWORK".into(), vec![id.into(), (&attributes[..]).into()]))
+    }
+
+    pub fn remove_network(&mut self, id: &str) -> Result<()> {
+        self.send(BOUNCER("DELNETWORK".into(), vec![id.into()]))
+    }
+
+    /// Use the bouncer network `id` on this connection. Call this before
+    /// `register`, since it's bound during capability negotiation.
+    pub fn bind_network(&mut self, id: &str) {
+        self.caps.want("soju.im/bouncer-networks");
+        self.bind = Some(id.to_owned());
+    }
+
+    /// The bouncer network this connection is bound to, as the bouncer says.
+    pub fn network_id(&self) -> Option<&str> { self.isupport.get("BOUNCER_NETID") }
+
+    /// Another connection to the same bouncer, for the network `id`, with the
+    /// same settings, capabilities and registration as this one.
+    /// Each network of a bouncer needs a connection of its own.
+    pub fn connect_network(&self, id: &str) -> Result<Client> {
+        let (host, port) = match self.address.clone() {
+            Some(a) => a,
+            None => return Result(Err(IrscError::NotConnected))
+        };
+        let mut client = Client::new();
+        client.tls = self.tls.clone();
+        client.proxy = self.proxy.clone();
+        client.options = self.options.clone();
+        client.encoding = self.encoding.clone();
+        client.clock = self.clock;
+        for cap in self.caps.wanted() { client.caps.want(cap) }
+        client.bind_network(id);
+
+        let connected = match self.websocket {
+            #[cfg(feature = "websocket")]
+            Some(ref url) => client.connect_websocket(url, &self.tls),
+            _ if self.is_secure() => client.connect_tls(&host, port, &self.tls),
+            _ => client.connect(&host, port)
+        };
+        if let Err(e) = connected.inner() { return Result(Err(e)) }
+        if let Some(r) = self.registration.clone() {
+            if let Err(e) = client.register(&r.nick, &r.user, &r.desc, r.pass.as_ref().map(|p| &p[..])).inner() {
+                return Result(Err(e))
+            }
+        }
+        Result(Ok(client))
+    }
+
+    /// What was seen of the bouncer's buffers, for `znc.in/playback`.
+    pub fn playback(&self) -> &Playback { &self.playback }
+
+    /// Have a ZNC bouncer replay `buffer` (`*` for all of them) since `from`,
+    /// or since the newest message seen. Needs `znc.in/playback`; with it, this
+    /// happens for all buffers after registration. Replayed messages arrive as
+    /// events of their own, with the time they were originally sent.
+    pub fn play_back(&mut self, buffer: &str, from: Option<Timestamp>) -> Result<()> {
+        if !self.caps.is_enabled("znc.in/playback") {
+            return Result(Err(IrscError::Unsupported("znc.in/playback")))
+        }
+        let play = self.playback.play(buffer, from);
+        self.send(PRIVMSG("*playback".into(), (&play[..]).into()))
+    }
+
+    /// How many bytes of text fit into a PRIVMSG to `target`, after the server
+    /// added our prefix to relay it.
+    fn text_budget(&self, target: &str) -> usize {
+        let prefix = match self.users.me().and_then(|me| self.users.get(me)) {
+            Some(u) if u.ident.host.is_some() => u.ident.to_string().len(),
+            // Our nickname, and the longest usual user and host.
+            _ => self.users.me().map(|m| m.len()).unwrap_or(30) + 1 + 10 + 1 + 63
+        };
+        // ":<prefix> PRIVMSG <target> :<text>\r\n"
+        510usize.saturating_sub(prefix + target.len() + 12)
+    }
+
+    /// Send `text` to `to`, keeping its line breaks. With `draft/multiline`, it's
+    /// sent as one message, in as few batches as the server allows; otherwise,
+    /// each line is a PRIVMSG of its own. Lines that are too long are split.
+    pub fn msg_multiline(&mut self, to: &str, text: &str) -> Result<()> {
+        let lines = multiline::split(text, self.text_budget(to));
+        let limits = if self.caps.is_enabled("draft/multiline") && self.caps.is_enabled("batch") {
+            self.caps.value("draft/multiline").and_then(Limits::parse)
+        } else { None };
+
+        let limits = match limits {
+            Some(l) => l,
+            None => {
+                for line in lines.iter().filter(|l| !l.text.is_empty()) {
+                    let r = self.send(PRIVMSG(to.into(), (&line.text[..]).into()));
+                    if r.is_err() { return r }
+                }
+                return Result(Ok(()))
+            }
+        };
+
+        for group in multiline::group(lines, &limits) {
+            self.next_reference += 1;
+            let reference = format!("irsc{}", self.next_reference);
+            let start = format!("+{}", reference);
+            let r = self.send(BATCH((&start[..]).into(), Some("draft/multiline".into()), vec![to.into()]));
+            if r.is_err() { return r }
+            for line in group {
+                let mut m = PRIVMSG(to.into(), (&line.text[..]).into()).to_message()
+                    .with_tag("batch", Some(&reference));
+                if line.concat { m = m.with_tag("draft/multiline-concat", None) }
+                let r = self.send_message(m);
+                if r.is_err() { return r }
+            }
+            let end = format!("-{}", reference);
+            let r = self.send(BATCH((&end[..]).into(), None, Vec::new()));
+            if r.is_err() { return r }
+        }
+        Result(Ok(()))
+    }
+
+    /// `cmd` with those of `tags` that the server allows. Needs `message-tags`,
+    /// which is requested during registration.
+    fn tagged(&self, cmd: Command, tags: &[ClientTag]) -> result::Result<Message, IrscError> {
+        if !self.caps.is_enabled("message-tags") {
+            return Err(IrscError::Unsupported("message-tags"))
+        }
+        Ok(tags.iter().filter(|t| t.is_allowed(&self.isupport))
+               .fold(cmd.to_message(), |m, t| t.apply(&m)))
+    }
+
+    /// Send a TAGMSG with client-only tags to `to`. Nothing is sent if the
+    /// server denies all of them.
+    pub fn send_tagmsg(&mut self, to: &str, tags: &[ClientTag]) -> Result<()> {
+        match self.tagged(TAGMSG(to.into()), tags) {
+            Ok(ref m) if m.tags().is_empty() => Result(Ok(())),
+            Ok(m) => self.send_message(m),
+            Err(e) => Result(Err(e))
+        }
+    }
+
+    /// Send a PRIVMSG with client-only tags, like `ClientTag::Reply`.
+    pub fn msg_tagged(&mut self, to: &str, message: &str, tags: &[ClientTag]) -> Result<()> {
+        match self.tagged(PRIVMSG(to.into(), message.into()), tags) {
+            Ok(m) => self.send_message(m),
+            Err(e) => Result(Err(e))
+        }
+    }
+
+    /// Tell `to` whether we're typing.
+    pub fn typing(&mut self, to: &str, typing: Typing) -> Result<()> {
+        self.send_tagmsg(to, &[ClientTag::Typing(typing)])
+    }
+
+    /// Reply to the message with the msgid `msgid`.
+    pub fn reply(&mut self, to: &str, msgid: &str, message: &str) -> Result<()> {
+        self.msg_tagged(to, message, &[ClientTag::Reply(msgid.to_owned())])
+    }
+
+    /// React to the message with the msgid `msgid`, with an emoji or short text.
+    pub fn react(&mut self, to: &str, msgid: &str, reaction: &str) -> Result<()> {
+        self.send_tagmsg(to, &[ClientTag::Reply(msgid.to_owned()), ClientTag::React(reaction.to_owned())])
+    }
+
+    /// Whether `msg` is one of our own messages, echoed back by the server, or
+    /// sent from another client of the same bouncer with `znc.in/self-message`.
+    fn is_own(&self, msg: &Message) -> bool {
+        match &*msg.command() {
+            b"PRIVMSG" | b"NOTICE" | b"TAGMSG" => (),
+            _ => return false
+        }
+        match (msg.ident(), self.users.me()) {
+            (Some(i), Some(me)) => self.isupport.casemapping().eq(&i.nickname, me),
+            _ => false
+        }
+    }
+
     pub fn listen<F>(&mut self, on_event: F) -> Result<()>
     where F: Fn(&mut Client, &Message, Option<Event>) {
-        let reader = BufReader::new(match self.stream {
-            Some(StreamKind::Plain(ref s)) => StreamKind::Plain((*s).try_clone().unwrap()),
-            Some(StreamKind::Ssl(ref s)) => StreamKind::Ssl((*s).try_clone().unwrap()),
-            None => return Result(Err(IrscError::NotConnected))
+        let mut reader = BufReader::new(match self.reader() {
+            Ok(r) => r,
+            Err(e) => return Result(Err(e))
         });
 
-        for raw_line in reader.lines() {
-            let line = Message::parse(raw_line.as_ref().unwrap().as_bytes());
-            info!("<< {}", raw_line.unwrap());
+        // Lines are read as bytes, since they might not be UTF-8.
+        let mut raw_line = Vec::new();
+        loop {
+            raw_line.clear();
+            match reader.read_until(b'\n', &mut raw_line) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(e) => {
+                    self.labels.fail_all();
+                    self.queries.fail_all();
+                    self.echoes.fail_all();
+                    self.history.fail_all();
+                    self.networks.fail_all();
+                    return Result(Err(IrscError::Io(e)))
+                }
+            }
+            let line = Message::parse(&raw_line).map(|m| m.decode(&self.encoding));
+            info!("<< {}", def_lossy_decode(&raw_line).trim_right());
 
-            if let Ok(msg) = line {
+            if let Ok(mut msg) = line {
+                msg.apply_clock(self.clock, self.caps.is_enabled("server-time"));
+                let replayed = msg.tag("batch").map(|b| self.batches.is_replay(b)) == Some(true);
+                if self.is_own(&msg) {
+                    msg.set_from_self(true);
+                    // Messages replayed in batches, like chathistory, aren't echoes.
+                    if !msg.has_tag("batch") && !self.echoes.handle(&msg, self.isupport.casemapping()) {
+                        continue
+                    }
+                }
+                // Replayed history is old news, which mustn't change what we know
+                // about users and channels now. It only goes to its batch.
+                if replayed {
+                    if self.caps.is_enabled("server-time") { self.playback.handle(&msg) }
+                    self.batches.handle(&msg);
+                    continue
+                }
                 self.handle_event(&msg);
+                if let Some(port) = self.sts_upgrade.take() {
+                    match self.upgrade(port) {
+                        Ok(r) => { reader = BufReader::new(r); continue },
+                        Err(e) => return Result(Err(e))
+                    }
+                }
+                self.queries.handle(&msg, self.isupport.casemapping());
+                self.users.handle(&msg);
+                self.history.handle(&msg);
+                if self.caps.is_enabled("server-time") { self.playback.handle(&msg) }
+                if let Some(change) = self.networks.handle(&msg) {
+                    // Those listed in answer to LISTNETWORKS aren't news.
+                    if !msg.has_tag("batch") { on_event(self, &msg, Some(Event::Network(change))) }
+                }
+                for change in self.presence.handle(&msg) {
+                    on_event(self, &msg, Some(match change {
+                        Change::Online(i) => Event::Online(i),
+                        Change::Offline(n) => Event::Offline(n)
+                    }));
+                }
+
+                // Messages in a batch are held back until it's complete.
+                match self.batches.handle(&msg) {
+                    Collected::Absorbed => continue,
+                    Collected::Complete(batch) => {
+                        let (taken, next) = self.history.handle_batch(&batch, self.isupport.casemapping());
+                        if let Some(params) = next { let _ = self.send_history(params); }
+                        if taken || self.networks.handle_batch(&batch) { continue }
+                        // Replayed messages are delivered as if they came live, with their own times.
+                        if batch.kind == "znc.in/playback" {
+                            for m in batch.own_messages() { on_event(self, m, event_of(m)) }
+                            continue
+                        }
+                        match batch.label.clone() {
+                            Some(label) => { self.labels.complete(&label, Response::Batch(batch)); },
+                            None => {
+                                // Multiline messages are delivered in one piece.
+                                let event = match Multiline::from_batch(&batch) {
+                                    Some(m) => Event::Multiline(m),
+                                    None => Event::Batch(batch)
+                                };
+                                on_event(self, &msg, Some(event))
+                            }
+                        }
+                        continue
+                    },
+                    Collected::Not => ()
+                }
+
+                // Answers to labeled commands go to whoever is waiting for them.
+                // Echoes of our own messages, and answers that came too late, are
+                // still delivered as events.
+                if let Some(label) = msg.tag("label").map(ToOwned::to_owned) {
+                    let response = if &*msg.command() == b"ACK" { Response::Ack }
+                                   else { Response::Message(msg.clone()) };
+                    if self.labels.complete(&label, response) && !msg.is_from_self() { continue }
+                }
 
                 // Try to parse the message into a Command or a Reply, and call back.
-                let event = match Command::from_message(&msg) {
-                    Some(m) => Some(Event::Command(m)),
-                    None => match Reply::from_message(&msg) {
-                        Some(r) => Some(Event::Reply(r)),
-                        None => None
-                    }
-                };
-                on_event(self, &msg, event);
+                on_event(self, &msg, event_of(&msg));
             }
         }
+        self.labels.fail_all();
+        self.queries.fail_all();
+        self.echoes.fail_all();
+        self.history.fail_all();
+        self.networks.fail_all();
+        self.networks.clear();
+        self.presence.reset();
+        self.isupport.clear();
+        self.users.clear();
         Result(Ok(()))
     }
 
     fn join(&mut self, channel: &str, password: Option<&str>) -> Result<()> {
-        self.send_message(JOIN(vec![channel.into()], password.iter().map(|&p| p.into()).collect()).to_message())
+        self.send_message(JOIN(vec![channel.into()], password.iter().map(|&p| p.into()).collect(), None).to_message())
     }
 
     fn msg(&mut self, to: &str, message: &str) -> Result<()> {
@@ -178,7 +843,25 @@ impl Client {
         self.msg(to, &line)
     }
 
-    fn register(&mut self, nick: &str, user: &str, desc: &str, pass: Option<&str>) -> Result<()> {
+    /// Register with the server, negotiating the capabilities asked for with
+    /// `request_capability` first.
+    pub fn register(&mut self, nick: &str, user: &str, desc: &str, pass: Option<&str>) -> Result<()> {
+        self.registration = Some(Registration {
+            nick: nick.to_owned(),
+            user: user.to_owned(),
+            desc: desc.to_owned(),
+            pass: pass.map(ToOwned::to_owned)
+        });
+
+        // Registration waits for CAP END. Capabilities are listed even if we
+        // want none, since the server might have an STS policy.
+        // Client-only tags, sent or received, always need message-tags.
+        self.caps.want("message-tags");
+        self.caps.start();
+        if let Err(e) = self.send(CAP(None, "LS".into(), false, "302".into())).inner() {
+            return Result(Err(e))
+        }
+
         Result(if let Some(pass) = pass {
             self.send_message(PASS(pass.into()).to_message()).inner()
         } else { Ok(()) }
@@ -187,3 +870,97 @@ impl Client {
         )
     }
 }
+
+/// The message parsed into a Command or a Reply.
+fn event_of(msg: &Message) -> Option<Event> {
+    // A TAGMSG is nothing but its tags.
+    if &*msg.command() == b"TAGMSG" { return Some(Event::Tags(ClientTag::from_message(msg))) }
+    match Command::from_message(msg) {
+        Some(m) => Some(Event::Command(m)),
+        None => match Reply::from_message(msg) {
+            Some(r) => Some(Event::Reply(r)),
+            None => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{ BufRead, BufReader, Write };
+    use std::thread;
+
+    use client::Client;
+    use event::Event;
+    use tags::{ ClientTag, Typing };
+    use transport::{ duplex, Transport };
+
+    #[test]
+    fn replayed_history_keeps_state() {
+        let (ours, mut server) = duplex();
+        let mut client = Client::from_transport(ours);
+        server.writer().write_all(b":irc.host 001 me :Welcome\r\n\
+            :me!m@h JOIN #rust\r\n\
+            :irc.host 353 me = #rust :me nick\r\n\
+            :irc.host BATCH +h chathistory #rust\r\n\
+            @batch=h :nick!u@h QUIT :Gone\r\n\
+            @batch=h :nick!u@h PRIVMSG #rust :old\r\n\
+            :irc.host BATCH -h\r\n\
+            :nick!u@h PRIVMSG #rust :live\r\n").unwrap();
+        server.shutdown().unwrap();
+
+        let seen = RefCell::new(Vec::new());
+        client.listen(|client, msg, event| {
+            let mut seen = seen.borrow_mut();
+            match event {
+                Some(Event::Batch(ref b)) => seen.push(format!("batch of {}", b.messages.len())),
+                _ if &*msg.command() == b"PRIVMSG" =>
+                    seen.push(format!("nick known: {}", client.users().get("nick").is_some())),
+                _ if &*msg.command() == b"QUIT" => seen.push("quit".to_owned()),
+                _ => ()
+            }
+        }).inner().unwrap();
+        assert_eq!(*seen.borrow(), vec!["batch of 2".to_owned(), "nick known: true".to_owned()]);
+    }
+
+    #[test]
+    fn client_tags() {
+        let (ours, mut server) = duplex();
+        let mut client = Client::from_transport(ours);
+        client.register("me", "me", "Me", None).inner().unwrap();
+        let reader = BufReader::new(server.reader().unwrap());
+        let t = thread::spawn(move || {
+            let mut lines = reader.lines().map(|l| l.unwrap());
+            server.writer().write_all(b":irc.host CAP * LS :message-tags server-time\r\n").unwrap();
+            let requested = lines.find(|l| l.starts_with("CAP") && l.contains("REQ")).unwrap();
+            server.writer().write_all(b":irc.host CAP * ACK :message-tags\r\n\
+                @+typing=active;+draft/reply=abc :n!u@h TAGMSG #rust\r\n\
+                @+draft/reply=abc :n!u@h PRIVMSG #rust :yes\r\n").unwrap();
+            let ended = lines.any(|l| l.starts_with("CAP") && l.contains("END"));
+            server.shutdown().unwrap();
+            (requested, ended)
+        });
+
+        let tags = RefCell::new(Vec::new());
+        let privmsgs = RefCell::new(0);
+        client.listen(|client, msg, event| {
+            match event {
+                Some(Event::Tags(t)) => {
+                    assert!(client.capabilities().is_enabled("message-tags"));
+                    tags.borrow_mut().push(t);
+                },
+                // One event per message, with the tags left on the message.
+                Some(_) if &*msg.command() == b"PRIVMSG" => {
+                    assert_eq!(ClientTag::from_message(msg), vec![ClientTag::Reply("abc".to_owned())]);
+                    *privmsgs.borrow_mut() += 1;
+                },
+                _ => ()
+            }
+        }).inner().unwrap();
+        let (requested, ended) = t.join().unwrap();
+        assert!(requested.ends_with("message-tags") && !requested.contains("server-time"));
+        assert!(ended);
+        assert_eq!(*tags.borrow(), vec![vec![ClientTag::Typing(Typing::Active), ClientTag::Reply("abc".to_owned())]]);
+        assert_eq!(*privmsgs.borrow(), 1);
+    }
+}
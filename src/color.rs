@@ -1,46 +1,467 @@
-pub const WHITE: &'static str = "00";
-pub const BLACK: &'static str = "01";
-pub const BLUE: &'static str = "02";
-pub const GREEN: &'static str = "03";
-pub const RED: &'static str = "04";
-pub const BROWN: &'static str = "05";
-pub const PURPLE: &'static str = "06";
-pub const ORANGE: &'static str = "07";
-pub const YELLOW: &'static str = "08";
-pub const LIME: &'static str = "09";
-pub const TEAL: &'static str = "10";
-pub const LIGHT_CYAN: &'static str = "11";
-pub const LIGHT_BLUE: &'static str = "12";
-pub const PINK: &'static str = "13";
-pub const GREY: &'static str = "14";
-pub const LIGHT_GREY: &'static str = "15";
-
-pub const TRANSPARENT: &'static str = "99";
+use std::fmt;
+
+/// Colours as understood by mIRC-compatible clients.
+///
+/// The first sixteen have names, `Extended` covers the rest of the palette
+/// (16 to 98), and `Rgb` is sent using the hex colour code (`\x04`), which
+/// fewer clients support.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Color {
+    White,
+    Black,
+    Blue,
+    Green,
+    Red,
+    Brown,
+    Purple,
+    Orange,
+    Yellow,
+    Lime,
+    Teal,
+    LightCyan,
+    LightBlue,
+    Pink,
+    Grey,
+    LightGrey,
+    /// Palette entries 16 to 98. Anything outside of that is clamped to 98.
+    Extended(u8),
+    Rgb(u8, u8, u8),
+    /// 99, whatever the client uses when no colour is set.
+    Default
+}
+
+/// Hex values of palette entries 16 to 98, as most clients render them.
+static EXTENDED: [u32; 83] = [
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472c,
+    0x004747, 0x002747, 0x000047, 0x2e0047, 0x470047, 0x47002a,
+    0x740000, 0x743a00, 0x747400, 0x517400, 0x007400, 0x007449,
+    0x007474, 0x004074, 0x000074, 0x4b0074, 0x740074, 0x740045,
+    0xb50000, 0xb56300, 0xb5b500, 0x7db500, 0x00b500, 0x00b571,
+    0x00b5b5, 0x0063b5, 0x0000b5, 0x7500b5, 0xb500b5, 0xb5006b,
+    0xff0000, 0xff8c00, 0xffff00, 0xb2ff00, 0x00ff00, 0x00ffa0,
+    0x00ffff, 0x008cff, 0x0000ff, 0xa500ff, 0xff00ff, 0xff0098,
+    0xff5959, 0xffb459, 0xffff71, 0xcfff60, 0x6fff6f, 0x65ffc9,
+    0x6dffff, 0x59b4ff, 0x5959ff, 0xc459ff, 0xff66ff, 0xff59bc,
+    0xff9c9c, 0xffd39c, 0xffff9c, 0xe2ff9c, 0x9cff9c, 0x9cffdb,
+    0x9cffff, 0x9cd3ff, 0x9c9cff, 0xdc9cff, 0xff9cff, 0xff94d3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4d4d4d, 0x656565,
+    0x818181, 0x9f9f9f, 0xbcbcbc, 0xe2e2e2, 0xffffff
+];
+
+static BASIC: [u32; 16] = [
+    0xffffff, 0x000000, 0x00007f, 0x009300, 0xff0000, 0x7f0000, 0x9c009c, 0xfc7f00,
+    0xffff00, 0x00fc00, 0x009393, 0x00ffff, 0x0000fc, 0xff00ff, 0x7f7f7f, 0xd2d2d2
+];
+
+impl Color {
+    pub fn from_code(code: u8) -> Option<Color> {
+        use self::Color::*;
+        Some(match code {
+            0 => White, 1 => Black, 2 => Blue, 3 => Green,
+            4 => Red, 5 => Brown, 6 => Purple, 7 => Orange,
+            8 => Yellow, 9 => Lime, 10 => Teal, 11 => LightCyan,
+            12 => LightBlue, 13 => Pink, 14 => Grey, 15 => LightGrey,
+            16...98 => Extended(code),
+            99 => Default,
+            _ => return None
+        })
+    }
+
+    /// The palette index, or `None` for `Rgb`.
+    pub fn code(&self) -> Option<u8> {
+        use self::Color::*;
+        Some(match *self {
+            White => 0, Black => 1, Blue => 2, Green => 3,
+            Red => 4, Brown => 5, Purple => 6, Orange => 7,
+            Yellow => 8, Lime => 9, Teal => 10, LightCyan => 11,
+            LightBlue => 12, Pink => 13, Grey => 14, LightGrey => 15,
+            Extended(n) if n < 16 => return Color::from_code(n).and_then(|c| c.code()),
+            Extended(n) if n > 98 => 98,
+            Extended(n) => n,
+            Default => 99,
+            Rgb(..) => return None
+        })
+    }
+
+    /// Approximate RGB value, or `None` for `Default`.
+    pub fn rgb(&self) -> Option<(u8, u8, u8)> {
+        let hex = match (*self, self.code()) {
+            (Color::Rgb(r, g, b), _) => return Some((r, g, b)),
+            (_, Some(n)) if n < 16 => BASIC[n as usize],
+            (_, Some(n)) if n < 99 => EXTENDED[n as usize - 16],
+            _ => return None
+        };
+        Some(((hex >> 16) as u8, (hex >> 8) as u8, hex as u8))
+    }
+
+    pub fn is_rgb(&self) -> bool {
+        match *self { Color::Rgb(..) => true, _ => false }
+    }
+}
+
+/// The formatting state at some point in a line.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub monospace: bool,
+    pub reverse: bool,
+    pub fg: Option<Color>,
+    pub bg: Option<Color>
+}
+
+impl Style {
+    /// `inner` applied on top of `self`; flags add up, colours override.
+    fn merge(&self, inner: &Style) -> Style {
+        Style {
+            bold: self.bold || inner.bold,
+            italic: self.italic || inner.italic,
+            underline: self.underline || inner.underline,
+            strikethrough: self.strikethrough || inner.strikethrough,
+            monospace: self.monospace || inner.monospace,
+            reverse: self.reverse || inner.reverse,
+            fg: inner.fg.or(self.fg),
+            bg: inner.bg.or(self.bg)
+        }
+    }
+
+    fn toggles(&self, to: &Style) -> String {
+        let mut s = String::new();
+        if self.bold != to.bold { s.push('\x02') }
+        if self.italic != to.italic { s.push('\x1D') }
+        if self.underline != to.underline { s.push('\x1F') }
+        if self.strikethrough != to.strikethrough { s.push('\x1E') }
+        if self.monospace != to.monospace { s.push('\x11') }
+        if self.reverse != to.reverse { s.push('\x16') }
+        s
+    }
+
+    fn has_rgb(&self) -> bool {
+        self.fg.map(|c| c.is_rgb()) == Some(true) || self.bg.map(|c| c.is_rgb()) == Some(true)
+    }
+
+    /// Control codes to get from `self` to `to`, given the character that will
+    /// follow them (so colour codes can be padded or separated if needed).
+    fn transition(&self, to: &Style, next: Option<char>) -> String {
+        let toggles = self.toggles(to);
+        let mut s = String::new();
+        if self.fg != to.fg || self.bg != to.bg {
+            let next = toggles.chars().next().or(next);
+            let clear = to.fg.is_none() && to.bg.is_none();
+            let dropped = (self.fg.is_some() && to.fg.is_none())
+                       || (self.bg.is_some() && to.bg.is_none())
+                       || (self.has_rgb() && !to.has_rgb());
+            if clear || dropped {
+                s.push_str(&reset_colors(self.has_rgb(), if clear { next } else { Some('\x03') }));
+            }
+            if !clear {
+                s.push_str(&set_colors(to.fg, to.bg, next));
+            }
+        }
+        s.push_str(&toggles);
+
+        // Resetting everything is shorter than undoing more than one thing.
+        if *to == Style::default() && s.len() > 1 { "\x0F".to_owned() } else { s }
+    }
+}
+
+fn swallows(next: Option<char>, hex: bool) -> bool {
+    next.map(|c| if hex { c.is_digit(16) } else { c.is_digit(10) }) == Some(true)
+}
+
+fn reset_colors(hex: bool, next: Option<char>) -> String {
+    let mut s = String::from("\x03");
+    if hex { s.push('\x04') }
+    // A bare colour code followed by a digit would start a new colour instead.
+    if swallows(next, hex) || next == Some(',') { s.push_str("\x02\x02") }
+    s
+}
+
+fn hex(c: Color) -> Option<String> {
+    c.rgb().map(|(r, g, b)| format!("{:02X}{:02X}{:02X}", r, g, b))
+}
+
+fn set_colors(fg: Option<Color>, bg: Option<Color>, next: Option<char>) -> String {
+    let rgb = fg.map(|c| c.is_rgb()) == Some(true) || bg.map(|c| c.is_rgb()) == Some(true);
+    let mut s = String::new();
+    if rgb {
+        // The hex code needs a foreground; without one, the background is dropped.
+        let fg = match fg.and_then(hex) { Some(f) => f, None => return s };
+        s.push('\x04');
+        s.push_str(&fg);
+        match bg.and_then(hex) {
+            Some(b) => { s.push(','); s.push_str(&b); },
+            None => if next == Some(',') { s.push_str("\x02\x02") }
+        }
+        if swallows(next, true) { s.push_str("\x02\x02") }
+        return s
+    }
+
+    let fg = fg.and_then(|c| c.code()).unwrap_or(99);
+    s.push('\x03');
+    match bg.and_then(|c| c.code()) {
+        Some(b) => {
+            s.push_str(&format!("{},", fg));
+            s.push_str(&if swallows(next, false) { format!("{:02}", b) } else { format!("{}", b) });
+        },
+        None => {
+            s.push_str(&if swallows(next, false) { format!("{:02}", fg) } else { format!("{}", fg) });
+            if next == Some(',') { s.push_str("\x02\x02") }
+        }
+    }
+    s
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Styled(Styled)
+}
+
+/// Builder for formatted text.
+///
+/// ```ignore
+/// let s = Styled::new("alert").bold().fg(Color::Red)
+///     .append(Styled::new(" (details)").italic());
+/// client.msg("#chan", &s.to_string());
+/// ```
+///
+/// Nested parts inherit the style of their parent, and the parent style is
+/// restored after them, instead of resetting everything with a bare `\x03`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Styled {
+    style: Style,
+    parts: Vec<Part>
+}
+
+impl Styled {
+    pub fn new<S: Into<String>>(s: S) -> Styled {
+        Styled { style: Style::default(), parts: vec![Part::Text(s.into())] }
+    }
+
+    pub fn empty() -> Styled { Styled::default() }
+
+    pub fn bold(mut self) -> Styled { self.style.bold = true; self }
+    pub fn italic(mut self) -> Styled { self.style.italic = true; self }
+    pub fn underline(mut self) -> Styled { self.style.underline = true; self }
+    pub fn strikethrough(mut self) -> Styled { self.style.strikethrough = true; self }
+    pub fn monospace(mut self) -> Styled { self.style.monospace = true; self }
+    pub fn reverse(mut self) -> Styled { self.style.reverse = true; self }
+    pub fn fg(mut self, c: Color) -> Styled { self.style.fg = Some(c); self }
+    pub fn bg(mut self, c: Color) -> Styled { self.style.bg = Some(c); self }
+
+    pub fn style(&self) -> &Style { &self.style }
+
+    /// Append unformatted text, which takes on this part's style.
+    pub fn push<S: Into<String>>(mut self, s: S) -> Styled {
+        self.parts.push(Part::Text(s.into()));
+        self
+    }
+
+    /// Append a nested part.
+    pub fn append(mut self, inner: Styled) -> Styled {
+        self.parts.push(Part::Styled(inner));
+        self
+    }
+
+    fn runs<'a>(&'a self, outer: &Style, out: &mut Vec<(Style, &'a str)>) {
+        let style = outer.merge(&self.style);
+        for part in &self.parts {
+            match part {
+                &Part::Text(ref t) => if !t.is_empty() { out.push((style, t)) },
+                &Part::Styled(ref s) => s.runs(&style, out)
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut runs = Vec::new();
+        self.runs(&Style::default(), &mut runs);
+
+        let mut s = String::new();
+        let mut current = Style::default();
+        for (style, text) in runs {
+            s.push_str(&current.transition(&style, text.chars().next()));
+            s.push_str(text);
+            current = style;
+        }
+        s.push_str(&current.transition(&Style::default(), None));
+        s
+    }
+}
+
+impl fmt::Display for Styled {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.render())
+    }
+}
+
+impl<'a> From<&'a str> for Styled {
+    fn from(s: &'a str) -> Styled { Styled::new(s) }
+}
+
+/// Split formatted text into runs of equal style, interpreting all control codes.
+pub fn parse(s: &str) -> Vec<(Style, String)> {
+    fn digits(c: &[char], i: &mut usize, max: usize, radix: u32) -> Option<u32> {
+        let start = *i;
+        while *i < c.len() && *i - start < max && c[*i].is_digit(radix) { *i += 1 }
+        if *i == start { return None }
+        u32::from_str_radix(&c[start..*i].iter().cloned().collect::<String>(), radix).ok()
+    }
+
+    fn hex_color(c: &[char], i: &mut usize) -> Option<Color> {
+        if c.len() < *i + 6 || !c[*i..*i + 6].iter().all(|c| c.is_digit(16)) { return None }
+        digits(c, i, 6, 16).map(|v| Color::Rgb((v >> 16) as u8, (v >> 8) as u8, v as u8))
+    }
+
+    let c: Vec<char> = s.chars().collect();
+    let mut runs: Vec<(Style, String)> = Vec::new();
+    let mut style = Style::default();
+    let mut i = 0;
+    while i < c.len() {
+        let ch = c[i];
+        i += 1;
+        match ch {
+            '\x02' => style.bold = !style.bold,
+            '\x1D' => style.italic = !style.italic,
+            '\x1F' => style.underline = !style.underline,
+            '\x1E' => style.strikethrough = !style.strikethrough,
+            '\x11' => style.monospace = !style.monospace,
+            '\x16' => style.reverse = !style.reverse,
+            '\x0F' => style = Style::default(),
+            '\x03' => match digits(&c, &mut i, 2, 10) {
+                Some(fg) => {
+                    style.fg = Color::from_code(fg as u8);
+                    if i + 1 < c.len() && c[i] == ',' && c[i + 1].is_digit(10) {
+                        i += 1;
+                        style.bg = digits(&c, &mut i, 2, 10).and_then(|b| Color::from_code(b as u8));
+                    }
+                },
+                None => { style.fg = None; style.bg = None }
+            },
+            '\x04' => match hex_color(&c, &mut i) {
+                Some(fg) => {
+                    style.fg = Some(fg);
+                    if i < c.len() && c[i] == ',' {
+                        let mut j = i + 1;
+                        if let Some(bg) = hex_color(&c, &mut j) { style.bg = Some(bg); i = j }
+                    }
+                },
+                None => { style.fg = None; style.bg = None }
+            },
+            ch => {
+                let same = runs.last().map(|&(ref s, _)| *s == style) == Some(true);
+                if !same { runs.push((style, String::new())) }
+                runs.last_mut().unwrap().1.push(ch);
+            }
+        }
+    }
+    runs
+}
+
+/// Remove all formatting.
+pub fn strip(s: &str) -> String {
+    parse(s).into_iter().map(|(_, t)| t).collect()
+}
 
 pub fn normal(s: &str) -> String {
     format!("\x0F{}\x0F", s)
 }
 
 pub fn bold(s: &str) -> String {
-    format!("\x02{}\x02", s)
+    Styled::new(s).bold().render()
 }
 
 pub fn italic(s: &str) -> String {
-    format!("\x1D{}\x1D", s)
+    Styled::new(s).italic().render()
 }
 
 pub fn underline(s: &str) -> String {
-    format!("\x1F{}\x1F", s)
+    Styled::new(s).underline().render()
+}
+
+pub fn strikethrough(s: &str) -> String {
+    Styled::new(s).strikethrough().render()
+}
+
+pub fn monospace(s: &str) -> String {
+    Styled::new(s).monospace().render()
+}
+
+pub fn foreground(s: &str, foreground: Color) -> String {
+    Styled::new(s).fg(foreground).render()
 }
 
-pub fn foreground(s: &str, foreground: &str) -> String {
-    format!("\x03{}{}\x03", foreground, s)
+pub fn background(s: &str, background: Color) -> String {
+    Styled::new(s).bg(background).render()
 }
 
-pub fn background(s: &str, background: &str) -> String {
-    format!("\x03,{}{}\x03", background, s)
+pub fn color(s: &str, foreground: Color, background: Color) -> String {
+    Styled::new(s).fg(foreground).bg(background).render()
 }
 
-pub fn color(s: &str, foreground: &str, background: &str) -> String {
-    format!("\x03{},{}{}\x03", foreground, background, s)
+#[cfg(test)]
+mod test {
+    use color::{ self, Color, Styled };
+
+    #[test]
+    fn codes() {
+        assert_eq!(Color::from_code(4), Some(Color::Red));
+        assert_eq!(Color::from_code(42).and_then(|c| c.code()), Some(42));
+        assert_eq!(Color::from_code(100), None);
+        assert_eq!(Color::Extended(4).code(), Some(4));
+        assert_eq!(Color::Rgb(1, 2, 3).code(), None);
+    }
+
+    #[test]
+    fn minimal() {
+        assert_eq!(Styled::new("x").bold().render(), "\x02x\x02");
+        assert_eq!(Styled::new("x").fg(Color::Red).render(), "\x034x\x03");
+        assert_eq!(Styled::new("x").fg(Color::Red).bg(Color::Blue).render(), "\x034,2x\x03");
+        assert_eq!(Styled::new("x").bold().fg(Color::Red).render(), "\x034\x02x\x0F");
+    }
+
+    #[test]
+    fn pads_digits() {
+        assert_eq!(Styled::new("5 cats").fg(Color::Red).render(), "\x03045 cats\x03");
+        assert_eq!(Styled::new("5").fg(Color::Red).bg(Color::Blue).render(), "\x034,025\x03");
+        assert_eq!(Styled::new(",x").fg(Color::Red).render(), "\x034\x02\x02,x\x03");
+    }
+
+    #[test]
+    fn nesting() {
+        let s = Styled::new("a").fg(Color::Red)
+            .append(Styled::new("b").fg(Color::Blue))
+            .push("c");
+        assert_eq!(s.render(), "\x034a\x032b\x034c\x03");
+
+        let s = Styled::new("a").bold().append(Styled::new("b").italic()).push("c");
+        assert_eq!(s.render(), "\x02a\x1Db\x1Dc\x02");
+    }
+
+    #[test]
+    fn reset_before_digit() {
+        let s = Styled::empty().append(Styled::new("a").fg(Color::Red)).push("1");
+        assert_eq!(s.render(), "\x034a\x0F1");
+    }
+
+    #[test]
+    fn rgb() {
+        assert_eq!(Styled::new("x").fg(Color::Rgb(255, 0, 16)).render(), "\x04FF0010x\x0F");
+        assert_eq!(Color::Extended(52).rgb(), Some((255, 0, 0)));
+    }
+
+    #[test]
+    fn parse() {
+        let runs = color::parse("a\x02b\x034,12c\x0F5\x0305d");
+        let text: Vec<&str> = runs.iter().map(|&(_, ref t)| &t[..]).collect();
+        assert_eq!(text, vec!["a", "b", "c", "5", "d"]);
+        assert!(runs[1].0.bold);
+        assert_eq!(runs[2].0.fg, Some(Color::Red));
+        assert_eq!(runs[2].0.bg, Some(Color::LightBlue));
+        assert_eq!(runs[4].0.fg, Some(Color::Brown));
+        assert_eq!(color::strip(&Styled::new("5").fg(Color::Rgb(1, 2, 3)).render()), "5");
+    }
 }
@@ -1,11 +1,11 @@
 #![allow(non_camel_case_types)]
 
-use std::borrow::{ Cow, IntoCow, Borrow, ToOwned };
+use std::borrow::{ Cow, Borrow, ToOwned };
 use std::borrow::Cow::*;
 use std::iter::Extend;
 
 use message::Message;
-use text::{ Text, TextSlice };
+use text::{ Text, TextSlice, EMPTY_RAW };
 
 pub type CS<'a> = Cow<'a, str>;
 
@@ -24,9 +24,11 @@ pub type CS<'a> = Cow<'a, str>;
 macro_rules! commands {
     ($( $name: ident {
         $id: expr, $doc: meta
-        b $($borrowed_items: ty),*
-        o $($owned_items: ty),*
-        t $($to_names: ident),+ => $($to_exprs: expr),+
+        b $($borrowed_items: ty),*;
+        o $($owned_items: ty),*;
+        t $($to_names: ident),+ => $($to_exprs: expr),+;
+        p $params: ident => $parse: expr;
+        f $($f_names: ident),+ => $trailing: expr, $format: expr
     }),+) => (
         #[allow(non_camel_case_types)]
         #[derive(Debug, Hash, Clone, PartialEq)]
@@ -55,20 +57,24 @@ macro_rules! commands {
                 }
             }
 
+            /// The command, if it's known and has the parameters it needs.
             pub fn from_message(msg: &'a Message) -> Option<Command<'a>> {
-                use Command::*;
-                match msg.command() {
-                    $(
-                        $id => msg.last().map(|&e| $name(e))
-                    ),+
-                }
+                use self::Command::*;
+                let command = msg.command();
+                $(
+                    if command.eq_ignore_ascii_case($id.as_bytes()) {
+                        let $params = msg.elements();
+                        return $parse
+                    }
+                )+
+                None
             }
 
             pub fn to_message(&self) -> Message {
-                use Command::*;
+                use self::Command::*;
                 match self {
                     $(
-                        &$name(ref s) => Message::format(None, $id, Vec::new(), s)
+                        &$name($(ref $f_names),+) => Message::from_params($id, $format, $trailing)
                     ),+
                 }
             }
@@ -76,6 +82,33 @@ macro_rules! commands {
     )
 }
 
+/// The bytes of each of `items`, as parameters.
+fn params(items: &[TextSlice]) -> Vec<Vec<u8>> { items.iter().map(|i| i.to_vec()).collect() }
+
+/// No parameter for `None`.
+fn optional(item: &Option<TextSlice>) -> Vec<Vec<u8>> { item.iter().map(|i| i.to_vec()).collect() }
+
+/// `items` as one parameter, like the comma-separated channels of `JOIN`.
+fn join(items: &[TextSlice], separator: u8) -> Vec<u8> {
+    let mut joined = Vec::new();
+    for (n, i) in items.iter().enumerate() {
+        if n > 0 { joined.push(separator) }
+        joined.extend_from_slice(i);
+    }
+    joined
+}
+
+/// The items of a list parameter, without empty ones.
+fn split<'a>(list: &TextSlice<'a>, separator: u8) -> Vec<TextSlice<'a>> {
+    match *list {
+        TextSlice::Raw(b) => b.split(|&c| c == separator).filter(|i| !i.is_empty()).map(TextSlice::Raw).collect(),
+        TextSlice::Utf8(s) => s.split(separator as char).filter(|i| !i.is_empty()).map(TextSlice::Utf8).collect()
+    }
+}
+
+/// The parameters from the `n`th on.
+fn rest<'a>(params: &[TextSlice<'a>], n: usize) -> Vec<TextSlice<'a>> { params.iter().skip(n).cloned().collect() }
+
 commands! {
     PASS {
         "PASS", doc = r#"```text
@@ -97,9 +130,11 @@ commands! {
 
            PASS secretpasswordhere
         ```"#
-        b TextSlice<'a>
-        o Text
-        t t => t.into()
+        b TextSlice<'a>;
+        o Text;
+        t t => t.into();
+        p p => p.get(0).cloned().map(PASS);
+        f pw => false, params(&[pw.clone()])
     },
     NICK {
         "NICK", doc = r#"```text
@@ -127,9 +162,11 @@ commands! {
                                    ; Server telling that WiZ changed his
                                    nickname to Kilroy.
         ```"#
-        b TextSlice<'a>
-        o Text
-        t t => t.into()
+        b TextSlice<'a>;
+        o Text;
+        t t => t.into();
+        p p => p.get(0).cloned().map(NICK);
+        f n => false, params(&[n.clone()])
     },
     USER {
         "USER", doc = r#"```text
@@ -165,9 +202,11 @@ commands! {
                                            "Ronnie Reagan", and asking to be set
                                            invisible.
         ```"#
-        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>
-        o Text, Text, Text, Text
-        t r, s, t, u => r.into(), s.into(), t.into(), u.into()
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text, Text;
+        t r, s, t, u => r.into(), s.into(), t.into(), u.into();
+        p p => if p.len() < 4 { None } else { Some(USER(p[0].clone(), p[1].clone(), p[2].clone(), p[3].clone())) };
+        f r, s, t, u => true, params(&[r.clone(), s.clone(), t.clone(), u.clone()])
     },
     JOIN {
         "JOIN", doc = r#"```text
@@ -230,29 +269,785 @@ commands! {
 
            :WiZ!jto@tolsun.oulu.fi JOIN #Twilight_zone ; JOIN message from WiZ
                                            on channel #Twilight_zone
+
+        With the IRCv3 extended-join capability, JOINs from the server also
+        carry the services account of the user ("*" if none) and their real
+        name, which are the third field here.
+
+           :WiZ!jto@tolsun.oulu.fi JOIN #Twilight_zone wiz :Jarkko Oikarinen
         ```"#
-        b Vec<TextSlice<'a>>, Vec<TextSlice<'a>>
-        o Vec<Text>, Vec<Text>
-        t c, p => c.into_iter().map(Into::into).collect(),
-                  p.into_iter().map(Into::into).collect()
+        b Vec<TextSlice<'a>>, Vec<TextSlice<'a>>, Option<(TextSlice<'a>, TextSlice<'a>)>;
+        o Vec<Text>, Vec<Text>, Option<(Text, Text)>;
+        t c, p, e => c.into_iter().map(Into::into).collect(),
+                     p.into_iter().map(Into::into).collect(),
+                     e.map(|(a, r)| (a.into(), r.into()));
+        p p => p.get(0).map(|c| {
+            // With extended-join, the server sends the account and real name.
+            if p.len() > 2 { JOIN(split(c, b','), Vec::new(), Some((p[1].clone(), p[2].clone()))) }
+            else { JOIN(split(c, b','), p.get(1).map(|k| split(k, b',')).unwrap_or(Vec::new()), None) }
+        });
+        f c, k, e => e.is_some(), match *e {
+            Some((ref account, ref realname)) => vec![join(c, b','), account.to_vec(), realname.to_vec()],
+            None if k.is_empty() => vec![join(c, b',')],
+            None => vec![join(c, b','), join(k, b',')]
+        }
     },
     PRIVMSG {
         "PRIVMSG", doc = ""
-        b TextSlice<'a>, TextSlice<'a>
-        o Text, Text
-        t target, content => target.into(), content.into()
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t target, content => target.into(), content.into();
+        p p => if p.len() < 2 { None } else { Some(PRIVMSG(p[0].clone(), p[1].clone())) };
+        f target, content => true, params(&[target.clone(), content.clone()])
+    },
+    NOTICE {
+        "NOTICE", doc = ""
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t target, content => target.into(), content.into();
+        p p => if p.len() < 2 { None } else { Some(NOTICE(p[0].clone(), p[1].clone())) };
+        f target, content => true, params(&[target.clone(), content.clone()])
+    },
+    TAGMSG {
+        "TAGMSG", doc = r#"```text
+        IRCv3 message-tags
+
+        Command: TAGMSG
+        Parameters: <msgtarget>
+
+        A message without text, sent to a channel or user only for its
+        tags, like typing notifications or reactions. Requires the
+        message-tags capability. Tags starting with "+" are client-only
+        tags, which the server relays as they are.
+
+        Examples:
+
+           @+typing=active TAGMSG #rust    ; We're typing in #rust.
+
+           @+draft/reply=abc;+draft/react=lol :nick!user@host TAGMSG #rust
+                                           ; nick reacted to the message abc.
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t t => t.into();
+        p p => p.get(0).cloned().map(TAGMSG);
+        f t => false, params(&[t.clone()])
     },
     PING {
         "PING", doc = ""
-        b TextSlice<'a>, Option<TextSlice<'a>>
-        o Text, Option<Text>
-        t s1, s2 => s1.into(), s2.map(Into::into)
+        b TextSlice<'a>, Option<TextSlice<'a>>;
+        o Text, Option<Text>;
+        t s1, s2 => s1.into(), s2.map(Into::into);
+        p p => p.get(0).map(|s| PING(s.clone(), p.get(1).cloned()));
+        f s1, s2 => false, [params(&[s1.clone()]), optional(s2)].concat()
     },
     PONG {
         "PONG", doc = ""
-        b TextSlice<'a>, Option<TextSlice<'a>>
-        o Text, Option<Text>
-        t s1, s2 => s1.into(), s2.into()
+        b TextSlice<'a>, Option<TextSlice<'a>>;
+        o Text, Option<Text>;
+        t s1, s2 => s1.into(), s2.map(Into::into);
+        p p => p.get(0).map(|s| PONG(s.clone(), p.get(1).cloned()));
+        f s1, s2 => true, [params(&[s1.clone()]), optional(s2)].concat()
+    },
+    CAP {
+        "CAP", doc = r#"```text
+        IRCv3 Capability negotiation
+
+        Command: CAP
+        Parameters: [<target>] <subcommand> [*] [:<capabilities>]
+
+        Used by clients to discover and enable optional protocol extensions,
+        like server-time or message-tags. Subcommands are LS, LIST, REQ, ACK,
+        NAK, NEW, DEL and END. The target is only present in messages from
+        the server, and is the nickname of the client (or "*" if it has none
+        yet). A "*" before the list of capabilities means that more lines
+        are to follow.
+
+        Examples:
+
+           CAP LS 302                      ; Ask for the supported capabilities.
+
+           :irc.example.net CAP * LS :multi-prefix sasl server-time
+                                           ; The server supports three.
+
+           CAP REQ :server-time            ; Enable server-time.
+
+           CAP END                         ; Finish negotiation.
+        ```"#
+        b Option<TextSlice<'a>>, TextSlice<'a>, bool, TextSlice<'a>;
+        o Option<Text>, Text, bool, Text;
+        t t, sub, more, caps => t.map(Into::into), sub.into(), more, caps.into();
+        p p => if p.len() > 2 {
+            // From the server, which always sends the target and the list.
+            let more = p.len() > 3 && &*p[2] == b"*";
+            Some(CAP(Some(p[0].clone()), p[1].clone(), more, p[p.len() - 1].clone()))
+        } else {
+            p.get(0).map(|sub| CAP(None, sub.clone(), false, p.get(1).cloned().unwrap_or(EMPTY_RAW)))
+        };
+        f t, sub, more, caps => t.is_some(), {
+            let mut p = optional(t);
+            p.push(sub.to_vec());
+            if *more { p.push(b"*".to_vec()) }
+            if t.is_some() || !caps.is_empty() { p.push(caps.to_vec()) }
+            p
+        }
+    },
+    BATCH {
+        "BATCH", doc = r#"```text
+        IRCv3 Batches
+
+        Command: BATCH
+        Parameters: ( "+" <reference> <type> *( <parameter> ) ) / ( "-" <reference> )
+
+        Groups the messages tagged with `batch=<reference>` that are sent
+        between the start and the end of the batch. Batches may be nested.
+        Requires the batch capability.
+
+        Examples:
+
+           :irc.host BATCH +yXNAbvnRHTRBv netsplit irc.hub other.host
+                                           ; Start of a netsplit batch.
+
+           @batch=yXNAbvnRHTRBv :aji!a@a QUIT :irc.hub other.host
+                                           ; One of the users that split.
+
+           :irc.host BATCH -yXNAbvnRHTRBv  ; End of the batch.
+        ```"#
+        b TextSlice<'a>, Option<TextSlice<'a>>, Vec<TextSlice<'a>>;
+        o Text, Option<Text>, Vec<Text>;
+        t r, kind, params => r.into(), kind.map(Into::into),
+                             params.into_iter().map(Into::into).collect();
+        p p => p.get(0).map(|r| BATCH(r.clone(), p.get(1).cloned(), rest(&p, 2)));
+        f r, kind, ps => false, [params(&[r.clone()]), optional(kind), params(ps)].concat()
+    },
+    LIST {
+        "LIST", doc = r#"```text
+        3.2.6 List message
+
+        Command: LIST
+        Parameters: [ <channel> *( "," <channel> ) [ <target> ] ]
+
+        The list command is used to list channels and their topics.  If the
+        <channel> parameter is used, only the status of that channel is
+        displayed.
+
+        Numeric Replies:
+
+           ERR_TOOMANYMATCHES              ERR_NOSUCHSERVER
+           RPL_LIST                        RPL_LISTEND
+
+        Examples:
+
+           LIST                            ; Command to list all channels.
+
+           LIST #twilight_zone,#42         ; Command to list channels
+                                           #twilight_zone and #42
+        ```"#
+        b Vec<TextSlice<'a>>, Option<TextSlice<'a>>;
+        o Vec<Text>, Option<Text>;
+        t c, t => c.into_iter().map(Into::into).collect(), t.map(Into::into);
+        p p => Some(LIST(p.get(0).map(|c| split(c, b',')).unwrap_or(Vec::new()), p.get(1).cloned()));
+        f c, t => false, [if c.is_empty() { Vec::new() } else { vec![join(c, b',')] }, optional(t)].concat()
+    },
+    WHO {
+        "WHO", doc = r#"```text
+        3.6.1 Who query
+
+        Command: WHO
+        Parameters: [ <mask> [ "o" ] ]
+
+        The WHO command is used by a client to generate a query which returns
+        a list of information which 'matches' the <mask> parameter given by
+        the client.  With WHOX, the second parameter is a "%" followed by the
+        fields to return, optionally followed by a "," and a token of up to
+        three digits, which the server repeats in each RPL_WHOSPCRPL (354).
+
+        Numeric Replies:
+
+           ERR_NOSUCHSERVER
+           RPL_WHOREPLY                  RPL_ENDOFWHO
+
+        Examples:
+
+           WHO *.fi                        ; Command to list all users who match
+                                           against "*.fi".
+
+           WHO jto* o                      ; Command to list all users with a
+                                           match against "jto*" if they are an
+                                           operator.
+
+           WHO #rust %tcnfa,42             ; WHOX query for channel, nick,
+                                           flags and account.
+        ```"#
+        b Option<TextSlice<'a>>, Option<TextSlice<'a>>;
+        o Option<Text>, Option<Text>;
+        t m, o => m.map(Into::into), o.map(Into::into);
+        p p => Some(WHO(p.get(0).cloned(), p.get(1).cloned()));
+        f m, o => false, [optional(m), optional(o)].concat()
+    },
+    WHOIS {
+        "WHOIS", doc = r#"```text
+        3.6.2 Whois query
+
+        Command: WHOIS
+        Parameters: [ <target> ] <mask> *( "," <mask> )
+
+        This command is used to query information about particular user.
+        The server will answer this command with several numeric messages
+        indicating different statuses of each user which matches the mask (if
+        you are entitled to see them).
+
+        Numeric Replies:
+
+           ERR_NOSUCHSERVER              ERR_NONICKNAMEGIVEN
+           RPL_WHOISUSER                 RPL_WHOISCHANNELS
+           RPL_WHOISCHANNELS             RPL_WHOISSERVER
+           RPL_AWAY                      RPL_WHOISOPERATOR
+           RPL_WHOISIDLE                 ERR_NOSUCHNICK
+           RPL_ENDOFWHOIS
+
+        Examples:
+
+           WHOIS wiz                       ; return available user information
+                                           about nick WiZ
+
+           WHOIS eff.org trillian          ; ask server eff.org for user
+                                           information  about trillian
+        ```"#
+        b Option<TextSlice<'a>>, Vec<TextSlice<'a>>;
+        o Option<Text>, Vec<Text>;
+        t t, m => t.map(Into::into), m.into_iter().map(Into::into).collect();
+        p p => if p.len() > 1 { Some(WHOIS(Some(p[0].clone()), split(&p[1], b','))) }
+        else { p.get(0).map(|m| WHOIS(None, split(m, b','))) };
+        f t, m => false, [optional(t), vec![join(m, b',')]].concat()
+    },
+    ISON {
+        "ISON", doc = r#"```text
+        4.9 Ison message
+
+        Command: ISON
+        Parameters: <nickname> *( SPACE <nickname> )
+
+        The ISON command was implemented to provide a quick and efficient
+        means to get a response about whether a given nickname was currently
+        on IRC.  The server replies with RPL_ISON, listing the nicknames that
+        are present.
+
+        Numeric Replies:
+
+           RPL_ISON                        ERR_NEEDMOREPARAMS
+
+        Example:
+
+           ISON phone trillian WiZ jarlek Avalon Angel Monstah syrk
+        ```"#
+        b Vec<TextSlice<'a>>;
+        o Vec<Text>;
+        t n => n.into_iter().map(Into::into).collect();
+        p p => Some(ISON(p.iter().flat_map(|n| split(n, b' ')).collect()));
+        f n => false, if n.is_empty() { Vec::new() } else { vec![join(n, b' ')] }
+    },
+    AWAY {
+        "AWAY", doc = r#"```text
+        4.1 Away
+
+        Command: AWAY
+        Parameters: [ <text> ]
+
+        With the AWAY command, clients can set an automatic reply string for
+        any PRIVMSG commands directed at them (not to a channel they are on).
+        With no parameter, the AWAY message is removed.
+
+        With the IRCv3 away-notify capability, the server sends AWAY with the
+        prefix of a user whenever somebody sharing a channel with the client
+        goes away or comes back.
+
+        Numeric Replies:
+
+           RPL_UNAWAY                    RPL_NOWAWAY
+
+        Examples:
+
+           AWAY :Gone to lunch.  Back in 5 ; Command to set away message to
+                                           "Gone to lunch.  Back in 5".
+
+           :nick!user@host AWAY            ; nick is back.
+        ```"#
+        b Option<TextSlice<'a>>;
+        o Option<Text>;
+        t t => t.map(Into::into);
+        p p => Some(AWAY(p.get(0).cloned()));
+        f t => true, optional(t)
+    },
+    ACCOUNT {
+        "ACCOUNT", doc = r#"```text
+        IRCv3 account-notify
+
+        Command: ACCOUNT
+        Parameters: <accountname>
+
+        Sent by the server when a user sharing a channel with the client logs
+        into services, or out of them, in which case the account is "*".
+
+        Examples:
+
+           :nick!user@host ACCOUNT accountname
+                                           ; nick logged in as accountname.
+
+           :nick!user@host ACCOUNT *       ; nick logged out.
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t a => a.into();
+        p p => p.get(0).cloned().map(ACCOUNT);
+        f a => false, params(&[a.clone()])
+    },
+    CHGHOST {
+        "CHGHOST", doc = r#"```text
+        IRCv3 chghost
+
+        Command: CHGHOST
+        Parameters: <new user> <new host>
+
+        Sent by the server when the username or hostname of a user sharing
+        a channel with the client changes, instead of a fake QUIT and JOIN.
+
+        Example:
+
+           :nick!user@host CHGHOST user new.host.goes.here
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t u, h => u.into(), h.into();
+        p p => if p.len() < 2 { None } else { Some(CHGHOST(p[0].clone(), p[1].clone())) };
+        f u, h => false, params(&[u.clone(), h.clone()])
+    },
+    SETNAME {
+        "SETNAME", doc = r#"```text
+        IRCv3 setname
+
+        Command: SETNAME
+        Parameters: <realname>
+
+        Changes the real name of the client. The server sends it to users
+        sharing a channel with the client, if they enabled setname.
+
+        Examples:
+
+           SETNAME :Jane Doe               ; Change our real name.
+
+           :nick!user@host SETNAME :Jane Doe
+                                           ; nick changed their real name.
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t r => r.into();
+        p p => p.get(0).cloned().map(SETNAME);
+        f r => true, params(&[r.clone()])
+    },
+    CHATHISTORY {
+        "CHATHISTORY", doc = r#"```text
+        IRCv3 chathistory
+
+        Command: CHATHISTORY
+        Parameters: LATEST <target> ( "*" / <reference> ) <limit>
+                    BEFORE <target> <reference> <limit>
+                    AFTER <target> <reference> <limit>
+                    AROUND <target> <reference> <limit>
+                    BETWEEN <target> <reference> <reference> <limit>
+                    TARGETS <timestamp> <timestamp> <limit>
+
+        Requests messages of a channel or query from the history the server
+        keeps. References are "msgid=<id>" or "timestamp=<time>". The server
+        answers with a batch of type chathistory, or FAIL CHATHISTORY. The
+        most messages it returns at once is the value of the CHATHISTORY
+        ISUPPORT token, 0 for no limit.
+
+        Example:
+
+           CHATHISTORY BEFORE #rust timestamp=2019-01-04T14:33:26.123Z 50
+        ```"#
+        b TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Vec<Text>;
+        t sub, p => sub.into(), p.into_iter().map(Into::into).collect();
+        p p => p.get(0).map(|sub| CHATHISTORY(sub.clone(), rest(&p, 1)));
+        f sub, ps => false, [params(&[sub.clone()]), params(ps)].concat()
+    },
+    MONITOR {
+        "MONITOR", doc = r#"```text
+        IRCv3 Monitor
+
+        Command: MONITOR
+        Parameters: ( "+" / "-" ) <target> *( "," <target> ) / "C" / "L" / "S"
+
+        Adds (+) or removes (-) nicknames from the list of users the server
+        notifies the client about when they come online or go offline. "C"
+        clears the list, "L" lists it, "S" shows the status of each entry.
+        The maximum size of the list is given by the MONITOR ISUPPORT token.
+
+        Numeric Replies:
+
+           RPL_MONONLINE (730)             RPL_MONOFFLINE (731)
+           RPL_MONLIST (732)               RPL_ENDOFMONLIST (733)
+           ERR_MONLISTFULL (734)
+
+        Examples:
+
+           MONITOR + jilles,kteom          ; Watch jilles and kteom.
+
+           :irc.host 730 me :jilles!jilles@localhost
+                                           ; jilles is online.
+        ```"#
+        b TextSlice<'a>, Option<TextSlice<'a>>;
+        o Text, Option<Text>;
+        t sub, t => sub.into(), t.map(Into::into);
+        p p => p.get(0).map(|sub| MONITOR(sub.clone(), p.get(1).cloned()));
+        f sub, t => false, [params(&[sub.clone()]), optional(t)].concat()
+    },
+    WATCH {
+        "WATCH", doc = r#"```text
+        Watch
+
+        Command: WATCH
+        Parameters: *( ( "+" / "-" ) <nickname> / "C" / "L" / "S" )
+
+        The predecessor of MONITOR, with the same purpose. The maximum size
+        of the list is given by the WATCH ISUPPORT token.
+
+        Numeric Replies:
+
+           RPL_LOGON (600)                 RPL_LOGOFF (601)
+           RPL_NOWON (604)                 RPL_NOWOFF (605)
+           ERR_TOOMANYWATCH (512)
+
+        Example:
+
+           WATCH +jilles +kteom            ; Watch jilles and kteom.
+        ```"#
+        b Vec<TextSlice<'a>>;
+        o Vec<Text>;
+        t e => e.into_iter().map(Into::into).collect();
+        p p => Some(WATCH(p.iter().flat_map(|e| split(e, b' ')).collect()));
+        f e => false, if e.is_empty() { Vec::new() } else { vec![join(e, b' ')] }
+    },
+    QUIT {
+        "QUIT", doc = r#"```text
+        3.1.7 Quit
+
+        Command: QUIT
+        Parameters: [ <Quit Message> ]
+
+        A client session is terminated with a quit message.  The server
+        acknowledges this by sending an ERROR message to the client.
+
+        Numeric Replies:
+
+           None.
+
+        Example:
+
+           QUIT :Gone to have lunch        ; Preferred message format.
+
+           :syrk!kalt@millennium.stealth.net QUIT :Gone to have lunch ; User
+                                           syrk has quit IRC to have lunch.
+        ```"#
+        b Option<TextSlice<'a>>;
+        o Option<Text>;
+        t m => m.map(Into::into);
+        p p => Some(QUIT(p.get(0).cloned()));
+        f m => true, optional(m)
+    },
+    PART {
+        "PART", doc = r##"```text
+        3.2.2 Part message
+
+        Command: PART
+        Parameters: <channel> *( "," <channel> ) [ <Part Message> ]
+
+        The PART command causes the user sending the message to be removed
+        from the list of active members for all given channels listed in the
+        parameter string.  If a "Part Message" is given, this will be sent
+        instead of the default message, the nickname.  This request is always
+        granted by the server.
+
+        Servers MUST be able to parse arguments in the form of a list of
+        target, but SHOULD NOT use lists when sending PART messages to
+        clients.
+
+        Numeric Replies:
+
+           ERR_NEEDMOREPARAMS              ERR_NOSUCHCHANNEL
+           ERR_NOTONCHANNEL
+
+        Examples:
+
+           PART #twilight_zone             ; Command to leave channel
+                                           "#twilight_zone"
+
+           PART #oz-ops,&group5            ; Command to leave both channels
+                                           "&group5" and "#oz-ops".
+
+           :WiZ!jto@tolsun.oulu.fi PART #playzone :I lost
+                                           ; User WiZ leaving channel
+                                           "#playzone" with the message "I
+                                           lost".
+        ```"##
+        b Vec<TextSlice<'a>>, Option<TextSlice<'a>>;
+        o Vec<Text>, Option<Text>;
+        t c, m => c.into_iter().map(Into::into).collect(), m.map(Into::into);
+        p p => p.get(0).map(|c| PART(split(c, b','), p.get(1).cloned()));
+        f c, m => m.is_some(), [vec![join(c, b',')], optional(m)].concat()
+    },
+    MODE {
+        "MODE", doc = r##"```text
+        3.2.3 Channel mode message
+
+        Command: MODE
+        Parameters: <channel> *( ( "-" / "+" ) *<modes> *<modeparams> )
+
+        The MODE command is provided so that users may query and change the
+        characteristics of a channel.  For more details on available modes
+        and their uses, see "Internet Relay Chat: Channel Management" [IRC-
+        CHAN].  Note that there is a maximum limit of three (3) changes per
+        command for modes that take a parameter.
+
+        Numeric Replies:
+
+           ERR_NEEDMOREPARAMS              ERR_KEYSET
+           ERR_NOCHANMODES                 ERR_CHANOPRIVSNEEDED
+           ERR_USERNOTINCHANNEL            ERR_UNKNOWNMODE
+           RPL_CHANNELMODEIS
+           RPL_BANLIST                     RPL_ENDOFBANLIST
+           RPL_EXCEPTLIST                  RPL_ENDOFEXCEPTLIST
+           RPL_INVITELIST                  RPL_ENDOFINVITELIST
+           RPL_UNIQOPIS
+
+        The following examples are given to help understanding the syntax of
+        the MODE command, but refer to modes defined in "Internet Relay Chat:
+        Channel Management" [IRC-CHAN].
+
+        Examples:
+
+           MODE #Finnish +imI *!*@*.fi     ; Command to make #Finnish channel
+                                           moderated and 'invite-only' with user
+                                           with a hostname matching *.fi
+                                           automatically invited.
+
+           MODE #Finnish +o Kilroy         ; Command to give 'chanop' privileges
+                                           to Kilroy on channel #Finnish.
+
+           MODE #Finnish +v Wiz            ; Command to allow WiZ to speak on
+                                           #Finnish.
+
+           MODE #Fins -s                   ; Command to remove 'secret' flag
+                                           from channel #Fins.
+
+           MODE #42 +k oulu                ; Command to set the channel key to
+                                           "oulu".
+
+           MODE #42 -k oulu                ; Command to remove the "oulu"
+                                           channel key on channel "#42".
+
+           MODE #eu-opers +l 10            ; Command to set the limit for the
+                                           number of users on channel
+                                           "#eu-opers" to 10.
+
+           :WiZ!jto@tolsun.oulu.fi MODE #eu-opers -l
+                                           ; User "WiZ" removing the limit for
+                                           the number of users on channel "#eu-
+                                           opers".
+
+           MODE &oulu +b                   ; Command to list ban masks set for
+                                           the channel "&oulu".
+
+           MODE &oulu +b *!*@*             ; Command to prevent all users from
+                                           joining.
+
+           MODE &oulu +b *!*@*.edu +e *!*@*.bu.edu
+                                           ; Command to prevent any user from a
+                                           hostname matching *.edu from joining,
+                                           except if matching *.bu.edu
+
+           MODE #bu +be *!*@*.edu *!*@*.bu.edu
+                                           ; Comment to prevent any user from a
+                                           hostname matching *.edu from joining,
+                                           except if matching *.bu.edu
+
+           MODE #meditation e              ; Command to list exception masks set
+                                           for the channel "#meditation".
+
+           MODE #meditation I              ; Command to list invitations masks
+                                           set for the channel "#meditation".
+
+           MODE !12345ircd O               ; Command to ask who the channel
+                                           creator for "!12345ircd" is
+        ```"##
+        b TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Vec<Text>;
+        t t, m => t.into(), m.into_iter().map(Into::into).collect();
+        p p => p.get(0).map(|t| MODE(t.clone(), rest(&p, 1)));
+        f t, m => false, [params(&[t.clone()]), params(m)].concat()
+    },
+    TOPIC {
+        "TOPIC", doc = r#"```text
+        3.2.4 Topic message
+
+        Command: TOPIC
+        Parameters: <channel> [ <topic> ]
+
+        The TOPIC command is used to change or view the topic of a channel.
+        The topic for channel <channel> is returned if there is no <topic>
+        given.  If the <topic> parameter is present, the topic for that
+        channel will be changed, if this action is allowed for the user
+        requesting it.  If the <topic> parameter is an empty string, the
+        topic for that channel will be removed.
+
+        Numeric Replies:
+
+           ERR_NEEDMOREPARAMS              ERR_NOTONCHANNEL
+           RPL_NOTOPIC                     RPL_TOPIC
+           ERR_CHANOPRIVSNEEDED            ERR_NOCHANMODES
+
+        Examples:
+
+           :WiZ!jto@tolsun.oulu.fi TOPIC #test :New topic ; User Wiz setting the
+                                           topic.
+
+           TOPIC #test :another topic      ; Command to set the topic on #test
+                                           to "another topic".
+
+           TOPIC #test :                   ; Command to clear the topic on
+                                           #test.
+
+           TOPIC #test                     ; Command to check the topic for
+                                           #test.
+        ```"#
+        b TextSlice<'a>, Option<TextSlice<'a>>;
+        o Text, Option<Text>;
+        t c, t => c.into(), t.map(Into::into);
+        p p => p.get(0).map(|c| TOPIC(c.clone(), p.get(1).cloned()));
+        f c, t => t.is_some(), [params(&[c.clone()]), optional(t)].concat()
+    },
+    NAMES {
+        "NAMES", doc = r#"```text
+        3.2.5 Names message
+
+        Command: NAMES
+        Parameters: [ <channel> *( "," <channel> ) [ <target> ] ]
+
+        By using the NAMES command, a user can list all nicknames that are
+        visible to him. For more details on what is visible and what is not,
+        see "Internet Relay Chat: Channel Management" [IRC-CHAN].  The
+        <channel> parameter specifies which channel(s) to return information
+        about.  There is no error reply for bad channel names.
+
+        If no <channel> parameter is given, a list of all channels and their
+        occupants is returned.  At the end of this list, a list of users who
+        are visible but either not on any channel or not on a visible channel
+        are listed as being on `channel' "*".
+
+        If the <target> parameter is specified, the request is forwarded to
+        that server which will generate the reply.
+
+        Wildcards are allowed in the <target> parameter.
+
+        Numerics:
+
+           ERR_TOOMANYMATCHES              ERR_NOSUCHSERVER
+           RPL_NAMREPLY                    RPL_ENDOFNAMES
+
+        Examples:
+
+           NAMES #twilight_zone,#42        ; Command to list visible users on
+                                           #twilight_zone and #42
+
+           NAMES                           ; Command to list all visible
+                                           channels and users
+        ```"#
+        b Vec<TextSlice<'a>>, Option<TextSlice<'a>>;
+        o Vec<Text>, Option<Text>;
+        t c, t => c.into_iter().map(Into::into).collect(), t.map(Into::into);
+        p p => Some(NAMES(p.get(0).map(|c| split(c, b',')).unwrap_or(Vec::new()), p.get(1).cloned()));
+        f c, t => false, [if c.is_empty() { Vec::new() } else { vec![join(c, b',')] }, optional(t)].concat()
+    },
+    KICK {
+        "KICK", doc = r#"```text
+        3.2.8 Kick command
+
+        Command: KICK
+        Parameters: <channel> *( "," <channel> ) <user> *( "," <user> )
+                    [<comment>]
+
+        The KICK command can be used to request the forced removal of a user
+        from a channel.  It causes the <user> to PART from the <channel> by
+        force.  For the message to be syntactically correct, there MUST be
+        either one channel parameter and multiple user parameter, or as many
+        channel parameters as there are user parameters.  If a "comment" is
+        given, this will be sent instead of the default message, the nickname
+        of the user issuing the KICK.
+
+        The server MUST NOT send KICK messages with multiple channels or
+        users to clients.  This is necessarily to maintain backward
+        compatibility with old client software.
+
+        Numeric Replies:
+
+           ERR_NEEDMOREPARAMS              ERR_NOSUCHCHANNEL
+           ERR_BADCHANMASK                 ERR_CHANOPRIVSNEEDED
+           ERR_USERNOTINCHANNEL            ERR_NOTONCHANNEL
+
+        Examples:
+
+           KICK &Melbourne Matthew         ; Command to kick Matthew from
+                                           &Melbourne
+
+           KICK #Finnish John :Speaking English
+                                           ; Command to kick John from #Finnish
+                                           using "Speaking English" as the
+                                           reason (comment).
+
+           :WiZ!jto@tolsun.oulu.fi KICK #Finnish John
+                                           ; KICK message on channel #Finnish
+                                           from WiZ to remove John from channel
+        ```"#
+        b Vec<TextSlice<'a>>, Vec<TextSlice<'a>>, Option<TextSlice<'a>>;
+        o Vec<Text>, Vec<Text>, Option<Text>;
+        t c, u, m => c.into_iter().map(Into::into).collect(),
+                     u.into_iter().map(Into::into).collect(), m.map(Into::into);
+        p p => if p.len() < 2 { None } else { Some(KICK(split(&p[0], b','), split(&p[1], b','), p.get(2).cloned())) };
+        f c, u, m => m.is_some(), [vec![join(c, b','), join(u, b',')], optional(m)].concat()
+    },
+    BOUNCER {
+        "BOUNCER", doc = r#"```text
+        soju.im/bouncer-networks
+
+        Command: BOUNCER
+        Parameters: LISTNETWORKS
+                    BIND <netid>
+                    ADDNETWORK <attributes>
+                    CHANGENETWORK <netid> <attributes>
+                    DELNETWORK <netid>
+
+        Manages the networks of a bouncer. Attributes are formatted like
+        message tags, e.g. "name=Libera;host=irc.libera.chat". BIND must be
+        sent before CAP END, and makes the connection one to that network.
+        LISTNETWORKS is answered with a batch of type
+        soju.im/bouncer-networks. With soju.im/bouncer-networks-notify,
+        changes are sent as they happen; "*" as attributes means the
+        network was removed.
+
+        Examples:
+
+           BOUNCER BIND 42                 ; Use the network with id 42.
+
+           :irc.host BOUNCER NETWORK 42 name=Libera;state=connected
+                                           ; Network 42 is connected.
+        ```"#
+        b TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Vec<Text>;
+        t sub, p => sub.into(), p.into_iter().map(Into::into).collect();
+        p p => p.get(0).map(|sub| BOUNCER(sub.clone(), rest(&p, 1)));
+        f sub, ps => false, [params(&[sub.clone()]), params(ps)].concat()
     }
 }
 /*
@@ -2039,3 +2834,93 @@ impl<'a> Command<'a> {
     }
 }
 */
+
+#[cfg(test)]
+mod test {
+    use command::Command;
+    use command::Command::*;
+    use message::Message;
+    use text::TextSlice;
+
+    /// Parsed messages are raw, so these compare equal to what is parsed.
+    fn t(s: &'static str) -> TextSlice<'static> { TextSlice::Raw(s.as_bytes()) }
+
+    fn line(c: Command) -> String { String::from_utf8(c.to_message().bytes().to_vec()).unwrap() }
+
+    fn round_trip(c: Command) {
+        let msg = c.to_message();
+        assert_eq!(Command::from_message(&msg), Some(c));
+    }
+
+    #[test]
+    fn cap() {
+        let msg = Message::parse(b":irc.host CAP * LS * :multi-prefix sasl\r\n").unwrap();
+        assert_eq!(Command::from_message(&msg), Some(CAP(Some(t("*")), t("LS"), true, t("multi-prefix sasl"))));
+        let msg = Message::parse(b":irc.host CAP bot ACK :server-time\r\n").unwrap();
+        assert_eq!(Command::from_message(&msg), Some(CAP(Some(t("bot")), t("ACK"), false, t("server-time"))));
+        let msg = Message::parse(b"CAP END\r\n").unwrap();
+        assert_eq!(Command::from_message(&msg), Some(CAP(None, t("END"), false, t(""))));
+
+        assert_eq!(line(CAP(None, "LS".into(), false, "302".into())), "CAP LS 302\r\n");
+        assert_eq!(line(CAP(None, "REQ".into(), false, "sasl server-time".into())), "CAP REQ :sasl server-time\r\n");
+        assert_eq!(line(CAP(None, "END".into(), false, "".into())), "CAP END\r\n");
+        assert_eq!(line(CAP(Some("*".into()), "LS".into(), false, "".into())), "CAP * LS :\r\n");
+
+        round_trip(CAP(None, t("REQ"), false, t("sasl server-time")));
+        round_trip(CAP(Some(t("*")), t("LS"), true, t("sasl")));
+        round_trip(CAP(Some(t("bot")), t("NAK"), false, t("")));
+    }
+
+    #[test]
+    fn lists() {
+        let msg = Message::parse(b"JOIN #a,#b key\r\n").unwrap();
+        assert_eq!(Command::from_message(&msg), Some(JOIN(vec![t("#a"), t("#b")], vec![t("key")], None)));
+        let msg = Message::parse(b":nick!u@h JOIN #a account :Real Name\r\n").unwrap();
+        assert_eq!(Command::from_message(&msg), Some(JOIN(vec![t("#a")], Vec::new(), Some((t("account"), t("Real Name"))))));
+        let msg = Message::parse(b"ISON alice :bob carol\r\n").unwrap();
+        assert_eq!(Command::from_message(&msg), Some(ISON(vec![t("alice"), t("bob"), t("carol")])));
+
+        assert_eq!(line(JOIN(vec!["#a".into(), "#b".into()], Vec::new(), None)), "JOIN #a,#b\r\n");
+        assert_eq!(line(KICK(vec!["#a".into()], vec!["x".into(), "y".into()], None)), "KICK #a x,y\r\n");
+        assert_eq!(line(ISON(vec!["alice".into(), "bob".into()])), "ISON :alice bob\r\n");
+
+        round_trip(JOIN(vec![t("#a"), t("#b")], vec![t("k1"), t("k2")], None));
+        round_trip(JOIN(vec![t("#a")], Vec::new(), Some((t("*"), t("Real Name")))));
+        round_trip(PART(vec![t("#a"), t("#b")], Some(t("bye now"))));
+        round_trip(KICK(vec![t("#a")], vec![t("x"), t("y")], Some(t("out"))));
+        round_trip(WHOIS(Some(t("irc.host")), vec![t("alice"), t("bob")]));
+        round_trip(LIST(vec![t("#a"), t("#b")], None));
+        round_trip(NAMES(Vec::new(), None));
+        round_trip(WATCH(vec![t("+alice"), t("-bob")]));
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(line(USER("bot".into(), "0".into(), "*".into(), "A Bot".into())), "USER bot 0 * :A Bot\r\n");
+        assert_eq!(line(PING("irsc-lag1".into(), None)), "PING irsc-lag1\r\n");
+        assert_eq!(line(TOPIC("#a".into(), Some("".into()))), "TOPIC #a :\r\n");
+        assert_eq!(line(TOPIC("#a".into(), None)), "TOPIC #a\r\n");
+        assert_eq!(line(PRIVMSG("#a".into(), ":)".into())), "PRIVMSG #a ::)\r\n");
+        assert_eq!(line(QUIT(None)), "QUIT\r\n");
+
+        round_trip(USER(t("bot"), t("0"), t("*"), t("A Bot")));
+        round_trip(PRIVMSG(t("#a"), t("hello there")));
+        round_trip(NOTICE(t("bob"), t("hi")));
+        round_trip(PONG(t("irc.host"), Some(t("token"))));
+        round_trip(BATCH(t("+ref"), Some(t("netsplit")), vec![t("irc.hub"), t("other.host")]));
+        round_trip(BATCH(t("-ref"), None, Vec::new()));
+        round_trip(CHATHISTORY(t("LATEST"), vec![t("#a"), t("*"), t("50")]));
+        round_trip(BOUNCER(t("ADDNETWORK"), vec![t("name=x;host=irc.x")]));
+        round_trip(WHO(Some(t("#a")), Some(t("%tnuhraf,42"))));
+        round_trip(MODE(t("#a"), vec![t("+ov"), t("alice"), t("bob")]));
+        round_trip(MONITOR(t("+"), Some(t("alice,bob"))));
+        round_trip(AWAY(Some(t("gone for now"))));
+        round_trip(AWAY(None));
+        round_trip(CHGHOST(t("user"), t("host")));
+
+        // Commands are case-insensitive, and need their parameters.
+        assert!(Command::from_message(&Message::parse(b"privmsg #a :hi\r\n").unwrap()).is_some());
+        assert_eq!(Command::from_message(&Message::parse(b"PRIVMSG #a\r\n").unwrap()), None);
+        assert_eq!(Command::from_message(&Message::parse(b"FOO bar\r\n").unwrap()), None);
+    }
+}
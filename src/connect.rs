@@ -0,0 +1,199 @@
+use std::io;
+use std::net::{ IpAddr, SocketAddr, TcpStream, ToSocketAddrs };
+use std::result;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use socket2::{ Domain, Protocol, Socket, Type };
+
+use proxy::Proxy;
+
+use ::IrscError;
+
+/// An IP version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Family {
+    V4,
+    V6
+}
+
+impl Family {
+    pub fn of(addr: &SocketAddr) -> Family {
+        match *addr {
+            SocketAddr::V4(_) => Family::V4,
+            SocketAddr::V6(_) => Family::V6
+        }
+    }
+}
+
+/// How connections are made.
+///
+/// Every address of the host is tried, alternating between IPv6 and IPv4,
+/// as in Happy Eyeballs (RFC 8305): if an attempt takes longer than the
+/// fallback delay, the next one is started alongside it, and the first to
+/// succeed is used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectOptions {
+    bind: Option<IpAddr>,
+    prefer: Option<Family>,
+    timeout: Option<Duration>,
+    fallback_delay: Duration
+}
+
+impl Default for ConnectOptions {
+    fn default() -> ConnectOptions {
+        ConnectOptions {
+            bind: None,
+            prefer: None,
+            timeout: Some(Duration::from_secs(10)),
+            fallback_delay: Duration::from_millis(250)
+        }
+    }
+}
+
+impl ConnectOptions {
+    pub fn new() -> ConnectOptions { ConnectOptions::default() }
+
+    /// Connect from this local address, e.g. for a vhost. Only addresses of
+    /// the same family are tried.
+    pub fn bind(mut self, addr: IpAddr) -> ConnectOptions { self.bind = Some(addr); self }
+
+    /// Try addresses of this family first. By default, the family of the
+    /// first address the resolver returns goes first.
+    pub fn prefer(mut self, family: Family) -> ConnectOptions { self.prefer = Some(family); self }
+
+    /// Give up on each address after this long. The default is 10 seconds.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> ConnectOptions { self.timeout = timeout; self }
+
+    /// How long to wait on one address before trying the next alongside it.
+    /// The default is 250 milliseconds.
+    pub fn fallback_delay(mut self, delay: Duration) -> ConnectOptions { self.fallback_delay = delay; self }
+
+    /// The order in which `addrs` are tried.
+    pub fn order(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let addrs: Vec<SocketAddr> = match self.bind {
+            Some(ip) => addrs.into_iter().filter(|a| a.is_ipv4() == ip.is_ipv4()).collect(),
+            None => addrs
+        };
+        let first = match self.prefer.or(addrs.first().map(Family::of)) {
+            Some(f) => f,
+            None => return addrs
+        };
+        let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.into_iter().partition(|a| Family::of(a) == first);
+        let mut ordered = Vec::new();
+        let mut p = preferred.into_iter();
+        let mut o = other.into_iter();
+        loop {
+            match (p.next(), o.next()) {
+                (None, None) => return ordered,
+                (a, b) => { ordered.extend(a); ordered.extend(b) }
+            }
+        }
+    }
+
+    /// Resolve `host`, and connect to one of its addresses.
+    pub fn connect(&self, host: &str, port: u16) -> result::Result<TcpStream, IrscError> {
+        let addrs = try!((host, port).to_socket_addrs().map_err(IrscError::Io)).collect();
+        self.connect_addrs(self.order(addrs))
+    }
+
+    /// Connect to `host`, through `proxy` if there is one.
+    pub fn connect_via(&self, proxy: Option<&Proxy>, host: &str, port: u16) -> result::Result<TcpStream, IrscError> {
+        match proxy {
+            Some(proxy) => {
+                let (proxy_host, proxy_port) = proxy.address();
+                let mut tcp = try!(self.connect(proxy_host, proxy_port));
+                try!(proxy.tunnel(&mut tcp, host, port).map_err(IrscError::Io));
+                Ok(tcp)
+            },
+            None => self.connect(host, port)
+        }
+    }
+
+    /// Connect to one of `addrs`, tried in the given order.
+    pub fn connect_addrs(&self, addrs: Vec<SocketAddr>) -> result::Result<TcpStream, IrscError> {
+        let (tx, rx) = mpsc::channel();
+        let mut remaining = addrs.into_iter().peekable();
+        let mut running = 0;
+        let mut failures = Vec::new();
+        loop {
+            if let Some(addr) = remaining.next() {
+                let tx = tx.clone();
+                let options = self.clone();
+                thread::spawn(move || { let _ = tx.send((addr, options.attempt(&addr))); });
+                running += 1;
+            }
+            if running == 0 { return Err(IrscError::Connect(failures)) }
+
+            let result = if remaining.peek().is_some() {
+                match rx.recv_timeout(self.fallback_delay) {
+                    Ok(r) => r,
+                    // Taking too long, start the next one.
+                    Err(_) => continue
+                }
+            } else {
+                // We hold a sender, so this can't fail.
+                rx.recv().unwrap()
+            };
+            running -= 1;
+            match result {
+                (_, Ok(stream)) => return Ok(stream),
+                (addr, Err(e)) => {
+                    debug!("Connecting to {} failed: {}", addr, e);
+                    failures.push((addr, e))
+                }
+            }
+        }
+    }
+
+    fn attempt(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        let socket = try!(Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP)));
+        if let Some(ip) = self.bind {
+            try!(socket.bind(&SocketAddr::new(ip, 0).into()));
+        }
+        match self.timeout {
+            Some(t) => try!(socket.connect_timeout(&(*addr).into(), t)),
+            None => try!(socket.connect(&(*addr).into()))
+        }
+        Ok(socket.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{ SocketAddr, TcpListener };
+
+    use connect::{ ConnectOptions, Family };
+    use ::IrscError;
+
+    fn addrs(s: &[&str]) -> Vec<SocketAddr> { s.iter().map(|a| a.parse().unwrap()).collect() }
+
+    #[test]
+    fn order() {
+        let resolved = addrs(&["1.1.1.1:6667", "2.2.2.2:6667", "[::1]:6667"]);
+        assert_eq!(ConnectOptions::new().order(resolved.clone()),
+                   addrs(&["1.1.1.1:6667", "[::1]:6667", "2.2.2.2:6667"]));
+        assert_eq!(ConnectOptions::new().prefer(Family::V6).order(resolved.clone()),
+                   addrs(&["[::1]:6667", "1.1.1.1:6667", "2.2.2.2:6667"]));
+        assert_eq!(ConnectOptions::new().bind("0.0.0.0".parse().unwrap()).order(resolved),
+                   addrs(&["1.1.1.1:6667", "2.2.2.2:6667"]));
+    }
+
+    #[test]
+    fn fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        // Bound, but not listening, so connecting is refused.
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let stream = ConnectOptions::new().connect_addrs(vec![closed, good]).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good);
+
+        match ConnectOptions::new().connect_addrs(vec![closed]) {
+            Err(IrscError::Connect(failures)) => assert_eq!(failures[0].0, closed),
+            _ => panic!("connected to a closed port")
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::borrow::ToOwned;
+use std::collections::VecDeque;
+
+use message::Message;
+use casemap::CaseMapping;
+use label::{ pending, Pending, Promise };
+use text;
+use ::IrscError;
+
+/// How many msgids of our own messages are remembered, to drop duplicate echoes.
+const SEEN: usize = 256;
+
+struct Sent {
+    label: Option<String>,
+    command: String,
+    target: String,
+    text: String,
+    promise: Promise<Message>
+}
+
+/// Our own messages, waiting to be echoed by the server with `echo-message`.
+///
+/// Echoes are matched with what was sent by their `label`, if
+/// `labeled-response` is enabled. Otherwise, the oldest message with the same
+/// command, target and text is taken, or failing that, with the same command
+/// and target, since the server may have changed the text.
+#[derive(Default)]
+pub struct Echoes {
+    next: u64,
+    sent: VecDeque<Sent>,
+    seen: VecDeque<String>
+}
+
+fn parts(msg: &Message) -> (String, String, String) {
+    let elements = msg.elements();
+    (text::def_lossy_decode(&msg.command()),
+     elements.first().map(|e| text::def_lossy_decode(e)).unwrap_or(String::new()),
+     if elements.len() > 1 { elements.last().map(|e| text::def_lossy_decode(e)).unwrap_or(String::new()) }
+     else { String::new() })
+}
+
+impl Echoes {
+    pub fn new() -> Echoes { Echoes::default() }
+
+    /// Expect `msg` to be echoed. With `labeled`, it's tagged with a label
+    /// before it's returned, ready to be sent.
+    pub fn expect(&mut self, msg: Message, labeled: bool) -> (Message, Pending<Message>) {
+        let label = if labeled {
+            self.next += 1;
+            Some(format!("echo{}", self.next))
+        } else { None };
+        let msg = match label {
+            Some(ref l) => msg.with_tag("label", Some(l)),
+            None => msg
+        };
+        let (command, target, text) = parts(&msg);
+        let (promise, pending) = pending(label.clone().unwrap_or(String::new()));
+        self.sent.push_back(Sent { label: label, command: command, target: target, text: text, promise: promise });
+        (msg, pending)
+    }
+
+    /// Look at one of our own messages. Returns false if it's an echo that
+    /// was seen before, by its msgid, and should be ignored.
+    pub fn handle(&mut self, msg: &Message, mapping: CaseMapping) -> bool {
+        if let Some(id) = msg.msgid() {
+            if self.seen.iter().any(|s| *s == id) { return false }
+            if self.seen.len() >= SEEN { self.seen.pop_front(); }
+            self.seen.push_back(id.to_owned());
+        }
+
+        let (command, target, text) = parts(msg);
+        let found = match msg.tag("label") {
+            Some(label) => self.sent.iter().position(|s| s.label.as_ref().map(|l| &l[..]) == Some(label)),
+            None => {
+                let same = |s: &Sent| s.label.is_none() && s.command == command && mapping.eq(&s.target, &target);
+                self.sent.iter().position(|s| same(s) && s.text == text)
+                    .or_else(|| self.sent.iter().position(|s| same(s)))
+            }
+        };
+        if let Some(i) = found {
+            self.sent.remove(i).unwrap().promise.complete(Ok(msg.clone()));
+        }
+        true
+    }
+
+    /// Fail everything that is still waiting, e.g. because the connection was lost.
+    pub fn fail_all(&mut self) {
+        for s in self.sent.drain(..) { s.promise.complete(Err(IrscError::NotConnected)) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use casemap::CaseMapping;
+    use echo::Echoes;
+
+    fn msg(s: &str) -> Message { Message::parse(s.as_bytes()).unwrap() }
+
+    #[test]
+    fn matching() {
+        let mut e = Echoes::new();
+        let (_, first) = e.expect(msg("PRIVMSG #rust :one"), false);
+        let (_, second) = e.expect(msg("PRIVMSG #rust :two"), false);
+        let (sent, labeled) = e.expect(msg("PRIVMSG #irc :three"), true);
+        assert_eq!(sent.tag("label"), Some("echo1"));
+
+        assert!(e.handle(&msg("@label=echo1 :me!u@h PRIVMSG #irc :three"), CaseMapping::Rfc1459));
+        assert!(e.handle(&msg("@msgid=b :me!u@h PRIVMSG #RUST :two"), CaseMapping::Rfc1459));
+        assert!(e.handle(&msg("@msgid=a :me!u@h PRIVMSG #rust :ONE"), CaseMapping::Rfc1459));
+        assert!(!e.handle(&msg("@msgid=a :me!u@h PRIVMSG #rust :ONE"), CaseMapping::Rfc1459));
+
+        assert_eq!(first.wait().unwrap().msgid(), Some("a"));
+        assert_eq!(second.wait().unwrap().msgid(), Some("b"));
+        assert!(labeled.wait().is_ok());
+    }
+}
@@ -2,11 +2,30 @@ use std::borrow::{ Borrow, ToOwned };
 
 use command;
 use reply;
+use batch;
+use multiline;
+use bouncer;
+use ident::Ident;
+use tags::ClientTag;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Event<'a> {
     Command(command::Command<'a>),
     Reply(reply::Reply<'a>),
+    /// A complete batch, with all messages that were part of it.
+    /// These aren't delivered on their own.
+    Batch(batch::Batch),
+    /// A `draft/multiline` batch, put back together.
+    Multiline(multiline::Multiline),
+    /// A user watched with `Client::watch` came online.
+    Online(Ident),
+    /// A user watched with `Client::watch` went offline.
+    Offline(String),
+    /// A network of the bouncer was added, changed or removed.
+    Network(bouncer::NetworkChange),
+    /// The client-only tags of a TAGMSG. On other messages, they can be
+    /// read with `ClientTag::from_message`.
+    Tags(Vec<ClientTag>),
     Connected,
     Disconnected
 }
@@ -17,6 +36,12 @@ impl<'a> Event<'a> {
         match self {
             &Command(ref c) => Command(c.to_static()),
             &Reply(ref r) => Reply(r.to_static()),
+            &Batch(ref b) => Batch(b.clone()),
+            &Multiline(ref m) => Multiline(m.clone()),
+            &Online(ref i) => Online(i.clone()),
+            &Offline(ref n) => Offline(n.clone()),
+            &Network(ref c) => Network(c.clone()),
+            &Tags(ref t) => Tags(t.clone()),
             &Connected => Connected,
             &Disconnected => Disconnected
         }
@@ -0,0 +1,271 @@
+use std::borrow::ToOwned;
+use std::collections::VecDeque;
+use std::cmp;
+
+use message::Message;
+use batch::Batch;
+use casemap::CaseMapping;
+use label::{ pending, Pending, Promise };
+use timestamp::Timestamp;
+use text;
+use ::IrscError;
+
+/// A point in the history of a channel or query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    MsgId(String),
+    Timestamp(Timestamp)
+}
+
+impl Anchor {
+    /// The anchor for `msg`, by its msgid or otherwise its server time.
+    pub fn of(msg: &Message) -> Option<Anchor> {
+        msg.msgid().map(|id| Anchor::MsgId(id.to_owned()))
+            .or_else(|| msg.server_time().map(Anchor::Timestamp))
+    }
+
+    fn param(&self) -> String {
+        match *self {
+            Anchor::MsgId(ref id) => format!("msgid={}", id),
+            Anchor::Timestamp(ref t) => format!("timestamp={}", t)
+        }
+    }
+}
+
+/// Which messages `CHATHISTORY` should return.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// The newest messages, or only those after the anchor.
+    Latest(Option<Anchor>),
+    Before(Anchor),
+    After(Anchor),
+    Around(Anchor),
+    /// Starting from the first anchor, towards the second. If the first is
+    /// the more recent one, the newest messages between them are returned.
+    Between(Anchor, Anchor)
+}
+
+impl Query {
+    fn params(&self) -> Vec<String> {
+        match *self {
+            Query::Latest(ref a) => vec!["LATEST".to_owned(), a.as_ref().map(Anchor::param).unwrap_or("*".to_owned())],
+            Query::Before(ref a) => vec!["BEFORE".to_owned(), a.param()],
+            Query::After(ref a) => vec!["AFTER".to_owned(), a.param()],
+            Query::Around(ref a) => vec!["AROUND".to_owned(), a.param()],
+            Query::Between(ref a, ref b) => vec!["BETWEEN".to_owned(), a.param(), b.param()]
+        }
+    }
+
+    /// Whether later pages are older than earlier ones.
+    fn backwards(&self) -> bool {
+        match *self {
+            Query::Latest(_) | Query::Before(_) => true,
+            Query::Between(Anchor::Timestamp(a), Anchor::Timestamp(b)) => a > b,
+            _ => false
+        }
+    }
+
+    /// The query for the page after `page`, if there can be one.
+    fn next(&self, page: &[Message]) -> Option<Query> {
+        let edge = if self.backwards() { page.first() } else { page.last() };
+        let edge = match edge.and_then(Anchor::of) {
+            Some(e) => e,
+            None => return None
+        };
+        match *self {
+            Query::Latest(None) | Query::Before(_) => Some(Query::Before(edge)),
+            Query::Latest(Some(ref a)) => Some(Query::Between(edge, a.clone())),
+            Query::After(_) => Some(Query::After(edge)),
+            Query::Between(_, ref b) => Some(Query::Between(edge, b.clone())),
+            Query::Around(_) => None
+        }
+    }
+}
+
+struct Request {
+    target: String,
+    query: Query,
+    wanted: usize,
+    page: usize,
+    paging: bool,
+    messages: Vec<Message>,
+    promise: Promise<Vec<Message>>
+}
+
+impl Request {
+    fn limit(&self) -> usize { cmp::min(self.page, self.wanted - self.messages.len()) }
+
+    /// Parameters for `CHATHISTORY`, subcommand first.
+    fn params(&self) -> Vec<String> {
+        let mut p = self.query.params();
+        p.insert(1, self.target.clone());
+        p.push(self.limit().to_string());
+        p
+    }
+}
+
+/// `CHATHISTORY` requests waiting for their batches.
+#[derive(Default)]
+pub struct Histories {
+    requests: VecDeque<Request>,
+    targets: VecDeque<Promise<Vec<(String, Timestamp)>>>
+}
+
+impl Histories {
+    pub fn new() -> Histories { Histories::default() }
+
+    /// Ask for up to `limit` messages of `target`. `max` is the most the server
+    /// returns at once, from the `CHATHISTORY` ISUPPORT token. With `paging`,
+    /// more requests are made until `limit` is reached or history runs out.
+    /// Returns the parameters of the `CHATHISTORY` command to send.
+    pub fn request(&mut self, target: &str, query: Query, limit: usize, max: Option<usize>, paging: bool)
+    -> (Pending<Vec<Message>>, Vec<String>) {
+        let (promise, pending) = pending(String::new());
+        let request = Request {
+            target: target.to_owned(),
+            query: query,
+            wanted: limit,
+            page: max.and_then(|m| if m == 0 { None } else { Some(m) }).unwrap_or(limit),
+            paging: paging,
+            messages: Vec::new(),
+            promise: promise
+        };
+        let params = request.params();
+        self.requests.push_back(request);
+        (pending, params)
+    }
+
+    /// Ask which targets had messages between `from` and `to`.
+    pub fn request_targets(&mut self, from: Timestamp, to: Timestamp, limit: usize)
+    -> (Pending<Vec<(String, Timestamp)>>, Vec<String>) {
+        let (promise, pending) = pending(String::new());
+        self.targets.push_back(promise);
+        (pending, vec!["TARGETS".to_owned(), format!("timestamp={}", from),
+                       format!("timestamp={}", to), limit.to_string()])
+    }
+
+    /// Take a completed batch, if it answers a request. Returns whether it did,
+    /// and the parameters of the next `CHATHISTORY` command if there's another page.
+    pub fn handle_batch(&mut self, batch: &Batch, mapping: CaseMapping) -> (bool, Option<Vec<String>>) {
+        if batch.kind == "draft/chathistory-targets" {
+            return match self.targets.pop_front() {
+                Some(promise) => {
+                    let targets = batch.own_messages().iter().filter_map(|m| {
+                        let p: Vec<String> = m.elements().iter().map(|e| text::def_lossy_decode(e)).collect();
+                        match (p.get(1), p.get(2).and_then(|t| Timestamp::parse_iso8601(t.trim_left_matches("timestamp=")))) {
+                            (Some(target), Some(time)) => Some((target.clone(), time)),
+                            _ => None
+                        }
+                    }).collect();
+                    promise.complete(Ok(targets));
+                    (true, None)
+                },
+                None => (false, None)
+            }
+        }
+
+        let i = match batch.chathistory_target()
+            .and_then(|t| self.requests.iter().position(|r| mapping.eq(&r.target, t))) {
+            Some(i) => i,
+            None => return (false, None)
+        };
+        let mut request = self.requests.remove(i).unwrap();
+        let page: Vec<Message> = batch.own_messages().into_iter().cloned().collect();
+        let full = page.len() >= request.limit();
+        let next = request.query.next(&page);
+
+        if request.query.backwards() {
+            let older = request.messages;
+            request.messages = page;
+            request.messages.extend(older.into_iter());
+        } else {
+            request.messages.extend(page.into_iter());
+        }
+
+        match next {
+            Some(q) if request.paging && full && request.messages.len() < request.wanted => {
+                request.query = q;
+                let params = request.params();
+                self.requests.push_back(request);
+                (true, Some(params))
+            },
+            _ => {
+                request.promise.complete(Ok(request.messages));
+                (true, None)
+            }
+        }
+    }
+
+    /// Look at `FAIL CHATHISTORY` errors, which fail the oldest request.
+    pub fn handle(&mut self, msg: &Message) {
+        if &*msg.command() != b"FAIL" { return }
+        let p: Vec<String> = msg.elements().iter().map(|e| text::def_lossy_decode(e)).collect();
+        if p.len() < 3 || p[0] != "CHATHISTORY" { return }
+        let error = IrscError::Reply(p[1].clone(), p[p.len() - 1].clone());
+        if p[2] == "TARGETS" {
+            if let Some(promise) = self.targets.pop_front() { promise.complete(Err(error)) }
+        } else if let Some(r) = self.requests.pop_front() {
+            r.promise.complete(Err(error));
+        }
+    }
+
+    /// Fail everything that is still waiting, e.g. because the connection was lost.
+    pub fn fail_all(&mut self) {
+        for r in self.requests.drain(..) { r.promise.complete(Err(IrscError::NotConnected)) }
+        for p in self.targets.drain(..) { p.complete(Err(IrscError::NotConnected)) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use batch::{ Batches, Collected, Batch };
+    use casemap::CaseMapping;
+    use history::{ Histories, Query, Anchor };
+
+    fn batch(lines: &[&str]) -> Batch {
+        let mut b = Batches::new();
+        for l in lines {
+            if let Collected::Complete(batch) = b.handle(&Message::parse(l.as_bytes()).unwrap()) {
+                return batch
+            }
+        }
+        panic!("incomplete batch")
+    }
+
+    #[test]
+    fn paging() {
+        let mut h = Histories::new();
+        let (pending, params) = h.request("#rust", Query::Latest(None), 3, Some(2), true);
+        assert_eq!(params, vec!["LATEST", "#rust", "*", "2"]);
+
+        let (taken, next) = h.handle_batch(&batch(&[
+            ":irc.host BATCH +a chathistory #rust",
+            "@batch=a;msgid=3 :n!u@h PRIVMSG #rust :three",
+            "@batch=a;msgid=4 :n!u@h PRIVMSG #rust :four",
+            ":irc.host BATCH -a"
+        ]), CaseMapping::Rfc1459);
+        assert!(taken);
+        assert_eq!(next, Some(vec!["BEFORE".to_owned(), "#rust".to_owned(), "msgid=3".to_owned(), "1".to_owned()]));
+
+        let (_, next) = h.handle_batch(&batch(&[
+            ":irc.host BATCH +b chathistory #RUST",
+            "@batch=b;msgid=2 :n!u@h PRIVMSG #rust :two",
+            ":irc.host BATCH -b"
+        ]), CaseMapping::Rfc1459);
+        assert_eq!(next, None);
+
+        let messages = pending.wait().unwrap();
+        let ids: Vec<_> = messages.iter().map(|m| m.msgid().unwrap().to_owned()).collect();
+        assert_eq!(ids, vec!["2", "3", "4"]);
+    }
+
+    #[test]
+    fn failure() {
+        let mut h = Histories::new();
+        let (pending, params) = h.request("#rust", Query::Before(Anchor::MsgId("x".to_owned())), 10, None, false);
+        assert_eq!(params, vec!["BEFORE", "#rust", "msgid=x", "10"]);
+        h.handle(&Message::parse(b"FAIL CHATHISTORY INVALID_TARGET BEFORE #rust :No such channel").unwrap());
+        assert!(pending.wait().is_err());
+    }
+}
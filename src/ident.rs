@@ -1,25 +1,309 @@
-use regex::Regex;
 use std::borrow::ToOwned;
+use std::fmt;
 
-static PATTERN: Regex = regex!("(.*)!(.*)@(.*)");
+use casemap::CaseMapping;
 
-#[derive(Debug, Clone)]
+/// The source of a message, in any of the forms a prefix can take:
+/// `nick!user@host`, `nick@host`, `nick!user`, or just `nick`.
+///
+/// Server names are parsed as a nickname without user and host,
+/// `is_server` tells them apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Ident {
     pub nickname: String,
-    pub user: String,
-    pub host: String
+    pub user: Option<String>,
+    pub host: Option<String>
 }
 
 impl Ident {
     pub fn parse(s: &str) -> Option<Ident> {
-        let c = match PATTERN.captures(s) {
-            Some(c) => c,
-            None => return None
+        let s = if s.starts_with(":") { &s[1..] } else { s };
+        if s.is_empty() || s.contains(' ') { return None }
+
+        let (rest, host) = match s.find('@') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None)
+        };
+        let (nick, user) = match rest.find('!') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None)
         };
+        if nick.is_empty() { return None }
+
         Some(Ident {
-            nickname: c.at(1).unwrap().to_owned(),
-            user: c.at(2).unwrap().to_owned(),
-            host: c.at(3).unwrap().to_owned()
+            nickname: nick.to_owned(),
+            user: user.map(ToOwned::to_owned),
+            host: host.map(ToOwned::to_owned)
+        })
+    }
+
+    /// Nicknames can't contain dots, so a lone name with one is a server.
+    pub fn is_server(&self) -> bool {
+        self.user.is_none() && self.host.is_none() && self.nickname.contains('.')
+    }
+
+    /// Whether the host looks like a cloak (e.g. `user/foo`, `unaffiliated/bar`)
+    /// rather than a real hostname or address.
+    pub fn is_cloaked(&self) -> bool {
+        self.host.as_ref().map(|h| h.contains('/')) == Some(true)
+    }
+
+    /// Build a ban mask in the given style. Missing parts become `*`.
+    pub fn ban_mask(&self, style: BanStyle) -> Mask {
+        let user = self.user.as_ref().map(|u| {
+            // A tilde means the user isn't verified by identd, and it may come and go.
+            if u.starts_with("~") { format!("*{}", &u[1..]) } else { u.clone() }
+        }).unwrap_or("*".to_owned());
+        let host = self.host.clone().unwrap_or("*".to_owned());
+        let domain = if self.is_cloaked() { host.clone() } else { wildcard_domain(&host) };
+
+        Mask::new(&match style {
+            BanStyle::Host => format!("*!*@{}", host),
+            BanStyle::UserHost => format!("*!{}@{}", user, host),
+            BanStyle::Domain => format!("*!*@{}", domain),
+            BanStyle::UserDomain => format!("*!{}@{}", user, domain),
+            BanStyle::Nick => format!("{}!*@*", self.nickname),
+            BanStyle::Full => format!("{}!{}@{}", self.nickname, user, host)
+        })
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(fmt.write_str(&self.nickname));
+        if let Some(ref u) = self.user { try!(write!(fmt, "!{}", u)) }
+        if let Some(ref h) = self.host { try!(write!(fmt, "@{}", h)) }
+        Ok(())
+    }
+}
+
+/// Where a message came from: a server, or a user (or service).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Prefix {
+    Server(String),
+    User(Ident)
+}
+
+impl Prefix {
+    pub fn parse(s: &str) -> Option<Prefix> {
+        Ident::parse(s).map(|i| if i.is_server() {
+            Prefix::Server(i.nickname)
+        } else { Prefix::User(i) })
+    }
+
+    pub fn ident(&self) -> Option<&Ident> {
+        match self {
+            &Prefix::User(ref i) => Some(i),
+            &Prefix::Server(_) => None
+        }
+    }
+
+    pub fn is_server(&self) -> bool {
+        match self { &Prefix::Server(_) => true, _ => false }
+    }
+
+    /// The server name or nickname.
+    pub fn name(&self) -> &str {
+        match self {
+            &Prefix::Server(ref s) => s,
+            &Prefix::User(ref i) => &i.nickname
+        }
+    }
+}
+
+impl From<Ident> for Prefix {
+    fn from(i: Ident) -> Prefix { Prefix::User(i) }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Prefix::Server(ref s) => fmt.write_str(s),
+            &Prefix::User(ref i) => i.fmt(fmt)
+        }
+    }
+}
+
+/// Replace the most specific part of a host with a wildcard:
+/// the first label of a hostname, or the last part of an address.
+fn wildcard_domain(host: &str) -> String {
+    if host.contains(':') {
+        // IPv6, keep the /64
+        let groups: Vec<&str> = host.split(':').collect();
+        if groups.len() > 4 { return format!("{}:*", groups[..4].connect(":")) }
+        return host.to_owned()
+    }
+    if host.split('.').all(|p| !p.is_empty() && p.chars().all(|c| c.is_digit(10))) {
+        return match host.rfind('.') {
+            Some(i) => format!("{}.*", &host[..i]),
+            None => host.to_owned()
+        }
+    }
+    match host.find('.') {
+        // Don't turn example.com into *.com
+        Some(i) if host[i + 1..].contains('.') => format!("*{}", &host[i..]),
+        _ => host.to_owned()
+    }
+}
+
+/// Common shapes of ban masks, from most to least specific.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BanStyle {
+    /// `nick!user@host`
+    Full,
+    /// `*!user@host`
+    UserHost,
+    /// `*!*@host`
+    Host,
+    /// `*!user@*.domain`, or `*!user@cloak` for cloaked hosts
+    UserDomain,
+    /// `*!*@*.domain`, or `*!*@cloak` for cloaked hosts
+    Domain,
+    /// `nick!*@*`
+    Nick
+}
+
+/// A hostmask with wildcards, as used in bans, invites and exceptions.
+///
+/// `*` matches any number of characters, `?` matches exactly one,
+/// and `\` makes the next character match literally.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Mask {
+    pattern: String
+}
+
+impl Mask {
+    pub fn new(pattern: &str) -> Mask {
+        Mask { pattern: pattern.to_owned() }
+    }
+
+    /// Fill in a partial mask the way servers do: `nick` becomes `nick!*@*`,
+    /// `user@host` becomes `*!user@host`, and so on.
+    pub fn normalize(pattern: &str) -> Mask {
+        let has_bang = pattern.contains('!');
+        let has_at = pattern.contains('@');
+        Mask::new(&match (has_bang, has_at) {
+            (true, true) => pattern.to_owned(),
+            (true, false) => format!("{}@*", pattern),
+            (false, true) => format!("*!{}", pattern),
+            (false, false) if pattern.contains('.') => format!("*!*@{}", pattern),
+            (false, false) => format!("{}!*@*", pattern)
         })
     }
+
+    pub fn as_str(&self) -> &str { &self.pattern }
+
+    pub fn matches(&self, s: &str, mapping: CaseMapping) -> bool {
+        let p: Vec<char> = self.pattern.chars().collect();
+        let s: Vec<char> = s.chars().collect();
+        let eq = |a: char, b: char| mapping.lower_char(a) == mapping.lower_char(b);
+
+        let (mut pi, mut si) = (0, 0);
+        // Position after the last star, and where in `s` it was tried from.
+        let mut backtrack: Option<(usize, usize)> = None;
+        while si < s.len() {
+            let step = match p.get(pi) {
+                Some(&'*') => {
+                    backtrack = Some((pi + 1, si));
+                    pi += 1;
+                    continue
+                },
+                Some(&'?') => Some(1),
+                Some(&'\\') if pi + 1 < p.len() => if eq(p[pi + 1], s[si]) { Some(2) } else { None },
+                Some(&c) => if eq(c, s[si]) { Some(1) } else { None },
+                None => None
+            };
+            match (step, backtrack) {
+                (Some(n), _) => { pi += n; si += 1; },
+                (None, Some((bp, bs))) => {
+                    pi = bp;
+                    si = bs + 1;
+                    backtrack = Some((bp, bs + 1));
+                },
+                (None, None) => return false
+            }
+        }
+        p[pi..].iter().all(|&c| c == '*')
+    }
+
+    pub fn matches_ident(&self, ident: &Ident, mapping: CaseMapping) -> bool {
+        self.matches(&format!("{}!{}@{}", ident.nickname,
+                              ident.user.as_ref().map(|s| &s[..]).unwrap_or(""),
+                              ident.host.as_ref().map(|s| &s[..]).unwrap_or("")), mapping)
+    }
+}
+
+impl fmt::Display for Mask {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.pattern)
+    }
+}
+
+/// Escape wildcards, so that `s` only matches itself.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '*' || c == '?' || c == '\\' { out.push('\\') }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use casemap::CaseMapping;
+    use ident::{ Ident, Prefix, Mask, BanStyle };
+
+    #[test]
+    fn parse() {
+        let i = Ident::parse("WiZ!jto@tolsun.oulu.fi").unwrap();
+        assert_eq!(i.nickname, "WiZ");
+        assert_eq!(i.user.as_ref().map(|s| &s[..]), Some("jto"));
+        assert_eq!(i.host.as_ref().map(|s| &s[..]), Some("tolsun.oulu.fi"));
+        assert_eq!(i.to_string(), "WiZ!jto@tolsun.oulu.fi");
+
+        let i = Ident::parse("nick@host").unwrap();
+        assert_eq!((i.user, i.host), (None, Some("host".to_owned())));
+        assert!(Ident::parse("irc.example.net").unwrap().is_server());
+        assert!(!Ident::parse("nick").unwrap().is_server());
+        assert_eq!(Ident::parse(""), None);
+
+        assert_eq!(Prefix::parse("irc.example.net"), Some(Prefix::Server("irc.example.net".to_owned())));
+        assert_eq!(Prefix::parse("nick").map(|p| p.is_server()), Some(false));
+        assert_eq!(Prefix::parse("a!b@c.d").unwrap().to_string(), "a!b@c.d");
+    }
+
+    #[test]
+    fn glob() {
+        let m = CaseMapping::Rfc1459;
+        assert!(Mask::new("*!*@*.example.com").matches("a!b@c.example.com", m));
+        assert!(!Mask::new("*!*@*.example.com").matches("a!b@example.com", m));
+        assert!(Mask::new("n?ck!*@*").matches("NICK!u@h", m));
+        assert!(Mask::new("[a]*").matches("{A}b", m));
+        assert!(!Mask::new("[a]*").matches("{A}b", CaseMapping::Ascii));
+        assert!(Mask::new("a\\*b").matches("a*b", m));
+        assert!(!Mask::new("a\\*b").matches("axb", m));
+        assert!(Mask::new("*a*b*").matches("xxaxxbxx", m));
+        assert!(Mask::new("**").matches("", m));
+        assert_eq!(Mask::normalize("nick").as_str(), "nick!*@*");
+        assert_eq!(Mask::normalize("user@host").as_str(), "*!user@host");
+    }
+
+    #[test]
+    fn ban_masks() {
+        let i = Ident::parse("nick!~user@a.b.example.com").unwrap();
+        assert_eq!(i.ban_mask(BanStyle::Host).as_str(), "*!*@a.b.example.com");
+        assert_eq!(i.ban_mask(BanStyle::UserDomain).as_str(), "*!*user@*.b.example.com");
+        assert_eq!(i.ban_mask(BanStyle::Nick).as_str(), "nick!*@*");
+        assert!(i.ban_mask(BanStyle::UserDomain).matches_ident(&i, CaseMapping::Rfc1459));
+
+        let i = Ident::parse("nick!user@192.168.0.1").unwrap();
+        assert_eq!(i.ban_mask(BanStyle::Domain).as_str(), "*!*@192.168.0.*");
+
+        let i = Ident::parse("nick!user@user/nick").unwrap();
+        assert_eq!(i.ban_mask(BanStyle::Domain).as_str(), "*!*@user/nick");
+
+        let i = Ident::parse("nick!user@example.com").unwrap();
+        assert_eq!(i.ban_mask(BanStyle::Domain).as_str(), "*!*@example.com");
+    }
 }
@@ -0,0 +1,53 @@
+use std::borrow::ToOwned;
+
+use linear_map::LinearMap;
+
+use casemap::CaseMapping;
+
+/// The features a server announces with `RPL_ISUPPORT` (005), like
+/// `CASEMAPPING=rfc1459`, `MONITOR=100` or `WHOX`.
+#[derive(Clone, Debug, Default)]
+pub struct ISupport {
+    tokens: LinearMap<String, String>
+}
+
+impl ISupport {
+    pub fn new() -> ISupport { ISupport::default() }
+
+    pub fn clear(&mut self) { self.tokens.clear() }
+
+    /// Process the parameters of a 005 line, without the leading nickname
+    /// and the trailing "are supported by this server".
+    pub fn handle<S: AsRef<str>>(&mut self, params: &[S]) {
+        for token in params {
+            let token = token.as_ref();
+            if token.starts_with("-") {
+                self.tokens.remove(&token[1..]);
+                continue
+            }
+            let (key, value) = match token.find('=') {
+                Some(i) => (&token[..i], &token[i + 1..]),
+                None => (token, "")
+            };
+            if !key.is_empty() {
+                self.tokens.insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+
+    pub fn has(&self, key: &str) -> bool { self.tokens.contains_key(key) }
+
+    /// The value of `key`, empty if it has none.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tokens.get(key).map(|v| &v[..])
+    }
+
+    /// The value of `key` as a number. `None` if it's missing, empty or not a number.
+    pub fn number(&self, key: &str) -> Option<usize> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        self.get("CASEMAPPING").and_then(|c| c.parse().ok()).unwrap_or_default()
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, Condvar };
+#[cfg(feature = "async")]
+use std::sync::Weak;
+#[cfg(feature = "async")]
+use std::thread;
+use std::time::{ Duration, Instant };
+use std::result;
+use std::borrow::ToOwned;
+
+#[cfg(feature = "async")]
+use futures::{ Future, Poll, Async };
+#[cfg(feature = "async")]
+use futures::task::{ self, Task };
+
+use message::Message;
+use batch::Batch;
+use ::IrscError;
+
+/// What the server answered to a labeled command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// The command was processed, but there was nothing to reply.
+    Ack,
+    /// A single message.
+    Message(Message),
+    /// Several messages, wrapped in a `labeled-response` batch.
+    Batch(Batch)
+}
+
+struct State<T> {
+    response: Option<result::Result<T, IrscError>>,
+    /// When to give up, set by `Pending::timeout`.
+    deadline: Option<Instant>,
+    #[cfg(feature = "async")]
+    task: Option<Task>
+}
+
+struct Slot<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar
+}
+
+impl<T> Slot<T> {
+    fn complete(&self, r: result::Result<T, IrscError>) {
+        let mut state = self.state.lock().unwrap();
+        if state.response.is_some() { return }
+        state.response = Some(r);
+        self.ready.notify_all();
+        state.wake();
+    }
+}
+
+impl<T> State<T> {
+    /// The response, or `IrscError::Timeout` once the deadline has passed.
+    fn take(&mut self) -> Option<result::Result<T, IrscError>> {
+        match self.response.take() {
+            Some(r) => Some(r),
+            None if self.deadline.map(|d| d <= Instant::now()) == Some(true) => Some(Err(IrscError::Timeout)),
+            None => None
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn wake(&mut self) {
+        if let Some(t) = self.task.take() { t.notify() }
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn wake(&mut self) {}
+}
+
+/// Handle for the response to a labeled command, or for the result of a
+/// query like `Client::whois`.
+///
+/// `wait` blocks until the response arrives, so it must not be called from
+/// the thread that runs `Client::listen`; hand the `Pending` to another thread.
+/// With the `async` feature, `Pending` is also a `Future`.
+pub struct Pending<T = Response> {
+    label: String,
+    slot: Arc<Slot<T>>
+}
+
+/// The other end of a `Pending`, for whoever produces the result.
+pub struct Promise<T> {
+    slot: Arc<Slot<T>>
+}
+
+impl<T> Promise<T> {
+    /// Deliver the result. Only the first one counts.
+    pub fn complete(&self, r: result::Result<T, IrscError>) { self.slot.complete(r) }
+
+    /// Whether nobody will see the result anymore, because the `Pending`
+    /// was dropped or timed out.
+    pub fn is_expired(&self) -> bool {
+        if Arc::strong_count(&self.slot) == 1 { return true }
+        let state = self.slot.state.lock().unwrap();
+        state.response.is_none() && state.deadline.map(|d| d <= Instant::now()) == Some(true)
+    }
+}
+
+/// A `Pending` for `label`, and the `Promise` to complete it with.
+pub fn pending<T>(label: String) -> (Promise<T>, Pending<T>) {
+    let slot = Arc::new(Slot {
+        state: Mutex::new(State {
+            response: None,
+            deadline: None,
+            #[cfg(feature = "async")]
+            task: None
+        }),
+        ready: Condvar::new()
+    });
+    (Promise { slot: slot.clone() }, Pending { label: label, slot: slot })
+}
+
+impl<T: Send + 'static> Pending<T> {
+    /// The label sent with the command, empty if it wasn't labeled.
+    pub fn label(&self) -> &str { &self.label }
+
+    /// The response, if it has arrived already.
+    pub fn try_get(&self) -> Option<result::Result<T, IrscError>> {
+        self.slot.state.lock().unwrap().take()
+    }
+
+    fn wait_until(self, until: Option<Instant>) -> result::Result<T, IrscError> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(r) = state.take() { return r }
+            let end = match (until, state.deadline) {
+                (Some(u), Some(d)) => Some(if u < d { u } else { d }),
+                (u, d) => u.or(d)
+            };
+            state = match end {
+                Some(end) => {
+                    let now = Instant::now();
+                    if end <= now { return state.take().unwrap_or(Err(IrscError::Timeout)) }
+                    self.slot.ready.wait_timeout(state, end - now).unwrap().0
+                },
+                None => self.slot.ready.wait(state).unwrap()
+            };
+        }
+    }
+
+    /// Block until the response arrives.
+    pub fn wait(self) -> result::Result<T, IrscError> {
+        self.wait_until(None)
+    }
+
+    /// Block until the response arrives, or give up after `timeout`.
+    pub fn wait_timeout(self, timeout: Duration) -> result::Result<T, IrscError> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    /// Fail with `IrscError::Timeout` if nothing arrived after `timeout`.
+    pub fn timeout(self, timeout: Duration) -> Pending<T> {
+        {
+            let mut state = self.slot.state.lock().unwrap();
+            let deadline = Instant::now() + timeout;
+            if state.deadline.map(|d| d > deadline) != Some(false) {
+                state.deadline = Some(deadline);
+                self.arm(timeout);
+            }
+        }
+        self
+    }
+
+    /// Wake the task polling this `Future` at the deadline, so it sees the timeout.
+    #[cfg(feature = "async")]
+    fn arm(&self, timeout: Duration) {
+        let slot: Weak<Slot<T>> = Arc::downgrade(&self.slot);
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            if let Some(slot) = slot.upgrade() {
+                slot.state.lock().unwrap().wake();
+            }
+        });
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn arm(&self, _: Duration) {}
+}
+
+#[cfg(feature = "async")]
+impl<T> Future for Pending<T> {
+    type Item = T;
+    type Error = IrscError;
+
+    fn poll(&mut self) -> Poll<T, IrscError> {
+        let mut state = self.slot.state.lock().unwrap();
+        match state.take() {
+            Some(Ok(r)) => Ok(Async::Ready(r)),
+            Some(Err(e)) => Err(e),
+            None => {
+                state.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Labels of commands that haven't been answered yet.
+#[derive(Default)]
+pub struct Labels {
+    next: u64,
+    pending: HashMap<String, Promise<Response>>
+}
+
+impl Labels {
+    pub fn new() -> Labels { Labels::default() }
+
+    /// Create a fresh label, and a handle for its response.
+    pub fn create(&mut self) -> Pending {
+        self.prune();
+        self.next += 1;
+        let (promise, pending) = pending(format!("irsc{}", self.next));
+        self.pending.insert(pending.label().to_owned(), promise);
+        pending
+    }
+
+    pub fn is_pending(&self, label: &str) -> bool {
+        self.pending.contains_key(label)
+    }
+
+    /// Deliver the response for `label`. Returns false if nobody was waiting
+    /// for it (anymore), so it can be handled like any other message.
+    pub fn complete(&mut self, label: &str, response: Response) -> bool {
+        match self.pending.remove(label) {
+            Some(ref promise) if !promise.is_expired() => { promise.complete(Ok(response)); true },
+            _ => false
+        }
+    }
+
+    /// Forget the labels whose `Pending` timed out or was dropped.
+    fn prune(&mut self) {
+        let expired: Vec<String> = self.pending.iter()
+            .filter(|&(_, p)| p.is_expired()).map(|(l, _)| l.clone()).collect();
+        for label in expired { self.pending.remove(&label); }
+    }
+
+    /// Forget `label`, e.g. because the command couldn't be sent.
+    pub fn cancel(&mut self, label: &str) {
+        if let Some(promise) = self.pending.remove(label) {
+            promise.complete(Err(IrscError::NotConnected));
+        }
+    }
+
+    /// Fail everything that is still waiting, e.g. because the connection was lost.
+    pub fn fail_all(&mut self) {
+        for (_, promise) in self.pending.drain() {
+            promise.complete(Err(IrscError::NotConnected));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{ Duration, Instant };
+    #[cfg(feature = "async")]
+    use futures::executor;
+
+    use message::Message;
+    use batch::{ Batches, Collected };
+    use label::{ Labels, Response, pending };
+    use ::IrscError;
+
+    fn parse(line: &str) -> Message { Message::parse(line.as_bytes()).unwrap() }
+
+    #[test]
+    fn ack_and_message() {
+        let mut l = Labels::new();
+        let ack = l.create();
+        let reply = l.create();
+        assert!(ack.label() != reply.label());
+
+        assert!(l.complete(ack.label(), Response::Ack));
+        assert!(!l.is_pending(ack.label()));
+        assert_eq!(ack.wait().unwrap(), Response::Ack);
+
+        let msg = parse(&format!("@label={} :irc.host 401 me ghost :No such nick", reply.label()));
+        assert!(l.complete(reply.label(), Response::Message(msg.clone())));
+        assert!(!l.complete(reply.label(), Response::Ack));
+        assert_eq!(reply.try_get().unwrap().unwrap(), Response::Message(msg));
+    }
+
+    #[test]
+    fn batch() {
+        let mut l = Labels::new();
+        let mut b = Batches::new();
+        let pending = l.create();
+        let start = format!("@label={} :irc.host BATCH +r labeled-response", pending.label());
+        let mut batch = None;
+        for line in &[&start[..], "@batch=r :irc.host 311 me nick u h * :Real", "@batch=r :irc.host 318 me nick :End",
+                      ":irc.host BATCH -r"] {
+            if let Collected::Complete(c) = b.handle(&parse(line)) { batch = Some(c) }
+        }
+        let batch = batch.unwrap();
+        let label = batch.label.clone().unwrap();
+        assert!(l.complete(&label, Response::Batch(batch)));
+        match pending.wait().unwrap() {
+            Response::Batch(b) => assert_eq!(b.messages.len(), 2),
+            r => panic!("Expected a batch, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut l = Labels::new();
+        let labeled = l.create();
+        match labeled.wait_timeout(Duration::from_millis(10)) {
+            Err(IrscError::Timeout) => (),
+            r => panic!("Expected a timeout, got {:?}", r)
+        }
+
+        let (promise, p) = pending::<()>(String::new());
+        let start = Instant::now();
+        let p = p.timeout(Duration::from_millis(10));
+        match p.wait() {
+            Err(IrscError::Timeout) => (),
+            r => panic!("Expected a timeout, got {:?}", r)
+        }
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        // Too late.
+        promise.complete(Ok(()));
+
+        let (promise, p) = pending::<()>(String::new());
+        let p = p.timeout(Duration::from_secs(60));
+        promise.complete(Ok(()));
+        assert!(p.wait_timeout(Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn late_answers() {
+        let mut l = Labels::new();
+        let timed_out = l.create().timeout(Duration::from_millis(10));
+        let label = timed_out.label().to_owned();
+        let dropped = l.create().label().to_owned();
+        assert!(timed_out.wait().is_err());
+        // Nobody waits for these anymore, so they are left to the event handler.
+        assert!(!l.complete(&label, Response::Ack));
+        assert!(!l.complete(&dropped, Response::Ack));
+
+        let a = l.create().timeout(Duration::from_millis(10));
+        let label = a.label().to_owned();
+        let _ = a.wait();
+        let b = l.create();
+        assert!(!l.is_pending(&label) && l.is_pending(b.label()));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn future_timeout() {
+        let (_promise, p) = pending::<()>(String::new());
+        let start = Instant::now();
+        match executor::spawn(p.timeout(Duration::from_millis(50))).wait_future() {
+            Err(IrscError::Timeout) => (),
+            r => panic!("Expected a timeout, got {:?}", r)
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let (promise, p) = pending::<u32>(String::new());
+        promise.complete(Ok(7));
+        assert_eq!(executor::spawn(p.timeout(Duration::from_secs(60))).wait_future().unwrap(), 7);
+    }
+
+    #[test]
+    fn fail_all_and_cancel() {
+        let mut l = Labels::new();
+        let a = l.create();
+        let b = l.create();
+        let c = l.create();
+        l.cancel(a.label());
+        assert!(!l.is_pending(a.label()) && l.is_pending(b.label()));
+        assert!(a.wait().is_err());
+
+        l.fail_all();
+        assert!(!l.is_pending(b.label()) && !l.is_pending(c.label()));
+        match b.wait() {
+            Err(IrscError::NotConnected) => (),
+            r => panic!("Expected NotConnected, got {:?}", r)
+        }
+        assert!(c.try_get().unwrap().is_err());
+    }
+}
@@ -1,19 +1,60 @@
 #![feature(plugin, custom_derive, slice_patterns)]
-#![plugin(regex_macros)]
 #![cfg_attr(feature = "lints", plugin(clippy))]
 
 #![deny(warnings)]
 #![allow(unused_imports)]
 
-extern crate regex;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "openssl")]
 extern crate openssl;
+#[cfg(feature = "rustls")]
+extern crate rustls_crate as rustls;
+#[cfg(feature = "rustls")]
+extern crate rustls_pemfile;
+#[cfg(feature = "rustls")]
+extern crate webpki_roots;
+#[cfg(feature = "rustls")]
+extern crate ring;
+#[cfg(feature = "rustls")]
+extern crate x509_parser;
 extern crate encoding;
 extern crate linear_map;
+extern crate time;
+extern crate socket2;
+#[cfg(feature = "websocket")]
+extern crate tungstenite;
+#[cfg(feature = "async")]
+extern crate futures;
 
 pub mod client;
+pub mod manager;
+pub mod tls;
+pub mod proxy;
+pub mod connect;
+pub mod transport;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod batch;
+pub mod label;
+pub mod query;
+pub mod isupport;
+pub mod presence;
+pub mod users;
+pub mod echo;
+pub mod history;
+pub mod bouncer;
+pub mod multiline;
+pub mod tags;
+pub mod sts;
+pub mod casemap;
+pub mod cap;
 pub mod color;
+pub mod markdown;
 pub mod ident;
 pub mod callback;
 pub mod message;
@@ -21,33 +62,54 @@ pub mod command;
 pub mod reply;
 pub mod event;
 pub mod text;
+pub mod timestamp;
 
 use std::io;
 use std::result;
+use std::net::SocketAddr;
 use std::ops::{ Deref, DerefMut };
 
-use openssl::ssl::error::SslError;
 
 use encoding::EncodingRef;
 
-pub use ident::Ident;
+pub use ident::{ Ident, Prefix, Mask };
+pub use casemap::CaseMapping;
 pub use message::Message;
 pub use command::Command;
 pub use reply::Reply;
 pub use event::Event;
+pub use batch::Batch;
+pub use label::{ Pending, Response };
+pub use query::{ WhoisInfo, WhoEntry, ListEntry };
+pub use isupport::ISupport;
+pub use users::{ User, Users };
+pub use bouncer::Network;
+pub use tags::{ ClientTag, Typing };
 pub use client::Client;
+pub use manager::{ ClientManager, NetworkConfig, Health, Incoming };
+pub use tls::TlsConfig;
+pub use proxy::Proxy;
+pub use connect::{ ConnectOptions, Family };
+pub use transport::Transport;
+pub use text::EncodingPolicy;
+pub use timestamp::{ Timestamp, Clock };
 
 #[derive(Debug)]
 pub enum IrscError {
     Io(io::Error),
+    /// No address could be connected to; each one that was tried, and why.
+    Connect(Vec<(SocketAddr, io::Error)>),
     AlreadyConnected,
     NotConnected,
     NotFound,
-    Ssl(SslError)
-}
-
-impl From<SslError> for IrscError {
-    fn from(e: SslError) -> IrscError { IrscError::Ssl(e) }
+    /// Setting up TLS failed, e.g. because the certificate wasn't trusted.
+    Tls(String),
+    /// Nothing happened in time.
+    Timeout,
+    /// Needs a capability that isn't enabled.
+    Unsupported(&'static str),
+    /// The server answered with an error numeric, and this text.
+    Reply(String, String)
 }
 
 pub struct Result<T>(result::Result<T, IrscError>);
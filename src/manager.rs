@@ -0,0 +1,618 @@
+//! One bot on several networks at once.
+//!
+//! Every network has a `Client` of its own, connected and registered on its
+//! own thread, and reconnected when the connection is lost. What they receive
+//! is merged into one stream of `Incoming` events, tagged with the name of
+//! the network.
+//!
+//! ```ignore
+//! let manager = ClientManager::new();
+//! let libera = NetworkConfig::new("irc.libera.chat", 6697, "bot")
+//!     .tls(TlsConfig::new())
+//!     .channel("#bots");
+//! assert!(manager.add("libera", libera).is_ok());
+//! for incoming in manager.events() {
+//!     if let Some(Event::Command(PRIVMSG(ref to, ref text))) = incoming.event {
+//!         // ...
+//!         manager.send(&incoming.network, PRIVMSG(to.clone(), "pong".into()));
+//!     }
+//! }
+//! ```
+
+use std::borrow::ToOwned;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{ self, Read, Write };
+use std::result;
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ self, Receiver, Sender };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use client::Client;
+use command::Command;
+use command::Command::*;
+use connect::ConnectOptions;
+use event::Event;
+use message::Message;
+use proxy::Proxy;
+use text;
+use tls::{ self, TlsConfig };
+use transport::Transport;
+use ::{ Result, IrscError };
+
+/// The longest wait between reconnection attempts.
+const MAX_RECONNECT_DELAY: u64 = 300;
+const LAG_TOKEN: &'static str = "irsc-lag";
+/// Lag checks in a row without an answer, after which the connection is
+/// considered dead, and made again.
+const MAX_UNANSWERED: u32 = 3;
+
+/// How to connect to one network, and what to do there.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    pub host: String,
+    pub port: u16,
+    /// Connect with TLS, set up like this. Without, STS policies don't apply either.
+    pub tls: Option<TlsConfig>,
+    pub proxy: Option<Proxy>,
+    pub options: ConnectOptions,
+    pub nick: String,
+    pub user: String,
+    pub realname: String,
+    pub password: Option<String>,
+    pub capabilities: Vec<String>,
+    /// Joined once registered.
+    pub channels: Vec<String>,
+    /// How long to wait before the first reconnection attempt. It doubles with
+    /// each failed one, up to five minutes. `None` to stay disconnected.
+    pub reconnect: Option<Duration>,
+    /// How often to measure the lag with a `PING`. After three of them without
+    /// an answer, the connection is dropped and made again.
+    pub lag_check: Option<Duration>
+}
+
+impl NetworkConfig {
+    pub fn new(host: &str, port: u16, nick: &str) -> NetworkConfig {
+        NetworkConfig {
+            host: host.to_owned(),
+            port: port,
+            tls: None,
+            proxy: None,
+            options: ConnectOptions::new(),
+            nick: nick.to_owned(),
+            user: nick.to_owned(),
+            realname: nick.to_owned(),
+            password: None,
+            capabilities: Vec::new(),
+            channels: Vec::new(),
+            reconnect: Some(Duration::from_secs(5)),
+            lag_check: Some(Duration::from_secs(60))
+        }
+    }
+
+    pub fn tls(mut self, config: TlsConfig) -> NetworkConfig { self.tls = Some(config); self }
+    pub fn proxy(mut self, proxy: Proxy) -> NetworkConfig { self.proxy = Some(proxy); self }
+    pub fn options(mut self, options: ConnectOptions) -> NetworkConfig { self.options = options; self }
+
+    pub fn user(mut self, user: &str, realname: &str) -> NetworkConfig {
+        self.user = user.to_owned();
+        self.realname = realname.to_owned();
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> NetworkConfig { self.password = Some(password.to_owned()); self }
+    pub fn capability(mut self, cap: &str) -> NetworkConfig { self.capabilities.push(cap.to_owned()); self }
+    pub fn channel(mut self, channel: &str) -> NetworkConfig { self.channels.push(channel.to_owned()); self }
+    pub fn reconnect(mut self, delay: Option<Duration>) -> NetworkConfig { self.reconnect = delay; self }
+    pub fn lag_check(mut self, interval: Option<Duration>) -> NetworkConfig { self.lag_check = interval; self }
+
+    fn open(&self) -> result::Result<Box<Transport>, IrscError> {
+        let tcp = try!(self.options.connect_via(self.proxy.as_ref(), &self.host, self.port));
+        match self.tls {
+            Some(ref config) => Ok(Box::new(try!(tls::wrap(tcp, &self.host, config)))),
+            None => Ok(Box::new(tcp))
+        }
+    }
+}
+
+/// How a network is doing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Health {
+    pub connected: bool,
+    /// The round trip of the last lag check.
+    pub lag: Option<Duration>,
+    /// Failed attempts since the connection was lost.
+    pub reconnect_attempts: u32,
+    /// How often the connection was lost and made again.
+    pub reconnects: u32,
+    /// Why the connection was lost, or the last attempt failed.
+    pub last_error: Option<String>
+}
+
+/// Something that happened on one of the networks.
+#[derive(Clone, Debug)]
+pub struct Incoming {
+    pub network: String,
+    /// The message, except for `Event::Connected` and `Event::Disconnected`.
+    pub message: Option<Message>,
+    pub event: Option<Event<'static>>
+}
+
+/// The connection of a managed client, which the manager writes to as well.
+/// Each write is a whole message, so they don't interleave.
+#[derive(Clone)]
+struct Shared(Arc<Mutex<Box<Transport>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.0.lock().unwrap().writer().write_all(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.0.lock().unwrap().writer().flush() }
+}
+
+impl Transport for Shared {
+    fn reader(&self) -> io::Result<Box<Read + Send>> { self.0.lock().unwrap().reader() }
+    fn writer(&mut self) -> &mut Write { self }
+    fn shutdown(&mut self) -> io::Result<()> { self.0.lock().unwrap().shutdown() }
+    fn is_secure(&self) -> bool { self.0.lock().unwrap().is_secure() }
+    fn try_clone(&self) -> io::Result<Box<Transport>> { Ok(Box::new(self.clone())) }
+}
+
+struct Slot {
+    config: NetworkConfig,
+    health: Health,
+    connection: Option<Shared>,
+    /// Counts connections, so lag checks of an old one stop.
+    generation: u64,
+    /// The token of the lag check that wasn't answered yet, and when it was sent.
+    ping: Option<(String, Instant)>,
+    /// Lag checks in a row that weren't answered.
+    unanswered: u32
+}
+
+type Slots = Arc<Mutex<HashMap<String, Slot>>>;
+
+/// Several clients, one for each network, with one stream of events.
+pub struct ClientManager {
+    slots: Slots,
+    sender: Sender<Incoming>,
+    receiver: Receiver<Incoming>
+}
+
+impl ClientManager {
+    pub fn new() -> ClientManager {
+        let (sender, receiver) = mpsc::channel();
+        ClientManager { slots: Arc::new(Mutex::new(HashMap::new())), sender: sender, receiver: receiver }
+    }
+
+    /// Connect to a network, called `name` from now on.
+    pub fn add(&self, name: &str, config: NetworkConfig) -> Result<()> {
+        {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.contains_key(name) { return Result(Err(IrscError::AlreadyConnected)) }
+            slots.insert(name.to_owned(), Slot {
+                config: config,
+                health: Health::default(),
+                connection: None,
+                generation: 0,
+                ping: None,
+                unanswered: 0
+            });
+        }
+        let (name, slots, sender) = (name.to_owned(), self.slots.clone(), self.sender.clone());
+        thread::spawn(move || run(name, slots, sender));
+        Result(Ok(()))
+    }
+
+    /// Quit `network`, and forget about it.
+    pub fn remove(&self, network: &str) -> Result<()> {
+        let slot = self.slots.lock().unwrap().remove(network);
+        match slot {
+            Some(Slot { connection: Some(mut c), .. }) => {
+                let _ = c.write_all(QUIT(None).to_message().bytes());
+                Result(c.shutdown().map_err(IrscError::Io))
+            },
+            Some(_) => Result(Ok(())),
+            None => Result(Err(IrscError::NotFound))
+        }
+    }
+
+    pub fn networks(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.slots.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn health(&self, network: &str) -> Option<Health> {
+        self.slots.lock().unwrap().get(network).map(|s| s.health.clone())
+    }
+
+    /// Send `cmd` to `network`.
+    pub fn send(&self, network: &str, cmd: Command) -> Result<()> {
+        self.send_message(network, cmd.to_message())
+    }
+
+    pub fn send_message(&self, network: &str, msg: Message) -> Result<()> {
+        let connection = match self.slots.lock().unwrap().get(network) {
+            Some(s) => s.connection.clone(),
+            None => return Result(Err(IrscError::NotFound))
+        };
+        match connection {
+            Some(mut c) => Result(c.write_all(msg.bytes()).map_err(IrscError::Io)),
+            None => Result(Err(IrscError::NotConnected))
+        }
+    }
+
+    /// Wait for the next event of any network.
+    pub fn events(&self) -> mpsc::Iter<Incoming> { self.receiver.iter() }
+
+    /// The next event, if one comes within `timeout`.
+    pub fn next_event(&self, timeout: Duration) -> Option<Incoming> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+fn backoff(first: Duration, attempts: u32) -> Duration {
+    let factor = 1u32 << cmp::min(attempts.saturating_sub(1), 16);
+    cmp::min(first * factor, Duration::from_secs(MAX_RECONNECT_DELAY))
+}
+
+/// Connect to the network `name`, and again whenever the connection is lost,
+/// until it's removed.
+fn run(name: String, slots: Slots, events: Sender<Incoming>) {
+    let notify = |event: Event<'static>| {
+        let _ = events.send(Incoming { network: name.clone(), message: None, event: Some(event) });
+    };
+    loop {
+        let config = match slots.lock().unwrap().get(&name) {
+            Some(s) => s.config.clone(),
+            None => return
+        };
+        let error = match config.open() {
+            Ok(transport) => {
+                let connection = Shared(Arc::new(Mutex::new(transport)));
+                let generation = {
+                    let mut slots = slots.lock().unwrap();
+                    let slot = match slots.get_mut(&name) {
+                        Some(s) => s,
+                        // Removed while connecting.
+                        None => { let _ = connection.clone().shutdown(); return }
+                    };
+                    if slot.generation > 0 { slot.health.reconnects += 1 }
+                    slot.health.connected = true;
+                    slot.health.reconnect_attempts = 0;
+                    slot.health.lag = None;
+                    slot.connection = Some(connection.clone());
+                    slot.generation += 1;
+                    slot.ping = None;
+                    slot.unanswered = 0;
+                    slot.generation
+                };
+                notify(Event::Connected);
+                if let Some(interval) = config.lag_check {
+                    let (name, slots) = (name.clone(), slots.clone());
+                    thread::spawn(move || check_lag(name, slots, generation, interval));
+                }
+                let result = session(&name, &config, connection, &slots, &events);
+
+                let unanswered = match slots.lock().unwrap().get_mut(&name) {
+                    Some(slot) => {
+                        slot.health.connected = false;
+                        slot.connection = None;
+                        slot.ping = None;
+                        slot.unanswered
+                    },
+                    None => 0
+                };
+                notify(Event::Disconnected);
+                match result {
+                    _ if unanswered >= MAX_UNANSWERED => format!("No answer to {} lag checks", unanswered),
+                    Ok(()) => "Connection closed".to_owned(),
+                    Err(e) => format!("{:?}", e)
+                }
+            },
+            Err(e) => format!("{:?}", e)
+        };
+
+        let attempts = {
+            let mut slots = slots.lock().unwrap();
+            let slot = match slots.get_mut(&name) {
+                Some(s) => s,
+                None => return
+            };
+            slot.health.last_error = Some(error);
+            slot.health.reconnect_attempts += 1;
+            slot.health.reconnect_attempts
+        };
+        match config.reconnect {
+            Some(delay) => thread::sleep(backoff(delay, attempts)),
+            None => return
+        }
+    }
+}
+
+/// Register, and pass on everything that happens until the connection is lost.
+fn session(name: &str, config: &NetworkConfig, connection: Shared, slots: &Slots, events: &Sender<Incoming>)
+-> result::Result<(), IrscError> {
+    let mut client = Client::from_transport(connection);
+    for cap in &config.capabilities {
+        try!(client.request_capability(cap).inner());
+    }
+    try!(client.register(&config.nick, &config.user, &config.realname,
+                         config.password.as_ref().map(|p| &p[..])).inner());
+
+    client.listen(|client, msg, event| {
+        match &*msg.command() {
+            b"PONG" => {
+                let token = msg.last().map(|t| text::def_lossy_decode(&t)).unwrap_or(String::new());
+                let mut slots = slots.lock().unwrap();
+                if let Some(slot) = slots.get_mut(name) {
+                    let sent = match slot.ping {
+                        Some((ref t, sent)) if *t == token => Some(sent),
+                        _ => None
+                    };
+                    if let Some(sent) = sent {
+                        slot.health.lag = Some(sent.elapsed());
+                        slot.ping = None;
+                        slot.unanswered = 0;
+                        // Lag checks are our business, not the bot's.
+                        return
+                    }
+                }
+            },
+            b"001" if !config.channels.is_empty() => {
+                for channel in &config.channels {
+                    let _ = client.send(JOIN(vec![(&channel[..]).into()], Vec::new(), None));
+                }
+            },
+            _ => ()
+        }
+        let _ = events.send(Incoming {
+            network: name.to_owned(),
+            message: Some(msg.clone()),
+            event: event.map(|e| e.to_static())
+        });
+    }).inner()
+}
+
+/// Send a `PING` every `interval`, as long as connection `generation` is up.
+/// Drop the connection once too many of them went unanswered.
+fn check_lag(name: String, slots: Slots, generation: u64, interval: Duration) {
+    let mut n = 0;
+    loop {
+        thread::sleep(interval);
+        n += 1;
+        let token = format!("{}{}", LAG_TOKEN, n);
+        let (mut connection, dead) = {
+            let mut slots = slots.lock().unwrap();
+            let slot = match slots.get_mut(&name) {
+                Some(s) => s,
+                None => return
+            };
+            let connection = match slot.connection {
+                Some(ref c) if slot.generation == generation => c.clone(),
+                _ => return
+            };
+            // Unanswered lag checks don't count for the lag, the last one sent does.
+            if slot.ping.is_some() { slot.unanswered += 1 }
+            let dead = slot.unanswered >= MAX_UNANSWERED;
+            if !dead { slot.ping = Some((token.clone(), Instant::now())) }
+            (connection, dead)
+        };
+        if dead {
+            info!("{} didn't answer {} lag checks, reconnecting", name, MAX_UNANSWERED);
+            // Not under the slots' lock, as a write might hold the connection's.
+            // The session ends, and the connection is made again.
+            let _ = connection.shutdown();
+            return
+        }
+        let ping = PING((&token[..]).into(), None).to_message();
+        if connection.write_all(ping.bytes()).is_err() { return }
+
+        // Writing might have had to wait, which isn't lag.
+        if let Some(slot) = slots.lock().unwrap().get_mut(&name) {
+            if let Some((ref t, ref mut sent)) = slot.ping {
+                if *t == token { *sent = Instant::now() }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ BufRead, BufReader, Read, Write };
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use connect::ConnectOptions;
+    use command::Command::*;
+    use event::Event;
+    use manager::{ ClientManager, Incoming, NetworkConfig };
+    use ::IrscError;
+
+    /// What a stand-in server answers to `line`, if anything. `Err` to hang up.
+    fn answer(name: &str, line: &str, pongs: bool) -> Result<Option<String>, ()> {
+        Ok(Some(if line.starts_with("CAP LS") { format!(":{} CAP * LS :", name) }
+            else if line.starts_with("USER") { format!(":{} 001 bot :Welcome to {}", name, name) }
+            else if line.starts_with("PING") && pongs { format!(":{} PONG {} :{}", name, name, line[5..].trim_left_matches(':')) }
+            else if line.starts_with("PRIVMSG") { format!(":{} NOTICE bot :{} got {}", name, name, &line[8..]) }
+            else if line.starts_with("QUIT") { return Err(()) }
+            else { return Ok(None) }))
+    }
+
+    /// A stand-in server, that takes `connections` connections one after the
+    /// other, registers the client and answers `PING` and `PRIVMSG`.
+    fn stand_in(name: &'static str, connections: usize) -> NetworkConfig {
+        serve(name, connections, true)
+    }
+
+    /// Like `stand_in`, but `PING`s are never answered.
+    fn deaf(name: &'static str, connections: usize) -> NetworkConfig {
+        serve(name, connections, false)
+    }
+
+    fn serve(name: &'static str, connections: usize, pongs: bool) -> NetworkConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut w = stream.unwrap();
+                let r = BufReader::new(w.try_clone().unwrap());
+                for line in r.lines() {
+                    let line = match line { Ok(l) => l, Err(_) => break };
+                    match answer(name, &line, pongs) {
+                        Ok(Some(reply)) => w.write_all(format!("{}\r\n", reply).as_bytes()).unwrap(),
+                        Ok(None) => (),
+                        Err(()) => break
+                    }
+                }
+            }
+        });
+        config(port)
+    }
+
+    /// A stand-in server over TLS, with a self-signed certificate, for one connection.
+    #[cfg(feature = "openssl")]
+    fn tls_stand_in(name: &'static str) -> NetworkConfig {
+        use openssl::asn1::Asn1Time;
+        use openssl::bn::BigNum;
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::ssl::{ SslAcceptor, SslMethod };
+        use openssl::x509::{ X509, X509NameBuilder };
+        use tls::TlsConfig;
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut tls = acceptor.accept(tcp).unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 512];
+            loop {
+                let n = tls.read(&mut chunk).unwrap_or(0);
+                if n == 0 { return }
+                buf.extend_from_slice(&chunk[..n]);
+                while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buf.drain(..end + 1).collect();
+                    match answer(name, String::from_utf8_lossy(&line).trim_right(), true) {
+                        Ok(Some(reply)) => tls.write_all(format!("{}\r\n", reply).as_bytes()).unwrap(),
+                        Ok(None) => (),
+                        Err(()) => return
+                    }
+                }
+            }
+        });
+        config(port).tls(TlsConfig::new().insecure())
+    }
+
+    fn config(port: u16) -> NetworkConfig {
+        NetworkConfig::new("127.0.0.1", port, "bot")
+            .options(ConnectOptions::new().timeout(Some(Duration::from_secs(1))))
+            .reconnect(Some(Duration::from_millis(10)))
+            .lag_check(None)
+    }
+
+    fn wait_for<F: Fn(&Incoming) -> bool>(manager: &ClientManager, f: F) -> Incoming {
+        loop {
+            let i = manager.next_event(Duration::from_secs(5)).expect("no event");
+            if f(&i) { return i }
+        }
+    }
+
+    fn command(i: &Incoming) -> String {
+        i.message.as_ref().map(|m| String::from_utf8_lossy(&m.command()).into_owned()).unwrap_or(String::new())
+    }
+
+    #[test]
+    fn routing() {
+        let manager = ClientManager::new();
+        manager.add("a", stand_in("a.irc", 1)).inner().unwrap();
+        manager.add("b", stand_in("b.irc", 1).lag_check(Some(Duration::from_millis(20)))).inner().unwrap();
+        assert!(manager.add("a", stand_in("a.irc", 0)).is_err());
+        assert_eq!(manager.networks(), vec!["a", "b"]);
+
+        // Both register, in any order.
+        let first = wait_for(&manager, |i| command(i) == "001").network;
+        wait_for(&manager, |i| i.network != first && command(i) == "001");
+        manager.send("b", PRIVMSG("#x".into(), "hi".into())).inner().unwrap();
+        let notice = wait_for(&manager, |i| command(i) == "NOTICE");
+        assert_eq!(notice.network, "b");
+
+        match manager.send("c", QUIT(None)).inner() {
+            Err(IrscError::NotFound) => (),
+            other => panic!("{:?}", other)
+        }
+        while manager.health("b").unwrap().lag.is_none() { thread::sleep(Duration::from_millis(10)) }
+        assert!(manager.health("a").unwrap().connected);
+
+        manager.remove("a").inner().unwrap();
+        assert_eq!(manager.networks(), vec!["b"]);
+    }
+
+    #[test]
+    fn reconnect() {
+        let manager = ClientManager::new();
+        manager.add("a", stand_in("a.irc", 2)).inner().unwrap();
+        wait_for(&manager, |i| command(i) == "001");
+        // The stand-in closes the connection on QUIT, and takes the next one.
+        manager.send("a", QUIT(None)).inner().unwrap();
+        wait_for(&manager, |i| i.event == Some(Event::Disconnected));
+        wait_for(&manager, |i| command(i) == "001");
+        let health = manager.health("a").unwrap();
+        assert!(health.connected);
+        assert_eq!(health.reconnects, 1);
+        assert_eq!(health.reconnect_attempts, 0);
+    }
+
+    #[test]
+    fn unanswered_lag_checks() {
+        let manager = ClientManager::new();
+        manager.add("a", deaf("a.irc", 2).lag_check(Some(Duration::from_millis(100)))).inner().unwrap();
+        wait_for(&manager, |i| command(i) == "001");
+        // Nothing but the lag checks tells that the connection is gone.
+        wait_for(&manager, |i| i.event == Some(Event::Disconnected));
+        wait_for(&manager, |i| command(i) == "001");
+        let health = manager.health("a").unwrap();
+        assert_eq!(health.reconnects, 1);
+        assert_eq!(health.last_error, Some("No answer to 3 lag checks".to_owned()));
+        assert!(health.lag.is_none());
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn tls() {
+        let manager = ClientManager::new();
+        manager.add("a", tls_stand_in("a.irc").lag_check(Some(Duration::from_millis(20)))).inner().unwrap();
+        wait_for(&manager, |i| command(i) == "001");
+        // The server is silent, so the session waits in a read while this is sent.
+        manager.send("a", PRIVMSG("#x".into(), "hi".into())).inner().unwrap();
+        assert_eq!(wait_for(&manager, |i| command(i) == "NOTICE").network, "a");
+        while manager.health("a").unwrap().lag.is_none() { thread::sleep(Duration::from_millis(10)) }
+        manager.remove("a").inner().unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+//! Conversion between the Markdown dialect used by most chat platforms and
+//! IRC formatting codes.
+//!
+//! Supported are `**bold**`, `*italic*` (or `_italic_`), `` `code` ``,
+//! `~~strike~~` and `||spoiler||`. Everything else is passed through as text.
+//!
+//! Things that can't be represented on the other side degrade like this:
+//!
+//! - IRC has no spoilers, they are sent as black on black, and text with the
+//!   same foreground and background colour is turned back into a spoiler.
+//! - Underline, reverse and all other colours are dropped when going to Markdown.
+//! - Markdown code can't contain backticks, those are replaced by `'`.
+
+use color::{ self, Color, Style, Styled };
+
+const SPOILER: Color = Color::Black;
+
+/// Characters that need a backslash to be taken literally.
+const MARKUP: &'static [char] = &['\\', '*', '_', '`', '~', '|'];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Marker {
+    Spoiler,
+    Bold,
+    Italic,
+    Strikethrough,
+    Code
+}
+
+impl Marker {
+    fn delimiter(&self) -> &'static str {
+        match *self {
+            Marker::Spoiler => "||",
+            Marker::Bold => "**",
+            Marker::Italic => "*",
+            Marker::Strikethrough => "~~",
+            Marker::Code => "`"
+        }
+    }
+
+    fn apply(&self, s: Styled) -> Styled {
+        match *self {
+            Marker::Spoiler => s.fg(SPOILER).bg(SPOILER),
+            Marker::Bold => s.bold(),
+            Marker::Italic => s.italic(),
+            Marker::Strikethrough => s.strikethrough(),
+            Marker::Code => s.monospace()
+        }
+    }
+}
+
+/// Escape all characters that would otherwise be read as Markdown.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if MARKUP.contains(&c) { out.push('\\') }
+        out.push(c);
+    }
+    out
+}
+
+struct Parser {
+    c: Vec<char>,
+    i: usize
+}
+
+impl Parser {
+    fn at(&self, i: usize, d: &str) -> bool {
+        d.chars().enumerate().all(|(n, d)| self.c.get(i + n) == Some(&d))
+    }
+
+    fn word_char(&self, i: usize) -> bool {
+        self.c.get(i).map(|c| c.is_alphanumeric()) == Some(true)
+    }
+
+    /// Whether `d` at position `i` can end a span.
+    fn closes(&self, i: usize, d: &str) -> bool {
+        self.at(i, d) && match d {
+            // A pair of asterisks belongs to bold, but a run of three
+            // closes both, like in `*a **b***`.
+            "*" => !self.at(i + 1, "*") || self.at(i + 2, "*"),
+            "_" => !self.word_char(i + 1),
+            _ => true
+        }
+    }
+
+    /// Position of the next unescaped closing `d`, starting at `i`.
+    fn find(&self, mut i: usize, d: &str) -> Option<usize> {
+        while i < self.c.len() {
+            if d != "`" && self.c[i] == '\\' { i += 2; continue }
+            if self.closes(i, d) { return Some(i) }
+            // Skip double asterisks when looking for single ones.
+            if d == "*" && self.at(i, "**") { i += 2 } else { i += 1 }
+        }
+        None
+    }
+
+    fn opener(&self) -> Option<(Marker, &'static str)> {
+        let i = self.i;
+        let candidates = [
+            (Marker::Bold, "**"), (Marker::Strikethrough, "~~"), (Marker::Spoiler, "||"),
+            (Marker::Code, "`"), (Marker::Italic, "*"), (Marker::Italic, "_")
+        ];
+        candidates.iter().cloned().find(|&(_, d)| {
+            self.at(i, d)
+                && (d != "_" || i == 0 || !self.word_char(i - 1))
+                && self.find(i + d.len(), d).map(|j| j > i + d.len()) == Some(true)
+        })
+    }
+
+    fn parse(&mut self, close: Option<&str>) -> Styled {
+        let mut out = Styled::empty();
+        let mut text = String::new();
+        while self.i < self.c.len() {
+            if self.c[self.i] == '\\' && self.c.get(self.i + 1).map(|c| MARKUP.contains(c)) == Some(true) {
+                text.push(self.c[self.i + 1]);
+                self.i += 2;
+                continue
+            }
+
+            if let Some(d) = close {
+                if self.closes(self.i, d) {
+                    self.i += d.len();
+                    break
+                }
+            }
+
+            match self.opener() {
+                Some((Marker::Code, d)) => {
+                    let end = self.find(self.i + 1, d).unwrap();
+                    let code: String = self.c[self.i + 1..end].iter().cloned().collect();
+                    out = out.push(text.split_off(0)).append(Styled::new(code).monospace());
+                    self.i = end + 1;
+                },
+                Some((marker, d)) => {
+                    self.i += d.len();
+                    let inner = self.parse(Some(d));
+                    out = out.push(text.split_off(0)).append(marker.apply(inner));
+                },
+                None => {
+                    text.push(self.c[self.i]);
+                    self.i += 1;
+                }
+            }
+        }
+        out.push(text)
+    }
+}
+
+/// Convert Markdown to IRC formatting codes.
+pub fn to_irc(md: &str) -> String {
+    Parser { c: md.chars().collect(), i: 0 }.parse(None).render()
+}
+
+fn markers(style: &Style) -> Vec<Marker> {
+    let mut m = Vec::new();
+    if style.fg.is_some() && style.fg == style.bg { m.push(Marker::Spoiler) }
+    if style.bold { m.push(Marker::Bold) }
+    if style.italic { m.push(Marker::Italic) }
+    if style.strikethrough { m.push(Marker::Strikethrough) }
+    if style.monospace { m.push(Marker::Code) }
+    m
+}
+
+/// Convert IRC formatting codes to Markdown, escaping literal markup.
+pub fn from_irc(irc: &str) -> String {
+    let mut out = String::new();
+    let mut open: Vec<Marker> = Vec::new();
+    for (style, text) in color::parse(irc) {
+        let wanted = markers(&style);
+        // Keep what is still wanted, as long as it's properly nested,
+        // and close everything from the first unwanted marker on.
+        let keep = open.iter().take_while(|m| wanted.contains(m)).count();
+        // Code can't contain anything else, so it has to be innermost.
+        let keep = if open[..keep].contains(&Marker::Code) && wanted.len() > keep { keep - 1 } else { keep };
+        while open.len() > keep {
+            out.push_str(open.pop().unwrap().delimiter());
+        }
+        for &m in &wanted {
+            if m != Marker::Code && !open.contains(&m) {
+                out.push_str(m.delimiter());
+                open.push(m);
+            }
+        }
+        if wanted.contains(&Marker::Code) && !open.contains(&Marker::Code) {
+            out.push('`');
+            open.push(Marker::Code);
+        }
+
+        if open.contains(&Marker::Code) {
+            out.push_str(&text.replace("`", "'"));
+        } else {
+            out.push_str(&escape(&text));
+        }
+    }
+    while let Some(m) = open.pop() {
+        out.push_str(m.delimiter());
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use markdown::{ to_irc, from_irc, escape };
+
+    #[test]
+    fn markdown_to_irc() {
+        assert_eq!(to_irc("**a**"), "\x02a\x02");
+        assert_eq!(to_irc("*a* and _b_"), "\x1Da\x1D and \x1Db\x1D");
+        assert_eq!(to_irc("~~a~~ `*b*`"), "\x1Ea\x1E \x11*b*\x11");
+        assert_eq!(to_irc("*a **b***"), "\x1Da \x02b\x0F");
+        assert_eq!(to_irc("||a||"), "\x031,1a\x03");
+        assert_eq!(to_irc("snake_case_name"), "snake_case_name");
+        assert_eq!(to_irc("\\*a\\* 2 * 3"), "*a* 2 * 3");
+    }
+
+    #[test]
+    fn irc_to_markdown() {
+        assert_eq!(from_irc("\x02a\x02"), "**a**");
+        assert_eq!(from_irc("\x02a\x1Db\x02c\x1D"), "**a*b****c*");
+        assert_eq!(from_irc("\x1Fa\x1F \x034b"), "a b");
+        assert_eq!(from_irc("\x031,1a\x03"), "||a||");
+        assert_eq!(from_irc("\x11a`b\x11"), "`a'b`");
+        assert_eq!(from_irc("2*3"), "2\\*3");
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in &["**a** *b* `c` ~~d~~ ||e||", "x \\* y", "**a *b***", "**a*b****c*"] {
+            assert_eq!(from_irc(&to_irc(s)), *s);
+        }
+        assert_eq!(to_irc(&escape("*not italic*")), "*not italic*");
+    }
+}
@@ -9,13 +9,12 @@ use std::fmt;
 use linear_map::LinearMap;
 
 use ::IrscError;
-use text::{ self, Text, TextSlice };
-use ident::Ident;
+use text::{ self, Text, TextSlice, EncodingPolicy };
+use ident::{ Ident, Prefix };
+use timestamp::{ Timestamp, Clock };
 
 /// Byte indices, be careful.
 /// TODO: more IRCv3 stuff
-/// TODO: have MaybeUTF8 enum, try to decode as UTF-8 first,
-///       with functions to decode as alternative charsets
 /// ircv3.net
 #[derive(Clone)]
 pub struct Message {
@@ -25,10 +24,19 @@ pub struct Message {
     content: Vec<Range<u16>>,
     suffix: Option<Range<u16>>,
     // only allocates if tags are present
-    tags: LinearMap<Text, Text>
+    tags: LinearMap<Text, Text>,
+    received: Timestamp,
+    timestamp: Timestamp,
+    from_self: bool
     //pub msg_type: MsgType
 }
 
+/// Messages are equal if they consist of the same bytes, regardless of when
+/// they were received.
+impl PartialEq for Message {
+    fn eq(&self, rhs: &Message) -> bool { self.bytes() == rhs.bytes() }
+}
+
 impl fmt::Debug for Message {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Message")
@@ -39,118 +47,109 @@ impl fmt::Debug for Message {
 
 impl Message {
     pub fn new(source: Text, prefix: Option<Range<u16>>, command: Range<u16>, content: Vec<Range<u16>>, suffix: Option<Range<u16>>) -> Message {
+        let now = Timestamp::now();
         Message {
             source: source,
             prefix: prefix,
             command: command,
             content: content,
             suffix: suffix,
-            tags: LinearMap::new()
+            tags: LinearMap::new(),
+            received: now,
+            timestamp: now,
+            from_self: false
         }
     }
 
-    fn parse(i: &[u8]) -> Result<Message, IrscError> {
-        let len = i.len();
+    pub fn parse(i: &[u8]) -> Result<Message, IrscError> {
         // Use indices instead of subslices, to store
         // remember, bytes, not chars
-        let mut s = 0;
+        let mut len = i.len();
+        // Servers aren't consistent about line endings, so accept both.
+        while len > 0 && (i[len - 1] == b'\n' || i[len - 1] == b'\r') { len -= 1 }
 
-        let prefix = if len >= 1 && i[s] == b':' {
-            i[s..].iter().cloned()
-                .find(|&b| b == b' ')
-                .map(|j| { let n = 1u16..(s + j as usize) as u16; s += j as usize + 1; n })
+        let next_space = |from: usize| i[from..len].iter()
+            .position(|&b| b == b' ').map(|p| from + p).unwrap_or(len);
+        let skip_spaces = |mut from: usize| { while from < len && i[from] == b' ' { from += 1 }; from };
+
+        let mut s = 0;
+        let tags = if len >= 1 && i[s] == b'@' {
+            let end = next_space(s);
+            let t = parse_tags(&i[s + 1..end]);
+            s = skip_spaces(end);
+            t
+        } else { LinearMap::new() };
+
+        let prefix = if s < len && i[s] == b':' {
+            let end = next_space(s);
+            let p = (s + 1) as u16..end as u16;
+            s = skip_spaces(end);
+            Some(p)
         } else { None };
 
-        let command = i[s..].iter().cloned()
-                        .find(|&b| b == b' ').map(|n| {
-            let p = s as u16..(s + n as usize) as u16;
-            s += n as usize;
-            p
-        });
+        if s >= len { return Err(IrscError::NotFound) }
+        let end = next_space(s);
+        let command = s as u16..end as u16;
+        s = skip_spaces(end);
 
         let mut content = Vec::with_capacity(3);
         let mut suffix = None;
-        while s < len - 3 {
+        while s < len {
             if i[s] == b':' {
-                suffix = Some(s as u16 + 1 as u16..i.len() as u16);
+                suffix = Some((s + 1) as u16..len as u16);
                 break
             }
-            i[s..].iter().cloned()
-                .find(|&b| b == b' ').map(|j| {
-                if j > 0 {
-                    content.push(s as u16..(s + j as usize) as u16);
-                    s += j as usize;
-                }
-            });
-            // if s.chars().next() == Some(' ') { s = &s[1..] };
-            s += 1;
+            let end = next_space(s);
+            content.push(s as u16..end as u16);
+            s = skip_spaces(end);
         }
 
-        /*let msg_type = if suffix.as_ref().map(|s| i[s.start as usize..].as_bytes()[0] == 1
-                                               && i[(s.end - 3) as usize..].as_bytes()[0] == 1)
-            == Some(true) { MsgType::Ctcp } else { MsgType::Irc };*/
-
-        command.map(move |c|
-            Ok(Message::new(
-                Text::Raw(i.to_owned()),
-                prefix,
-                c,
-                content,
-                // strip \{1} if CTCP message
-                // strip \r\n for each line, relying on their existence
-                suffix
-                /*match msg_type {
-                    MsgType::Irc => suffix.map(|s| s.start..s.end - 1),
-                    MsgType::Ctcp => suffix.map(|s| s.start + 1..s.end - 2)
-                },
-                msg_type*/
-            ))
-        ).unwrap()
+        let mut m = Message::new(Text::Raw(i.to_owned()), prefix, command, content, suffix);
+        m.tags = tags;
+        Ok(m)
     }
 
-    #[allow(unused_assignments)]
     pub fn format<T: Deref<Target=[u8]>>(prefix: Option<T>, command: T, content: Vec<T>, suffix: Option<T>) -> Message {
-        let mut s = Vec::with_capacity(512);
-        let mut i = 0;
+        // Appends `part`, and returns where it is.
+        fn push(s: &mut Vec<u8>, part: &[u8]) -> Range<u16> {
+            let start = s.len();
+            s.extend_from_slice(part);
+            start as u16..s.len() as u16
+        }
 
-        let mut i_prefix = None;
-        if let Some(ref p) = prefix {
-            i_prefix = Some((i + 1) as u16..(i + 2 + p.len()) as u16);
+        let mut s = Vec::with_capacity(512);
+        let i_prefix = prefix.map(|p| {
             s.push(b':');
-            s.push_all(p);
+            let r = push(&mut s, &p);
             s.push(b' ');
-            i = s.len();
-        }
-
-        let i_command = i as u16..(i + command.len()) as u16;
-        s.push_all(&command);
-        s.push(b' ');
-        i = s.len();
-
-        let mut i_content = Vec::new();
-        for part in content.iter() {
-            i_content.push(i as u16..(i + part.len()) as u16);
-            s.push_all(part);
+            r
+        });
+        let i_command = push(&mut s, &command);
+        let i_content = content.iter().map(|part| {
             s.push(b' ');
-            i = s.len();
-        }
-
-        let mut i_suffix = None;
-        if let Some(ref p) = suffix {
-            s.push(b':');
-            //if let MsgType::Ctcp = msg_type { s.push('\u{1}'); i += 1; }
-            let n = i;
-            s.push_all(p);
-            //if let MsgType::Ctcp = msg_type { s.push('\u{1}'); i += 1; }
-            i_suffix = Some(n as u16..(n + p.len()) as u16);
-            i = s.len();
-        }
-
-        s.push_all(b"\r\n");
+            push(&mut s, part)
+        }).collect();
+        let i_suffix = suffix.map(|p| {
+            s.extend_from_slice(b" :");
+            push(&mut s, &p)
+        });
+        s.extend_from_slice(b"\r\n");
 
         Message::new(Text::Raw(s), i_prefix, i_command, i_content, i_suffix)
     }
 
+    /// A message without prefix or tags. The last parameter is sent as
+    /// trailing if `trailing` is set, and also if it has to be: when it's
+    /// empty, has spaces or starts with a colon.
+    pub fn from_params(command: &str, mut params: Vec<Vec<u8>>, trailing: bool) -> Message {
+        let suffix = match params.last() {
+            Some(l) => trailing || l.is_empty() || l.contains(&b' ') || l[0] == b':',
+            None => false
+        };
+        let suffix = if suffix { params.pop() } else { None };
+        Message::format(None, command.as_bytes().to_vec(), params, suffix)
+    }
+
     pub fn byte_range(&self, r: &Range<u16>) -> &[u8] {
         &self.source[r.start as usize..r.end as usize]
     }
@@ -169,8 +168,161 @@ impl Message {
 
     pub fn bytes(&self) -> &[u8] { &*self.source }
 
-    pub fn prefix<'a>(&'a self) -> Option<TextSlice<'a>> {
+    /// The same message, as sent by `prefix` (or without a prefix).
+    pub fn with_prefix(&self, prefix: Option<&Prefix>) -> Message {
+        Message::format(
+            prefix.map(|p| p.to_string().into_bytes()),
+            self.byte_range(&self.command).to_owned(),
+            self.content.iter().map(|r| self.byte_range(r).to_owned()).collect(),
+            self.suffix.as_ref().map(|r| self.byte_range(r).to_owned()))
+            .inherit(self)
+    }
+
+    /// Take tags and times from `from`, after rebuilding a message from its parts.
+    fn inherit(self, from: &Message) -> Message {
+        let mut m = self.retag(from.tags.clone());
+        m.received = from.received;
+        m.timestamp = from.timestamp;
+        m.from_self = from.from_self;
+        m
+    }
+
+    /// Replace all tags, which means rewriting the tag section of the source.
+    fn retag(&self, tags: LinearMap<Text, Text>) -> Message {
+        let start = self.prefix.as_ref().map(|p| p.start - 1).unwrap_or(self.command.start) as i32;
+        let mut source = Vec::with_capacity(self.source.length());
+        if !tags.is_empty() {
+            source.push(b'@');
+            let serialized: Vec<String> = tags.iter().map(|(k, v)| if v.is_empty() {
+                text::def_lossy_decode(k)
+            } else {
+                format!("{}={}", text::def_lossy_decode(k), escape_tag_value(&text::def_lossy_decode(v)))
+            }).collect();
+            source.extend_from_slice(serialized.join(";").as_bytes());
+            source.push(b' ');
+        }
+        let delta = source.len() as i32 - start;
+        source.extend_from_slice(&self.source[start as usize..]);
+
+        let shift = |r: &Range<u16>| (r.start as i32 + delta) as u16..(r.end as i32 + delta) as u16;
+        let mut m = self.clone();
+        m.source = match self.source {
+            Text::Utf8(_) => Text::Utf8(text::def_lossy_decode(&source)),
+            Text::Raw(_) => Text::Raw(source)
+        };
+        m.prefix = self.prefix.as_ref().map(&shift);
+        m.command = shift(&self.command);
+        m.content = self.content.iter().map(&shift).collect();
+        m.suffix = self.suffix.as_ref().map(&shift);
+        m.tags = tags;
+        m
+    }
+
+    /// Value of the tag `key`. Tags without a value have an empty one.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|&(k, _)| k.utf8() == Some(key)).and_then(|(_, v)| v.utf8())
+    }
+
+    pub fn has_tag(&self, key: &str) -> bool { self.tag(key).is_some() }
+
+    pub fn tags(&self) -> Vec<(&str, &str)> {
+        self.tags.iter().filter_map(|(k, v)| match (k.utf8(), v.utf8()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None
+        }).collect()
+    }
+
+    /// The same message, with the tag `key` set to `value` (or no value).
+    pub fn with_tag(&self, key: &str, value: Option<&str>) -> Message {
+        let mut tags = self.tags.clone();
+        tags.insert(Text::Utf8(key.to_owned()), Text::Utf8(value.unwrap_or("").to_owned()));
+        self.retag(tags)
+    }
+
+    pub fn without_tag(&self, key: &str) -> Message {
+        let mut tags = self.tags.clone();
+        tags.remove(&Text::Utf8(key.to_owned()));
+        self.retag(tags)
+    }
+
+    /// When this message was received (or created).
+    pub fn received(&self) -> Timestamp { self.received }
+
+    /// The time from the `time` tag, which servers with `server-time` enabled send.
+    pub fn server_time(&self) -> Option<Timestamp> {
+        self.tag("time").and_then(Timestamp::parse_iso8601)
+    }
+
+    /// When this message happened, according to the clock chosen by the client
+    /// that received it; the time of receipt if nothing else is known.
+    pub fn timestamp(&self) -> Timestamp { self.timestamp }
+
+    /// Pick the timestamp according to `clock`. `trust_tag` says whether the
+    /// `time` tag is meaningful, i.e. if `server-time` has been negotiated.
+    pub fn apply_clock(&mut self, clock: Clock, trust_tag: bool) {
+        self.timestamp = match clock {
+            Clock::Server if trust_tag => self.server_time().unwrap_or(self.received),
+            _ => self.received
+        };
+    }
+
+    /// The `msgid` tag, which identifies a message on the network.
+    pub fn msgid(&self) -> Option<&str> { self.tag("msgid") }
+
+    /// Whether this is a message we sent ourselves, which the server echoed back
+    /// with `echo-message`.
+    pub fn is_from_self(&self) -> bool { self.from_self }
+    pub fn set_from_self(&mut self, from_self: bool) { self.from_self = from_self }
+
+    /// Nickname in the prefix, without decoding or validating anything else.
+    fn raw_nick(&self) -> Option<&[u8]> {
+        self.prefix.as_ref().map(|r| self.byte_range(r))
+            .map(|p| p.split(|&b| b == b'!' || b == b'@').next().unwrap_or(p))
+    }
+
+    /// Rebuild this message with `f` applied to every part.
+    fn map_parts<F>(&self, f: F) -> Message where F: Fn(&[u8]) -> Vec<u8> {
+        Message::format(
+            self.prefix.as_ref().map(|r| f(self.byte_range(r))),
+            f(self.byte_range(&self.command)),
+            self.content.iter().map(|r| f(self.byte_range(r))).collect(),
+            self.suffix.as_ref().map(|r| f(self.byte_range(r))))
+            .inherit(self)
+    }
+
+    /// Decode all parts into UTF-8, using the charset configured for the channel
+    /// this was sent to, or otherwise the sender.
+    pub fn decode(&self, policy: &EncodingPolicy) -> Message {
+        if str::from_utf8(self.bytes()).is_ok() {
+            let mut m = self.clone();
+            m.source = Text::Utf8(text::def_lossy_decode(self.bytes()));
+            return m
+        }
+
+        let target = self.content.first().map(|r| self.byte_range(r))
+            .and_then(|t| if t.first().map(|&c| c == b'#' || c == b'&') == Some(true) {
+                Some(t) } else { None })
+            .or_else(|| self.raw_nick())
+            .map(text::def_lossy_decode);
+        let mut m = self.map_parts(|b| policy.decode(b, target.as_ref().map(|t| &t[..])).into_bytes());
+        m.source = Text::Utf8(text::def_lossy_decode(m.bytes()));
+        m
+    }
+
+    /// Encode all parts in the charset configured for the target of this message.
+    pub fn encode(&self, policy: &EncodingPolicy) -> Message {
+        let target = self.content.first().and_then(|r| self.str_range(r));
+        if policy.is_utf8(target) { return self.clone() }
+        self.map_parts(|b| match str::from_utf8(b) {
+            Ok(s) => policy.encode(s, target),
+            Err(_) => b.to_owned()
+        })
+    }
+
+    pub fn raw_prefix<'a>(&'a self) -> Option<TextSlice<'a>> {
         self.prefix.as_ref().map(|r| self.text_range(r)) }
+    pub fn prefix(&self) -> Option<Prefix> {
+        self.prefix.as_ref().map(|r| self.string_range(r)).and_then(|p| Prefix::parse(&p)) }
     pub fn command<'a>(&'a self) -> TextSlice<'a> {
         self.text_range(&self.command) }
     pub fn content<'a>(&'a self) -> Vec<TextSlice<'a>> {
@@ -182,13 +334,62 @@ impl Message {
     pub fn elements<'a>(&'a self) -> Vec<TextSlice<'a>> {
         let mut s = self.content(); self.suffix().map(|f| s.push(f)); s }
     pub fn ident(&self) -> Option<Ident> {
-        self.prefix().and_then(|p| p.utf8()).and_then(Ident::parse) }
+        match self.prefix() { Some(Prefix::User(i)) => Some(i), _ => None } }
+    pub fn is_from_server(&self) -> bool {
+        self.prefix().map(|p| p.is_server()) == Some(true) }
     pub fn is_ctcp(&self) -> bool {
         self.source.get(0) == Some(&1)
      && self.source.get(self.source.length() - 3) == Some(&1)
     }
 }
 
+fn parse_tags(b: &[u8]) -> LinearMap<Text, Text> {
+    let mut tags = LinearMap::new();
+    for tag in b.split(|&c| c == b';').filter(|t| !t.is_empty()) {
+        let (k, v) = match tag.iter().position(|&c| c == b'=') {
+            Some(p) => (&tag[..p], unescape_tag_value(&tag[p + 1..])),
+            None => (tag, String::new())
+        };
+        tags.insert(Text::Utf8(text::def_lossy_decode(k)), Text::Utf8(v));
+    }
+    tags
+}
+
+/// Escape a tag value, as described in the IRCv3 message-tags specification.
+pub fn escape_tag_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+pub fn unescape_tag_value(b: &[u8]) -> String {
+    let s = text::def_lossy_decode(b);
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' { out.push(c); continue }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            // A trailing backslash is dropped.
+            None => ()
+        }
+    }
+    out
+}
+
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Mode {
     Away,
@@ -221,45 +422,37 @@ pub enum SetMode {
 
 #[cfg(test)]
 mod test {
-    use std::borrow::{ ToOwned };
-    use message::{ Message, MsgType };
+    use message::Message;
 
     #[test]
     fn parse_message1() {
         let b = ":d PRIVMSG You :\u{1}ACTION sends you funny pictures of cats!\u{1}\r\n";
-        let b2 = Message::new(
-            b.to_owned(),
-            Some(1..2),
-            3..10,
-            vec![11..14],
-            Some(17..58),
-            MsgType::Ctcp
-        );
-
-        assert_eq!(b.parse::<Message>().unwrap(), b2.clone());
-        assert_eq!(b2.to_string(), b);
+        let m = Message::parse(b.as_bytes()).unwrap();
+        assert_eq!(&*m.raw_prefix().unwrap(), b"d");
+        assert_eq!(&*m.command(), b"PRIVMSG");
+        assert_eq!(&*m.content()[0], b"You");
+        assert_eq!(&*m.suffix().unwrap(), "\u{1}ACTION sends you funny pictures of cats!\u{1}".as_bytes());
+        assert_eq!(m.bytes(), b.as_bytes());
     }
 
     #[test]
     fn parse_message2() {
         let a = ":a.b.c NOTICE AUTH :*** Looking up your hostname...\r\n";
-        // I'm not even kidding...
-        let a2 = Message::new(
-            a.to_owned(),
-            Some(1..6),
-            7..13,
-            vec![14..18],
-            Some(20..52),
-            MsgType::Irc
-        );
-        assert_eq!(a.parse::<Message>().unwrap(), a2.clone());
-        assert_eq!(a2.to_string(), a);
+        let m = Message::parse(a.as_bytes()).unwrap();
+        assert_eq!(&*m.raw_prefix().unwrap(), b"a.b.c");
+        assert_eq!(&*m.command(), b"NOTICE");
+        assert_eq!(m.elements().len(), 2);
+        assert_eq!(m.bytes(), a.as_bytes());
     }
 
     #[test]
     fn format_message() {
-        let a = Message::format(Some("a.b.c"), "NOTICE", vec!["AUTH"], Some("*** Looking up your hostname..."), MsgType::Irc);
+        let a = Message::format(Some(&b"a.b.c"[..]), &b"NOTICE"[..], vec![&b"AUTH"[..]],
+                                Some(&b"*** Looking up your hostname..."[..]));
         let a2 = ":a.b.c NOTICE AUTH :*** Looking up your hostname...\r\n";
-        assert_eq!(a.to_string(), a2);
+        assert_eq!(a.bytes(), a2.as_bytes());
+        assert_eq!(Message::parse(a2.as_bytes()).unwrap(), a);
+        assert_eq!(Message::from_params("NICK", vec![b"bot".to_vec()], false).bytes(), b"NICK bot\r\n");
+        assert_eq!(Message::from_params("AWAY", vec![b"".to_vec()], false).bytes(), b"AWAY :\r\n");
     }
 }
@@ -0,0 +1,421 @@
+//! A scripted IRC server, to test clients and bots without a network.
+//! Needs the `mock` feature.
+//!
+//! ```ignore
+//! let script = Script::new()
+//!     .register("bot")
+//!     .join("bot", "#test")
+//!     .send(":alice!a@mock PRIVMSG #test :!ping")
+//!     .expect("PRIVMSG #test :pong");
+//! let (server, transport) = MockServer::memory(script);
+//! let mut client = Client::from_transport(transport);
+//! // ... run the bot ...
+//! server.assert_done().assert_received("PRIVMSG #test :pong");
+//! ```
+
+use std::borrow::ToOwned;
+use std::fmt;
+use std::io::{ self, BufRead, BufReader, Write };
+use std::net::{ SocketAddr, TcpListener };
+use std::result;
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ self, Receiver };
+use std::thread;
+use std::time::Duration;
+
+use message::Message;
+use command::Command;
+use reply::Reply;
+use transport::{ self, MemoryTransport, Transport };
+use text;
+
+/// The name the mock server uses as its prefix.
+pub const SERVER: &'static str = "irc.mock";
+
+/// One step of a script.
+pub enum Step {
+    /// Wait for a message with the same command and parameters. Tags on
+    /// the expected message must be there too, other tags are ignored.
+    Expect(Message),
+    /// Wait for a message that passes the test, described by the string.
+    ExpectThat(String, Box<Fn(&Message) -> bool + Send>),
+    Send(Message)
+}
+
+fn line(s: &str) -> Message {
+    let s = format!("{}\r\n", s.trim_right_matches(|c| c == '\r' || c == '\n'));
+    match Message::parse(s.as_bytes()) {
+        Ok(m) => m,
+        Err(_) => panic!("Not a valid IRC line in script: {:?}", s)
+    }
+}
+
+fn params(m: &Message) -> Vec<String> {
+    m.elements().iter().map(|e| text::def_lossy_decode(e)).collect()
+}
+
+/// Whether `got` is what `expected` describes.
+pub fn matches(expected: &Message, got: &Message) -> bool {
+    &*expected.command() == &*got.command()
+        && params(expected) == params(got)
+        && expected.tags().iter().all(|&(k, v)| got.tag(k) == Some(v))
+}
+
+/// What the mock server does, in order. Lines the client sends with one of
+/// the ignored commands are skipped; any other line must be expected.
+pub struct Script {
+    steps: Vec<Step>,
+    ignored: Vec<String>
+}
+
+impl Script {
+    /// An empty script, that ignores `PONG`.
+    pub fn new() -> Script {
+        Script { steps: Vec::new(), ignored: vec!["PONG".to_owned()] }
+    }
+
+    pub fn step(mut self, step: Step) -> Script { self.steps.push(step); self }
+
+    pub fn expect(self, l: &str) -> Script { self.step(Step::Expect(line(l))) }
+    pub fn expect_command(self, c: Command) -> Script { self.step(Step::Expect(c.to_message())) }
+
+    pub fn expect_that<F>(self, description: &str, test: F) -> Script
+    where F: Fn(&Message) -> bool + Send + 'static {
+        self.step(Step::ExpectThat(description.to_owned(), Box::new(test)))
+    }
+
+    /// Wait for any message with this command.
+    pub fn expect_any(self, command: &str) -> Script {
+        let c = command.to_owned();
+        self.expect_that(command, move |m| &*m.command() == c.as_bytes())
+    }
+
+    pub fn send(self, l: &str) -> Script { self.step(Step::Send(line(l))) }
+    pub fn send_command(self, c: Command) -> Script { self.step(Step::Send(c.to_message())) }
+    pub fn send_reply(self, r: Reply) -> Script { self.step(Step::Send(r.to_message())) }
+
+    /// Skip lines with `command` the client sends, wherever they come.
+    pub fn ignore(mut self, command: &str) -> Script { self.ignored.push(command.to_owned()); self }
+
+    /// What `Client::register` sends first: `CAP LS 302`, then `NICK` and `USER`.
+    pub fn handshake(self, nick: &str) -> Script {
+        self.expect("CAP LS 302")
+            .expect(&format!("NICK {}", nick))
+            .expect_any("USER")
+    }
+
+    /// Offer capabilities, and acknowledge the ones in `requested`, which the
+    /// client is expected to request.
+    pub fn negotiate(self, offered: &[&str], requested: &[&str]) -> Script {
+        let s = self.send(&format!(":{} CAP * LS :{}", SERVER, offered.join(" ")));
+        if requested.is_empty() { return s }
+        let list = requested.join(" ");
+        s.expect(&format!("CAP REQ :{}", list))
+         .send(&format!(":{} CAP * ACK :{}", SERVER, list))
+    }
+
+    /// Accept `SASL PLAIN` for `account`.
+    pub fn sasl_plain(self, nick: &str, account: &str, password: &str) -> Script {
+        let payload = text::base64(format!("{}\0{}\0{}", account, account, password).as_bytes());
+        self.expect("AUTHENTICATE PLAIN")
+            .send("AUTHENTICATE +")
+            .expect(&format!("AUTHENTICATE {}", payload))
+            .send(&format!(":{} 900 {} {}!{}@mock {} :You are now logged in as {}",
+                           SERVER, nick, nick, nick, account, account))
+            .send(&format!(":{} 903 {} :SASL authentication successful", SERVER, nick))
+    }
+
+    pub fn end_negotiation(self) -> Script { self.expect("CAP END") }
+
+    /// The welcome numerics and an empty MOTD.
+    pub fn welcome(self, nick: &str) -> Script {
+        self.send(&format!(":{} 001 {} :Welcome to the mock network, {}", SERVER, nick, nick))
+            .send(&format!(":{} 002 {} :Your host is {}", SERVER, nick, SERVER))
+            .send(&format!(":{} 003 {} :This server was created just now", SERVER, nick))
+            .send(&format!(":{} 004 {} {} irsc-mock o o", SERVER, nick, SERVER))
+            .send(&format!(":{} 422 {} :MOTD File is missing", SERVER, nick))
+    }
+
+    /// A whole registration, without capabilities.
+    pub fn register(self, nick: &str) -> Script {
+        self.handshake(nick).negotiate(&[], &[]).end_negotiation().welcome(nick)
+    }
+
+    /// The client joins `channel`, where it's alone.
+    pub fn join(self, nick: &str, channel: &str) -> Script {
+        self.expect(&format!("JOIN {}", channel))
+            .send(&format!(":{}!{}@mock JOIN {}", nick, nick, channel))
+            .send(&format!(":{} 353 {} = {} :@{}", SERVER, nick, channel, nick))
+            .send(&format!(":{} 366 {} {} :End of /NAMES list.", SERVER, nick, channel))
+    }
+}
+
+/// Why a script didn't run to its end. Steps are counted from 1.
+#[derive(Debug)]
+pub enum Failure {
+    /// The client sent something else.
+    Unexpected { step: usize, expected: String, got: Message },
+    /// The client closed the connection.
+    Closed { step: usize, expected: String },
+    /// The client took too long.
+    TimedOut { step: usize, expected: String },
+    Io(io::Error)
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Unexpected { step, ref expected, ref got } =>
+                write!(f, "Step {}: expected {}, got {:?}", step, expected,
+                       text::def_lossy_decode(got.bytes()).trim_right()),
+            Failure::Closed { step, ref expected } =>
+                write!(f, "Step {}: expected {}, but the connection was closed", step, expected),
+            Failure::TimedOut { step, ref expected } =>
+                write!(f, "Step {}: expected {}, but nothing came in time", step, expected),
+            Failure::Io(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+/// Everything the client sent, including ignored lines.
+#[derive(Clone, Debug)]
+pub struct Transcript {
+    pub received: Vec<Message>
+}
+
+impl Transcript {
+    /// The messages with this command.
+    pub fn with_command(&self, command: &str) -> Vec<&Message> {
+        self.received.iter().filter(|m| &*m.command() == command.as_bytes()).collect()
+    }
+
+    /// Panic unless a message matching `l` was received.
+    pub fn assert_received(&self, l: &str) -> &Transcript {
+        let expected = line(l);
+        if !self.received.iter().any(|m| matches(&expected, m)) {
+            panic!("{:?} was not received. Received:\n{}", l, self)
+        }
+        self
+    }
+
+    /// Panic if a message matching `l` was received.
+    pub fn assert_not_received(&self, l: &str) -> &Transcript {
+        let expected = line(l);
+        if self.received.iter().any(|m| matches(&expected, m)) {
+            panic!("{:?} was received. Received:\n{}", l, self)
+        }
+        self
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for m in &self.received {
+            try!(writeln!(f, "{}", text::def_lossy_decode(m.bytes()).trim_right()));
+        }
+        Ok(())
+    }
+}
+
+fn describe(step: &Step) -> String {
+    match *step {
+        Step::Expect(ref m) => format!("{:?}", text::def_lossy_decode(m.bytes()).trim_right()),
+        Step::ExpectThat(ref d, _) => d.clone(),
+        Step::Send(_) => "nothing".to_owned()
+    }
+}
+
+fn accepts(step: &Step, msg: &Message) -> bool {
+    match *step {
+        Step::Expect(ref expected) => matches(expected, msg),
+        Step::ExpectThat(_, ref test) => test(msg),
+        Step::Send(_) => true
+    }
+}
+
+type Outcome = result::Result<Transcript, Failure>;
+
+/// Run `script`, then close the connection, whether it succeeded or not.
+fn run(script: Script, mut transport: Box<Transport>) -> Outcome {
+    let outcome = steps(script, &mut *transport);
+    let _ = transport.shutdown();
+    outcome
+}
+
+fn steps(script: Script, transport: &mut Transport) -> Outcome {
+    let mut reader = BufReader::new(try!(transport.reader().map_err(Failure::Io)));
+    let mut transcript = Transcript { received: Vec::new() };
+    let mut raw = Vec::new();
+    for (i, step) in script.steps.iter().enumerate() {
+        if let Step::Send(ref m) = *step {
+            try!(transport.writer().write_all(m.bytes()).and_then(|_| transport.writer().flush())
+                 .map_err(Failure::Io));
+            continue
+        }
+        loop {
+            raw.clear();
+            match reader.read_until(b'\n', &mut raw) {
+                Ok(0) => return Err(Failure::Closed { step: i + 1, expected: describe(step) }),
+                Ok(_) => (),
+                Err(e) => return Err(Failure::Io(e))
+            }
+            let msg = match Message::parse(&raw) {
+                Ok(m) => m,
+                Err(_) => continue
+            };
+            transcript.received.push(msg.clone());
+            if script.ignored.iter().any(|c| &*msg.command() == c.as_bytes()) { continue }
+            if accepts(step, &msg) { break }
+            return Err(Failure::Unexpected { step: i + 1, expected: describe(step), got: msg })
+        }
+    }
+    Ok(transcript)
+}
+
+/// A mock server running a script on its own thread, for one connection.
+pub struct MockServer {
+    outcome: Receiver<Outcome>,
+    /// To shut the connection down, if the script times out.
+    control: Arc<Mutex<Option<Box<Transport>>>>,
+    timeout: Duration
+}
+
+impl MockServer {
+    /// Run `script` on an in-memory connection. Give the returned end to the client.
+    pub fn memory(script: Script) -> (MockServer, MemoryTransport) {
+        let (client, server) = transport::duplex();
+        let control: Box<Transport> = Box::new(server.clone());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || { let _ = tx.send(run(script, Box::new(server))); });
+        (MockServer::new(rx, Some(control)), client)
+    }
+
+    /// Run `script` for the first connection on a random loopback port.
+    pub fn tcp(script: Script) -> io::Result<(MockServer, SocketAddr)> {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let addr = try!(listener.local_addr());
+        let (tx, rx) = mpsc::channel();
+        let server = MockServer::new(rx, None);
+        let control = server.control.clone();
+        thread::spawn(move || {
+            let outcome = listener.accept().map_err(Failure::Io).and_then(|(stream, _)| {
+                let copy = try!(stream.try_clone().map_err(Failure::Io));
+                *control.lock().unwrap() = Some(Box::new(copy));
+                run(script, Box::new(stream))
+            });
+            let _ = tx.send(outcome);
+        });
+        Ok((server, addr))
+    }
+
+    fn new(outcome: Receiver<Outcome>, control: Option<Box<Transport>>) -> MockServer {
+        MockServer { outcome: outcome, control: Arc::new(Mutex::new(control)), timeout: Duration::from_secs(5) }
+    }
+
+    /// How long `finish` waits for the script to end. The default is 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> MockServer { self.timeout = timeout; self }
+
+    /// Wait for the script to end, and return what the client sent.
+    pub fn finish(self) -> result::Result<Transcript, Failure> {
+        if let Ok(outcome) = self.outcome.recv_timeout(self.timeout) { return outcome }
+
+        // Cut the client off, so the script notices where it's stuck.
+        match self.control.lock().unwrap().as_mut() {
+            Some(c) => { let _ = c.shutdown(); },
+            None => return Err(Failure::TimedOut { step: 0, expected: "a connection".to_owned() })
+        }
+        match self.outcome.recv() {
+            Ok(Err(Failure::Closed { step, expected })) => Err(Failure::TimedOut { step: step, expected: expected }),
+            Ok(outcome) => outcome,
+            Err(_) => Err(Failure::TimedOut { step: 0, expected: "the script to end".to_owned() })
+        }
+    }
+
+    /// Wait for the script to end, and panic if it failed.
+    pub fn assert_done(self) -> Transcript {
+        match self.finish() {
+            Ok(t) => t,
+            Err(f) => panic!("Mock server script failed: {}", f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{ BufRead, BufReader, Write };
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use client::Client;
+    use command::Command::JOIN;
+    use event::Event;
+    use mock::{ MockServer, Script, Failure };
+    use transport::Transport;
+    use text;
+
+    #[test]
+    fn registration() {
+        let script = Script::new()
+            .handshake("bot")
+            .negotiate(&["sasl", "server-time"], &["sasl"])
+            .sasl_plain("bot", "bot", "hunter2")
+            .end_negotiation()
+            .welcome("bot")
+            .join("bot", "#test");
+        let (server, mut client) = MockServer::memory(script);
+        let mut reader = BufReader::new(client.reader().unwrap());
+        client.writer().write_all(b"CAP LS 302\r\nNICK bot\r\nUSER bot 0 * :A bot\r\n").unwrap();
+
+        let mut l = String::new();
+        reader.read_line(&mut l).unwrap();
+        assert_eq!(l, ":irc.mock CAP * LS :sasl server-time\r\n");
+        client.writer().write_all(b"CAP REQ :sasl\r\nPONG :x\r\nAUTHENTICATE PLAIN\r\n").unwrap();
+        client.writer().write_all(b"AUTHENTICATE Ym90AGJvdABodW50ZXIy\r\nCAP END\r\nJOIN #test\r\n").unwrap();
+
+        let lines: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+        assert_eq!(lines.last().map(|l| &l[..]), Some(":irc.mock 366 bot #test :End of /NAMES list."));
+        let transcript = server.assert_done();
+        transcript.assert_received("JOIN #test").assert_not_received("PART #test");
+        assert_eq!(transcript.with_command("PONG").len(), 1);
+    }
+
+    #[test]
+    fn client() {
+        let (server, transport) = MockServer::memory(Script::new().register("bot").join("bot", "#test"));
+        let mut client = Client::from_transport(transport);
+        client.register("bot", "bot", "A bot", None).inner().unwrap();
+        let joined = RefCell::new(Vec::new());
+        client.listen(|client, msg, event| {
+            match event {
+                // Registration is over.
+                Some(_) if &*msg.command() == b"422" => {
+                    client.send(JOIN(vec!["#test".into()], Vec::new(), None)).inner().unwrap();
+                },
+                Some(Event::Command(JOIN(channels, _, _))) => {
+                    assert_eq!(client.users().me(), Some("bot"));
+                    joined.borrow_mut().extend(channels.iter().map(|c| text::def_lossy_decode(c)));
+                },
+                _ => ()
+            }
+        }).inner().unwrap();
+        assert_eq!(*joined.borrow(), vec!["#test".to_owned()]);
+        server.assert_done().assert_received("USER bot 0 * :A bot");
+    }
+
+    #[test]
+    fn failures() {
+        let (server, addr) = MockServer::tcp(Script::new().expect("NICK bot")).unwrap();
+        TcpStream::connect(addr).unwrap().write_all(b"NICK other\r\n").unwrap();
+        match server.finish() {
+            Err(Failure::Unexpected { step: 1, .. }) => (),
+            other => panic!("{:?}", other)
+        }
+
+        let (server, _client) = MockServer::memory(Script::new().expect("NICK bot"));
+        match server.timeout(Duration::from_millis(10)).finish() {
+            Err(Failure::TimedOut { step: 1, .. }) => (),
+            other => panic!("{:?}", other)
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::borrow::ToOwned;
+
+use batch::Batch;
+use ident::Prefix;
+use text;
+
+/// The limits a server gives as the value of `draft/multiline`,
+/// like `max-bytes=4096,max-lines=24`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Total bytes of text in one batch.
+    pub max_bytes: usize,
+    pub max_lines: Option<usize>
+}
+
+impl Limits {
+    pub fn parse(value: &str) -> Option<Limits> {
+        let mut bytes = None;
+        let mut lines = None;
+        for pair in value.split(',') {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next().and_then(|v| v.parse().ok())) {
+                (Some("max-bytes"), Some(v)) => bytes = Some(v),
+                (Some("max-lines"), Some(v)) => lines = Some(v),
+                _ => ()
+            }
+        }
+        // max-bytes is mandatory.
+        bytes.map(|b| Limits { max_bytes: b, max_lines: lines })
+    }
+}
+
+/// A line of a multiline message. `concat` means it continues the previous
+/// line, without a line break in between.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub text: String,
+    pub concat: bool
+}
+
+/// Split `text` at line breaks, and lines longer than `budget` bytes at the
+/// last space that fits, or anywhere if there is none. Pieces of long lines
+/// keep their spaces, so concatenating them gives the original line.
+pub fn split(text: &str, budget: usize) -> Vec<Line> {
+    let budget = if budget == 0 { 1 } else { budget };
+    let mut lines = Vec::new();
+    for line in text.split('\n') {
+        let mut rest = line.trim_right_matches('\r');
+        let mut concat = false;
+        loop {
+            if rest.len() <= budget {
+                lines.push(Line { text: rest.to_owned(), concat: concat });
+                break
+            }
+            let mut end = budget;
+            while !rest.is_char_boundary(end) { end -= 1 }
+            if end == 0 { end = rest.chars().next().map(|c| c.len_utf8()).unwrap_or(rest.len()) }
+            let end = match rest[..end].rfind(' ') {
+                Some(i) if i > 0 => i + 1,
+                _ => end
+            };
+            lines.push(Line { text: rest[..end].to_owned(), concat: concat });
+            rest = &rest[end..];
+            concat = true;
+        }
+    }
+    lines
+}
+
+/// Group lines into batches that stay within `limits`. A batch never starts
+/// with a concatenated line.
+pub fn group(lines: Vec<Line>, limits: &Limits) -> Vec<Vec<Line>> {
+    let mut groups: Vec<Vec<Line>> = Vec::new();
+    let mut bytes = 0;
+    for mut line in lines {
+        // Line breaks count as one byte each.
+        let size = line.text.len() + if line.concat { 0 } else { 1 };
+        let full = match groups.last() {
+            Some(g) => bytes + size > limits.max_bytes
+                       || limits.max_lines.map(|m| g.len() >= m) == Some(true),
+            None => true
+        };
+        if full {
+            groups.push(Vec::new());
+            bytes = 0;
+            line.concat = false;
+        }
+        bytes += size;
+        groups.last_mut().unwrap().push(line);
+    }
+    groups
+}
+
+/// A message received as a `draft/multiline` batch, put back together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Multiline {
+    pub prefix: Option<Prefix>,
+    /// `PRIVMSG` or `NOTICE`.
+    pub command: String,
+    pub target: String,
+    pub text: String,
+    pub batch: Batch
+}
+
+impl Multiline {
+    pub fn from_batch(batch: &Batch) -> Option<Multiline> {
+        if batch.kind != "draft/multiline" { return None }
+        let target = match batch.params.first() {
+            Some(t) => t.clone(),
+            None => return None
+        };
+        let messages = batch.own_messages();
+        let first = match messages.first() {
+            Some(m) => *m,
+            None => return None
+        };
+
+        let mut text = String::new();
+        for (i, m) in messages.iter().enumerate() {
+            if i > 0 && !m.has_tag("draft/multiline-concat") { text.push('\n') }
+            if let Some(t) = m.suffix().or_else(|| m.elements().get(1).cloned()) {
+                text.push_str(&text::def_lossy_decode(&t));
+            }
+        }
+
+        Some(Multiline {
+            prefix: first.prefix(),
+            command: text::def_lossy_decode(&first.command()),
+            target: target,
+            text: text,
+            batch: batch.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use batch::{ Batches, Collected };
+    use multiline::{ Limits, Line, Multiline, split, group };
+
+    fn line(t: &str, concat: bool) -> Line { Line { text: t.to_owned(), concat: concat } }
+
+    #[test]
+    fn splitting() {
+        assert_eq!(split("one\r\ntwo three four", 10), vec![
+            line("one", false), line("two three ", false), line("four", true)
+        ]);
+        assert_eq!(split("abcdefgh", 3), vec![line("abc", false), line("def", true), line("gh", true)]);
+        assert_eq!(split("äöü", 3).len(), 3);
+        assert_eq!(Limits::parse("max-bytes=4096,max-lines=24"),
+                   Some(Limits { max_bytes: 4096, max_lines: Some(24) }));
+
+        let groups = group(split("aaaa\nbbbb cccc\ndddd", 5), &Limits { max_bytes: 12, max_lines: None });
+        assert_eq!(groups, vec![
+            vec![line("aaaa", false), line("bbbb ", false)],
+            vec![line("cccc", false), line("dddd", false)]
+        ]);
+    }
+
+    #[test]
+    fn reassembly() {
+        let mut b = Batches::new();
+        let mut complete = None;
+        for l in &[":n!u@h BATCH +m draft/multiline #rust",
+                   "@batch=m :n!u@h PRIVMSG #rust :hello",
+                   "@batch=m :n!u@h PRIVMSG #rust :wor",
+                   "@batch=m;draft/multiline-concat :n!u@h PRIVMSG #rust :ld",
+                   ":n!u@h BATCH -m"] {
+            if let Collected::Complete(batch) = b.handle(&Message::parse(l.as_bytes()).unwrap()) {
+                complete = Some(batch)
+            }
+        }
+        let m = Multiline::from_batch(&complete.unwrap()).unwrap();
+        assert_eq!(m.text, "hello\nworld");
+        assert_eq!(m.target, "#rust");
+        assert_eq!(m.command, "PRIVMSG");
+    }
+}
@@ -0,0 +1,334 @@
+use std::borrow::ToOwned;
+use std::collections::VecDeque;
+
+use message::Message;
+use ident::Ident;
+use isupport::ISupport;
+use casemap::CaseMapping;
+use text;
+
+/// How the server is told which users to watch.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// IRCv3 `MONITOR`, numerics 730 to 734.
+    Monitor,
+    /// `WATCH`, numerics 600 to 607.
+    Watch,
+    /// Neither is supported, so `ISON` has to be sent now and then.
+    Ison
+}
+
+/// What the client should send to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Watch these, with `MONITOR +` or `WATCH +`.
+    Add(Vec<String>),
+    /// Stop watching these.
+    Remove(Vec<String>),
+    /// Ask which of these are online, with `ISON`.
+    Ison(Vec<String>)
+}
+
+/// A watched user came online, or went offline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Online(Ident),
+    Offline(String)
+}
+
+/// Tracks whether a list of users is online.
+///
+/// `MONITOR` is used if the server supports it, `WATCH` otherwise. Both have
+/// a limit on the number of entries; users beyond it, and all users on servers
+/// with neither, are checked with `ISON` whenever `poll` is called.
+#[derive(Clone, Debug, Default)]
+pub struct Presence {
+    targets: Vec<String>,
+    watched: Vec<String>,
+    /// The nicknames of each ISON sent, oldest first, as replies come in that order.
+    polled: VecDeque<Vec<String>>,
+    online: Vec<Ident>,
+    method: Option<Method>,
+    limit: Option<usize>,
+    casemapping: CaseMapping
+}
+
+// Keep lines well below 512 bytes.
+const MAX_LINE: usize = 400;
+
+fn chunks(list: Vec<String>) -> Vec<Vec<String>> {
+    let mut chunks: Vec<Vec<String>> = Vec::new();
+    let mut len = MAX_LINE;
+    for nick in list {
+        if len + nick.len() + 2 > MAX_LINE {
+            chunks.push(Vec::new());
+            len = 0;
+        }
+        len += nick.len() + 2;
+        chunks.last_mut().unwrap().push(nick);
+    }
+    chunks
+}
+
+impl Presence {
+    pub fn new() -> Presence { Presence::default() }
+
+    /// `None` until the server said what it supports.
+    pub fn method(&self) -> Option<Method> { self.method }
+
+    /// Everybody that should be watched.
+    pub fn targets(&self) -> &[String] { &self.targets }
+
+    pub fn online(&self) -> &[Ident] { &self.online }
+
+    pub fn is_online(&self, nick: &str) -> bool {
+        self.online.iter().any(|i| self.casemapping.eq(&i.nickname, nick))
+    }
+
+    fn position(&self, list: &[String], nick: &str) -> Option<usize> {
+        list.iter().position(|n| self.casemapping.eq(n, nick))
+    }
+
+    fn has_room(&self) -> bool {
+        self.method != Some(Method::Ison) && self.limit.map(|l| self.watched.len() < l) != Some(false)
+    }
+
+    fn set_online(&mut self, ident: Ident) -> Option<Change> {
+        if self.position(&self.targets, &ident.nickname).is_none() { return None }
+        match self.online.iter().position(|i| self.casemapping.eq(&i.nickname, &ident.nickname)) {
+            Some(i) => { self.online[i] = ident; None },
+            None => { self.online.push(ident.clone()); Some(Change::Online(ident)) }
+        }
+    }
+
+    fn set_offline(&mut self, nick: &str) -> Option<Change> {
+        match self.online.iter().position(|i| self.casemapping.eq(&i.nickname, nick)) {
+            Some(i) => Some(Change::Offline(self.online.remove(i).nickname)),
+            None => None
+        }
+    }
+
+    /// Called once registration is complete, to pick a method and watch
+    /// everybody that was added before.
+    pub fn start(&mut self, isupport: &ISupport) -> Vec<Action> {
+        self.casemapping = isupport.casemapping();
+        let (method, key) = if isupport.has("MONITOR") { (Method::Monitor, "MONITOR") }
+                            else if isupport.has("WATCH") { (Method::Watch, "WATCH") }
+                            else { (Method::Ison, "") };
+        self.method = Some(method);
+        self.limit = isupport.number(key);
+        self.watched.clear();
+        self.polled.clear();
+        self.online.clear();
+
+        for t in self.targets.clone() {
+            if !self.has_room() { break }
+            self.watched.push(t);
+        }
+        let mut actions: Vec<Action> = chunks(self.watched.clone()).into_iter().map(Action::Add).collect();
+        actions.extend(self.poll().into_iter());
+        actions
+    }
+
+    /// Forget what the server said, for a new connection. The targets are kept.
+    pub fn reset(&mut self) {
+        self.method = None;
+        self.limit = None;
+        self.watched.clear();
+        self.polled.clear();
+        self.online.clear();
+    }
+
+    pub fn watch(&mut self, nick: &str) -> Vec<Action> {
+        if self.position(&self.targets, nick).is_some() { return Vec::new() }
+        self.targets.push(nick.to_owned());
+        if self.method.is_some() && self.has_room() {
+            self.watched.push(nick.to_owned());
+            vec![Action::Add(vec![nick.to_owned()])]
+        } else if self.method.is_some() {
+            self.polled.push_back(vec![nick.to_owned()]);
+            vec![Action::Ison(vec![nick.to_owned()])]
+        } else { Vec::new() }
+    }
+
+    pub fn unwatch(&mut self, nick: &str) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if let Some(i) = self.position(&self.targets, nick) { self.targets.remove(i); }
+        self.set_offline(nick);
+        if let Some(i) = self.position(&self.watched, nick) {
+            actions.push(Action::Remove(vec![self.watched.remove(i)]));
+            // Make room for somebody who had to be polled so far.
+            let next = self.targets.iter()
+                .find(|t| self.position(&self.watched, t).is_none()).cloned();
+            if let Some(next) = next {
+                self.watched.push(next.clone());
+                actions.push(Action::Add(vec![next]));
+            }
+        }
+        actions
+    }
+
+    /// Check the users that can't be watched, with `ISON`.
+    pub fn poll(&mut self) -> Vec<Action> {
+        if self.method.is_none() { return Vec::new() }
+        let polled = self.targets.iter()
+            .filter(|t| self.position(&self.watched, t).is_none()).cloned().collect();
+        let chunks = chunks(polled);
+        self.polled.extend(chunks.iter().cloned());
+        chunks.into_iter().map(Action::Ison).collect()
+    }
+
+    /// Look at a message from the server, to see if somebody came or went.
+    pub fn handle(&mut self, msg: &Message) -> Vec<Change> {
+        let command = text::def_lossy_decode(&msg.command());
+        // The first parameter of a numeric is our own nickname.
+        let p: Vec<String> = msg.elements().iter().skip(1)
+            .map(|e| text::def_lossy_decode(e)).collect();
+        let list = |i: usize, sep: char| -> Vec<String> {
+            p.get(i).map(|l| l.split(sep).filter(|n| !n.is_empty()).map(ToOwned::to_owned).collect())
+             .unwrap_or(Vec::new())
+        };
+
+        let mut changes = Vec::new();
+        match &command[..] {
+            // RPL_MONONLINE, RPL_MONOFFLINE
+            "730" => for i in list(0, ',').iter().filter_map(|p| Ident::parse(p)) {
+                changes.extend(self.set_online(i).into_iter());
+            },
+            "731" => for n in list(0, ',') {
+                changes.extend(self.set_offline(&n).into_iter());
+            },
+            // ERR_MONLISTFULL: <limit> <targets>. Those have to be polled instead.
+            "734" => {
+                self.limit = p.get(0).and_then(|l| l.parse().ok());
+                for n in list(1, ',') {
+                    if let Some(i) = self.position(&self.watched, &n) { self.watched.remove(i); }
+                }
+            },
+            // RPL_LOGON, RPL_NOWON: <nick> <user> <host> <time>
+            "600" | "604" => if p.len() >= 3 {
+                let ident = Ident { nickname: p[0].clone(), user: Some(p[1].clone()), host: Some(p[2].clone()) };
+                changes.extend(self.set_online(ident).into_iter());
+            },
+            // RPL_LOGOFF, RPL_NOWOFF
+            "601" | "605" => if let Some(n) = p.get(0) {
+                changes.extend(self.set_offline(n).into_iter());
+            },
+            // ERR_TOOMANYWATCH: <nick>
+            "512" => if let Some(n) = p.get(0) {
+                if let Some(i) = self.position(&self.watched, n) { self.watched.remove(i); }
+                self.limit = Some(self.watched.len());
+            },
+            // RPL_ISON: those asked for in the oldest ISON that are online.
+            "303" => if let Some(asked) = self.polled.pop_front() {
+                let on = list(0, ' ');
+                for n in asked {
+                    let change = if on.iter().any(|o| self.casemapping.eq(o, &n)) {
+                        self.set_online(Ident { nickname: n, user: None, host: None })
+                    } else {
+                        self.set_offline(&n)
+                    };
+                    changes.extend(change.into_iter());
+                }
+            },
+            _ => ()
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use isupport::ISupport;
+    use presence::{ Presence, Action, Change, Method };
+
+    fn feed(p: &mut Presence, line: &str) -> Vec<Change> {
+        p.handle(&Message::parse(line.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn monitor_with_limit() {
+        let mut p = Presence::new();
+        assert_eq!(p.watch("a"), vec![]);
+        p.watch("b");
+        p.watch("c");
+        let mut isupport = ISupport::new();
+        isupport.handle(&["MONITOR=2", "CASEMAPPING=ascii"]);
+        assert_eq!(p.start(&isupport), vec![
+            Action::Add(vec!["a".to_owned(), "b".to_owned()]),
+            Action::Ison(vec!["c".to_owned()])
+        ]);
+        assert_eq!(p.method(), Some(Method::Monitor));
+
+        let changes = feed(&mut p, ":irc.host 730 me :A!u@h,b!u@h");
+        assert_eq!(changes.len(), 2);
+        assert!(p.is_online("a"));
+        assert_eq!(feed(&mut p, ":irc.host 731 me :a"), vec![Change::Offline("A".to_owned())]);
+        assert_eq!(feed(&mut p, ":irc.host 303 me :c"),
+                   vec![Change::Online(::ident::Ident { nickname: "c".to_owned(), user: None, host: None })]);
+
+        assert_eq!(p.unwatch("a"), vec![
+            Action::Remove(vec!["a".to_owned()]),
+            Action::Add(vec!["c".to_owned()])
+        ]);
+        assert_eq!(p.poll(), vec![]);
+    }
+
+    #[test]
+    fn ison_chunks() {
+        let nicks: Vec<String> = (0..30).map(|i| format!("user{:016}", i)).collect();
+        let mut p = Presence::new();
+        for n in &nicks { p.watch(n); }
+        let actions = p.start(&ISupport::new());
+        assert_eq!(actions.len(), 2);
+        let second = match actions[1] { Action::Ison(ref l) => l.clone(), _ => panic!() };
+
+        // Each reply only speaks for the nicknames of its own ISON.
+        let first = feed(&mut p, &format!(":irc.host 303 me :{}", nicks[0]));
+        assert_eq!(first.len(), 1);
+        let changes = feed(&mut p, &format!(":irc.host 303 me :{}", second[0]));
+        assert_eq!(changes, vec![Change::Online(::ident::Ident { nickname: second[0].clone(), user: None, host: None })]);
+        assert!(p.is_online(&nicks[0]) && p.is_online(&second[0]));
+
+        p.poll();
+        p.poll();
+        assert_eq!(feed(&mut p, ":irc.host 303 me :"), vec![Change::Offline(nicks[0].clone())]);
+        assert_eq!(feed(&mut p, &format!(":irc.host 303 me :{}", second[0])), vec![]);
+        assert!(p.is_online(&second[0]));
+    }
+
+    #[test]
+    fn watch_past_limit() {
+        let mut p = Presence::new();
+        p.watch("a");
+        let mut isupport = ISupport::new();
+        isupport.handle(&["MONITOR=1"]);
+        assert_eq!(p.start(&isupport), vec![Action::Add(vec!["a".to_owned()])]);
+        assert_eq!(feed(&mut p, ":irc.host 730 me :a!u@h").len(), 1);
+
+        assert_eq!(p.watch("b"), vec![Action::Ison(vec!["b".to_owned()])]);
+        assert_eq!(feed(&mut p, ":irc.host 303 me :b"),
+                   vec![Change::Online(::ident::Ident { nickname: "b".to_owned(), user: None, host: None })]);
+        assert!(p.is_online("a"));
+        assert_eq!(p.poll(), vec![Action::Ison(vec!["b".to_owned()])]);
+        assert_eq!(feed(&mut p, ":irc.host 303 me :"), vec![Change::Offline("b".to_owned())]);
+        assert!(p.is_online("a"));
+    }
+
+    #[test]
+    fn watch_and_ison() {
+        let mut p = Presence::new();
+        p.watch("a");
+        let mut isupport = ISupport::new();
+        isupport.handle(&["WATCH=128"]);
+        p.start(&isupport);
+        assert_eq!(p.method(), Some(Method::Watch));
+        assert_eq!(feed(&mut p, ":irc.host 604 me a u h 0 :is online").len(), 1);
+        assert_eq!(feed(&mut p, ":irc.host 601 me a u h 0 :logged offline"),
+                   vec![Change::Offline("a".to_owned())]);
+
+        p.reset();
+        assert_eq!(p.start(&ISupport::new()), vec![Action::Ison(vec!["a".to_owned()])]);
+    }
+}
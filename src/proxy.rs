@@ -0,0 +1,211 @@
+use std::borrow::ToOwned;
+use std::io::{ self, Read, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+
+use text;
+
+/// A proxy to make connections through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Proxy {
+    /// A SOCKS5 proxy at `host`. Hostnames are resolved by the proxy.
+    Socks5 { host: String, port: u16, auth: Option<(String, String)> },
+    /// An HTTP proxy at `host` that supports `CONNECT`. `auth` is sent with Basic authentication.
+    Http { host: String, port: u16, auth: Option<(String, String)> }
+}
+
+fn error(msg: String) -> io::Error { io::Error::new(io::ErrorKind::Other, msg) }
+
+impl Proxy {
+    pub fn socks5(host: &str, port: u16) -> Proxy {
+        Proxy::Socks5 { host: host.to_owned(), port: port, auth: None }
+    }
+
+    pub fn http(host: &str, port: u16) -> Proxy {
+        Proxy::Http { host: host.to_owned(), port: port, auth: None }
+    }
+
+    /// The same proxy, authenticating with `user` and `pass`.
+    pub fn auth(self, user: &str, pass: &str) -> Proxy {
+        let a = Some((user.to_owned(), pass.to_owned()));
+        match self {
+            Proxy::Socks5 { host, port, .. } => Proxy::Socks5 { host: host, port: port, auth: a },
+            Proxy::Http { host, port, .. } => Proxy::Http { host: host, port: port, auth: a }
+        }
+    }
+
+    pub fn address(&self) -> (&str, u16) {
+        match *self {
+            Proxy::Socks5 { ref host, port, .. } | Proxy::Http { ref host, port, .. } => (host, port)
+        }
+    }
+
+    /// Connect to the proxy, and through it to `host` on `port`.
+    pub fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let addrs: Vec<_> = try!(self.address().to_socket_addrs()).collect();
+        let mut stream = try!(TcpStream::connect(&addrs[..]));
+        try!(self.tunnel(&mut stream, host, port));
+        Ok(stream)
+    }
+
+    /// Ask the proxy at the other end of `stream` to connect to `host` on `port`.
+    pub fn tunnel<S: Read + Write>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+        match *self {
+            Proxy::Socks5 { ref auth, .. } => socks5(stream, host, port, auth.as_ref()),
+            Proxy::Http { ref auth, .. } => http(stream, host, port, auth.as_ref())
+        }
+    }
+}
+
+fn read_exact<S: Read>(stream: &mut S, n: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; n];
+    try!(stream.read_exact(&mut buf));
+    Ok(buf)
+}
+
+fn socks5<S: Read + Write>(stream: &mut S, host: &str, port: u16, auth: Option<&(String, String)>)
+-> io::Result<()> {
+    if host.len() > 255 { return Err(error(format!("Hostname too long for SOCKS5: {}", host))) }
+
+    // Greeting: version 5, and the methods we can do.
+    let methods: &[u8] = if auth.is_some() { &[0x00, 0x02] } else { &[0x00] };
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    try!(stream.write_all(&greeting));
+    let chosen = try!(read_exact(stream, 2));
+    if chosen[0] != 0x05 { return Err(error("Not a SOCKS5 proxy".to_owned())) }
+    match (chosen[1], auth) {
+        (0x00, _) => (),
+        (0x02, Some(&(ref user, ref pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(error("SOCKS5 username or password too long".to_owned()))
+            }
+            let mut req = vec![0x01, user.len() as u8];
+            req.extend_from_slice(user.as_bytes());
+            req.push(pass.len() as u8);
+            req.extend_from_slice(pass.as_bytes());
+            try!(stream.write_all(&req));
+            if try!(read_exact(stream, 2))[1] != 0x00 {
+                return Err(error("SOCKS5 authentication failed".to_owned()))
+            }
+        },
+        _ => return Err(error("SOCKS5 proxy accepts none of our authentication methods".to_owned()))
+    }
+
+    // CONNECT, with the hostname for the proxy to resolve.
+    let mut req = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    req.extend_from_slice(host.as_bytes());
+    req.push((port >> 8) as u8);
+    req.push(port as u8);
+    try!(stream.write_all(&req));
+
+    let reply = try!(read_exact(stream, 4));
+    if reply[1] != 0x00 {
+        let reason = match reply[1] {
+            0x02 => "not allowed by ruleset",
+            0x03 => "network unreachable",
+            0x04 => "host unreachable",
+            0x05 => "connection refused",
+            0x06 => "TTL expired",
+            _ => "general failure"
+        };
+        return Err(error(format!("SOCKS5 proxy could not connect to {}:{}: {}", host, port, reason)))
+    }
+    // Skip the bound address and port.
+    let len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => try!(read_exact(stream, 1))[0] as usize,
+        _ => return Err(error("SOCKS5 proxy sent an unknown address type".to_owned()))
+    };
+    try!(read_exact(stream, len + 2));
+    Ok(())
+}
+
+fn http<S: Read + Write>(stream: &mut S, host: &str, port: u16, auth: Option<&(String, String)>)
+-> io::Result<()> {
+    // IPv6 addresses need brackets.
+    let target = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some(&(ref user, ref pass)) = auth {
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", text::base64(format!("{}:{}", user, pass).as_bytes())));
+    }
+    req.push_str("\r\n");
+    try!(stream.write_all(req.as_bytes()));
+
+    // Read the response head byte by byte, so nothing after it is consumed.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8192 { return Err(error("HTTP proxy response too long".to_owned())) }
+        head.push(try!(read_exact(stream, 1))[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or("");
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(error(format!("HTTP proxy could not connect to {}: {}", target, status)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ Read, Write };
+    use std::net::TcpListener;
+    use std::thread;
+
+    use proxy::Proxy;
+
+    /// A stand-in proxy on a random local port, that runs `script` on the first connection.
+    fn stand_in<F: FnOnce(&mut Read, &mut Write) + Send + 'static>(script: F) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut w = s.try_clone().unwrap();
+            script(&mut s, &mut w);
+        });
+        port
+    }
+
+    fn expect(r: &mut Read, bytes: &[u8]) {
+        let mut buf = vec![0; bytes.len()];
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], bytes);
+    }
+
+    #[test]
+    fn socks5() {
+        let port = stand_in(|r, w| {
+            expect(r, &[5, 2, 0, 2]);
+            w.write_all(&[5, 2]).unwrap();
+            expect(r, b"\x01\x04user\x04pass");
+            w.write_all(&[1, 0]).unwrap();
+            expect(r, b"\x05\x01\x00\x03\x0birc.example\x1a\x0b");
+            w.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+            w.write_all(b"hello").unwrap();
+        });
+        let mut s = Proxy::socks5("127.0.0.1", port).auth("user", "pass").connect("irc.example", 6667).unwrap();
+        let mut hello = String::new();
+        s.read_to_string(&mut hello).unwrap();
+        assert_eq!(hello, "hello");
+    }
+
+    #[test]
+    fn http() {
+        let port = stand_in(|r, w| {
+            expect(r, b"CONNECT irc.example:6697 HTTP/1.1\r\nHost: irc.example:6697\r\n\
+                        Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n");
+            w.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello").unwrap();
+        });
+        let mut s = Proxy::http("127.0.0.1", port).auth("user", "pass").connect("irc.example", 6697).unwrap();
+        let mut hello = String::new();
+        s.read_to_string(&mut hello).unwrap();
+        assert_eq!(hello, "hello");
+
+        let port = stand_in(|r, w| {
+            let mut buf = [0; 64];
+            let _ = r.read(&mut buf);
+            w.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").unwrap();
+        });
+        assert!(Proxy::http("127.0.0.1", port).connect("irc.example", 6697).is_err());
+    }
+}
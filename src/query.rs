@@ -0,0 +1,408 @@
+use std::borrow::ToOwned;
+use std::collections::VecDeque;
+
+use message::Message;
+use ident::Ident;
+use casemap::CaseMapping;
+use label::{ pending, Pending, Promise };
+use timestamp::Timestamp;
+use text;
+use ::IrscError;
+
+/// Everything a `WHOIS` told about a user.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WhoisInfo {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub realname: String,
+    pub server: Option<String>,
+    pub server_info: Option<String>,
+    pub operator: bool,
+    /// Seconds since the user last said something.
+    pub idle: Option<u64>,
+    pub signon: Option<Timestamp>,
+    /// Channels, with their membership prefixes like `@#rust`.
+    pub channels: Vec<String>,
+    pub account: Option<String>,
+    /// The away message, if the user is away.
+    pub away: Option<String>,
+    /// Connected with TLS.
+    pub secure: bool
+}
+
+impl WhoisInfo {
+    pub fn ident(&self) -> Ident {
+        Ident { nickname: self.nick.clone(), user: Some(self.user.clone()), host: Some(self.host.clone()) }
+    }
+}
+
+/// One line of a `WHO` reply.
+///
+/// With WHOX, only the fields that were asked for are present.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WhoEntry {
+    pub channel: Option<String>,
+    pub user: Option<String>,
+    pub ip: Option<String>,
+    pub host: Option<String>,
+    pub server: Option<String>,
+    pub nick: String,
+    /// `H` (here) or `G` (gone), followed by `*` for operators and
+    /// the channel membership prefixes.
+    pub flags: String,
+    pub hops: Option<u32>,
+    pub idle: Option<u64>,
+    pub account: Option<String>,
+    pub realname: Option<String>
+}
+
+impl WhoEntry {
+    pub fn is_away(&self) -> bool { self.flags.starts_with("G") }
+    pub fn is_operator(&self) -> bool { self.flags.contains('*') }
+}
+
+/// One channel of a `LIST` reply.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListEntry {
+    pub channel: String,
+    pub users: u32,
+    pub topic: String
+}
+
+/// The WHOX fields irsc understands, in the order the server sends them.
+const WHOX_FIELDS: &'static str = "tcuihsnfdlaor";
+
+struct Whois {
+    nick: String,
+    info: WhoisInfo,
+    promise: Promise<WhoisInfo>
+}
+
+struct Who {
+    mask: String,
+    fields: Option<String>,
+    token: Option<String>,
+    entries: Vec<WhoEntry>,
+    promise: Promise<Vec<WhoEntry>>
+}
+
+struct List {
+    entries: Vec<ListEntry>,
+    promise: Promise<Vec<ListEntry>>
+}
+
+/// WHOIS, WHO and LIST queries waiting for their replies.
+///
+/// The server answers commands in the order they were sent, so each kind of
+/// query is a queue, and replies belong to the oldest query of their kind.
+/// WHO is the exception: WHOX replies carry a token, the others belong to the
+/// oldest query without one, and the end of each reply names the mask.
+#[derive(Default)]
+pub struct Queries {
+    whois: VecDeque<Whois>,
+    who: VecDeque<Who>,
+    list: VecDeque<List>,
+    next_token: u16
+}
+
+fn number<T: ::std::str::FromStr>(s: Option<&String>) -> Option<T> {
+    s.and_then(|s| s.parse().ok())
+}
+
+impl Queries {
+    pub fn new() -> Queries { Queries::default() }
+
+    pub fn whois(&mut self, nick: &str) -> Pending<WhoisInfo> {
+        let (promise, pending) = pending(String::new());
+        self.whois.push_back(Whois {
+            nick: nick.to_owned(),
+            info: WhoisInfo { nick: nick.to_owned(), ..WhoisInfo::default() },
+            promise: promise
+        });
+        pending
+    }
+
+    /// Start a WHO query for `mask`. With `fields`, it's a WHOX query, and the
+    /// token to send along is returned.
+    pub fn who(&mut self, mask: &str, fields: Option<&str>) -> (Pending<Vec<WhoEntry>>, Option<String>) {
+        let (promise, pending) = pending(String::new());
+        let token = fields.map(|_| {
+            // WHOX tokens have at most three digits.
+            self.next_token = self.next_token % 999 + 1;
+            self.next_token.to_string()
+        });
+        // The token is always requested, to tell WHOX replies apart.
+        let fields = fields.map(|f| {
+            let mut f: String = f.trim_left_matches('%').chars().filter(|&c| c != 't').collect();
+            f.push('t');
+            f
+        });
+        self.who.push_back(Who { mask: mask.to_owned(), fields: fields, token: token.clone(), entries: Vec::new(), promise: promise });
+        (pending, token)
+    }
+
+    pub fn list(&mut self) -> Pending<Vec<ListEntry>> {
+        let (promise, pending) = pending(String::new());
+        self.list.push_back(List { entries: Vec::new(), promise: promise });
+        pending
+    }
+
+    /// Fail all queries, e.g. because the connection was lost.
+    pub fn fail_all(&mut self) {
+        for q in self.whois.drain(..) { q.promise.complete(Err(IrscError::NotConnected)) }
+        for q in self.who.drain(..) { q.promise.complete(Err(IrscError::NotConnected)) }
+        for q in self.list.drain(..) { q.promise.complete(Err(IrscError::NotConnected)) }
+    }
+
+    fn whois_for(&mut self, nick: Option<&String>, mapping: CaseMapping) -> Option<&mut Whois> {
+        match (self.whois.front_mut(), nick) {
+            (Some(q), Some(n)) if mapping.eq(&q.nick, n) => Some(q),
+            _ => None
+        }
+    }
+
+    /// Look at a message from the server, to see if it belongs to a query.
+    /// Nicknames are compared according to the server's `mapping`.
+    pub fn handle(&mut self, msg: &Message, mapping: CaseMapping) {
+        let command = text::def_lossy_decode(&msg.command());
+        // The first parameter of a numeric is our own nickname.
+        let p: Vec<String> = msg.elements().iter().skip(1)
+            .map(|e| text::def_lossy_decode(e)).collect();
+        let error = |p: &[String]| IrscError::Reply(command.clone(), p.last().cloned().unwrap_or(String::new()));
+
+        match &command[..] {
+            // WHOIS
+            "311" => if let Some(q) = self.whois_for(p.get(0), mapping) {
+                q.info.nick = p[0].clone();
+                q.info.user = p.get(1).cloned().unwrap_or(String::new());
+                q.info.host = p.get(2).cloned().unwrap_or(String::new());
+                q.info.realname = p.get(4).cloned().unwrap_or(String::new());
+            },
+            "312" => if let Some(q) = self.whois_for(p.get(0), mapping) {
+                q.info.server = p.get(1).cloned();
+                q.info.server_info = p.get(2).cloned();
+            },
+            "313" => if let Some(q) = self.whois_for(p.get(0), mapping) { q.info.operator = true },
+            "317" => if let Some(q) = self.whois_for(p.get(0), mapping) {
+                q.info.idle = number(p.get(1));
+                if p.len() > 3 {
+                    q.info.signon = number::<i64>(p.get(2)).map(|s| Timestamp(s * 1000));
+                }
+            },
+            "319" => if let Some(q) = self.whois_for(p.get(0), mapping) {
+                q.info.channels.extend(p.get(1).iter()
+                    .flat_map(|c| c.split(' ')).filter(|c| !c.is_empty()).map(ToOwned::to_owned));
+            },
+            "330" => if let Some(q) = self.whois_for(p.get(0), mapping) { q.info.account = p.get(1).cloned() },
+            "301" => if let Some(q) = self.whois_for(p.get(0), mapping) { q.info.away = p.get(1).cloned() },
+            "671" => if let Some(q) = self.whois_for(p.get(0), mapping) { q.info.secure = true },
+            "318" => if self.whois_for(p.get(0), mapping).is_some() {
+                let q = self.whois.pop_front().unwrap();
+                if q.info.user.is_empty() {
+                    q.promise.complete(Err(IrscError::NotFound));
+                } else {
+                    q.promise.complete(Ok(q.info));
+                }
+            },
+            // ERR_NOSUCHNICK and ERR_NOSUCHSERVER, the latter for `WHOIS server nick`.
+            "401" | "402" => if self.whois_for(p.get(0), mapping).is_some() {
+                self.whois.pop_front().unwrap().promise.complete(Err(error(&p)));
+            },
+
+            // WHO
+            "352" => if let Some(q) = self.who.iter_mut().find(|q| q.token.is_none()) {
+                // <channel> <user> <host> <server> <nick> <flags> :<hops> <realname>
+                let (hops, realname) = match p.get(6) {
+                    Some(s) => match s.find(' ') {
+                        Some(i) => (s[..i].parse().ok(), Some(s[i + 1..].to_owned())),
+                        None => (s.parse().ok(), None)
+                    },
+                    None => (None, None)
+                };
+                q.entries.push(WhoEntry {
+                    channel: p.get(0).cloned().and_then(|c| if c == "*" { None } else { Some(c) }),
+                    user: p.get(1).cloned(),
+                    host: p.get(2).cloned(),
+                    server: p.get(3).cloned(),
+                    nick: p.get(4).cloned().unwrap_or(String::new()),
+                    flags: p.get(5).cloned().unwrap_or(String::new()),
+                    hops: hops,
+                    realname: realname,
+                    ..WhoEntry::default()
+                });
+            },
+            "354" => {
+                let q = match self.who.iter_mut().find(|q| q.token.is_some() && q.token.as_ref() == p.get(0)) {
+                    Some(q) => q,
+                    None => return
+                };
+                let mut entry = WhoEntry::default();
+                let requested: Vec<char> = {
+                    let fields = q.fields.as_ref().map(|f| &f[..]).unwrap_or("");
+                    WHOX_FIELDS.chars().filter(|&c| fields.contains(c)).collect()
+                };
+                for (&field, value) in requested.iter().zip(p.iter()) {
+                    let value = value.clone();
+                    match field {
+                        'c' => entry.channel = if value == "*" { None } else { Some(value) },
+                        'u' => entry.user = Some(value),
+                        'i' => entry.ip = Some(value),
+                        'h' => entry.host = Some(value),
+                        's' => entry.server = Some(value),
+                        'n' => entry.nick = value,
+                        'f' => entry.flags = value,
+                        'd' => entry.hops = value.parse().ok(),
+                        'l' => entry.idle = value.parse().ok(),
+                        'a' => entry.account = if value == "0" { None } else { Some(value) },
+                        'r' => entry.realname = Some(value),
+                        _ => ()
+                    }
+                }
+                q.entries.push(entry);
+            },
+            "315" => {
+                let i = match p.get(0).and_then(|m| self.who.iter().position(|q| mapping.eq(&q.mask, m))) {
+                    Some(i) => i,
+                    None => return
+                };
+                let q = self.who.remove(i).unwrap();
+                q.promise.complete(Ok(q.entries));
+            },
+
+            // LIST
+            "322" => if let Some(q) = self.list.front_mut() {
+                q.entries.push(ListEntry {
+                    channel: p.get(0).cloned().unwrap_or(String::new()),
+                    users: number(p.get(1)).unwrap_or(0),
+                    topic: p.get(2).cloned().unwrap_or(String::new())
+                });
+            },
+            "323" => if let Some(q) = self.list.pop_front() {
+                q.promise.complete(Ok(q.entries));
+            },
+
+            // RPL_TRYAGAIN and ERR_TOOMANYMATCHES name the command that failed.
+            "263" | "416" => match p.get(0).map(|c| c.to_uppercase()) {
+                Some(ref c) if c == "WHO" => if let Some(q) = self.who.pop_front() {
+                    q.promise.complete(Err(error(&p)))
+                },
+                Some(ref c) if c == "LIST" => if let Some(q) = self.list.pop_front() {
+                    q.promise.complete(Err(error(&p)))
+                },
+                _ => ()
+            },
+            _ => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use casemap::CaseMapping;
+    use query::Queries;
+
+    fn feed_with(q: &mut Queries, mapping: CaseMapping, lines: &[&str]) {
+        for l in lines { q.handle(&Message::parse(l.as_bytes()).unwrap(), mapping) }
+    }
+
+    fn feed(q: &mut Queries, lines: &[&str]) { feed_with(q, CaseMapping::default(), lines) }
+
+    #[test]
+    fn whois() {
+        let mut q = Queries::new();
+        let missing = q.whois("ghost");
+        let found = q.whois("Nick");
+        feed(&mut q, &[
+            ":irc.host 401 me ghost :No such nick/channel",
+            ":irc.host 318 me ghost :End of /WHOIS list.",
+            ":irc.host 311 me nick ~u example.org * :Real Name",
+            ":irc.host 312 me nick irc.host :The server",
+            ":irc.host 317 me nick 42 1319042451 :seconds idle, signon time",
+            ":irc.host 319 me nick :@#rust +#irc",
+            ":irc.host 330 me nick acct :is logged in as",
+            ":irc.host 318 me nick :End of /WHOIS list."
+        ]);
+        assert!(missing.wait().is_err());
+        let info = found.wait().unwrap();
+        assert_eq!(info.nick, "nick");
+        assert_eq!(info.host, "example.org");
+        assert_eq!(info.realname, "Real Name");
+        assert_eq!(info.idle, Some(42));
+        assert_eq!(info.channels, vec!["@#rust".to_owned(), "+#irc".to_owned()]);
+        assert_eq!(info.account, Some("acct".to_owned()));
+    }
+
+    #[test]
+    fn whois_casemapping() {
+        let mut q = Queries::new();
+        let strict = q.whois("a[b]~");
+        feed_with(&mut q, CaseMapping::StrictRfc1459, &[
+            ":irc.host 311 me A{B}~ ~u example.org * :Real Name",
+            ":irc.host 318 me A{B}~ :End of /WHOIS list."
+        ]);
+        assert_eq!(strict.wait().unwrap().nick, "A{B}~");
+
+        // With ascii, these are different nicknames, and the replies aren't ours.
+        let ascii = q.whois("a[b]");
+        feed_with(&mut q, CaseMapping::Ascii, &[
+            ":irc.host 311 me a{b} ~u example.org * :Real Name",
+            ":irc.host 318 me a{b} :End of /WHOIS list."
+        ]);
+        assert!(ascii.try_get().is_none());
+        feed_with(&mut q, CaseMapping::Ascii, &[
+            ":irc.host 311 me A[B] ~u example.org * :Real Name",
+            ":irc.host 318 me A[B] :End of /WHOIS list."
+        ]);
+        assert_eq!(ascii.wait().unwrap().nick, "A[B]");
+    }
+
+    #[test]
+    fn who() {
+        let mut q = Queries::new();
+        let plain = q.who("#rust", None).0;
+        let (whox, token) = q.who("#Rust", Some("%cnfa"));
+        assert_eq!(token, Some("1".to_owned()));
+        feed(&mut q, &[
+            ":irc.host 352 me #rust ~u example.org irc.host nick G*@ :3 Real Name",
+            ":irc.host 315 me #rust :End of /WHO list.",
+            ":irc.host 354 me 1 #rust nick H acct",
+            ":irc.host 354 me 1 #rust other H+ 0",
+            ":irc.host 315 me #rust :End of /WHO list."
+        ]);
+        let plain = plain.wait().unwrap();
+        assert!(plain[0].is_away() && plain[0].is_operator());
+        assert_eq!(plain[0].hops, Some(3));
+        assert_eq!(plain[0].realname, Some("Real Name".to_owned()));
+        let whox = whox.wait().unwrap();
+        assert_eq!(whox.len(), 2);
+        assert_eq!(whox[0].account, Some("acct".to_owned()));
+        assert_eq!(whox[1].account, None);
+        assert_eq!(whox[1].flags, "H+");
+    }
+
+    #[test]
+    fn who_and_whox_at_once() {
+        let mut q = Queries::new();
+        let (whox, token) = q.who("#a", Some("cn"));
+        let plain = q.who("#B[", None).0;
+        assert_eq!(token, Some("1".to_owned()));
+        feed(&mut q, &[
+            ":irc.host 354 me 1 #a alice",
+            ":irc.host 352 me #b{ ~u example.org irc.host bob H :0 Bob",
+            ":irc.host 315 me #b{ :End of /WHO list."
+        ]);
+        // The plain WHO got its own reply, even though the WHOX query is older.
+        let plain = plain.wait().unwrap();
+        assert_eq!(plain.len(), 1);
+        assert_eq!(plain[0].nick, "bob");
+        assert!(whox.try_get().is_none());
+        feed(&mut q, &[
+            ":irc.host 354 me 1 #a carol",
+            ":irc.host 315 me #A :End of /WHO list."
+        ]);
+        let whox = whox.wait().unwrap();
+        assert_eq!(whox.iter().map(|e| &e.nick[..]).collect::<Vec<_>>(), vec!["alice", "carol"]);
+    }
+}
@@ -1,19 +1,17 @@
-use std::str::FromStr;
-use std::borrow::{ Cow, ToOwned };
-use std::borrow::Cow::*;
-
-use ::{ Result, IrscError };
-use ::message::Message;
+use message::Message;
+use text::{ Text, TextSlice };
 
 macro_rules! replies {
     ($( $name: ident {
         $id: expr, $doc: meta
-        b $($borrowed_items: ty),*
-        o $($owned_items: ty),*
-        t $($to_names: ident),+ => $($to_exprs: expr),+
+        b $($borrowed_items: ty),*;
+        o $($owned_items: ty),*;
+        t $($to_names: ident),+ => $($to_exprs: expr),+;
+        p $params: ident => $parse: expr;
+        f $($f_names: ident),+ => $trailing: expr, $format: expr
     }),+) => (
         #[allow(non_camel_case_types)]
-        #[derive(Debug, Hash, Clone, PartialEq, Eq)]
+        #[derive(Debug, Hash, Clone, PartialEq)]
         pub enum Reply<'a> {
             $(
                 #[$doc]
@@ -22,7 +20,7 @@ macro_rules! replies {
         }
 
         #[allow(non_camel_case_types)]
-        #[derive(Debug, Hash, Clone, PartialEq, Eq)]
+        #[derive(Debug, Hash, Clone, PartialEq)]
         pub enum OwnedReply {
             $(
                 #[$doc]
@@ -39,20 +37,26 @@ macro_rules! replies {
                 }
             }
 
+            /// The reply, if it's known and has the parameters it needs.
             pub fn from_message(msg: &'a Message) -> Option<Reply<'a>> {
-                use Reply::*;
-                match msg.command() {
-                    $(
-                        $id => msg.last().map(|&e| $name(e))
-                    ),+
-                }
+                use self::Reply::*;
+                let command = msg.command();
+                $(
+                    if &*command == $id.as_bytes() {
+                        let $params = msg.elements();
+                        return $parse
+                    }
+                )+
+                None
             }
 
+            /// The reply, without a prefix. Fixed texts like "No such channel"
+            /// are filled in.
             pub fn to_message(&self) -> Message {
-                use Reply::*;
+                use self::Reply::*;
                 match self {
                     $(
-                        &$name(ref s) => Message::format(None, $id, Vec::new(), s)
+                        &$name($(ref $f_names),+) => Message::from_params($id, $format, $trailing)
                     ),+
                 }
             }
@@ -60,39 +64,590 @@ macro_rules! replies {
     )
 }
 
+/// The bytes of each of `items`, as parameters.
+fn params(items: &[TextSlice]) -> Vec<Vec<u8>> { items.iter().map(|i| i.to_vec()).collect() }
+
+/// `items`, followed by the fixed `text` of the reply.
+fn with_text(items: &[TextSlice], text: &str) -> Vec<Vec<u8>> {
+    let mut p = params(items);
+    p.push(text.as_bytes().to_vec());
+    p
+}
+
+/// `items` as one parameter, separated by spaces, like the nicknames of `RPL_NAMREPLY`.
+fn words(items: &[TextSlice]) -> Vec<u8> {
+    let mut joined = Vec::new();
+    for (n, i) in items.iter().enumerate() {
+        if n > 0 { joined.push(b' ') }
+        joined.extend_from_slice(i);
+    }
+    joined
+}
+
+/// The words of a parameter.
+fn split<'a>(list: &TextSlice<'a>) -> Vec<TextSlice<'a>> {
+    match *list {
+        TextSlice::Raw(b) => b.split(|&c| c == b' ').filter(|i| !i.is_empty()).map(TextSlice::Raw).collect(),
+        TextSlice::Utf8(s) => s.split(' ').filter(|i| !i.is_empty()).map(TextSlice::Utf8).collect()
+    }
+}
+
+/// The first word of a parameter, and the rest of it.
+fn first_word<'a>(s: &TextSlice<'a>) -> (TextSlice<'a>, TextSlice<'a>) {
+    match *s {
+        TextSlice::Raw(b) => match b.iter().position(|&c| c == b' ') {
+            Some(i) => (TextSlice::Raw(&b[..i]), TextSlice::Raw(&b[i + 1..])),
+            None => (TextSlice::Raw(b), TextSlice::Raw(&[]))
+        },
+        TextSlice::Utf8(s) => match s.find(' ') {
+            Some(i) => (TextSlice::Utf8(&s[..i]), TextSlice::Utf8(&s[i + 1..])),
+            None => (TextSlice::Utf8(s), TextSlice::Utf8(""))
+        }
+    }
+}
+
+fn owned(items: Vec<TextSlice>) -> Vec<Text> { items.into_iter().map(Into::into).collect() }
+
+// The first parameter of every reply is the nickname of the client it's sent
+// to, or "*" before it has one.
 replies! {
     RPL_WELCOME {
-        "001", doc = r#"001    RPL_WELCOME
+        "001", doc = r#"```text
+        001    RPL_WELCOME
         "Welcome to the Internet Relay Network
-        <nick>!<user>@<host>""#
-        b &'a [u8]
-        o Vec<u8>
-        t s => s.into()
+        <nick>!<user>@<host>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, text => to.into(), text.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_WELCOME(p[0].clone(), p[1].clone())) };
+        f to, text => true, params(&[to.clone(), text.clone()])
     },
     RPL_YOURHOST {
-        "002", doc = r#"002    RPL_YOURHOST
-        "Your host is <servername>, running version <ver>""#
-        b &'a [u8]
-        o Vec<u8>
-        t s => s.into()
+        "002", doc = r#"```text
+        002    RPL_YOURHOST
+        "Your host is <servername>, running version <ver>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, text => to.into(), text.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_YOURHOST(p[0].clone(), p[1].clone())) };
+        f to, text => true, params(&[to.clone(), text.clone()])
     },
     RPL_CREATED {
-        "003", doc = r#"003    RPL_CREATED
-        "This server was created <date>""#
-        b &'a [u8]
-        o Vec<u8>
-        t s => s.into()
+        "003", doc = r#"```text
+        003    RPL_CREATED
+        "This server was created <date>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, text => to.into(), text.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_CREATED(p[0].clone(), p[1].clone())) };
+        f to, text => true, params(&[to.clone(), text.clone()])
     },
     RPL_MYINFO {
-        "004", doc = r#"004    RPL_MYINFO
+        "004", doc = r#"```text
+        004    RPL_MYINFO
         "<servername> <version> <available user modes>
          <available channel modes>"
 
         - The server sends Replies 001 to 004 to a user upon
-          successful registration."#
-        b &'a [u8], &'a [u8], &'a [u8]
-        o Vec<u8>
-        t s => s.into()
+          successful registration.
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text, Text, Text;
+        t to, server, version, user_modes, channel_modes =>
+            to.into(), server.into(), version.into(), user_modes.into(), channel_modes.into();
+        p p => if p.len() < 5 { None } else {
+            Some(RPL_MYINFO(p[0].clone(), p[1].clone(), p[2].clone(), p[3].clone(), p[4].clone()))
+        };
+        f to, server, version, user_modes, channel_modes =>
+            false, params(&[to.clone(), server.clone(), version.clone(), user_modes.clone(),
+                channel_modes.clone()])
+    },
+    RPL_ISUPPORT {
+        "005", doc = r#"```text
+        005    RPL_ISUPPORT
+        "<token> *( " " <token> ) :are supported by this server"
+
+        - The features of the server, like CASEMAPPING=rfc1459,
+          see `ISupport`. Replaces RPL_BOUNCE of RFC 2812.
+        ```"#
+        b TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Vec<Text>;
+        t to, tokens => to.into(), owned(tokens);
+        p p => if p.len() < 2 { None } else { Some(RPL_ISUPPORT(p[0].clone(), p[1..p.len() - 1].to_vec())) };
+        f to, tokens =>
+            true, with_text(&[&[to.clone()][..], &tokens[..]].concat(), "are supported by this server")
+    },
+    RPL_UMODEIS {
+        "221", doc = r#"```text
+        221    RPL_UMODEIS
+        "<user mode string>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, modes => to.into(), modes.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_UMODEIS(p[0].clone(), p[1].clone())) };
+        f to, modes => false, params(&[to.clone(), modes.clone()])
+    },
+    RPL_AWAY {
+        "301", doc = r#"```text
+        301    RPL_AWAY
+        "<nick> :<away message>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text;
+        t to, nick, message => to.into(), nick.into(), message.into();
+        p p => if p.len() < 3 { None } else { Some(RPL_AWAY(p[0].clone(), p[1].clone(), p[2].clone())) };
+        f to, nick, message => true, params(&[to.clone(), nick.clone(), message.clone()])
+    },
+    RPL_ISON {
+        "303", doc = r#"```text
+        303    RPL_ISON
+        ":*1<nick> *( " " <nick> )"
+        ```"#
+        b TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Vec<Text>;
+        t to, nicks => to.into(), owned(nicks);
+        p p => p.get(0).map(|to| RPL_ISON(to.clone(), p.get(1).map(split).unwrap_or(Vec::new())));
+        f to, nicks => true, vec![to.to_vec(), words(nicks)]
+    },
+    RPL_UNAWAY {
+        "305", doc = r#"```text
+        305    RPL_UNAWAY
+        ":You are no longer marked as being away"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(RPL_UNAWAY);
+        f to => true, with_text(&[to.clone()], "You are no longer marked as being away")
+    },
+    RPL_NOWAWAY {
+        "306", doc = r#"```text
+        306    RPL_NOWAWAY
+        ":You have been marked as being away"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(RPL_NOWAWAY);
+        f to => true, with_text(&[to.clone()], "You have been marked as being away")
+    },
+    RPL_WHOISUSER {
+        "311", doc = r#"```text
+        311    RPL_WHOISUSER
+        "<nick> <user> <host> * :<real name>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text, Text, Text;
+        t to, nick, user, host, realname => to.into(), nick.into(), user.into(), host.into(), realname.into();
+        p p => if p.len() < 6 { None } else {
+            Some(RPL_WHOISUSER(p[0].clone(), p[1].clone(), p[2].clone(), p[3].clone(), p[5].clone()))
+        };
+        f to, nick, user, host, realname =>
+            true, params(&[to.clone(), nick.clone(), user.clone(), host.clone(), TextSlice::Utf8("*"),
+                realname.clone()])
+    },
+    RPL_WHOISSERVER {
+        "312", doc = r#"```text
+        312    RPL_WHOISSERVER
+        "<nick> <server> :<server info>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text, Text;
+        t to, nick, server, info => to.into(), nick.into(), server.into(), info.into();
+        p p => if p.len() < 4 { None } else {
+            Some(RPL_WHOISSERVER(p[0].clone(), p[1].clone(), p[2].clone(), p[3].clone()))
+        };
+        f to, nick, server, info => true, params(&[to.clone(), nick.clone(), server.clone(), info.clone()])
+    },
+    RPL_ENDOFWHO {
+        "315", doc = r#"```text
+        315    RPL_ENDOFWHO
+        "<name> :End of WHO list"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, mask => to.into(), mask.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_ENDOFWHO(p[0].clone(), p[1].clone())) };
+        f to, mask => true, with_text(&[to.clone(), mask.clone()], "End of /WHO list.")
+    },
+    RPL_ENDOFWHOIS {
+        "318", doc = r#"```text
+        318    RPL_ENDOFWHOIS
+        "<nick> :End of WHOIS list"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, nick => to.into(), nick.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_ENDOFWHOIS(p[0].clone(), p[1].clone())) };
+        f to, nick => true, with_text(&[to.clone(), nick.clone()], "End of /WHOIS list.")
+    },
+    RPL_WHOISCHANNELS {
+        "319", doc = r#"```text
+        319    RPL_WHOISCHANNELS
+        "<nick> :*( ( "@" / "+" ) <channel> " " )"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Text, Vec<Text>;
+        t to, nick, channels => to.into(), nick.into(), owned(channels);
+        p p => if p.len() < 3 { None } else {
+            Some(RPL_WHOISCHANNELS(p[0].clone(), p[1].clone(), split(&p[2])))
+        };
+        f to, nick, channels => true, vec![to.to_vec(), nick.to_vec(), words(channels)]
+    },
+    RPL_LIST {
+        "322", doc = r#"```text
+        322    RPL_LIST
+        "<channel> <# visible> :<topic>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text, Text;
+        t to, channel, visible, topic => to.into(), channel.into(), visible.into(), topic.into();
+        p p => if p.len() < 4 { None } else {
+            Some(RPL_LIST(p[0].clone(), p[1].clone(), p[2].clone(), p[3].clone()))
+        };
+        f to, channel, visible, topic =>
+            true, params(&[to.clone(), channel.clone(), visible.clone(), topic.clone()])
+    },
+    RPL_LISTEND {
+        "323", doc = r#"```text
+        323    RPL_LISTEND
+        ":End of LIST"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(RPL_LISTEND);
+        f to => true, with_text(&[to.clone()], "End of /LIST")
+    },
+    RPL_CHANNELMODEIS {
+        "324", doc = r#"```text
+        324    RPL_CHANNELMODEIS
+        "<channel> <mode> <mode params>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Text, Text, Vec<Text>;
+        t to, channel, modes, args => to.into(), channel.into(), modes.into(), owned(args);
+        p p => if p.len() < 3 { None } else {
+            Some(RPL_CHANNELMODEIS(p[0].clone(), p[1].clone(), p[2].clone(), p[3..].to_vec()))
+        };
+        f to, channel, modes, args =>
+            false, [params(&[to.clone(), channel.clone(), modes.clone()]), params(args)].concat()
+    },
+    RPL_CREATIONTIME {
+        "329", doc = r#"```text
+        329    RPL_CREATIONTIME
+        "<channel> <creation time>"
+
+        - When the channel was created, in seconds since the epoch.
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text;
+        t to, channel, time => to.into(), channel.into(), time.into();
+        p p => if p.len() < 3 { None } else {
+            Some(RPL_CREATIONTIME(p[0].clone(), p[1].clone(), p[2].clone()))
+        };
+        f to, channel, time => false, params(&[to.clone(), channel.clone(), time.clone()])
+    },
+    RPL_NOTOPIC {
+        "331", doc = r#"```text
+        331    RPL_NOTOPIC
+        "<channel> :No topic is set"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_NOTOPIC(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "No topic is set")
+    },
+    RPL_TOPIC {
+        "332", doc = r#"```text
+        332    RPL_TOPIC
+        "<channel> :<topic>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text;
+        t to, channel, topic => to.into(), channel.into(), topic.into();
+        p p => if p.len() < 3 { None } else { Some(RPL_TOPIC(p[0].clone(), p[1].clone(), p[2].clone())) };
+        f to, channel, topic => true, params(&[to.clone(), channel.clone(), topic.clone()])
+    },
+    RPL_TOPICWHOTIME {
+        "333", doc = r#"```text
+        333    RPL_TOPICWHOTIME
+        "<channel> <nick> <set at>"
+
+        - Who set the topic, and when, in seconds since the epoch.
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text, Text;
+        t to, channel, nick, time => to.into(), channel.into(), nick.into(), time.into();
+        p p => if p.len() < 4 { None } else {
+            Some(RPL_TOPICWHOTIME(p[0].clone(), p[1].clone(), p[2].clone(), p[3].clone()))
+        };
+        f to, channel, nick, time => false, params(&[to.clone(), channel.clone(), nick.clone(), time.clone()])
+    },
+    RPL_WHOREPLY {
+        "352", doc = r#"```text
+        352    RPL_WHOREPLY
+        "<channel> <user> <host> <server> <nick>
+        ( "H" / "G" > ["*"] [ ( "@" / "+" ) ]
+        :<hopcount> <real name>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, TextSlice<'a>,
+          TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text, Text, Text, Text, Text, Text, Text;
+        t to, channel, user, host, server, nick, flags, hops, realname =>
+            to.into(), channel.into(), user.into(), host.into(), server.into(), nick.into(), flags.into(),
+                hops.into(), realname.into();
+        p p => if p.len() < 8 { None } else {
+            let (hops, realname) = first_word(&p[7]);
+            Some(RPL_WHOREPLY(p[0].clone(), p[1].clone(), p[2].clone(), p[3].clone(), p[4].clone(),
+                              p[5].clone(), p[6].clone(), hops, realname))
+        };
+        f to, channel, user, host, server, nick, flags, hops, realname =>
+            true, [params(&[to.clone(), channel.clone(), user.clone(), host.clone(), server.clone(),
+                nick.clone(), flags.clone()]), vec![[&hops[..], b" ", &realname[..]].concat()]].concat()
+    },
+    RPL_NAMREPLY {
+        "353", doc = r#"```text
+        353    RPL_NAMREPLY
+        "( "=" / "*" / "@" ) <channel>
+         :[ "@" / "+" ] <nick> *( " " [ "@" / "+" ] <nick> )"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>, Vec<TextSlice<'a>>;
+        o Text, Text, Text, Vec<Text>;
+        t to, symbol, channel, names => to.into(), symbol.into(), channel.into(), owned(names);
+        p p => if p.len() < 4 { None } else {
+            Some(RPL_NAMREPLY(p[0].clone(), p[1].clone(), p[2].clone(), split(&p[3])))
+        };
+        f to, symbol, channel, names =>
+            true, vec![to.to_vec(), symbol.to_vec(), channel.to_vec(), words(names)]
+    },
+    RPL_ENDOFNAMES {
+        "366", doc = r#"```text
+        366    RPL_ENDOFNAMES
+        "<channel> :End of NAMES list"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_ENDOFNAMES(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "End of /NAMES list.")
+    },
+    RPL_ENDOFBANLIST {
+        "368", doc = r#"```text
+        368    RPL_ENDOFBANLIST
+        "<channel> :End of channel ban list"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_ENDOFBANLIST(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "End of channel ban list")
+    },
+    RPL_MOTD {
+        "372", doc = r#"```text
+        372    RPL_MOTD
+        ":- <text>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, text => to.into(), text.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_MOTD(p[0].clone(), p[1].clone())) };
+        f to, text => true, params(&[to.clone(), text.clone()])
+    },
+    RPL_MOTDSTART {
+        "375", doc = r#"```text
+        375    RPL_MOTDSTART
+        ":- <server> Message of the day - "
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, text => to.into(), text.into();
+        p p => if p.len() < 2 { None } else { Some(RPL_MOTDSTART(p[0].clone(), p[1].clone())) };
+        f to, text => true, params(&[to.clone(), text.clone()])
+    },
+    RPL_ENDOFMOTD {
+        "376", doc = r#"```text
+        376    RPL_ENDOFMOTD
+        ":End of MOTD command"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(RPL_ENDOFMOTD);
+        f to => true, with_text(&[to.clone()], "End of /MOTD command.")
+    },
+    ERR_NOSUCHNICK {
+        "401", doc = r#"```text
+        401    ERR_NOSUCHNICK
+        "<nickname> :No such nick/channel"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, nick => to.into(), nick.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_NOSUCHNICK(p[0].clone(), p[1].clone())) };
+        f to, nick => true, with_text(&[to.clone(), nick.clone()], "No such nick/channel")
+    },
+    ERR_NOSUCHCHANNEL {
+        "403", doc = r#"```text
+        403    ERR_NOSUCHCHANNEL
+        "<channel name> :No such channel"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_NOSUCHCHANNEL(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "No such channel")
+    },
+    ERR_CANNOTSENDTOCHAN {
+        "404", doc = r#"```text
+        404    ERR_CANNOTSENDTOCHAN
+        "<channel name> :Cannot send to channel"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_CANNOTSENDTOCHAN(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "Cannot send to channel")
+    },
+    ERR_UNKNOWNCOMMAND {
+        "421", doc = r#"```text
+        421    ERR_UNKNOWNCOMMAND
+        "<command> :Unknown command"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, command => to.into(), command.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_UNKNOWNCOMMAND(p[0].clone(), p[1].clone())) };
+        f to, command => true, with_text(&[to.clone(), command.clone()], "Unknown command")
+    },
+    ERR_NOMOTD {
+        "422", doc = r#"```text
+        422    ERR_NOMOTD
+        ":MOTD File is missing"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(ERR_NOMOTD);
+        f to => true, with_text(&[to.clone()], "MOTD File is missing")
+    },
+    ERR_ERRONEUSNICKNAME {
+        "432", doc = r#"```text
+        432    ERR_ERRONEUSNICKNAME
+        "<nick> :Erroneous nickname"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, nick => to.into(), nick.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_ERRONEUSNICKNAME(p[0].clone(), p[1].clone())) };
+        f to, nick => true, with_text(&[to.clone(), nick.clone()], "Erroneous nickname")
+    },
+    ERR_NICKNAMEINUSE {
+        "433", doc = r#"```text
+        433    ERR_NICKNAMEINUSE
+        "<nick> :Nickname is already in use"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, nick => to.into(), nick.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_NICKNAMEINUSE(p[0].clone(), p[1].clone())) };
+        f to, nick => true, with_text(&[to.clone(), nick.clone()], "Nickname is already in use")
+    },
+    ERR_USERNOTINCHANNEL {
+        "441", doc = r#"```text
+        441    ERR_USERNOTINCHANNEL
+        "<nick> <channel> :They aren't on that channel"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>, TextSlice<'a>;
+        o Text, Text, Text;
+        t to, nick, channel => to.into(), nick.into(), channel.into();
+        p p => if p.len() < 3 { None } else {
+            Some(ERR_USERNOTINCHANNEL(p[0].clone(), p[1].clone(), p[2].clone()))
+        };
+        f to, nick, channel =>
+            true, with_text(&[to.clone(), nick.clone(), channel.clone()], "They aren't on that channel")
+    },
+    ERR_NOTONCHANNEL {
+        "442", doc = r#"```text
+        442    ERR_NOTONCHANNEL
+        "<channel> :You're not on that channel"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_NOTONCHANNEL(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "You're not on that channel")
+    },
+    ERR_NOTREGISTERED {
+        "451", doc = r#"```text
+        451    ERR_NOTREGISTERED
+        ":You have not registered"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(ERR_NOTREGISTERED);
+        f to => true, with_text(&[to.clone()], "You have not registered")
+    },
+    ERR_ALREADYREGISTRED {
+        "462", doc = r#"```text
+        462    ERR_ALREADYREGISTRED
+        ":Unauthorized command (already registered)"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(ERR_ALREADYREGISTRED);
+        f to => true, with_text(&[to.clone()], "You may not reregister")
+    },
+    ERR_UNKNOWNMODE {
+        "472", doc = r#"```text
+        472    ERR_UNKNOWNMODE
+        "<char> :is unknown mode char to me for <channel>"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, mode => to.into(), mode.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_UNKNOWNMODE(p[0].clone(), p[1].clone())) };
+        f to, mode => true, with_text(&[to.clone(), mode.clone()], "is unknown mode char to me")
+    },
+    ERR_BADCHANNELKEY {
+        "475", doc = r#"```text
+        475    ERR_BADCHANNELKEY
+        "<channel> :Cannot join channel (+k)"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_BADCHANNELKEY(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "Cannot join channel (+k)")
+    },
+    ERR_CHANOPRIVSNEEDED {
+        "482", doc = r#"```text
+        482    ERR_CHANOPRIVSNEEDED
+        "<channel> :You're not channel operator"
+        ```"#
+        b TextSlice<'a>, TextSlice<'a>;
+        o Text, Text;
+        t to, channel => to.into(), channel.into();
+        p p => if p.len() < 2 { None } else { Some(ERR_CHANOPRIVSNEEDED(p[0].clone(), p[1].clone())) };
+        f to, channel => true, with_text(&[to.clone(), channel.clone()], "You're not channel operator")
+    },
+    ERR_USERSDONTMATCH {
+        "502", doc = r#"```text
+        502    ERR_USERSDONTMATCH
+        ":Cannot change mode for other users"
+        ```"#
+        b TextSlice<'a>;
+        o Text;
+        t to => to.into();
+        p p => p.get(0).cloned().map(ERR_USERSDONTMATCH);
+        f to => true, with_text(&[to.clone()], "Can't change mode for other users")
     }
 }
     /*
@@ -1398,3 +1953,52 @@ impl<'a> Reply<'a> {
         }
      }
 }*/
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use reply::Reply;
+    use reply::Reply::*;
+    use text::TextSlice;
+
+    fn t(s: &'static str) -> TextSlice<'static> { TextSlice::Raw(s.as_bytes()) }
+
+    fn line(r: Reply) -> String { String::from_utf8(r.to_message().bytes().to_vec()).unwrap() }
+
+    fn round_trip(r: Reply) {
+        let msg = r.to_message();
+        assert_eq!(Reply::from_message(&msg), Some(r.clone()));
+    }
+
+    #[test]
+    fn fixed_texts() {
+        assert_eq!(line(ERR_NICKNAMEINUSE(t("*"), t("bob"))), "433 * bob :Nickname is already in use\r\n");
+        assert_eq!(line(ERR_NOTREGISTERED(t("*"))), "451 * :You have not registered\r\n");
+        assert_eq!(line(RPL_ISUPPORT(t("bob"), vec![t("CHANTYPES=#"), t("NICKLEN=30")])),
+                   "005 bob CHANTYPES=# NICKLEN=30 :are supported by this server\r\n");
+        assert_eq!(line(RPL_WHOREPLY(t("bob"), t("#test"), t("~alice"), t("localhost"), t("irc.local"),
+                                     t("alice"), t("H@"), t("0"), t("Alice A."))),
+                   "352 bob #test ~alice localhost irc.local alice H@ :0 Alice A.\r\n");
+        assert_eq!(line(RPL_CHANNELMODEIS(t("bob"), t("#test"), t("+kt"), vec![t("key")])),
+                   "324 bob #test +kt key\r\n");
+    }
+
+    #[test]
+    fn parameters() {
+        round_trip(RPL_WELCOME(t("bob"), t("Welcome to the irsc IRC Network bob!~bob@localhost")));
+        round_trip(RPL_MYINFO(t("bob"), t("irc.local"), t("irscd"), t("i"), t("kmnotv")));
+        round_trip(RPL_ISUPPORT(t("bob"), vec![t("CASEMAPPING=ascii")]));
+        round_trip(RPL_ISON(t("bob"), vec![t("alice"), t("carol")]));
+        round_trip(RPL_WHOISUSER(t("bob"), t("alice"), t("~alice"), t("localhost"), t("Alice A.")));
+        round_trip(RPL_NAMREPLY(t("bob"), t("="), t("#test"), vec![t("@alice"), t("bob")]));
+        round_trip(RPL_WHOREPLY(t("bob"), t("*"), t("~alice"), t("localhost"), t("irc.local"),
+                                t("alice"), t("G"), t("0"), t("Alice A.")));
+        round_trip(ERR_USERNOTINCHANNEL(t("bob"), t("carol"), t("#test")));
+        round_trip(RPL_ENDOFMOTD(t("bob")));
+
+        let msg = Message::parse(b":irc.host 333 bob #test alice 1319042451\r\n").unwrap();
+        assert_eq!(Reply::from_message(&msg), Some(RPL_TOPICWHOTIME(t("bob"), t("#test"), t("alice"), t("1319042451"))));
+        let msg = Message::parse(b":irc.host 401 bob\r\n").unwrap();
+        assert_eq!(Reply::from_message(&msg), None);
+    }
+}
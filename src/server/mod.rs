@@ -0,0 +1,335 @@
+//! A small single-server IRC daemon, to run bots against each other without
+//! an external server. Needs the `server` feature.
+//!
+//! It knows registration, channels with the `o`, `v`, `k`, `m`, `n` and `t`
+//! modes, messages, `WHO`, `WHOIS`, `LIST` and `PING`, and nothing about
+//! links, services or flood limits.
+//!
+//! ```ignore
+//! let server = Server::new(Config::default());
+//! let addr = server.spawn("127.0.0.1:0").unwrap();
+//! // ... connect clients to addr ...
+//! ```
+
+mod state;
+
+use std::borrow::ToOwned;
+use std::io::{ self, BufRead, BufReader };
+use std::net::{ SocketAddr, TcpListener, ToSocketAddrs };
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ self, Receiver };
+use std::thread::{ self, JoinHandle };
+
+use casemap::CaseMapping;
+use message::Message;
+use transport::{ self, MemoryTransport, Transport };
+
+use self::state::{ Overflow, State };
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The server name, used as prefix of everything the server sends.
+    pub name: String,
+    pub network: String,
+    /// Lines of the message of the day. `422` is sent if empty.
+    pub motd: Vec<String>,
+    pub casemapping: CaseMapping,
+    /// How many messages may wait for a client that doesn't read, before
+    /// it's disconnected with "SendQ exceeded".
+    pub sendq: usize
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            name: "irc.local".to_owned(),
+            network: "irsc".to_owned(),
+            motd: Vec::new(),
+            casemapping: CaseMapping::default(),
+            sendq: 1000
+        }
+    }
+}
+
+/// A running server. Clones share the same users and channels.
+#[derive(Clone)]
+pub struct Server {
+    state: Arc<Mutex<State>>
+}
+
+impl Server {
+    pub fn new(config: Config) -> Server {
+        Server { state: Arc::new(Mutex::new(State::new(config))) }
+    }
+
+    /// Serve a client connected through `transport`, which came from `host`.
+    /// The returned thread ends when the client disconnects.
+    ///
+    /// Each client has a writer thread of its own, so one that doesn't read
+    /// only holds up what is sent to itself, until its `Config::sendq` is full.
+    pub fn serve<T: Transport + 'static>(&self, transport: T, host: &str) -> io::Result<JoinHandle<()>> {
+        let reader = try!(transport.reader());
+        let closer = transport.try_clone().ok();
+        let (outgoing, queue) = mpsc::sync_channel(self.config().sendq);
+        let overflow = Overflow::default();
+        let error = overflow.clone();
+        thread::spawn(move || write_queue(Box::new(transport), queue, error));
+        let id = self.state.lock().unwrap().add(outgoing, overflow, closer, host);
+        let state = self.state.clone();
+        Ok(thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut raw = Vec::new();
+            let reason = loop {
+                raw.clear();
+                match reader.read_until(b'\n', &mut raw) {
+                    Ok(0) => break "Connection closed".to_owned(),
+                    Ok(_) => (),
+                    Err(e) => break format!("Read error: {}", e)
+                }
+                let msg = match Message::parse(&raw) {
+                    Ok(m) => m,
+                    Err(_) => continue
+                };
+                if !state.lock().unwrap().handle(id, &msg) { return }
+            };
+            state.lock().unwrap().quit(id, &reason);
+        }))
+    }
+
+    /// Accept connections on `listener` until it fails.
+    pub fn listen(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = try!(stream);
+            let host = match stream.peer_addr() {
+                Ok(a) => a.ip().to_string(),
+                Err(_) => "unknown".to_owned()
+            };
+            if let Err(e) = self.serve(stream, &host) {
+                debug!("Could not serve {}: {}", host, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Listen on `addr` on another thread, and return the address listened on.
+    /// Port 0 picks a free port.
+    pub fn spawn<A: ToSocketAddrs>(&self, addr: A) -> io::Result<SocketAddr> {
+        let listener = try!(TcpListener::bind(addr));
+        let addr = try!(listener.local_addr());
+        let server = self.clone();
+        thread::spawn(move || { let _ = server.listen(listener); });
+        Ok(addr)
+    }
+
+    /// Connect a client in memory, returning its end of the connection.
+    pub fn connect_memory(&self) -> io::Result<MemoryTransport> {
+        let (client, server) = transport::duplex();
+        try!(self.serve(server, "localhost"));
+        Ok(client)
+    }
+
+    pub fn config(&self) -> Config { self.state.lock().unwrap().config().clone() }
+
+    /// The nicknames of all registered users.
+    pub fn users(&self) -> Vec<String> { self.state.lock().unwrap().nicks() }
+
+    /// The names of all channels that have members.
+    pub fn channels(&self) -> Vec<String> { self.state.lock().unwrap().channels() }
+
+    /// The nicknames on `channel`, in the order they connected.
+    pub fn members(&self, channel: &str) -> Vec<String> { self.state.lock().unwrap().members(channel) }
+}
+
+/// Write what is queued for a client, until it's removed and everything
+/// is sent, or the connection is lost. Then close the connection.
+///
+/// Once its queue overflowed, the rest of it is dropped, and only the
+/// error in `overflow` is sent.
+fn write_queue(mut transport: Box<Transport>, queue: Receiver<Message>, overflow: Overflow) {
+    let mut write = |msg: &Message| {
+        let w = transport.writer();
+        w.write_all(msg.bytes()).and_then(|_| w.flush()).map_err(|e| debug!("Could not write to a client: {}", e))
+    };
+    for msg in queue.iter() {
+        if overflow.lock().unwrap().is_some() || write(&msg).is_err() { break }
+    }
+    if let Some(error) = overflow.lock().unwrap().take() { let _ = write(&error); }
+    let _ = transport.shutdown();
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ BufRead, BufReader, Read, Write };
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use message::Message;
+    use server::{ Config, Server };
+    use text::def_lossy_decode;
+    use transport::{ MemoryTransport, Transport };
+
+    struct Client {
+        transport: MemoryTransport,
+        reader: BufReader<Box<Read + Send>>
+    }
+
+    impl Client {
+        fn new(server: &Server) -> Client {
+            let transport = server.connect_memory().unwrap();
+            let reader = BufReader::new(transport.reader().unwrap());
+            Client { transport: transport, reader: reader }
+        }
+
+        fn connect(server: &Server, nick: &str) -> Client {
+            let mut c = Client::new(server);
+            c.send(&format!("NICK {}\r\nUSER {} 0 * :{}", nick, nick, nick));
+            c.until("422");
+            c
+        }
+
+        fn send(&mut self, l: &str) {
+            self.transport.writer().write_all(format!("{}\r\n", l).as_bytes()).unwrap();
+        }
+
+        /// The next line, as prefix and then command and parameters. Empty once closed.
+        fn line(&mut self) -> Vec<String> {
+            let mut raw = Vec::new();
+            self.reader.read_until(b'\n', &mut raw).unwrap();
+            if raw.is_empty() { return Vec::new() }
+            let msg = Message::parse(&raw).unwrap();
+            let mut parts = vec![msg.prefix().map(|p| p.to_string()).unwrap_or(String::new()),
+                                 def_lossy_decode(&msg.command())];
+            parts.extend(msg.elements().iter().map(|e| def_lossy_decode(e)));
+            parts
+        }
+
+        /// Read up to the first line with `command`, and return it.
+        fn until(&mut self, command: &str) -> Vec<String> {
+            loop {
+                let l = self.line();
+                assert!(!l.is_empty(), "connection closed before {}", command);
+                if l[1] == command { return l }
+            }
+        }
+    }
+
+    #[test]
+    fn registration() {
+        let server = Server::new(Config::default());
+        let mut a = Client::connect(&server, "alice");
+        let mut b = Client::new(&server);
+        b.send("CAP LS 302");
+        assert_eq!(b.line(), ["irc.local", "CAP", "*", "LS", ""]);
+        b.send("NICK Alice");
+        assert_eq!(b.line(), ["irc.local", "433", "*", "Alice", "Nickname is already in use"]);
+        b.send("NICK bob\r\nUSER bob 0 * :Bob");
+        b.send("PING :early");
+        assert_eq!(b.line(), ["irc.local", "PONG", "irc.local", "early"]);
+        b.send("CAP END");
+        assert_eq!(b.line()[3], "Welcome to the irsc IRC Network bob!~bob@localhost");
+        b.until("422");
+        assert_eq!(server.users().len(), 2);
+
+        a.send("ISON bob carol");
+        assert_eq!(a.until("303")[3], "bob");
+        a.send("WHOIS bob");
+        assert_eq!(a.until("311"), ["irc.local", "311", "alice", "bob", "~bob", "localhost", "*", "Bob"]);
+        b.send("QUIT :bye");
+        assert_eq!(b.line(), ["", "ERROR", "Closing Link: localhost (bye)"]);
+        assert!(b.line().is_empty());
+        a.send("ISON bob");
+        assert_eq!(a.until("303")[3], "");
+    }
+
+    #[test]
+    fn channels() {
+        let server = Server::new(Config::default());
+        let mut a = Client::connect(&server, "alice");
+        let mut b = Client::connect(&server, "bob");
+
+        a.send("JOIN #test");
+        assert_eq!(a.line(), ["alice!~alice@localhost", "JOIN", "#test"]);
+        assert_eq!(a.line(), ["irc.local", "353", "alice", "=", "#test", "@alice"]);
+        a.send("TOPIC #test :Testing");
+        a.until("TOPIC");
+        b.send("JOIN #TEST");
+        assert_eq!(b.line(), ["bob!~bob@localhost", "JOIN", "#test"]);
+        assert_eq!(b.line(), ["irc.local", "332", "bob", "#test", "Testing"]);
+        assert_eq!(b.until("353")[5], "@alice bob");
+        assert_eq!(server.members("#test"), ["alice", "bob"]);
+
+        b.send("PRIVMSG #test :hi");
+        assert_eq!(a.until("PRIVMSG"), ["bob!~bob@localhost", "PRIVMSG", "#test", "hi"]);
+        b.send("MODE #test +m");
+        assert_eq!(b.until("482"), ["irc.local", "482", "bob", "#test", "You're not channel operator"]);
+        a.send("MODE #test +mv bob");
+        assert_eq!(b.line(), ["alice!~alice@localhost", "MODE", "#test", "+mv", "bob"]);
+        a.send("MODE #test -v bob");
+        b.until("MODE");
+        b.send("PRIVMSG #test :still here?");
+        assert_eq!(b.until("404")[3], "#test");
+
+        a.send("KICK #test bob :out");
+        assert_eq!(b.until("KICK"), ["alice!~alice@localhost", "KICK", "#test", "bob", "out"]);
+        a.send("WHO #test");
+        assert_eq!(a.until("352")[2..9].join(" "), "alice #test ~alice localhost irc.local alice H@");
+        a.send("PART #test");
+        a.until("PART");
+        assert!(server.channels().is_empty());
+    }
+
+    #[test]
+    fn slow_reader() {
+        let server = Server::new(Config::default());
+        let addr = server.spawn("127.0.0.1:0").unwrap();
+        // Bob joins, and then never reads what he's sent.
+        let mut bob = TcpStream::connect(addr).unwrap();
+        bob.write_all(b"NICK bob\r\nUSER bob 0 * :Bob\r\nJOIN #flood\r\n").unwrap();
+        while server.members("#flood").is_empty() { thread::sleep(Duration::from_millis(10)) }
+
+        let mut a = Client::connect(&server, "alice");
+        a.send("JOIN #flood");
+        a.until("353");
+        // Much more than fits into the socket buffers.
+        let text = "x".repeat(400);
+        for _ in 0..20000 { a.send(&format!("PRIVMSG #flood :{}", text)) }
+        a.send("PING :still there");
+
+        let (done, pong) = mpsc::channel();
+        thread::spawn(move || { let _ = done.send(a.until("PONG")); });
+        assert_eq!(pong.recv_timeout(Duration::from_secs(10)).unwrap()[3], "still there");
+    }
+
+    #[test]
+    fn sendq() {
+        let server = Server::new(Config { sendq: 10, ..Config::default() });
+        let addr = server.spawn("127.0.0.1:0").unwrap();
+        let mut bob = TcpStream::connect(addr).unwrap();
+        bob.write_all(b"NICK bob\r\nUSER bob 0 * :Bob\r\nJOIN #flood\r\n").unwrap();
+        while server.members("#flood").is_empty() { thread::sleep(Duration::from_millis(10)) }
+
+        let mut a = Client::connect(&server, "alice");
+        a.send("JOIN #flood");
+        a.until("353");
+        // Until the socket buffers and bob's queue are full.
+        let text = "x".repeat(400);
+        let quit = (0..100).filter_map(|_| {
+            for _ in 0..1000 { a.send(&format!("PRIVMSG #flood :{}", text)) }
+            a.send("PING :more");
+            loop {
+                let line = a.line();
+                if line[1] == "QUIT" { return Some(line) }
+                if line[1] == "PONG" { return None }
+            }
+        }).next().expect("bob was never disconnected");
+        assert_eq!(quit, ["bob!~bob@127.0.0.1", "QUIT", "SendQ exceeded"]);
+        assert_eq!(server.users(), ["alice"]);
+
+        // Closed, since his writer is stuck.
+        bob.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut rest = Vec::new();
+        bob.read_to_end(&mut rest).unwrap();
+    }
+}
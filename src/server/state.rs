@@ -0,0 +1,754 @@
+use std::borrow::ToOwned;
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ SyncSender, TrySendError };
+use std::thread;
+use std::time::Duration;
+
+use message::Message;
+use command::Command;
+use command::Command::*;
+use reply::Reply;
+use reply::Reply::*;
+use ident::{ Ident, Prefix };
+use timestamp::Timestamp;
+use text::def_lossy_decode;
+use transport::Transport;
+use server::Config;
+
+pub type Id = u64;
+
+/// What the writer of a client sends instead of what is queued, once too much was.
+pub type Overflow = Arc<Mutex<Option<Message>>>;
+
+struct Conn {
+    /// What is sent to the client, queued for its writer, see `Server::serve`.
+    outgoing: SyncSender<Message>,
+    overflow: Overflow,
+    /// Closes the connection, if the writer is stuck on a client that doesn't read.
+    closer: Option<Box<Transport>>,
+    host: String,
+    nick: Option<String>,
+    /// Username and real name, from `USER`.
+    user: Option<(String, String)>,
+    registered: bool,
+    /// Registration waits for `CAP END`, once `CAP` was used.
+    negotiating: bool,
+    away: Option<String>
+}
+
+impl Conn {
+    fn nick(&self) -> &str { self.nick.as_ref().map(|n| &n[..]).unwrap_or("*") }
+
+    fn prefix(&self) -> Prefix {
+        Prefix::User(Ident {
+            nickname: self.nick().to_owned(),
+            user: Some(format!("~{}", self.user.as_ref().map(|u| &u.0[..]).unwrap_or("user"))),
+            host: Some(self.host.clone())
+        })
+    }
+
+    /// Queue `msg`. False if the queue is full, see `Config::sendq`.
+    fn send(&mut self, msg: &Message) -> bool {
+        match self.outgoing.try_send(msg.clone()) {
+            Err(TrySendError::Full(_)) => false,
+            // The writer only stops once the connection is lost, and then the
+            // reader quits it soon.
+            _ => true
+        }
+    }
+
+    /// Have the writer drop what is queued, and send `error` instead. If it
+    /// doesn't get to, the connection is closed a second later.
+    fn overflow(&mut self, error: Message) {
+        *self.overflow.lock().unwrap() = Some(error);
+        if let Some(mut closer) = self.closer.take() {
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(1));
+                let _ = closer.shutdown();
+            });
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Member {
+    op: bool,
+    voice: bool
+}
+
+impl Member {
+    fn symbol(&self) -> &'static str {
+        if self.op { "@" } else if self.voice { "+" } else { "" }
+    }
+}
+
+struct Channel {
+    name: String,
+    created: i64,
+    /// Text, who set it, and when.
+    topic: Option<(String, String, i64)>,
+    /// By `Id`, which is in order of connection.
+    members: BTreeMap<Id, Member>,
+    key: Option<String>,
+    moderated: bool,
+    no_external: bool,
+    topic_ops: bool
+}
+
+impl Channel {
+    fn modes(&self) -> (String, Vec<String>) {
+        let mut modes = "+".to_owned();
+        let mut params = Vec::new();
+        if self.key.is_some() { modes.push('k') }
+        if self.moderated { modes.push('m') }
+        if self.no_external { modes.push('n') }
+        if self.topic_ops { modes.push('t') }
+        if let Some(ref k) = self.key { params.push(k.clone()) }
+        (modes, params)
+    }
+}
+
+fn now() -> i64 { Timestamp::now().seconds() }
+
+fn is_channel(s: &str) -> bool { s.starts_with('#') || s.starts_with('&') }
+
+fn valid_nick(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 30
+        && !s.starts_with(|c: char| c.is_digit(10) || c == '-' || c == '#' || c == '&' || c == ':')
+        && !s.contains(|c: char| c == ' ' || c == ',' || c == '*' || c == '?' || c == '!' || c == '@')
+}
+
+/// Everything the server knows, behind one lock.
+pub struct State {
+    config: Config,
+    next: Id,
+    conns: HashMap<Id, Conn>,
+    /// Casefolded nicknames.
+    nicks: HashMap<String, Id>,
+    /// By casefolded name.
+    channels: HashMap<String, Channel>
+}
+
+impl State {
+    pub fn new(config: Config) -> State {
+        State { config: config, next: 0, conns: HashMap::new(), nicks: HashMap::new(), channels: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &Config { &self.config }
+
+    fn fold(&self, s: &str) -> String { self.config.casemapping.lower(s) }
+
+    /// Take a new connection from `host`, which is sent what is queued on `outgoing`.
+    /// `closer` is another handle on it, see `Transport::try_clone`.
+    pub fn add(&mut self, outgoing: SyncSender<Message>, overflow: Overflow, closer: Option<Box<Transport>>,
+               host: &str) -> Id {
+        self.next += 1;
+        self.conns.insert(self.next, Conn {
+            outgoing: outgoing,
+            overflow: overflow,
+            closer: closer,
+            host: host.to_owned(),
+            nick: None,
+            user: None,
+            registered: false,
+            negotiating: false,
+            away: None
+        });
+        self.next
+    }
+
+    pub fn nicks(&self) -> Vec<String> {
+        self.conns.values().filter(|c| c.registered).map(|c| c.nick().to_owned()).collect()
+    }
+
+    pub fn channels(&self) -> Vec<String> {
+        self.channels.values().map(|c| c.name.clone()).collect()
+    }
+
+    pub fn members(&self, channel: &str) -> Vec<String> {
+        match self.channels.get(&self.fold(channel)) {
+            Some(c) => c.members.keys().filter_map(|id| self.conns.get(id)).map(|c| c.nick().to_owned()).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Send to `id`, who is disconnected if it's too far behind.
+    fn send(&mut self, id: Id, msg: &Message) {
+        let full = match self.conns.get_mut(&id) {
+            Some(c) => !c.send(msg),
+            None => false
+        };
+        if full { self.quit(id, "SendQ exceeded") }
+    }
+
+    /// A numeric reply to `id`, from the server.
+    fn reply(&mut self, id: Id, reply: Reply) {
+        let msg = reply.to_message().with_prefix(Some(&Prefix::Server(self.config.name.clone())));
+        self.send(id, &msg);
+    }
+
+    /// `cmd`, as sent by `id`.
+    fn sent_by(&self, id: Id, cmd: Command) -> Message {
+        let prefix = self.conns.get(&id).map(Conn::prefix);
+        cmd.to_message().with_prefix(prefix.as_ref())
+    }
+
+    fn send_channel(&mut self, channel: &str, msg: &Message, except: Option<Id>) {
+        let ids: Vec<Id> = match self.channels.get(&self.fold(channel)) {
+            Some(c) => c.members.keys().cloned().filter(|&m| Some(m) != except).collect(),
+            None => return
+        };
+        for id in ids { self.send(id, msg) }
+    }
+
+    /// Send to `id`, and everyone on a channel with them, once each.
+    fn send_common(&mut self, id: Id, msg: &Message) {
+        let mut ids: Vec<Id> = self.channels.values()
+            .filter(|c| c.members.contains_key(&id))
+            .flat_map(|c| c.members.keys().cloned())
+            .collect();
+        ids.push(id);
+        ids.sort();
+        ids.dedup();
+        for i in ids { self.send(i, msg) }
+    }
+
+    fn find(&self, nick: &str) -> Option<Id> { self.nicks.get(&self.fold(nick)).cloned() }
+
+    fn nick_of(&self, id: Id) -> String {
+        self.conns.get(&id).map(|c| c.nick().to_owned()).unwrap_or("*".to_owned())
+    }
+
+    /// Handle a line from `id`. Returns false if the connection should be closed.
+    pub fn handle(&mut self, id: Id, msg: &Message) -> bool {
+        let registered = match self.conns.get(&id) {
+            Some(c) => c.registered,
+            None => return false
+        };
+        let me = self.nick_of(id);
+        let me = &me[..];
+        let cmd = match Command::from_message(msg) {
+            Some(c) => c,
+            None => {
+                let name = def_lossy_decode(&msg.command());
+                if registered { self.reply(id, ERR_UNKNOWNCOMMAND(me.into(), name[..].into())) }
+                else { self.reply(id, ERR_NOTREGISTERED(me.into())) }
+                return true
+            }
+        };
+        match cmd {
+            CAP(_, sub, _, caps) => self.cap(id, &def_lossy_decode(&sub), &def_lossy_decode(&caps)),
+            PASS(_) => (),
+            NICK(n) => self.nick(id, &def_lossy_decode(&n)),
+            USER(u, _, _, r) => {
+                if registered { self.reply(id, ERR_ALREADYREGISTRED(me.into())) }
+                else {
+                    if let Some(c) = self.conns.get_mut(&id) {
+                        c.user = Some((def_lossy_decode(&u), def_lossy_decode(&r)));
+                    }
+                    self.try_register(id);
+                }
+            },
+            PING(s, _) => {
+                let pong = PONG(self.config.name[..].into(), Some(s)).to_message()
+                    .with_prefix(Some(&Prefix::Server(self.config.name.clone())));
+                self.send(id, &pong);
+            },
+            PONG(..) => (),
+            QUIT(m) => {
+                let reason = m.map(|m| def_lossy_decode(&m)).unwrap_or("Client Quit".to_owned());
+                self.quit(id, &reason);
+                return false
+            },
+            _ if !registered => self.reply(id, ERR_NOTREGISTERED(me.into())),
+            PRIVMSG(t, text) => self.message(id, "PRIVMSG", &def_lossy_decode(&t), &def_lossy_decode(&text)),
+            NOTICE(t, text) => self.message(id, "NOTICE", &def_lossy_decode(&t), &def_lossy_decode(&text)),
+            JOIN(chans, keys, _) => {
+                let keys: Vec<String> = keys.iter().map(|k| def_lossy_decode(k)).collect();
+                for (i, c) in chans.iter().enumerate() {
+                    let c = def_lossy_decode(c);
+                    if c == "0" { self.part_all(id) }
+                    else { self.join(id, &c, keys.get(i).map(|k| &k[..])) }
+                }
+            },
+            PART(chans, m) => {
+                let m = m.map(|m| def_lossy_decode(&m));
+                for c in &chans { self.part(id, &def_lossy_decode(c), m.as_ref().map(|m| &m[..])) }
+            },
+            KICK(chans, users, m) => {
+                let chans: Vec<String> = chans.iter().map(|c| def_lossy_decode(c)).collect();
+                let m = m.map(|m| def_lossy_decode(&m));
+                for (i, u) in users.iter().enumerate() {
+                    // One channel for all users, or one for each.
+                    let c = if chans.len() == 1 { chans.get(0) } else { chans.get(i) };
+                    if let Some(c) = c { self.kick(id, c, &def_lossy_decode(u), m.as_ref().map(|m| &m[..])) }
+                }
+            },
+            TOPIC(c, t) => self.topic(id, &def_lossy_decode(&c), t.map(|t| def_lossy_decode(&t))),
+            MODE(t, args) => {
+                let args: Vec<String> = args.iter().map(|a| def_lossy_decode(a)).collect();
+                self.mode(id, &def_lossy_decode(&t), &args)
+            },
+            NAMES(chans, _) => for c in &chans { self.names(id, &def_lossy_decode(c)) },
+            WHO(mask, _) => self.who(id, &mask.map(|m| def_lossy_decode(&m)).unwrap_or("*".to_owned())),
+            WHOIS(_, nicks) => for n in &nicks { self.whois(id, &def_lossy_decode(n)) },
+            AWAY(m) => {
+                let m = m.map(|m| def_lossy_decode(&m)).and_then(|m| if m.is_empty() { None } else { Some(m) });
+                let set = m.is_some();
+                if let Some(c) = self.conns.get_mut(&id) { c.away = m }
+                if set { self.reply(id, RPL_NOWAWAY(me.into())) }
+                else { self.reply(id, RPL_UNAWAY(me.into())) }
+            },
+            ISON(nicks) => {
+                let online: Vec<String> = nicks.iter().map(|n| def_lossy_decode(n))
+                    .filter_map(|n| self.find(&n).map(|i| self.nick_of(i))).collect();
+                self.reply(id, RPL_ISON(me.into(), online.iter().map(|n| n[..].into()).collect()));
+            },
+            LIST(..) => {
+                let mut list: Vec<(String, usize, String)> = self.channels.values().map(|c| {
+                    (c.name.clone(), c.members.len(), c.topic.as_ref().map(|t| t.0.clone()).unwrap_or(String::new()))
+                }).collect();
+                list.sort();
+                for (name, count, topic) in list {
+                    self.reply(id, RPL_LIST(me.into(), name[..].into(), count.to_string()[..].into(), topic[..].into()));
+                }
+                self.reply(id, RPL_LISTEND(me.into()));
+            },
+            _ => {
+                let name = def_lossy_decode(&msg.command());
+                self.reply(id, ERR_UNKNOWNCOMMAND(me.into(), name[..].into()))
+            }
+        }
+        true
+    }
+
+    fn cap(&mut self, id: Id, sub: &str, caps: &str) {
+        let nick = self.nick_of(id);
+        let name = self.config.name.clone();
+        let reply = |sub: &str, list: &str| Message::format(
+            Some(name.as_bytes().to_owned()), b"CAP".to_vec(),
+            vec![nick.as_bytes().to_owned(), sub.as_bytes().to_owned()], Some(list.as_bytes().to_owned()));
+        // No capabilities are supported, so every request is refused.
+        let msg = match sub {
+            "LS" => Some(reply("LS", "")),
+            "LIST" => Some(reply("LIST", "")),
+            "REQ" => Some(reply("NAK", caps)),
+            _ => None
+        };
+        if sub == "LS" || sub == "REQ" {
+            if let Some(c) = self.conns.get_mut(&id) { if !c.registered { c.negotiating = true } }
+        }
+        if let Some(m) = msg { self.send(id, &m) }
+        if sub == "END" {
+            if let Some(c) = self.conns.get_mut(&id) { c.negotiating = false }
+            self.try_register(id);
+        }
+    }
+
+    fn nick(&mut self, id: Id, nick: &str) {
+        let me = self.nick_of(id);
+        if !valid_nick(nick) { return self.reply(id, ERR_ERRONEUSNICKNAME(me[..].into(), nick.into())) }
+        let folded = self.fold(nick);
+        match self.nicks.get(&folded) {
+            Some(&other) if other != id => return self.reply(id, ERR_NICKNAMEINUSE(me[..].into(), nick.into())),
+            _ => ()
+        }
+        let (old, registered) = match self.conns.get(&id) {
+            Some(c) => (c.nick.clone(), c.registered),
+            None => return
+        };
+        if registered {
+            let msg = self.sent_by(id, NICK(nick.into()));
+            self.send_common(id, &msg);
+        }
+        if let Some(old) = old {
+            let old = self.fold(&old);
+            self.nicks.remove(&old);
+        }
+        self.nicks.insert(folded, id);
+        if let Some(c) = self.conns.get_mut(&id) { c.nick = Some(nick.to_owned()) }
+        self.try_register(id);
+    }
+
+    fn try_register(&mut self, id: Id) {
+        let nick = match self.conns.get_mut(&id) {
+            Some(c) => {
+                if c.registered || c.negotiating || c.nick.is_none() || c.user.is_none() { return }
+                c.registered = true;
+                c.nick().to_owned()
+            },
+            None => return
+        };
+        let name = self.config.name.clone();
+        let network = self.config.network.clone();
+        let prefix = self.conns.get(&id).map(|c| c.prefix().to_string()).unwrap_or(nick.clone());
+        let me = &nick[..];
+        self.reply(id, RPL_WELCOME(me.into(), format!("Welcome to the {} IRC Network {}", network, prefix)[..].into()));
+        self.reply(id, RPL_YOURHOST(me.into(), format!("Your host is {}, running irscd", name)[..].into()));
+        self.reply(id, RPL_CREATED(me.into(), "This server was created just now".into()));
+        self.reply(id, RPL_MYINFO(me.into(), name[..].into(), "irscd".into(), "i".into(), "kmnotv".into()));
+        let casemapping = format!("CASEMAPPING={}", self.config.casemapping.name());
+        let network = format!("NETWORK={}", network);
+        self.reply(id, RPL_ISUPPORT(me.into(), vec!["CHANTYPES=#&".into(), "PREFIX=(ov)@+".into(),
+                                                    "CHANMODES=,k,,mnt".into(), "NICKLEN=30".into(),
+                                                    casemapping[..].into(), network[..].into()]));
+        self.motd(id);
+    }
+
+    fn motd(&mut self, id: Id) {
+        let me = self.nick_of(id);
+        let me = &me[..];
+        if self.config.motd.is_empty() {
+            return self.reply(id, ERR_NOMOTD(me.into()))
+        }
+        let name = self.config.name.clone();
+        self.reply(id, RPL_MOTDSTART(me.into(), format!("- {} Message of the day - ", name)[..].into()));
+        for line in self.config.motd.clone() {
+            self.reply(id, RPL_MOTD(me.into(), format!("- {}", line)[..].into()));
+        }
+        self.reply(id, RPL_ENDOFMOTD(me.into()));
+    }
+
+    fn message(&mut self, id: Id, command: &str, target: &str, text: &str) {
+        let me = self.nick_of(id);
+        let me = &me[..];
+        let notice = command == "NOTICE";
+        let msg = if notice { self.sent_by(id, NOTICE(target.into(), text.into())) }
+                  else { self.sent_by(id, PRIVMSG(target.into(), text.into())) };
+        if is_channel(target) {
+            let allowed = match self.channels.get(&self.fold(target)) {
+                Some(c) => match c.members.get(&id) {
+                    Some(m) => !c.moderated || m.op || m.voice,
+                    None => !c.no_external && !c.moderated
+                },
+                None => {
+                    if !notice { self.reply(id, ERR_NOSUCHCHANNEL(me.into(), target.into())) }
+                    return
+                }
+            };
+            if allowed { self.send_channel(target, &msg, Some(id)) }
+            else if !notice { self.reply(id, ERR_CANNOTSENDTOCHAN(me.into(), target.into())) }
+        } else {
+            match self.find(target) {
+                Some(to) => {
+                    self.send(to, &msg);
+                    let away = self.conns.get(&to).and_then(|c| c.away.clone());
+                    if let (false, Some(away)) = (notice, away) {
+                        let nick = self.nick_of(to);
+                        self.reply(id, RPL_AWAY(me.into(), nick[..].into(), away[..].into()));
+                    }
+                },
+                None => if !notice { self.reply(id, ERR_NOSUCHNICK(me.into(), target.into())) }
+            }
+        }
+    }
+
+    fn join(&mut self, id: Id, name: &str, key: Option<&str>) {
+        let me = self.nick_of(id);
+        let me = &me[..];
+        if !is_channel(name) || name.len() < 2 {
+            return self.reply(id, ERR_NOSUCHCHANNEL(me.into(), name.into()))
+        }
+        let folded = self.fold(name);
+        let (joined, bad_key) = match self.channels.get(&folded) {
+            Some(c) => (c.members.contains_key(&id), c.key.is_some() && c.key.as_ref().map(|k| &k[..]) != key),
+            None => (false, false)
+        };
+        if joined { return }
+        if bad_key { return self.reply(id, ERR_BADCHANNELKEY(me.into(), name.into())) }
+
+        let channel = self.channels.entry(folded.clone()).or_insert_with(|| Channel {
+            name: name.to_owned(),
+            created: now(),
+            topic: None,
+            members: BTreeMap::new(),
+            key: None,
+            moderated: false,
+            no_external: true,
+            topic_ops: true
+        });
+        // Whoever creates the channel is its operator.
+        let first = channel.members.is_empty();
+        channel.members.insert(id, Member { op: first, voice: false });
+        let name = channel.name.clone();
+
+        let msg = self.sent_by(id, JOIN(vec![name[..].into()], Vec::new(), None));
+        self.send_channel(&name, &msg, None);
+        let topic = self.channels.get(&folded).and_then(|c| c.topic.clone());
+        if let Some((text, by, at)) = topic {
+            self.reply(id, RPL_TOPIC(me.into(), name[..].into(), text[..].into()));
+            self.reply(id, RPL_TOPICWHOTIME(me.into(), name[..].into(), by[..].into(), at.to_string()[..].into()));
+        }
+        self.names(id, &name);
+    }
+
+    fn names(&mut self, id: Id, name: &str) {
+        let names: Option<Vec<String>> = self.channels.get(&self.fold(name)).map(|c| {
+            c.members.iter().filter_map(|(m, member)| {
+                self.conns.get(m).map(|conn| format!("{}{}", member.symbol(), conn.nick()))
+            }).collect()
+        });
+        let me = self.nick_of(id);
+        if let Some(names) = names {
+            let names = names.iter().map(|n| n[..].into()).collect();
+            self.reply(id, RPL_NAMREPLY(me[..].into(), "=".into(), name.into(), names));
+        }
+        self.reply(id, RPL_ENDOFNAMES(me[..].into(), name.into()));
+    }
+
+    /// Check that `id` is on `name`, and an operator if `op`. Sends the error if not.
+    fn check(&mut self, id: Id, name: &str, op: bool) -> bool {
+        let me = self.nick_of(id);
+        let me = &me[..];
+        let member = match self.channels.get(&self.fold(name)) {
+            Some(c) => c.members.get(&id).cloned(),
+            None => { self.reply(id, ERR_NOSUCHCHANNEL(me.into(), name.into())); return false }
+        };
+        match member {
+            None => { self.reply(id, ERR_NOTONCHANNEL(me.into(), name.into())); false },
+            Some(m) if op && !m.op => { self.reply(id, ERR_CHANOPRIVSNEEDED(me.into(), name.into())); false },
+            Some(_) => true
+        }
+    }
+
+    fn remove(&mut self, id: Id, name: &str) {
+        let folded = self.fold(name);
+        let empty = match self.channels.get_mut(&folded) {
+            Some(c) => { c.members.remove(&id); c.members.is_empty() },
+            None => return
+        };
+        if empty { self.channels.remove(&folded); }
+    }
+
+    fn part(&mut self, id: Id, name: &str, reason: Option<&str>) {
+        if !self.check(id, name, false) { return }
+        let msg = self.sent_by(id, PART(vec![name.into()], reason.map(Into::into)));
+        self.send_channel(name, &msg, None);
+        self.remove(id, name);
+    }
+
+    fn part_all(&mut self, id: Id) {
+        let names: Vec<String> = self.channels.values()
+            .filter(|c| c.members.contains_key(&id)).map(|c| c.name.clone()).collect();
+        for n in names { self.part(id, &n, None) }
+    }
+
+    fn kick(&mut self, id: Id, name: &str, nick: &str, reason: Option<&str>) {
+        if !self.check(id, name, true) { return }
+        let kicker = self.nick_of(id);
+        let target = match self.find(nick) {
+            Some(t) => t,
+            None => return self.reply(id, ERR_NOSUCHNICK(kicker[..].into(), nick.into()))
+        };
+        let on = self.channels.get(&self.fold(name)).map(|c| c.members.contains_key(&target)) == Some(true);
+        if !on { return self.reply(id, ERR_USERNOTINCHANNEL(kicker[..].into(), nick.into(), name.into())) }
+        let msg = self.sent_by(id, KICK(vec![name.into()], vec![nick.into()], Some(reason.unwrap_or(&kicker).into())));
+        self.send_channel(name, &msg, None);
+        self.remove(target, name);
+    }
+
+    fn topic(&mut self, id: Id, name: &str, topic: Option<String>) {
+        let me = self.nick_of(id);
+        let me = &me[..];
+        let topic_ops = match self.channels.get(&self.fold(name)) {
+            Some(c) => c.topic_ops,
+            None => return self.reply(id, ERR_NOSUCHCHANNEL(me.into(), name.into()))
+        };
+        let topic = match topic {
+            Some(t) => t,
+            None => {
+                let current = self.channels.get(&self.fold(name)).and_then(|c| c.topic.clone());
+                return match current {
+                    Some((text, by, at)) => {
+                        self.reply(id, RPL_TOPIC(me.into(), name.into(), text[..].into()));
+                        let at = at.to_string();
+                        self.reply(id, RPL_TOPICWHOTIME(me.into(), name.into(), by[..].into(), at[..].into()));
+                    },
+                    None => self.reply(id, RPL_NOTOPIC(me.into(), name.into()))
+                }
+            }
+        };
+        if !self.check(id, name, topic_ops) { return }
+        let folded = self.fold(name);
+        if let Some(c) = self.channels.get_mut(&folded) {
+            c.topic = if topic.is_empty() { None } else { Some((topic.clone(), me.to_owned(), now())) };
+        }
+        let msg = self.sent_by(id, TOPIC(name.into(), Some(topic[..].into())));
+        self.send_channel(name, &msg, None);
+    }
+
+    fn mode(&mut self, id: Id, target: &str, args: &[String]) {
+        let me = self.nick_of(id);
+        let me = &me[..];
+        if !is_channel(target) {
+            let own = self.fold(me) == self.fold(target);
+            if !own { return self.reply(id, ERR_USERSDONTMATCH(me.into())) }
+            return self.reply(id, RPL_UMODEIS(me.into(), "+".into()))
+        }
+        let folded = self.fold(target);
+        let (name, created, modes) = match self.channels.get(&folded) {
+            Some(c) => (c.name.clone(), c.created, c.modes()),
+            None => return self.reply(id, ERR_NOSUCHCHANNEL(me.into(), target.into()))
+        };
+        if args.is_empty() {
+            let params = modes.1.iter().map(|p| p[..].into()).collect();
+            self.reply(id, RPL_CHANNELMODEIS(me.into(), name[..].into(), modes.0[..].into(), params));
+            return self.reply(id, RPL_CREATIONTIME(me.into(), name[..].into(), created.to_string()[..].into()))
+        }
+        if args.len() == 1 && (args[0] == "b" || args[0] == "+b") {
+            return self.reply(id, RPL_ENDOFBANLIST(me.into(), name[..].into()))
+        }
+        if !self.check(id, &name, true) { return }
+
+        let mut params = args[1..].iter();
+        let mut adding = true;
+        // The changes that were made, as (sign, mode, parameter).
+        let mut applied: Vec<(bool, char, Option<String>)> = Vec::new();
+        for m in args[0].chars() {
+            match m {
+                '+' => adding = true,
+                '-' => adding = false,
+                'o' | 'v' => {
+                    let nick = match params.next() { Some(n) => n.clone(), None => continue };
+                    let target = self.find(&nick);
+                    let found = match target.and_then(|i| self.channels.get_mut(&folded).unwrap().members.get_mut(&i)) {
+                        Some(member) => {
+                            if m == 'o' { member.op = adding } else { member.voice = adding }
+                            true
+                        },
+                        None => false
+                    };
+                    if found { applied.push((adding, m, Some(nick))) }
+                    else { self.reply(id, ERR_USERNOTINCHANNEL(me.into(), nick[..].into(), name[..].into())) }
+                },
+                'k' => {
+                    let key = if adding { match params.next() { Some(k) => Some(k.clone()), None => continue } }
+                              else { params.next(); None };
+                    self.channels.get_mut(&folded).unwrap().key = key.clone();
+                    applied.push((adding, 'k', Some(key.unwrap_or("*".to_owned()))));
+                },
+                'm' | 'n' | 't' => {
+                    let channel = self.channels.get_mut(&folded).unwrap();
+                    match m {
+                        'm' => channel.moderated = adding,
+                        'n' => channel.no_external = adding,
+                        _ => channel.topic_ops = adding
+                    }
+                    applied.push((adding, m, None));
+                },
+                _ => self.reply(id, ERR_UNKNOWNMODE(me.into(), m.to_string()[..].into()))
+            }
+        }
+
+        let mut changes = String::new();
+        let mut change_params = Vec::new();
+        let mut sign = None;
+        for (adding, m, param) in applied {
+            if sign != Some(adding) {
+                changes.push(if adding { '+' } else { '-' });
+                sign = Some(adding);
+            }
+            changes.push(m);
+            change_params.extend(param);
+        }
+        if changes.is_empty() { return }
+        let mut mode_args = vec![changes[..].into()];
+        mode_args.extend(change_params.iter().map(|p| p[..].into()));
+        let msg = self.sent_by(id, MODE(name[..].into(), mode_args));
+        self.send_channel(&name, &msg, None);
+    }
+
+    fn who(&mut self, id: Id, mask: &str) {
+        let ids: Vec<(Id, Option<(String, Member)>)> = if is_channel(mask) {
+            match self.channels.get(&self.fold(mask)) {
+                Some(c) => c.members.iter().map(|(&i, &m)| (i, Some((c.name.clone(), m)))).collect(),
+                None => Vec::new()
+            }
+        } else {
+            self.find(mask).into_iter().map(|i| (i, None)).collect()
+        };
+        let me = self.nick_of(id);
+        let me = &me[..];
+        let server = self.config.name.clone();
+        for (i, channel) in ids {
+            let (nick, user, host, real, away) = match self.conns.get(&i) {
+                Some(c) => (c.nick().to_owned(), c.prefix().ident().and_then(|i| i.user.clone()).unwrap_or(String::new()),
+                            c.host.clone(), c.user.as_ref().map(|u| u.1.clone()).unwrap_or(String::new()),
+                            c.away.is_some()),
+                None => continue
+            };
+            let (chan, symbol) = match channel {
+                Some((c, m)) => (c, m.symbol()),
+                None => ("*".to_owned(), "")
+            };
+            let flags = format!("{}{}", if away { "G" } else { "H" }, symbol);
+            self.reply(id, RPL_WHOREPLY(me.into(), chan[..].into(), user[..].into(), host[..].into(), server[..].into(),
+                                        nick[..].into(), flags[..].into(), "0".into(), real[..].into()));
+        }
+        self.reply(id, RPL_ENDOFWHO(me.into(), mask.into()));
+    }
+
+    fn whois(&mut self, id: Id, nick: &str) {
+        let me = self.nick_of(id);
+        let me = &me[..];
+        let target = match self.find(nick) {
+            Some(t) => t,
+            None => {
+                self.reply(id, ERR_NOSUCHNICK(me.into(), nick.into()));
+                return self.reply(id, RPL_ENDOFWHOIS(me.into(), nick.into()))
+            }
+        };
+        let (nick, user, host, real, away) = match self.conns.get(&target) {
+            Some(c) => (c.nick().to_owned(), c.prefix().ident().and_then(|i| i.user.clone()).unwrap_or(String::new()),
+                        c.host.clone(), c.user.as_ref().map(|u| u.1.clone()).unwrap_or(String::new()), c.away.clone()),
+            None => return
+        };
+        let channels: Vec<String> = self.channels.values()
+            .filter_map(|c| c.members.get(&target).map(|m| format!("{}{}", m.symbol(), c.name)))
+            .collect();
+        let server = self.config.name.clone();
+        let network = self.config.network.clone();
+        let nick = &nick[..];
+        self.reply(id, RPL_WHOISUSER(me.into(), nick.into(), user[..].into(), host[..].into(), real[..].into()));
+        if !channels.is_empty() {
+            self.reply(id, RPL_WHOISCHANNELS(me.into(), nick.into(), channels.iter().map(|c| c[..].into()).collect()))
+        }
+        self.reply(id, RPL_WHOISSERVER(me.into(), nick.into(), server[..].into(), network[..].into()));
+        if let Some(away) = away { self.reply(id, RPL_AWAY(me.into(), nick.into(), away[..].into())) }
+        self.reply(id, RPL_ENDOFWHOIS(me.into(), nick.into()));
+    }
+
+    /// Remove `id`, tell everyone who shared a channel, and close the connection.
+    pub fn quit(&mut self, id: Id, reason: &str) {
+        let msg = self.sent_by(id, QUIT(Some(reason.into())));
+        // Gone before anyone is told, since telling them might overflow
+        // their queues, and quit them too.
+        let mut c = match self.conns.remove(&id) {
+            Some(c) => c,
+            None => return
+        };
+        if let Some(ref n) = c.nick {
+            let folded = self.config.casemapping.lower(n);
+            if self.nicks.get(&folded) == Some(&id) { self.nicks.remove(&folded); }
+        }
+        let mut ids: Vec<Id> = self.channels.values()
+            .filter(|c| c.members.contains_key(&id))
+            .flat_map(|c| c.members.keys().cloned())
+            .filter(|&i| i != id)
+            .collect();
+        ids.sort();
+        ids.dedup();
+        let names: Vec<String> = self.channels.values()
+            .filter(|c| c.members.contains_key(&id)).map(|c| c.name.clone()).collect();
+        for n in names { self.remove(id, &n) }
+        if c.registered {
+            for i in ids { self.send(i, &msg) }
+        }
+
+        let error = Message::format(None, b"ERROR".to_vec(), Vec::new(),
+                                    Some(format!("Closing Link: {} ({})", c.host, reason).into_bytes()));
+        // The writer closes the connection once it sent this.
+        if !c.send(&error) { c.overflow(error) }
+    }
+}
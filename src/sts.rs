@@ -0,0 +1,182 @@
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ self, Read, Write };
+use std::path::{ Path, PathBuf };
+
+use timestamp::Timestamp;
+
+/// A Strict Transport Security policy: connect to `host` only with TLS,
+/// on `port`, until `expires`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Policy {
+    pub host: String,
+    pub port: u16,
+    pub expires: Timestamp,
+    /// The server asks to be put on preload lists.
+    pub preload: bool
+}
+
+/// What the value of the `sts` capability says.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Advertised {
+    /// Sent over plaintext: reconnect with TLS on this port.
+    Upgrade(u16),
+    /// Sent over TLS: remember the policy for this many seconds, or forget it for 0.
+    Persist { duration: u64, preload: bool }
+}
+
+impl Advertised {
+    /// Parse the value of `sts`, like `port=6697,duration=2592000`.
+    /// Plaintext connections need `port`, secure ones `duration`.
+    pub fn parse(value: &str, secure: bool) -> Option<Advertised> {
+        let mut port = None;
+        let mut duration = None;
+        let mut preload = false;
+        for pair in value.split(',') {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("port"), Some(p)) => port = p.parse().ok(),
+                (Some("duration"), Some(d)) => duration = d.parse().ok(),
+                (Some("preload"), _) => preload = true,
+                _ => ()
+            }
+        }
+        if secure {
+            duration.map(|d| Advertised::Persist { duration: d, preload: preload })
+        } else {
+            port.map(Advertised::Upgrade)
+        }
+    }
+}
+
+impl Policy {
+    pub fn new(host: &str, port: u16, duration: u64, preload: bool) -> Policy {
+        Policy {
+            host: host.to_lowercase(),
+            port: port,
+            expires: Timestamp(Timestamp::now().millis() + duration as i64 * 1000),
+            preload: preload
+        }
+    }
+
+    pub fn is_expired(&self) -> bool { self.expires <= Timestamp::now() }
+}
+
+/// Where policies are kept between connections.
+pub trait PolicyStore: Send {
+    /// The policy for `host`, if there is one and it hasn't expired.
+    fn get(&self, host: &str) -> Option<Policy>;
+    fn set(&mut self, policy: Policy);
+    fn remove(&mut self, host: &str);
+    fn all(&self) -> Vec<Policy>;
+
+    fn clear(&mut self) {
+        for p in self.all() { self.remove(&p.host) }
+    }
+}
+
+/// Keeps policies as long as the program runs.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    policies: HashMap<String, Policy>
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore { MemoryStore::default() }
+}
+
+impl PolicyStore for MemoryStore {
+    fn get(&self, host: &str) -> Option<Policy> {
+        self.policies.get(&host.to_lowercase()).and_then(|p| if p.is_expired() { None } else { Some(p.clone()) })
+    }
+    fn set(&mut self, policy: Policy) { self.policies.insert(policy.host.clone(), policy); }
+    fn remove(&mut self, host: &str) { self.policies.remove(&host.to_lowercase()); }
+    fn all(&self) -> Vec<Policy> { self.policies.values().cloned().collect() }
+}
+
+/// Keeps policies in a file, one per line: `<host> <port> <expiry in ms> <preload>`.
+/// The file is rewritten on every change.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    memory: MemoryStore
+}
+
+impl FileStore {
+    /// Load the policies from `path`, which doesn't need to exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileStore> {
+        let mut store = FileStore { path: path.as_ref().to_owned(), memory: MemoryStore::new() };
+        let mut content = String::new();
+        match File::open(&store.path) {
+            Ok(mut f) => { try!(f.read_to_string(&mut content)); },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e)
+        }
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() != 4 { continue }
+            if let (Ok(port), Ok(expires)) = (fields[1].parse(), fields[2].parse()) {
+                store.memory.set(Policy {
+                    host: fields[0].to_owned(),
+                    port: port,
+                    expires: Timestamp(expires),
+                    preload: fields[3] == "1"
+                });
+            }
+        }
+        Ok(store)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut f = try!(File::create(&self.path));
+        for p in self.memory.all().iter().filter(|p| !p.is_expired()) {
+            try!(writeln!(f, "{} {} {} {}", p.host, p.port, p.expires.millis(), if p.preload { 1 } else { 0 }));
+        }
+        Ok(())
+    }
+}
+
+impl PolicyStore for FileStore {
+    fn get(&self, host: &str) -> Option<Policy> { self.memory.get(host) }
+    fn set(&mut self, policy: Policy) {
+        self.memory.set(policy);
+        if let Err(e) = self.save() { warn!("Could not save STS policies: {}", e) }
+    }
+    fn remove(&mut self, host: &str) {
+        self.memory.remove(host);
+        if let Err(e) = self.save() { warn!("Could not save STS policies: {}", e) }
+    }
+    fn all(&self) -> Vec<Policy> { self.memory.all() }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use sts::{ Advertised, Policy, PolicyStore, FileStore };
+
+    #[test]
+    fn advertised() {
+        assert_eq!(Advertised::parse("port=6697,duration=300", false), Some(Advertised::Upgrade(6697)));
+        assert_eq!(Advertised::parse("duration=300,preload", true),
+                   Some(Advertised::Persist { duration: 300, preload: true }));
+        assert_eq!(Advertised::parse("duration=300", false), None);
+    }
+
+    #[test]
+    fn file_store() {
+        let path = env::temp_dir().join("irsc-sts-test");
+        let _ = fs::remove_file(&path);
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.set(Policy::new("IRC.example.org", 6697, 60, false));
+            store.set(Policy::new("gone.example.org", 6697, 0, false));
+        }
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("irc.example.org").map(|p| p.port), Some(6697));
+        assert!(store.get("gone.example.org").is_none());
+        let _ = fs::remove_file(&path);
+    }
+}
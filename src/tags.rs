@@ -0,0 +1,114 @@
+use std::borrow::ToOwned;
+
+use message::Message;
+use isupport::ISupport;
+
+/// The value of a `+typing` tag.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Typing {
+    /// Typing right now. Should be repeated every 3 seconds while it lasts.
+    Active,
+    /// Stopped typing, but there is text in the input box.
+    Paused,
+    /// Stopped typing, and cleared the input box.
+    Done
+}
+
+/// Client-only tags (those starting with `+`) that irsc understands.
+/// They need the `message-tags` capability, both to send and to receive them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ClientTag {
+    Typing(Typing),
+    /// This message replies to the one with this msgid.
+    Reply(String),
+    /// An emoji or short text in reaction to the message in `Reply`.
+    React(String),
+    /// The channel a private message belongs to, e.g. a bot's answer to a
+    /// command given there.
+    ChannelContext(String)
+}
+
+impl ClientTag {
+    /// The name of the tag, including the `+`.
+    pub fn key(&self) -> &'static str {
+        match *self {
+            ClientTag::Typing(_) => "+typing",
+            ClientTag::Reply(_) => "+draft/reply",
+            ClientTag::React(_) => "+draft/react",
+            ClientTag::ChannelContext(_) => "+draft/channel-context"
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match *self {
+            ClientTag::Typing(Typing::Active) => "active",
+            ClientTag::Typing(Typing::Paused) => "paused",
+            ClientTag::Typing(Typing::Done) => "done",
+            ClientTag::Reply(ref v) | ClientTag::React(ref v) | ClientTag::ChannelContext(ref v) => v
+        }
+    }
+
+    pub fn parse(key: &str, value: &str) -> Option<ClientTag> {
+        match (key, value) {
+            ("+typing", "active") => Some(ClientTag::Typing(Typing::Active)),
+            ("+typing", "paused") => Some(ClientTag::Typing(Typing::Paused)),
+            ("+typing", "done") => Some(ClientTag::Typing(Typing::Done)),
+            (_, "") => None,
+            ("+draft/reply", v) | ("+reply", v) => Some(ClientTag::Reply(v.to_owned())),
+            ("+draft/react", v) | ("+react", v) => Some(ClientTag::React(v.to_owned())),
+            ("+draft/channel-context", v) | ("+channel-context", v) => Some(ClientTag::ChannelContext(v.to_owned())),
+            _ => None
+        }
+    }
+
+    /// The client tags on `msg` that irsc understands.
+    pub fn from_message(msg: &Message) -> Vec<ClientTag> {
+        msg.tags().into_iter().filter_map(|(k, v)| ClientTag::parse(k, v)).collect()
+    }
+
+    /// `msg` with this tag added.
+    pub fn apply(&self, msg: &Message) -> Message {
+        msg.with_tag(self.key(), Some(self.value()))
+    }
+
+    /// Whether the server lets this tag through, according to `CLIENTTAGDENY`.
+    pub fn is_allowed(&self, isupport: &ISupport) -> bool {
+        let deny = match isupport.get("CLIENTTAGDENY") {
+            Some(d) => d,
+            None => return true
+        };
+        let name = &self.key()[1..];
+        let mut allowed = true;
+        for entry in deny.split(',') {
+            if entry == "*" { allowed = false }
+            else if entry.starts_with("-") && &entry[1..] == name { allowed = true }
+            else if entry == name { allowed = false }
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use isupport::ISupport;
+    use tags::{ ClientTag, Typing };
+
+    #[test]
+    fn client_tags() {
+        let msg = Message::parse(b"@+typing=active;+draft/reply=abc;msgid=x :n!u@h TAGMSG #rust").unwrap();
+        assert_eq!(ClientTag::from_message(&msg), vec![
+            ClientTag::Typing(Typing::Active), ClientTag::Reply("abc".to_owned())
+        ]);
+
+        let react = ClientTag::React("👍 yes".to_owned());
+        let sent = react.apply(&Message::parse(b"TAGMSG #rust").unwrap());
+        let received = Message::parse(sent.bytes()).unwrap();
+        assert_eq!(ClientTag::from_message(&received), vec![react.clone()]);
+
+        let mut isupport = ISupport::new();
+        isupport.handle(&["CLIENTTAGDENY=*,-draft/react"]);
+        assert!(react.is_allowed(&isupport));
+        assert!(!ClientTag::Typing(Typing::Done).is_allowed(&isupport));
+    }
+}
@@ -1,10 +1,15 @@
-use encoding::types::{ DecoderTrap, EncodingRef };
-use encoding::all::encodings;
+use encoding::types::{ Encoding, DecoderTrap, EncoderTrap, EncodingRef };
+use encoding::all::{ self, encodings };
+use encoding::label::encoding_from_whatwg_label;
 
 use std::ops::{ Range, Deref, Index };
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::str;
 use std::fmt;
 
+use casemap::CaseMapping;
+
 // shorthand-exports for construction
 pub use self::Text::Raw as tr;
 pub use self::Text::Utf8 as tu;
@@ -30,16 +35,16 @@ pub const EMPTY_UTF8: TextSlice<'static> = TextSlice::Utf8("");
 impl Text {
     pub fn decode_with(&self, e: EncodingRef, d: DecoderTrap) -> Text {
         match self {
-            &Text::Raw(b) => match e.decode(&b, d) {
+            &Text::Raw(ref b) => match e.decode(b, d) {
                 Ok(s) => Text::Utf8(s),
-                Err(_) => Text::Raw(b)
+                Err(_) => Text::Raw(b.clone())
             },
-            &Text::Utf8(s) => Text::Utf8(s)
+            &Text::Utf8(ref s) => Text::Utf8(s.clone())
         }
     }
 
     pub fn try_decode_with(&self, e: EncodingRef) -> Text {
-        self.decode_with(DecoderTrap::Strict)
+        self.decode_with(e, DecoderTrap::Strict)
     }
 
     pub fn try_decode_as(&self, e: &str) -> Option<Text> {
@@ -47,7 +52,15 @@ impl Text {
     }
 
     pub fn lossy_decode_with(&self, e: EncodingRef) -> Text {
-        self.decode_with(DecoderTrap::Replace)
+        self.decode_with(e, DecoderTrap::Replace)
+    }
+
+    /// Decode according to `policy`, as if it was sent to or by `target`.
+    pub fn decode_for(&self, policy: &EncodingPolicy, target: Option<&str>) -> Text {
+        match self {
+            &Text::Raw(ref b) => Text::Utf8(policy.decode(b, target)),
+            &Text::Utf8(ref s) => Text::Utf8(s.clone())
+        }
     }
 
     pub fn lossy_decode_as(&self, e: &str) -> Option<Text> {
@@ -70,8 +83,8 @@ impl Text {
 
     pub fn slice<'a>(&'a self, r: &Range<usize>) -> TextSlice<'a> {
         match self {
-            &Text::Raw(ref b) => TextSlice::Raw(&b[r]),
-            &Text::Utf8(ref s) => TextSlice::Utf8(&s[r])
+            &Text::Raw(ref b) => TextSlice::Raw(&b[r.clone()]),
+            &Text::Utf8(ref s) => TextSlice::Utf8(&s[r.clone()])
         }
     }
 
@@ -146,8 +159,8 @@ impl<'a> Deref for TextSlice<'a> {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
         match self {
-            &Text::Raw(ref b) => b,
-            &Text::Utf8(ref s) => s.as_bytes()
+            &TextSlice::Raw(b) => b,
+            &TextSlice::Utf8(s) => s.as_bytes()
         }
     }
 }
@@ -182,8 +195,116 @@ impl PartialEq for Text {
     }
 }*/
 
+/// Look up an encoding by name, like "utf-8", "cp1252", "iso-8859-2", "koi8-r"
+/// or "shift_jis". Both the names of the `encoding` crate and WHATWG labels work.
 pub fn encoding(s: &str) -> Option<EncodingRef> {
     encodings().into_iter().cloned().find(|e| e.name() == s)
+        .or_else(|| encoding_from_whatwg_label(s))
+}
+
+/// Decides how received bytes are decoded, and how outgoing text is encoded.
+///
+/// Incoming text is first tried as UTF-8. If that fails, the charset configured
+/// for the target (channel or nickname) is used, then the one for the network,
+/// and finally the fallback, which replaces everything it can't decode.
+///
+/// Outgoing text is encoded in the charset of its target, or that of the network.
+#[derive(Clone)]
+pub struct EncodingPolicy {
+    network: EncodingRef,
+    fallback: EncodingRef,
+    casemapping: CaseMapping,
+    // Keyed by the casemapped name, which the name as given is kept next to.
+    targets: HashMap<String, (String, EncodingRef)>
+}
+
+impl fmt::Debug for EncodingPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("EncodingPolicy")
+            .field("network", &self.network.name())
+            .field("fallback", &self.fallback.name())
+            .field("targets", &self.targets.iter()
+                   .map(|(_, &(ref t, e))| (t.clone(), e.name())).collect::<HashMap<_, _>>())
+            .finish()
+    }
+}
+
+impl Default for EncodingPolicy {
+    fn default() -> EncodingPolicy { EncodingPolicy::new() }
+}
+
+impl EncodingPolicy {
+    /// UTF-8 for everything, with CP1252 as the fallback, since that's
+    /// what most legacy clients send.
+    pub fn new() -> EncodingPolicy {
+        EncodingPolicy {
+            network: all::UTF_8,
+            fallback: all::WINDOWS_1252,
+            casemapping: CaseMapping::default(),
+            targets: HashMap::new()
+        }
+    }
+
+    /// Charset of the whole network, used for targets without their own.
+    pub fn network(mut self, e: EncodingRef) -> EncodingPolicy {
+        self.network = e;
+        self
+    }
+
+    /// Charset to decode with when nothing else worked.
+    pub fn fallback(mut self, e: EncodingRef) -> EncodingPolicy {
+        self.fallback = e;
+        self
+    }
+
+    pub fn target(mut self, target: &str, e: EncodingRef) -> EncodingPolicy {
+        self.set_target(target, e);
+        self
+    }
+
+    pub fn set_target(&mut self, target: &str, e: EncodingRef) {
+        self.targets.insert(self.casemapping.lower(target), (target.into(), e));
+    }
+
+    pub fn remove_target(&mut self, target: &str) -> Option<EncodingRef> {
+        self.targets.remove(&self.casemapping.lower(target)).map(|(_, e)| e)
+    }
+
+    /// How target names compare, as announced by the server in 005.
+    pub fn set_casemapping(&mut self, mapping: CaseMapping) {
+        if mapping == self.casemapping { return }
+        self.casemapping = mapping;
+        let targets: Vec<(String, EncodingRef)> = self.targets.drain().map(|(_, t)| t).collect();
+        for (t, e) in targets { self.targets.insert(mapping.lower(&t), (t, e)); }
+    }
+
+    /// The charset that text to `target` is sent in.
+    pub fn for_target(&self, target: Option<&str>) -> EncodingRef {
+        target.and_then(|t| self.targets.get(&self.casemapping.lower(t)).map(|&(_, e)| e))
+              .unwrap_or(self.network)
+    }
+
+    pub fn decode(&self, b: &[u8], target: Option<&str>) -> String {
+        if let Ok(s) = str::from_utf8(b) { return s.to_owned() }
+
+        let mut chain = vec![self.for_target(target), self.network];
+        chain.retain(|e| e.name() != all::UTF_8.name());
+        for e in chain {
+            if let Ok(s) = e.decode(b, DecoderTrap::Strict) { return s }
+        }
+        lossy_decode(b, self.fallback)
+    }
+
+    pub fn encode(&self, s: &str, target: Option<&str>) -> Vec<u8> {
+        let e = self.for_target(target);
+        if e.name() == all::UTF_8.name() { return s.as_bytes().to_owned() }
+        e.encode(s, EncoderTrap::Replace).ok().expect("Shouldn't error with replacing trap")
+    }
+
+    /// Whether outgoing text to `target` would be sent as is.
+    pub fn is_utf8(&self, target: Option<&str>) -> bool {
+        self.for_target(target).name() == all::UTF_8.name()
+    }
 }
 
 pub fn lossy_decode(b: &[u8], e: EncodingRef) -> String {
@@ -194,3 +315,78 @@ pub fn lossy_decode(b: &[u8], e: EncodingRef) -> String {
 pub fn def_lossy_decode(b: &[u8]) -> String {
     lossy_decode(b, ::ENCODING)
 }
+
+/// Standard base64, with padding, as used by SASL and HTTP.
+pub fn base64(data: &[u8]) -> String {
+    const CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() { out.push(CHARS[(n >> (18 - 6 * i)) & 63] as char) }
+            else { out.push('=') }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use encoding::all;
+    use encoding::types::Encoding;
+    use casemap::CaseMapping;
+    use text::{ EncodingPolicy, base64 };
+
+    const KOI8_PRIVET: &'static [u8] = b"\xd0\xd2\xc9\xd7\xc5\xd4";
+
+    #[test]
+    fn decode_utf8_first() {
+        let policy = EncodingPolicy::new().target("#ru", all::KOI8_R);
+        assert_eq!(policy.decode("h\u{e9}llo".as_bytes(), None), "h\u{e9}llo");
+        assert_eq!(policy.decode("\u{43f}\u{440}\u{438}".as_bytes(), Some("#ru")), "\u{43f}\u{440}\u{438}");
+        // Not UTF-8, so the fallback has to do.
+        assert_eq!(policy.decode(b"h\xe9llo", None), "h\u{e9}llo");
+        assert_eq!(policy.decode(KOI8_PRIVET, Some("#RU")), "\u{43f}\u{440}\u{438}\u{432}\u{435}\u{442}");
+        assert_eq!(policy.decode(KOI8_PRIVET, Some("#other")), "\u{d0}\u{d2}\u{c9}\u{d7}\u{c5}\u{d4}");
+    }
+
+    #[test]
+    fn for_target() {
+        let mut policy = EncodingPolicy::new().network(all::ISO_8859_2).target("#ru", all::KOI8_R);
+        assert_eq!(policy.for_target(Some("#Ru")).name(), all::KOI8_R.name());
+        assert_eq!(policy.for_target(Some("#other")).name(), all::ISO_8859_2.name());
+        assert_eq!(policy.for_target(None).name(), all::ISO_8859_2.name());
+        assert!(policy.remove_target("#RU").is_some());
+        assert_eq!(policy.for_target(Some("#ru")).name(), all::ISO_8859_2.name());
+    }
+
+    #[test]
+    fn casemapped_targets() {
+        let mut policy = EncodingPolicy::new().target("#Chan[", all::KOI8_R);
+        assert_eq!(policy.for_target(Some("#chan{")).name(), all::KOI8_R.name());
+        policy.set_casemapping(CaseMapping::Ascii);
+        assert_eq!(policy.for_target(Some("#chan{")).name(), all::UTF_8.name());
+        assert_eq!(policy.for_target(Some("#CHAN[")).name(), all::KOI8_R.name());
+        policy.set_casemapping(CaseMapping::Rfc1459);
+        assert_eq!(policy.remove_target("#chan{").map(|e| e.name()), Some(all::KOI8_R.name()));
+    }
+
+    #[test]
+    fn encode_legacy() {
+        let policy = EncodingPolicy::new().target("#ru", all::KOI8_R);
+        assert_eq!(policy.encode("\u{43f}\u{440}\u{438}\u{432}\u{435}\u{442}", Some("#ru")), KOI8_PRIVET);
+        assert_eq!(policy.encode("\u{43f}", None), "\u{43f}".as_bytes());
+        assert!(!policy.is_utf8(Some("#ru")));
+        assert!(policy.is_utf8(Some("#en")));
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+        assert_eq!(base64(b"\0bot\0hunter2"), "AGJvdABodW50ZXIy");
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt;
+use std::str::FromStr;
+
+use time;
+
+use ::IrscError;
+
+/// Milliseconds since the Unix epoch, in UTC.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub i64);
+
+/// Which clock to trust for the time of a message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Clock {
+    /// Use the `time` tag if the server sent one (with `server-time` enabled),
+    /// and the time of receipt otherwise. This is right for replayed history.
+    Server,
+    /// Always use the time of receipt.
+    Local
+}
+
+impl Default for Clock {
+    fn default() -> Clock { Clock::Server }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        let t = time::get_time();
+        Timestamp(t.sec * 1000 + t.nsec as i64 / 1_000_000)
+    }
+
+    pub fn millis(&self) -> i64 { self.0 }
+    pub fn seconds(&self) -> i64 { self.0 / 1000 }
+
+    /// Parse the format used by `server-time`, `YYYY-MM-DDThh:mm:ss.sssZ`.
+    /// The fraction is optional, and so is the `Z`; other offsets are rejected.
+    pub fn parse_iso8601(s: &str) -> Option<Timestamp> {
+        let s = s.trim_right_matches('Z');
+        let (date, time) = match s.find('T') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return None
+        };
+        let (time, frac) = match time.find('.') {
+            Some(i) => (&time[..i], Some(&time[i + 1..])),
+            None => (time, None)
+        };
+
+        let date: Vec<i64> = match date.split('-').map(|p| p.parse().ok()).collect() {
+            Some(d) => d,
+            None => return None
+        };
+        let time: Vec<i64> = match time.split(':').map(|p| p.parse().ok()).collect() {
+            Some(t) => t,
+            None => return None
+        };
+        if date.len() != 3 || time.len() != 3 { return None }
+        if date[1] < 1 || date[1] > 12 || date[2] < 1 || date[2] > 31
+        || time[0] > 23 || time[1] > 59 || time[2] > 60 { return None }
+
+        // Only the first three digits matter.
+        let millis = match frac {
+            Some(f) if !f.is_empty() && f.chars().all(|c| c.is_digit(10)) =>
+                f.chars().chain("00".chars()).take(3).collect::<String>().parse().unwrap_or(0),
+            Some(_) => return None,
+            None => 0
+        };
+
+        let days = days_from_civil(date[0], date[1], date[2]);
+        Some(Timestamp(((days * 24 + time[0]) * 60 + time[1]) * 60_000 + time[2] * 1000 + millis))
+    }
+
+    /// Format like `server-time` does, with milliseconds.
+    pub fn to_iso8601(&self) -> String {
+        let secs = if self.0 >= 0 { self.0 / 1000 } else { (self.0 - 999) / 1000 };
+        let millis = self.0 - secs * 1000;
+        let days = if secs >= 0 { secs / 86400 } else { (secs - 86399) / 86400 };
+        let rem = secs - days * 86400;
+        let (y, m, d) = civil_from_days(days);
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                y, m, d, rem / 3600, rem / 60 % 60, rem % 60, millis)
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = IrscError;
+    fn from_str(s: &str) -> ::std::result::Result<Timestamp, IrscError> {
+        Timestamp::parse_iso8601(s).ok_or(IrscError::NotFound)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.to_iso8601())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use timestamp::Timestamp;
+
+    #[test]
+    fn iso8601() {
+        assert_eq!(Timestamp::parse_iso8601("1970-01-01T00:00:00.000Z"), Some(Timestamp(0)));
+        assert_eq!(Timestamp::parse_iso8601("2011-10-19T16:40:51.620Z"), Some(Timestamp(1319042451620)));
+        assert_eq!(Timestamp::parse_iso8601("2011-10-19T16:40:51.6Z"), Some(Timestamp(1319042451600)));
+        assert_eq!(Timestamp::parse_iso8601("2011-10-19T16:40:51Z"), Some(Timestamp(1319042451000)));
+        assert_eq!(Timestamp::parse_iso8601("2011-10-19 16:40:51Z"), None);
+        assert_eq!(Timestamp::parse_iso8601("2020-01-01T16:40:51+02:00"), None);
+        assert_eq!(Timestamp::parse_iso8601("2020-01-01T16:40:51.123+02:00"), None);
+        assert_eq!(Timestamp::parse_iso8601("2020-01-0xT16:40:51Z"), None);
+        assert_eq!(Timestamp(1319042451620).to_iso8601(), "2011-10-19T16:40:51.620Z");
+        assert_eq!(Timestamp(-1).to_iso8601(), "1969-12-31T23:59:59.999Z");
+    }
+}
@@ -0,0 +1,256 @@
+//! TLS for connections, implemented with openssl (the `openssl` feature,
+//! on by default) or rustls (the `rustls` feature).
+
+use std::borrow::ToOwned;
+use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, TcpStream };
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::result;
+
+use transport::{ self, Interleaved };
+use ::IrscError;
+
+#[cfg(feature = "openssl")]
+mod with_openssl;
+#[cfg(feature = "rustls")]
+mod with_rustls;
+
+/// Which TLS implementation to use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Backend {
+    #[cfg(feature = "openssl")]
+    OpenSsl,
+    #[cfg(feature = "rustls")]
+    Rustls
+}
+
+impl Backend {
+    /// openssl if it's available, rustls otherwise.
+    pub fn default() -> Option<Backend> {
+        #[cfg(feature = "openssl")]
+        fn pick() -> Option<Backend> { Some(Backend::OpenSsl) }
+        #[cfg(all(feature = "rustls", not(feature = "openssl")))]
+        fn pick() -> Option<Backend> { Some(Backend::Rustls) }
+        #[cfg(not(any(feature = "openssl", feature = "rustls")))]
+        fn pick() -> Option<Backend> { None }
+        pick()
+    }
+}
+
+/// How to set up TLS.
+///
+/// The defaults are secure: TLS 1.2 or newer, certificates are verified
+/// against the system's CAs (or the Mozilla ones, with rustls), and so is the
+/// hostname, which is also sent with SNI.
+///
+/// ```ignore
+/// let config = TlsConfig::new().client_cert("bot.pem", "bot.key");
+/// client.connect_tls("irc.example.org", 6697, &config);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    backend: Option<Backend>,
+    ca_file: Option<PathBuf>,
+    client_cert: Option<(PathBuf, PathBuf)>,
+    pins: Vec<String>,
+    server_name: Option<String>,
+    insecure: bool
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig { TlsConfig::default() }
+
+    pub fn backend(mut self, backend: Backend) -> TlsConfig { self.backend = Some(backend); self }
+
+    /// Trust only the CAs in this PEM file, instead of the default ones.
+    pub fn ca_file<P: AsRef<Path>>(mut self, path: P) -> TlsConfig {
+        self.ca_file = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Present this certificate, for CertFP or SASL EXTERNAL. Both files are PEM.
+    pub fn client_cert<P: AsRef<Path>, Q: AsRef<Path>>(mut self, cert: P, key: Q) -> TlsConfig {
+        self.client_cert = Some((cert.as_ref().to_owned(), key.as_ref().to_owned()));
+        self
+    }
+
+    /// Only accept servers whose public key (the DER of its SubjectPublicKeyInfo)
+    /// has this SHA-256 fingerprint, in hex, with or without colons.
+    /// Give several to allow for key rollover.
+    pub fn pin(mut self, fingerprint: &str) -> TlsConfig {
+        self.pins.push(fingerprint.chars().filter(|&c| c != ':').collect::<String>().to_lowercase());
+        self
+    }
+
+    /// Verify the certificate against `name`, and send it with SNI, instead
+    /// of the host that is connected to.
+    pub fn server_name(mut self, name: &str) -> TlsConfig {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+
+    /// Accept any certificate for any hostname. Pins are still checked, so
+    /// this is safe with them; without, it's only good for testing.
+    pub fn insecure(mut self) -> TlsConfig { self.insecure = true; self }
+
+    pub fn is_insecure(&self) -> bool { self.insecure }
+
+    /// Whether a public key with this SHA-256 digest is acceptable.
+    fn accepts_key(&self, digest: &[u8]) -> bool {
+        self.pins.is_empty() || self.pins.contains(&fingerprint(digest))
+    }
+}
+
+/// Lowercase hex, the format of `TlsConfig::pin`.
+pub fn fingerprint(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A connection that can be read from and written to, like a TLS session.
+pub trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A TLS connection. Clones share it, so one can read while another writes;
+/// a blocking read lets writes in every few milliseconds, see `Interleaved`.
+#[derive(Clone)]
+pub struct TlsStream {
+    inner: Arc<Interleaved<Box<Stream>>>,
+    /// The connection underneath, to shut it down while a read is blocking.
+    socket: Arc<TcpStream>
+}
+
+impl TlsStream {
+    /// Close the underlying connection, without a TLS close_notify.
+    pub fn shutdown(&self) -> io::Result<()> { self.socket.shutdown(Shutdown::Both) }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.inner.read(|s| s.read(buf)) }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.inner.write(|s| s.write(buf)) }
+    fn flush(&mut self) -> io::Result<()> { self.inner.write(|s| s.flush()) }
+}
+
+/// Connect to `host` on `port`, and do the TLS handshake.
+pub fn connect(host: &str, port: u16, config: &TlsConfig) -> result::Result<TlsStream, IrscError> {
+    let tcp = try!(TcpStream::connect((host, port)).map_err(IrscError::Io));
+    wrap(tcp, host, config)
+}
+
+/// Do the TLS handshake on a connection to `host` that is already open,
+/// e.g. through a proxy.
+pub fn wrap(tcp: TcpStream, host: &str, config: &TlsConfig) -> result::Result<TlsStream, IrscError> {
+    let socket = try!(tcp.try_clone().map_err(IrscError::Io));
+    let stream = try!(handshake(tcp, host, config));
+    let inner = try!(transport::interleave(stream, &socket).map_err(IrscError::Io));
+    Ok(TlsStream { inner: Arc::new(inner), socket: Arc::new(socket) })
+}
+
+/// Like `wrap`, but the connection isn't shared, so it's only good for one
+/// thread at a time, or for sharing it in some other way.
+pub fn handshake(tcp: TcpStream, host: &str, config: &TlsConfig) -> result::Result<Box<Stream>, IrscError> {
+    let name = config.server_name.as_ref().map(|n| &n[..]).unwrap_or(host);
+    let stream: Box<Stream> = match config.backend.or(Backend::default()) {
+        #[cfg(feature = "openssl")]
+        Some(Backend::OpenSsl) => Box::new(try!(with_openssl::connect(tcp, name, config))),
+        #[cfg(feature = "rustls")]
+        Some(Backend::Rustls) => Box::new(try!(with_rustls::connect(tcp, name, config))),
+        _ => return Err(IrscError::Unsupported("tls"))
+    };
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{ BufRead, BufReader, Write };
+    use std::net::{ TcpListener, TcpStream };
+    use std::thread;
+
+    use tls::{ self, Backend, Stream, TlsConfig, fingerprint };
+
+    /// Signed `irc.test.pem`, which is for irc.test and goes with `irc.test.key`.
+    const CA: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tls/test/ca.pem");
+    const CERT: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tls/test/irc.test.pem");
+    const KEY: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tls/test/irc.test.key");
+
+    #[test]
+    fn pins() {
+        assert_eq!(fingerprint(&[0, 0xAB, 0x10]), "00ab10");
+        let config = TlsConfig::new().pin("00:AB:10");
+        assert!(config.accepts_key(&[0, 0xAB, 0x10]));
+        assert!(!config.accepts_key(&[1, 0xAB, 0x10]));
+        assert!(TlsConfig::new().accepts_key(&[1]));
+    }
+
+    #[cfg(feature = "openssl")]
+    fn openssl_server(tcp: TcpStream) -> Option<Box<Stream>> {
+        use openssl::ssl::{ SslAcceptor, SslFiletype, SslMethod };
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key_file(KEY, SslFiletype::PEM).unwrap();
+        acceptor.set_certificate_chain_file(CERT).unwrap();
+        match acceptor.build().accept(tcp) {
+            Ok(s) => Some(Box::new(s)),
+            Err(_) => None
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls_server(tcp: TcpStream) -> Option<Box<Stream>> {
+        use std::sync::Arc;
+        use rustls::{ Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned };
+        use rustls_pemfile;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(CERT).unwrap())).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(KEY).unwrap())).unwrap().remove(0);
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+            .unwrap();
+        let mut stream = StreamOwned::new(ServerConnection::new(Arc::new(config)).unwrap(), tcp);
+        while stream.conn.is_handshaking() {
+            if stream.conn.complete_io(&mut stream.sock).is_err() { return None }
+        }
+        Some(Box::new(stream))
+    }
+
+    /// A server that does the handshake with `accept`, and sends back the first line.
+    fn echo(accept: fn(TcpStream) -> Option<Box<Stream>>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            if let Some(stream) = accept(tcp) {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                if stream.read_line(&mut line).is_ok() {
+                    let _ = stream.get_mut().write_all(line.as_bytes());
+                }
+            }
+        });
+        port
+    }
+
+    fn handshake(backend: Backend, accept: fn(TcpStream) -> Option<Box<Stream>>) {
+        let config = TlsConfig::new().backend(backend).server_name("irc.test");
+        // No default CA signed it.
+        assert!(tls::connect("127.0.0.1", echo(accept), &config).is_err());
+
+        let mut s = tls::connect("127.0.0.1", echo(accept), &config.ca_file(CA)).unwrap();
+        s.write_all(b"PING :irc.test\r\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(s).read_line(&mut line).unwrap();
+        assert_eq!(line, "PING :irc.test\r\n");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn openssl() { handshake(Backend::OpenSsl, openssl_server) }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn rustls() { handshake(Backend::Rustls, rustls_server) }
+}
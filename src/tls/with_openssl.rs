@@ -0,0 +1,60 @@
+use std::fs::File;
+use std::io::Read;
+use std::net::TcpStream;
+use std::result;
+
+use openssl::hash::{ hash, MessageDigest };
+use openssl::ssl::{ SslConnector, SslMethod, SslStream, SslVerifyMode, SslFiletype, SslVersion };
+use openssl::x509::{ X509, X509StoreContextRef };
+use openssl::x509::store::X509StoreBuilder;
+
+use tls::TlsConfig;
+use ::IrscError;
+
+fn error<E: ::std::fmt::Display>(e: E) -> IrscError { IrscError::Tls(e.to_string()) }
+
+/// Whether the leaf certificate's public key matches one of the pins.
+fn pinned(ctx: &X509StoreContextRef, config: &TlsConfig) -> bool {
+    // Only the leaf is pinned, intermediates are checked like always.
+    if ctx.error_depth() != 0 { return true }
+    let spki = ctx.current_cert()
+        .and_then(|cert| cert.public_key().ok())
+        .and_then(|key| key.public_key_to_der().ok());
+    match spki.and_then(|der| hash(MessageDigest::sha256(), &der).ok()) {
+        Some(digest) => config.accepts_key(&digest),
+        None => false
+    }
+}
+
+pub fn connect(tcp: TcpStream, name: &str, config: &TlsConfig)
+-> result::Result<SslStream<TcpStream>, IrscError> {
+    let mut builder = try!(SslConnector::builder(SslMethod::tls()).map_err(error));
+    try!(builder.set_min_proto_version(Some(SslVersion::TLS1_2)).map_err(error));
+    if let Some(ref ca) = config.ca_file {
+        // The builder trusts the system's CAs already, set_ca_file would only add to them.
+        let mut pem = Vec::new();
+        try!(File::open(ca).and_then(|mut f| f.read_to_end(&mut pem)).map_err(IrscError::Io));
+        let mut store = try!(X509StoreBuilder::new().map_err(error));
+        for cert in try!(X509::stack_from_pem(&pem).map_err(error)) {
+            try!(store.add_cert(cert).map_err(error));
+        }
+        builder.set_cert_store(store.build());
+    }
+    if let Some((ref cert, ref key)) = config.client_cert {
+        try!(builder.set_certificate_chain_file(cert).map_err(error));
+        try!(builder.set_private_key_file(key, SslFiletype::PEM).map_err(error));
+        try!(builder.check_private_key().map_err(error));
+    }
+
+    let insecure = config.insecure;
+    let pins = config.clone();
+    builder.set_verify_callback(SslVerifyMode::PEER, move |ok, ctx| {
+        (ok || insecure) && pinned(ctx, &pins)
+    });
+
+    let mut connection = try!(builder.build().configure().map_err(error));
+    if insecure {
+        connection = connection.verify_hostname(false);
+    }
+    connection.connect(name, tcp).map_err(error)
+}
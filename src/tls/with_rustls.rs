@@ -0,0 +1,99 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::Path;
+use std::result;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::{ self, Certificate, CertificateError, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerName, StreamOwned };
+use rustls::client::{ ServerCertVerified, ServerCertVerifier, WebPkiVerifier };
+use ring::digest;
+use rustls_pemfile;
+use webpki_roots;
+use x509_parser::prelude::{ FromDer, X509Certificate };
+
+use tls::TlsConfig;
+use ::IrscError;
+
+fn error<E: ::std::fmt::Display>(e: E) -> IrscError { IrscError::Tls(e.to_string()) }
+
+fn certificates(path: &Path) -> result::Result<Vec<Certificate>, IrscError> {
+    let mut reader = BufReader::new(try!(File::open(path).map_err(IrscError::Io)));
+    let certs = try!(rustls_pemfile::certs(&mut reader).map_err(IrscError::Io));
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn private_key(path: &Path) -> result::Result<PrivateKey, IrscError> {
+    let mut reader = BufReader::new(try!(File::open(path).map_err(IrscError::Io)));
+    loop {
+        match try!(rustls_pemfile::read_one(&mut reader).map_err(IrscError::Io)) {
+            Some(rustls_pemfile::Item::RSAKey(k)) |
+            Some(rustls_pemfile::Item::PKCS8Key(k)) |
+            Some(rustls_pemfile::Item::ECKey(k)) => return Ok(PrivateKey(k)),
+            Some(_) => (),
+            None => return Err(IrscError::Tls(format!("No private key in {}", path.display())))
+        }
+    }
+}
+
+/// Checks the certificate like webpki does, unless the config is insecure,
+/// and then the pins.
+struct Verifier {
+    webpki: WebPkiVerifier,
+    config: TlsConfig
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate],
+                          name: &ServerName, scts: &mut Iterator<Item = &[u8]>,
+                          ocsp: &[u8], now: SystemTime)
+    -> result::Result<ServerCertVerified, rustls::Error> {
+        if !self.config.insecure {
+            try!(self.webpki.verify_server_cert(end_entity, intermediates, name, scts, ocsp, now));
+        }
+        let spki = match X509Certificate::from_der(&end_entity.0) {
+            Ok((_, cert)) => digest::digest(&digest::SHA256, cert.public_key().raw),
+            Err(_) => return Err(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))
+        };
+        if self.config.accepts_key(spki.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("Certificate does not match any pin".to_owned()))
+        }
+    }
+}
+
+pub fn connect(tcp: TcpStream, name: &str, config: &TlsConfig)
+-> result::Result<StreamOwned<ClientConnection, TcpStream>, IrscError> {
+    let mut roots = RootCertStore::empty();
+    match config.ca_file {
+        Some(ref ca) => for cert in try!(certificates(ca)) {
+            try!(roots.add(&cert).map_err(error));
+        },
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        }))
+    }
+
+    let verifier = Verifier { webpki: WebPkiVerifier::new(roots, None), config: config.clone() };
+    // rustls only speaks TLS 1.2 and 1.3.
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let client_config = match config.client_cert {
+        Some((ref cert, ref key)) =>
+            try!(builder.with_client_auth_cert(try!(certificates(cert)), try!(private_key(key))).map_err(error)),
+        None => builder.with_no_client_auth()
+    };
+
+    let server_name = try!(ServerName::try_from(name).map_err(error));
+    let connection = try!(ClientConnection::new(Arc::new(client_config), server_name).map_err(error));
+    let mut stream = StreamOwned::new(connection, tcp);
+    // Handshake now, so errors show up here and not on the first write.
+    while stream.conn.is_handshaking() {
+        try!(stream.conn.complete_io(&mut stream.sock).map_err(IrscError::Io));
+    }
+    Ok(stream)
+}
@@ -0,0 +1,219 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, TcpStream };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+use std::time::Duration;
+
+use tls::TlsStream;
+#[cfg(feature = "websocket")]
+use websocket::WsStream;
+
+/// Something IRC can be spoken over. Messages are written to `writer`, while
+/// `listen` reads from its own `reader`, usually on the same thread.
+pub trait Transport: Send {
+    /// Another handle on the connection, to read from.
+    fn reader(&self) -> io::Result<Box<Read + Send>>;
+    fn writer(&mut self) -> &mut Write;
+    /// Close the connection, in both directions. Readers should see its end.
+    fn shutdown(&mut self) -> io::Result<()>;
+    fn is_secure(&self) -> bool { false }
+    /// Another handle on the whole connection, e.g. to shut it down while
+    /// a write blocks. Not every transport has one.
+    fn try_clone(&self) -> io::Result<Box<Transport>> {
+        Err(io::Error::new(io::ErrorKind::Other, "This transport can't be cloned"))
+    }
+}
+
+impl Transport for TcpStream {
+    fn reader(&self) -> io::Result<Box<Read + Send>> { Ok(Box::new(try!(self.try_clone()))) }
+    fn writer(&mut self) -> &mut Write { self }
+    fn shutdown(&mut self) -> io::Result<()> { TcpStream::shutdown(self, Shutdown::Both) }
+    fn try_clone(&self) -> io::Result<Box<Transport>> { Ok(Box::new(try!(TcpStream::try_clone(self)))) }
+}
+
+impl Transport for TlsStream {
+    fn reader(&self) -> io::Result<Box<Read + Send>> { Ok(Box::new(self.clone())) }
+    fn writer(&mut self) -> &mut Write { self }
+    fn shutdown(&mut self) -> io::Result<()> { TlsStream::shutdown(self) }
+    fn is_secure(&self) -> bool { true }
+    fn try_clone(&self) -> io::Result<Box<Transport>> { Ok(Box::new(self.clone())) }
+}
+
+#[cfg(feature = "websocket")]
+impl Transport for WsStream {
+    fn reader(&self) -> io::Result<Box<Read + Send>> { Ok(Box::new(self.clone())) }
+    fn writer(&mut self) -> &mut Write { self }
+    fn shutdown(&mut self) -> io::Result<()> { WsStream::shutdown(self) }
+    fn is_secure(&self) -> bool { WsStream::is_secure(self) }
+    fn try_clone(&self) -> io::Result<Box<Transport>> { Ok(Box::new(self.clone())) }
+}
+
+/// How long a read of an `Interleaved` stream waits for data, before it
+/// lets writers in, in milliseconds.
+const READ_SLICE: u64 = 50;
+
+/// A stream that can't be split into halves, like a TLS connection, which
+/// one thread reads from while others write to it.
+///
+/// Reads wait for data in slices, with a read timeout on the socket, and
+/// let waiting writers go first between them. Writes wait at most a slice.
+pub struct Interleaved<S> {
+    stream: Mutex<S>,
+    /// Writers waiting for the stream.
+    writers: AtomicUsize
+}
+
+/// Share `stream`, which speaks over `socket`. Set up the socket before,
+/// since this sets its read timeout.
+pub fn interleave<S>(stream: S, socket: &TcpStream) -> io::Result<Interleaved<S>> {
+    try!(socket.set_read_timeout(Some(Duration::from_millis(READ_SLICE))));
+    Ok(Interleaved { stream: Mutex::new(stream), writers: AtomicUsize::new(0) })
+}
+
+fn timed_out(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+impl<S> Interleaved<S> {
+    /// Read with `f`, which is tried again until the socket doesn't time out.
+    pub fn read<T, F>(&self, mut f: F) -> io::Result<T> where F: FnMut(&mut S) -> io::Result<T> {
+        loop {
+            match f(&mut *self.stream.lock().unwrap()) {
+                Err(ref e) if timed_out(e) => (),
+                r => return r
+            }
+            while self.writers.load(Ordering::SeqCst) > 0 { thread::yield_now() }
+        }
+    }
+
+    /// Write with `f`, as soon as the current read slice is over.
+    pub fn write<T, F>(&self, f: F) -> io::Result<T> where F: FnOnce(&mut S) -> io::Result<T> {
+        self.writers.fetch_add(1, Ordering::SeqCst);
+        let mut stream = self.stream.lock().unwrap();
+        self.writers.fetch_sub(1, Ordering::SeqCst);
+        f(&mut *stream)
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    closed: bool
+}
+
+type Shared = Arc<(Mutex<Pipe>, Condvar)>;
+
+fn close(pipe: &Shared) {
+    pipe.0.lock().unwrap().closed = true;
+    pipe.1.notify_all();
+}
+
+/// One end of an in-memory connection, made with `duplex`. What is written
+/// to one end is read from the other.
+#[derive(Clone)]
+pub struct MemoryTransport {
+    incoming: Shared,
+    outgoing: Shared
+}
+
+/// Both ends of a new in-memory connection.
+pub fn duplex() -> (MemoryTransport, MemoryTransport) {
+    let a: Shared = Arc::default();
+    let b: Shared = Arc::default();
+    (MemoryTransport { incoming: a.clone(), outgoing: b.clone() },
+     MemoryTransport { incoming: b, outgoing: a })
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (ref lock, ref ready) = *self.incoming;
+        let mut pipe = lock.lock().unwrap();
+        while pipe.data.is_empty() && !pipe.closed {
+            pipe = ready.wait(pipe).unwrap();
+        }
+        let n = cmp::min(buf.len(), pipe.data.len());
+        for (i, b) in pipe.data.drain(..n).enumerate() { buf[i] = b }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (ref lock, ref ready) = *self.outgoing;
+        let mut pipe = lock.lock().unwrap();
+        if pipe.closed { return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed")) }
+        pipe.data.extend(buf.iter().cloned());
+        ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Transport for MemoryTransport {
+    fn reader(&self) -> io::Result<Box<Read + Send>> { Ok(Box::new(self.clone())) }
+    fn writer(&mut self) -> &mut Write { self }
+    fn shutdown(&mut self) -> io::Result<()> {
+        close(&self.incoming);
+        close(&self.outgoing);
+        Ok(())
+    }
+    fn try_clone(&self) -> io::Result<Box<Transport>> { Ok(Box::new(self.clone())) }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ BufRead, BufReader, Read, Write };
+    use std::net::{ TcpListener, TcpStream };
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use transport::{ duplex, interleave, Transport };
+
+    #[test]
+    fn memory() {
+        let (mut client, mut server) = duplex();
+        let reader = server.reader().unwrap();
+        let t = thread::spawn(move || {
+            BufReader::new(reader).lines().map(|l| l.unwrap()).collect::<Vec<_>>()
+        });
+        client.writer().write_all(b"NICK irsc\r\nUSER irsc 0 * :irsc\r\n").unwrap();
+        client.shutdown().unwrap();
+        assert_eq!(t.join().unwrap(), vec!["NICK irsc", "USER irsc 0 * :irsc"]);
+
+        let mut rest = Vec::new();
+        assert_eq!(server.read_to_end(&mut rest).unwrap(), 0);
+        assert!(server.write_all(b"PING x\r\n").is_err());
+    }
+
+    #[test]
+    fn interleaved() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let shared = Arc::new(interleave(socket.try_clone().unwrap(), &socket).unwrap());
+
+        // The reader blocks, since the server says nothing until it got the line.
+        let (done, read) = mpsc::channel();
+        let reader = shared.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 16];
+            let n = reader.read(|s| s.read(&mut buf)).unwrap();
+            done.send(buf[..n].to_vec()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(120));
+        shared.write(|s| s.write_all(b"PING x\r\n")).unwrap();
+
+        let mut line = String::new();
+        BufReader::new(server.try_clone().unwrap()).read_line(&mut line).unwrap();
+        assert_eq!(line, "PING x\r\n");
+        server.write_all(b"PONG x\r\n").unwrap();
+        assert_eq!(read.recv_timeout(Duration::from_secs(5)).unwrap(), b"PONG x\r\n".to_vec());
+    }
+}
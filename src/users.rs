@@ -0,0 +1,216 @@
+use std::borrow::ToOwned;
+use std::collections::HashMap;
+
+use message::Message;
+use ident::Ident;
+use casemap::CaseMapping;
+use text;
+
+/// What is known about a user that shares a channel with us.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub ident: Ident,
+    /// Services account, from extended-join, account-notify or account-tag.
+    pub account: Option<String>,
+    /// Away message, from away-notify.
+    pub away: Option<String>,
+    /// From extended-join or setname.
+    pub realname: Option<String>,
+    pub channels: Vec<String>
+}
+
+impl User {
+    fn new(ident: Ident) -> User {
+        User { ident: ident, account: None, away: None, realname: None, channels: Vec::new() }
+    }
+
+    pub fn nick(&self) -> &str { &self.ident.nickname }
+    pub fn is_away(&self) -> bool { self.away.is_some() }
+}
+
+/// Users on the channels we're in, kept current with `account-notify`,
+/// `away-notify`, `extended-join`, `chghost`, `setname` and `account-tag`
+/// as far as these capabilities are enabled.
+///
+/// Users are forgotten when they leave the last channel we share with them.
+#[derive(Clone, Debug, Default)]
+pub struct Users {
+    me: Option<String>,
+    users: HashMap<String, User>,
+    casemapping: CaseMapping
+}
+
+// "*" stands for "no account" in ACCOUNT and extended JOIN.
+fn account(s: &str) -> Option<String> {
+    if s == "*" || s.is_empty() { None } else { Some(s.to_owned()) }
+}
+
+impl Users {
+    pub fn new() -> Users { Users::default() }
+
+    pub fn clear(&mut self) {
+        self.me = None;
+        self.users.clear();
+    }
+
+    /// Our own nickname, as far as the server told us.
+    pub fn me(&self) -> Option<&str> { self.me.as_ref().map(|m| &m[..]) }
+
+    pub fn set_casemapping(&mut self, mapping: CaseMapping) {
+        if mapping == self.casemapping { return }
+        self.casemapping = mapping;
+        let users: Vec<User> = self.users.drain().map(|(_, u)| u).collect();
+        for u in users { self.users.insert(mapping.lower(u.nick()), u); }
+    }
+
+    pub fn get(&self, nick: &str) -> Option<&User> {
+        self.users.get(&self.casemapping.lower(nick))
+    }
+
+    pub fn iter(&self) -> ::std::collections::hash_map::Values<String, User> {
+        self.users.values()
+    }
+
+    /// Users on `channel`.
+    pub fn on(&self, channel: &str) -> Vec<&User> {
+        self.users.values()
+            .filter(|u| u.channels.iter().any(|c| self.casemapping.eq(c, channel)))
+            .collect()
+    }
+
+    fn is_me(&self, nick: &str) -> bool {
+        self.me.as_ref().map(|m| self.casemapping.eq(m, nick)) == Some(true)
+    }
+
+    fn entry(&mut self, ident: &Ident) -> &mut User {
+        let key = self.casemapping.lower(&ident.nickname);
+        let user = self.users.entry(key)
+            .or_insert_with(|| User::new(ident.clone()));
+        // Fill in user and host, if we only knew the nickname so far.
+        if ident.user.is_some() { user.ident.user = ident.user.clone() }
+        if ident.host.is_some() { user.ident.host = ident.host.clone() }
+        user
+    }
+
+    fn joined(&mut self, ident: &Ident, channel: &str) {
+        let mapping = self.casemapping;
+        let user = self.entry(ident);
+        if !user.channels.iter().any(|c| mapping.eq(c, channel)) {
+            user.channels.push(channel.to_owned());
+        }
+    }
+
+    fn left(&mut self, nick: &str, channel: &str) {
+        let mapping = self.casemapping;
+        if self.is_me(nick) {
+            for u in self.users.values_mut() { u.channels.retain(|c| !mapping.eq(c, channel)) }
+        } else if let Some(u) = self.users.get_mut(&mapping.lower(nick)) {
+            u.channels.retain(|c| !mapping.eq(c, channel));
+        }
+        self.users.retain(|_, u| !u.channels.is_empty());
+    }
+
+    /// Look at a message from the server, and update what we know.
+    pub fn handle(&mut self, msg: &Message) {
+        let command = text::def_lossy_decode(&msg.command());
+        let p: Vec<String> = msg.elements().iter().map(|e| text::def_lossy_decode(e)).collect();
+        let source = msg.ident();
+        let mapping = self.casemapping;
+
+        // With account-tag, every message says which account sent it.
+        if let (Some(i), Some(a)) = (source.as_ref(), msg.tag("account")) {
+            if let Some(u) = self.users.get_mut(&mapping.lower(&i.nickname)) {
+                u.account = account(a);
+            }
+        }
+
+        match (&command[..], source) {
+            ("001", _) => self.me = p.get(0).cloned(),
+            // RPL_NAMREPLY: <me> <symbol> <channel> :[prefix]<nick>[!user@host] ...
+            ("353", _) => if p.len() >= 4 {
+                for name in p[3].split(' ').filter(|n| !n.is_empty()) {
+                    let name = name.trim_left_matches(|c| "~&@%+".contains(c));
+                    if let Some(i) = Ident::parse(name) { self.joined(&i, &p[2]) }
+                }
+            },
+            ("JOIN", Some(i)) => if let Some(channel) = p.get(0) {
+                self.joined(&i, channel);
+                // extended-join: JOIN <channel> <account> :<realname>
+                if p.len() >= 3 {
+                    let u = self.entry(&i);
+                    u.account = account(&p[1]);
+                    u.realname = Some(p[2].clone());
+                }
+            },
+            ("PART", Some(i)) => if let Some(channel) = p.get(0) { self.left(&i.nickname, channel) },
+            ("KICK", _) => if p.len() >= 2 { self.left(&p[1], &p[0]) },
+            ("QUIT", Some(i)) => { self.users.remove(&mapping.lower(&i.nickname)); },
+            ("NICK", Some(i)) => if let Some(new) = p.get(0) {
+                if self.is_me(&i.nickname) { self.me = Some(new.clone()) }
+                if let Some(mut u) = self.users.remove(&mapping.lower(&i.nickname)) {
+                    u.ident.nickname = new.clone();
+                    self.users.insert(mapping.lower(new), u);
+                }
+            },
+            ("ACCOUNT", Some(i)) => if let Some(a) = p.get(0) {
+                if let Some(u) = self.users.get_mut(&mapping.lower(&i.nickname)) { u.account = account(a) }
+            },
+            ("AWAY", Some(i)) => if let Some(u) = self.users.get_mut(&mapping.lower(&i.nickname)) {
+                u.away = p.get(0).cloned();
+            },
+            ("CHGHOST", Some(i)) => if p.len() >= 2 {
+                if let Some(u) = self.users.get_mut(&mapping.lower(&i.nickname)) {
+                    u.ident.user = Some(p[0].clone());
+                    u.ident.host = Some(p[1].clone());
+                }
+            },
+            ("SETNAME", Some(i)) => if let Some(name) = p.get(0) {
+                if let Some(u) = self.users.get_mut(&mapping.lower(&i.nickname)) { u.realname = Some(name.clone()) }
+            },
+            _ => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use message::Message;
+    use users::Users;
+
+    fn feed(u: &mut Users, lines: &[&str]) {
+        for l in lines { u.handle(&Message::parse(l.as_bytes()).unwrap()) }
+    }
+
+    #[test]
+    fn tracking() {
+        let mut u = Users::new();
+        feed(&mut u, &[
+            ":irc.host 001 me :Welcome",
+            ":me!m@h JOIN #rust * :Me",
+            ":irc.host 353 me = #rust :me @op!o@h",
+            ":nick!u@h JOIN #rust acct :Real Name",
+            ":nick!u@h AWAY :lunch",
+            ":nick!u@h CHGHOST u2 cloak",
+            ":nick!u@h NICK Other"
+        ]);
+        let other = u.get("other").unwrap();
+        assert_eq!(other.account, Some("acct".to_owned()));
+        assert_eq!(other.away, Some("lunch".to_owned()));
+        assert_eq!(other.realname, Some("Real Name".to_owned()));
+        assert_eq!(other.ident.host, Some("cloak".to_owned()));
+        assert_eq!(u.on("#rust").len(), 3);
+
+        feed(&mut u, &[
+            ":Other!u2@cloak ACCOUNT *",
+            ":Other!u2@cloak SETNAME :New Name",
+            ":Other!u2@cloak AWAY"
+        ]);
+        let other = u.get("OTHER").unwrap();
+        assert_eq!(other.account, None);
+        assert_eq!(other.realname, Some("New Name".to_owned()));
+        assert!(!other.is_away());
+
+        feed(&mut u, &[":me!m@h PART #rust"]);
+        assert!(u.get("op").is_none());
+    }
+}
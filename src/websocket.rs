@@ -0,0 +1,215 @@
+//! IRC over WebSocket, with the `text.ircv3.net` and `binary.ircv3.net`
+//! subprotocols. Needs the `websocket` feature.
+
+use std::borrow::ToOwned;
+use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, TcpStream };
+use std::result;
+use std::sync::Arc;
+
+use tungstenite::{ self, WebSocket };
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::header::HeaderValue;
+
+use tls::{ self, TlsConfig };
+use transport::{ self, Interleaved };
+use ::IrscError;
+
+/// The subprotocol the server chose.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// Lines are sent as text frames, so they must be UTF-8.
+    Text,
+    /// Lines are sent as binary frames.
+    Binary
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Protocol::Text => "text.ircv3.net",
+            Protocol::Binary => "binary.ircv3.net"
+        }
+    }
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+struct Inner {
+    socket: WebSocket<Box<Stream>>,
+    /// The rest of the last frame that was read, with its line ending.
+    incoming: Vec<u8>,
+    /// What was written, up to the end of the last complete line.
+    outgoing: Vec<u8>
+}
+
+fn error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed =>
+            io::Error::new(io::ErrorKind::ConnectionAborted, "WebSocket closed"),
+        e => io::Error::new(io::ErrorKind::Other, e.to_string())
+    }
+}
+
+/// A WebSocket connection, that looks like a plain IRC connection: every
+/// line written is sent as one frame, and every frame read as one line,
+/// with `\r\n` added.
+///
+/// Clones share the connection; like `tls::TlsStream`, a blocking read lets
+/// writes from other threads in every few milliseconds.
+#[derive(Clone)]
+pub struct WsStream {
+    inner: Arc<Interleaved<Inner>>,
+    protocol: Protocol,
+    secure: bool,
+    address: (String, u16),
+    socket: Arc<TcpStream>
+}
+
+impl WsStream {
+    /// Close the underlying connection, without a closing handshake.
+    pub fn shutdown(&self) -> io::Result<()> { self.socket.shutdown(Shutdown::Both) }
+
+    pub fn protocol(&self) -> Protocol { self.protocol }
+    pub fn is_secure(&self) -> bool { self.secure }
+
+    /// Host and port of the gateway, from the URL.
+    pub fn address(&self) -> (&str, u16) { (&self.address.0, self.address.1) }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(|inner| {
+            while inner.incoming.is_empty() {
+                let mut line = match inner.socket.read() {
+                    Ok(tungstenite::Message::Text(t)) => t.into_bytes(),
+                    Ok(tungstenite::Message::Binary(b)) => b,
+                    // Pings are answered by tungstenite.
+                    Ok(tungstenite::Message::Close(_)) => return Ok(0),
+                    Ok(_) => continue,
+                    Err(tungstenite::Error::ConnectionClosed) => return Ok(0),
+                    Err(e) => return Err(error(e))
+                };
+                line.extend_from_slice(b"\r\n");
+                inner.incoming = line;
+            }
+            let n = ::std::cmp::min(buf.len(), inner.incoming.len());
+            buf[..n].copy_from_slice(&inner.incoming[..n]);
+            inner.incoming.drain(..n);
+            Ok(n)
+        })
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let protocol = self.protocol;
+        self.inner.write(|inner| {
+            inner.outgoing.extend_from_slice(buf);
+            while let Some(end) = inner.outgoing.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = inner.outgoing.drain(..end + 1).collect();
+                let line = &line[..line.len() - if line.ends_with(b"\r\n") { 2 } else { 1 }];
+                let frame = match protocol {
+                    Protocol::Text => tungstenite::Message::Text(String::from_utf8_lossy(line).into_owned()),
+                    Protocol::Binary => tungstenite::Message::Binary(line.to_owned())
+                };
+                try!(inner.socket.write(frame).map_err(error));
+            }
+            Ok(buf.len())
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.write(|inner| inner.socket.flush().map_err(error))
+    }
+}
+
+/// Connect to `url`, which starts with `ws://` or `wss://`, over the TCP
+/// connection made by `open`. `wss://` uses TLS as set up by `config`.
+pub fn connect<F>(url: &str, open: F, config: &TlsConfig) -> result::Result<WsStream, IrscError>
+where F: FnOnce(&str, u16) -> result::Result<TcpStream, IrscError> {
+    let mut request: Request = try!(url.into_client_request()
+        .map_err(|e| IrscError::Io(error(e))));
+    request.headers_mut().insert("Sec-WebSocket-Protocol",
+        HeaderValue::from_static("binary.ircv3.net, text.ircv3.net"));
+
+    let (secure, host, port) = {
+        let uri = request.uri();
+        let secure = uri.scheme_str() == Some("wss");
+        let host = match uri.host() {
+            Some(h) => h.trim_matches(|c| c == '[' || c == ']').to_owned(),
+            None => return Err(IrscError::NotFound)
+        };
+        (secure, host, uri.port_u16().unwrap_or(if secure { 443 } else { 80 }))
+    };
+    let tcp = try!(open(&host, port));
+    let underlying = Arc::new(try!(tcp.try_clone().map_err(IrscError::Io)));
+    // The TLS session isn't shared on its own, since all of the WebSocket is.
+    let stream: Box<Stream> = if secure {
+        Box::new(try!(tls::handshake(tcp, &host, config)))
+    } else {
+        Box::new(tcp)
+    };
+
+    let (socket, response) = try!(tungstenite::client(request, stream)
+        .map_err(|e| IrscError::Io(io::Error::new(io::ErrorKind::Other, e.to_string()))));
+    // Without a subprotocol, servers usually mean text.
+    let protocol = match response.headers().get("Sec-WebSocket-Protocol").and_then(|p| p.to_str().ok()) {
+        Some("binary.ircv3.net") => Protocol::Binary,
+        _ => Protocol::Text
+    };
+    let inner = Inner { socket: socket, incoming: Vec::new(), outgoing: Vec::new() };
+    let inner = try!(transport::interleave(inner, &underlying).map_err(IrscError::Io));
+    Ok(WsStream {
+        inner: Arc::new(inner),
+        protocol: protocol,
+        secure: secure,
+        address: (host, port),
+        socket: underlying
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ BufRead, BufReader, Write };
+    use std::net::TcpListener;
+    use std::thread;
+
+    use tungstenite::{ self, Message };
+    use tungstenite::handshake::server::{ Request, Response };
+    use tungstenite::http::header::HeaderValue;
+
+    use connect::ConnectOptions;
+    use tls::TlsConfig;
+    use websocket::{ connect, Protocol };
+
+    #[test]
+    fn frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept_hdr(tcp, |_: &Request, mut res: Response| {
+                res.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("binary.ircv3.net"));
+                Ok(res)
+            }).unwrap();
+            assert_eq!(ws.read().unwrap(), Message::Binary(b"NICK irsc".to_vec()));
+            assert_eq!(ws.read().unwrap(), Message::Binary(b"USER irsc 0 * :irsc".to_vec()));
+            ws.send(Message::Binary(b":irc.host 001 irsc :Welcome".to_vec())).unwrap();
+        });
+
+        let url = format!("ws://127.0.0.1:{}/", port);
+        let mut s = connect(&url, |h, p| ConnectOptions::new().connect(h, p), &TlsConfig::new()).unwrap();
+        assert_eq!(s.protocol(), Protocol::Binary);
+        assert_eq!(s.address(), ("127.0.0.1", port));
+        s.write_all(b"NICK irsc\r\nUSER irsc 0 * ").unwrap();
+        s.write_all(b":irsc\r\n").unwrap();
+        s.flush().unwrap();
+        let mut line = String::new();
+        BufReader::new(s).read_line(&mut line).unwrap();
+        assert_eq!(line, ":irc.host 001 irsc :Welcome\r\n");
+    }
+}